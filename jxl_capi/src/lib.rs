@@ -0,0 +1,538 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+//! A subset of the libjxl decoder API, ABI-compatible with `jxl/decode.h`, so
+//! that C and C++ applications can switch decoders without code changes.
+//!
+//! Only the image structure is available: the basic info, the color profile
+//! and frame events. Pixels are not decoded yet, so subscribing to events that
+//! produce them fails.
+
+// Function names follow libjxl.
+#![allow(non_snake_case)]
+
+use jxl::bmff::CONTAINER_SIGNATURE;
+use jxl::decode::streaming::{DecoderEvent, StreamingDecoder};
+use jxl::error::Error;
+use jxl::headers::color_encoding::ColorSpace;
+use jxl::headers::extra_channels::ExtraChannel;
+use jxl::headers::FileHeaders;
+use jxl::icc::synthesize::synthesize_icc;
+use std::collections::VecDeque;
+use std::os::raw::{c_int, c_void};
+use std::{ptr, slice};
+
+pub type JxlBool = c_int;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JxlDecoderStatus {
+    Success = 0,
+    Error = 1,
+    NeedMoreInput = 2,
+    BasicInfo = 0x40,
+    ColorEncoding = 0x100,
+    Frame = 0x400,
+}
+
+const SUPPORTED_EVENTS: c_int = JxlDecoderStatus::BasicInfo as c_int
+    | JxlDecoderStatus::ColorEncoding as c_int
+    | JxlDecoderStatus::Frame as c_int;
+
+/// `JxlColorProfileTarget`: both targets return the same profile, as there
+/// is no pixel output whose color space could differ from the original one.
+const COLOR_PROFILE_TARGET_DATA: c_int = 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JxlPreviewHeader {
+    pub xsize: u32,
+    pub ysize: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JxlAnimationHeader {
+    pub tps_numerator: u32,
+    pub tps_denominator: u32,
+    pub num_loops: u32,
+    pub have_timecodes: JxlBool,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JxlBasicInfo {
+    pub have_container: JxlBool,
+    pub xsize: u32,
+    pub ysize: u32,
+    pub bits_per_sample: u32,
+    pub exponent_bits_per_sample: u32,
+    pub intensity_target: f32,
+    pub min_nits: f32,
+    pub relative_to_max_display: JxlBool,
+    pub linear_below: f32,
+    pub uses_original_profile: JxlBool,
+    pub have_preview: JxlBool,
+    pub have_animation: JxlBool,
+    pub orientation: u32,
+    pub num_color_channels: u32,
+    pub num_extra_channels: u32,
+    pub alpha_bits: u32,
+    pub alpha_exponent_bits: u32,
+    pub alpha_premultiplied: JxlBool,
+    pub preview: JxlPreviewHeader,
+    pub animation: JxlAnimationHeader,
+    pub intrinsic_xsize: u32,
+    pub intrinsic_ysize: u32,
+    pub padding: [u8; 100],
+}
+
+impl JxlBasicInfo {
+    fn new(headers: &FileHeaders, have_container: bool) -> JxlBasicInfo {
+        let metadata = &headers.image_metadata;
+        let tone_mapping = &metadata.tone_mapping;
+        let alpha = metadata
+            .extra_channel_info
+            .iter()
+            .find(|ec| ec.ec_type() == ExtraChannel::Alpha);
+        let (xsize, ysize) = (headers.size.xsize(), headers.size.ysize());
+        let (intrinsic_xsize, intrinsic_ysize) = metadata
+            .intrinsic_size
+            .as_ref()
+            .map_or((xsize, ysize), |s| (s.xsize(), s.ysize()));
+        JxlBasicInfo {
+            have_container: have_container as JxlBool,
+            xsize,
+            ysize,
+            bits_per_sample: metadata.bit_depth.bits_per_sample(),
+            exponent_bits_per_sample: metadata.bit_depth.exponent_bits_per_sample(),
+            intensity_target: tone_mapping.intensity_target,
+            min_nits: tone_mapping.min_nits,
+            relative_to_max_display: tone_mapping.relative_to_max_display as JxlBool,
+            linear_below: tone_mapping.linear_below,
+            uses_original_profile: !metadata.xyb_encoded as JxlBool,
+            have_preview: metadata.preview.is_some() as JxlBool,
+            have_animation: metadata.animation.is_some() as JxlBool,
+            orientation: metadata.orientation as u32,
+            num_color_channels: match metadata.color_encoding.color_space {
+                ColorSpace::Gray => 1,
+                _ => 3,
+            },
+            num_extra_channels: metadata.extra_channel_info.len() as u32,
+            alpha_bits: alpha.map_or(0, |ec| ec.bit_depth().bits_per_sample()),
+            alpha_exponent_bits: alpha.map_or(0, |ec| ec.bit_depth().exponent_bits_per_sample()),
+            alpha_premultiplied: alpha.is_some_and(|ec| ec.alpha_associated()) as JxlBool,
+            preview: JxlPreviewHeader {
+                xsize: metadata.preview.as_ref().map_or(0, |p| p.xsize()),
+                ysize: metadata.preview.as_ref().map_or(0, |p| p.ysize()),
+            },
+            animation: match metadata.animation {
+                Some(ref a) => JxlAnimationHeader {
+                    tps_numerator: a.tps_numerator,
+                    tps_denominator: a.tps_denominator,
+                    num_loops: a.num_loops,
+                    have_timecodes: a.have_timecodes as JxlBool,
+                },
+                None => JxlAnimationHeader {
+                    tps_numerator: 0,
+                    tps_denominator: 0,
+                    num_loops: 0,
+                    have_timecodes: 0,
+                },
+            },
+            intrinsic_xsize,
+            intrinsic_ysize,
+            padding: [0; 100],
+        }
+    }
+}
+
+pub struct JxlDecoder {
+    decoder: StreamingDecoder,
+    events_wanted: c_int,
+    /// The buffer passed to `JxlDecoderSetInput`, and whether it has been
+    /// handed to `decoder` already.
+    input: Option<(*const u8, usize, bool)>,
+    input_closed: bool,
+    /// The first bytes of the file, to tell whether it is a container.
+    prefix: Vec<u8>,
+    events: VecDeque<DecoderEvent>,
+    basic_info: Option<JxlBasicInfo>,
+    icc: Option<Vec<u8>>,
+    finished: bool,
+    failed: bool,
+}
+
+impl JxlDecoder {
+    fn new() -> JxlDecoder {
+        JxlDecoder {
+            decoder: StreamingDecoder::new(),
+            events_wanted: 0,
+            input: None,
+            input_closed: false,
+            prefix: vec![],
+            events: VecDeque::new(),
+            basic_info: None,
+            icc: None,
+            finished: false,
+            failed: false,
+        }
+    }
+
+    fn wants(&self, event: JxlDecoderStatus) -> bool {
+        self.events_wanted & event as c_int != 0
+    }
+
+    // Handles decoder events until one that was subscribed to, feeding the
+    // current input once all of them are handled.
+    fn process_input(&mut self) -> Result<JxlDecoderStatus, Error> {
+        loop {
+            while let Some(event) = self.events.pop_front() {
+                let headers = self.decoder.headers();
+                match event {
+                    DecoderEvent::BasicInfo(_) => {
+                        let have_container = self.prefix.starts_with(&CONTAINER_SIGNATURE);
+                        self.basic_info = Some(JxlBasicInfo::new(headers.unwrap(), have_container));
+                        if self.wants(JxlDecoderStatus::BasicInfo) {
+                            return Ok(JxlDecoderStatus::BasicInfo);
+                        }
+                    }
+                    DecoderEvent::ColorProfile(icc) => {
+                        self.icc = Some(match icc {
+                            Some(icc) => icc,
+                            None => {
+                                synthesize_icc(&headers.unwrap().image_metadata.color_encoding)?
+                            }
+                        });
+                        if self.wants(JxlDecoderStatus::ColorEncoding) {
+                            return Ok(JxlDecoderStatus::ColorEncoding);
+                        }
+                    }
+                    DecoderEvent::FrameStarted(_) => {
+                        if self.wants(JxlDecoderStatus::Frame) {
+                            return Ok(JxlDecoderStatus::Frame);
+                        }
+                    }
+                    DecoderEvent::Finished => self.finished = true,
+                    DecoderEvent::FrameDone(_) | DecoderEvent::NeedsMoreData(_) => {}
+                }
+            }
+            if self.finished {
+                return Ok(JxlDecoderStatus::Success);
+            }
+            match self.input {
+                Some((ptr, size, false)) => {
+                    let data = if size == 0 {
+                        &[]
+                    } else {
+                        // SAFETY: the caller of `JxlDecoderSetInput` keeps the
+                        // buffer alive until it is released.
+                        unsafe { slice::from_raw_parts(ptr, size) }
+                    };
+                    let missing = CONTAINER_SIGNATURE.len().saturating_sub(self.prefix.len());
+                    self.prefix
+                        .extend_from_slice(&data[..missing.min(data.len())]);
+                    self.events.extend(self.decoder.feed(data)?);
+                    self.input = Some((ptr, size, true));
+                }
+                _ if self.input_closed => return Err(Error::FileTruncated),
+                _ => return Ok(JxlDecoderStatus::NeedMoreInput),
+            }
+        }
+    }
+}
+
+/// Returns the library version as `major * 1000000 + minor * 1000 + patch`.
+#[no_mangle]
+pub extern "C" fn JxlDecoderVersion() -> u32 {
+    let part = |v: &str| v.parse::<u32>().unwrap_or(0);
+    part(env!("CARGO_PKG_VERSION_MAJOR")) * 1000000
+        + part(env!("CARGO_PKG_VERSION_MINOR")) * 1000
+        + part(env!("CARGO_PKG_VERSION_PATCH"))
+}
+
+/// Creates a decoder. Custom memory managers are not supported, and passing
+/// one returns null.
+#[no_mangle]
+pub extern "C" fn JxlDecoderCreate(memory_manager: *const c_void) -> *mut JxlDecoder {
+    if !memory_manager.is_null() {
+        return ptr::null_mut();
+    }
+    Box::into_raw(Box::new(JxlDecoder::new()))
+}
+
+/// Returns the decoder to its initial state.
+///
+/// # Safety
+/// `dec` must come from `JxlDecoderCreate`.
+#[no_mangle]
+pub unsafe extern "C" fn JxlDecoderReset(dec: *mut JxlDecoder) {
+    *dec = JxlDecoder::new();
+}
+
+/// # Safety
+/// `dec` must be null or come from `JxlDecoderCreate`, and must not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn JxlDecoderDestroy(dec: *mut JxlDecoder) {
+    if !dec.is_null() {
+        drop(Box::from_raw(dec));
+    }
+}
+
+/// Selects the events reported by `JxlDecoderProcessInput`. Fails for events
+/// that need pixel data.
+///
+/// # Safety
+/// `dec` must come from `JxlDecoderCreate`.
+#[no_mangle]
+pub unsafe extern "C" fn JxlDecoderSubscribeEvents(
+    dec: *mut JxlDecoder,
+    events_wanted: c_int,
+) -> JxlDecoderStatus {
+    if events_wanted & !SUPPORTED_EVENTS != 0 {
+        return JxlDecoderStatus::Error;
+    }
+    (*dec).events_wanted = events_wanted;
+    JxlDecoderStatus::Success
+}
+
+/// Sets the next chunk of input. The previous chunk must have been released.
+///
+/// # Safety
+/// `dec` must come from `JxlDecoderCreate`, and `data` must point to `size`
+/// bytes that stay valid until `JxlDecoderReleaseInput` is called.
+#[no_mangle]
+pub unsafe extern "C" fn JxlDecoderSetInput(
+    dec: *mut JxlDecoder,
+    data: *const u8,
+    size: usize,
+) -> JxlDecoderStatus {
+    let dec = &mut *dec;
+    if dec.input.is_some() || dec.input_closed || (data.is_null() && size != 0) {
+        return JxlDecoderStatus::Error;
+    }
+    dec.input = Some((data, size, false));
+    JxlDecoderStatus::Success
+}
+
+/// Releases the current input and returns how many of its bytes at the end
+/// were not consumed. Input is consumed as a whole, so this is either 0 or the
+/// size of the input if `JxlDecoderProcessInput` was not called.
+///
+/// # Safety
+/// `dec` must come from `JxlDecoderCreate`.
+#[no_mangle]
+pub unsafe extern "C" fn JxlDecoderReleaseInput(dec: *mut JxlDecoder) -> usize {
+    match (*dec).input.take() {
+        Some((_, size, false)) => size,
+        _ => 0,
+    }
+}
+
+/// Marks the current input as the last one.
+///
+/// # Safety
+/// `dec` must come from `JxlDecoderCreate`.
+#[no_mangle]
+pub unsafe extern "C" fn JxlDecoderCloseInput(dec: *mut JxlDecoder) {
+    (*dec).input_closed = true;
+}
+
+/// Decodes until the next subscribed event, the end of the input or the end
+/// of the file. Errors are final.
+///
+/// # Safety
+/// `dec` must come from `JxlDecoderCreate`.
+#[no_mangle]
+pub unsafe extern "C" fn JxlDecoderProcessInput(dec: *mut JxlDecoder) -> JxlDecoderStatus {
+    let dec = &mut *dec;
+    if dec.failed {
+        return JxlDecoderStatus::Error;
+    }
+    dec.process_input().unwrap_or_else(|_| {
+        dec.failed = true;
+        JxlDecoderStatus::Error
+    })
+}
+
+/// Copies the basic info to `info`, if not null. Returns
+/// `JXL_DEC_NEED_MORE_INPUT` before the basic info event.
+///
+/// # Safety
+/// `dec` must come from `JxlDecoderCreate`, and `info` must be null or valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn JxlDecoderGetBasicInfo(
+    dec: *const JxlDecoder,
+    info: *mut JxlBasicInfo,
+) -> JxlDecoderStatus {
+    match (*dec).basic_info {
+        Some(basic_info) => {
+            if !info.is_null() {
+                *info = basic_info;
+            }
+            JxlDecoderStatus::Success
+        }
+        None => JxlDecoderStatus::NeedMoreInput,
+    }
+}
+
+fn icc_profile(dec: &JxlDecoder, target: c_int) -> Result<&[u8], JxlDecoderStatus> {
+    if !(0..=COLOR_PROFILE_TARGET_DATA).contains(&target) {
+        return Err(JxlDecoderStatus::Error);
+    }
+    dec.icc.as_deref().ok_or(JxlDecoderStatus::NeedMoreInput)
+}
+
+/// Writes the size of the ICC profile to `size`. The profile is available
+/// from the color encoding event on, and is synthesized if the file does not
+/// embed one.
+///
+/// # Safety
+/// `dec` must come from `JxlDecoderCreate`, and `size` must be null or valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn JxlDecoderGetICCProfileSize(
+    dec: *const JxlDecoder,
+    target: c_int,
+    size: *mut usize,
+) -> JxlDecoderStatus {
+    match icc_profile(&*dec, target) {
+        Ok(icc) => {
+            if !size.is_null() {
+                *size = icc.len();
+            }
+            JxlDecoderStatus::Success
+        }
+        Err(status) => status,
+    }
+}
+
+/// Copies the ICC profile to `icc_profile`, which must be at least as large
+/// as reported by `JxlDecoderGetICCProfileSize`.
+///
+/// # Safety
+/// `dec` must come from `JxlDecoderCreate`, and `icc_profile` must be valid
+/// for writes of `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn JxlDecoderGetColorAsICCProfile(
+    dec: *const JxlDecoder,
+    target: c_int,
+    icc_profile: *mut u8,
+    size: usize,
+) -> JxlDecoderStatus {
+    match self::icc_profile(&*dec, target) {
+        Ok(icc) if icc.len() > size || icc_profile.is_null() => JxlDecoderStatus::Error,
+        Ok(icc) => {
+            ptr::copy_nonoverlapping(icc.as_ptr(), icc_profile, icc.len());
+            JxlDecoderStatus::Success
+        }
+        Err(status) => status,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jxl::bit_writer::BitWriter;
+
+    // Signature, a 64x64 size header, default metadata and transform data;
+    // the first frame header is missing.
+    fn headers_only() -> Vec<u8> {
+        let mut bw = BitWriter::new();
+        bw.write(8, 0xFF);
+        bw.write(8, 0x0A);
+        bw.write(1, 1);
+        bw.write(5, 7);
+        bw.write(3, 1);
+        bw.write(1, 1);
+        bw.write(1, 1);
+        bw.finalize()
+    }
+
+    #[test]
+    fn test_basic_info() {
+        let file = headers_only();
+        unsafe {
+            let dec = JxlDecoderCreate(ptr::null());
+            let events =
+                JxlDecoderStatus::BasicInfo as c_int | JxlDecoderStatus::ColorEncoding as c_int;
+            assert_eq!(
+                JxlDecoderSubscribeEvents(dec, events),
+                JxlDecoderStatus::Success
+            );
+            assert_eq!(
+                JxlDecoderGetBasicInfo(dec, ptr::null_mut()),
+                JxlDecoderStatus::NeedMoreInput
+            );
+
+            assert_eq!(
+                JxlDecoderSetInput(dec, file.as_ptr(), 1),
+                JxlDecoderStatus::Success
+            );
+            assert_eq!(JxlDecoderProcessInput(dec), JxlDecoderStatus::NeedMoreInput);
+            assert_eq!(JxlDecoderReleaseInput(dec), 0);
+            JxlDecoderSetInput(dec, file[1..].as_ptr(), file.len() - 1);
+            assert_eq!(JxlDecoderProcessInput(dec), JxlDecoderStatus::BasicInfo);
+            let mut info = std::mem::MaybeUninit::<JxlBasicInfo>::uninit();
+            assert_eq!(
+                JxlDecoderGetBasicInfo(dec, info.as_mut_ptr()),
+                JxlDecoderStatus::Success
+            );
+            let info = info.assume_init();
+            assert_eq!((info.xsize, info.ysize), (64, 64));
+            assert_eq!((info.intrinsic_xsize, info.intrinsic_ysize), (64, 64));
+            assert_eq!(info.bits_per_sample, 8);
+            assert_eq!(info.num_color_channels, 3);
+            assert_eq!(info.have_container, 0);
+            assert_eq!(info.uses_original_profile, 0);
+            assert_eq!(info.orientation, 1);
+
+            assert_eq!(JxlDecoderProcessInput(dec), JxlDecoderStatus::ColorEncoding);
+            let mut size = 0;
+            assert_eq!(
+                JxlDecoderGetICCProfileSize(dec, 0, &mut size),
+                JxlDecoderStatus::Success
+            );
+            let mut icc = vec![0; size + 4];
+            assert_eq!(
+                JxlDecoderGetColorAsICCProfile(dec, 0, icc.as_mut_ptr(), size - 1),
+                JxlDecoderStatus::Error
+            );
+            // Larger buffers are accepted, as with libjxl.
+            assert_eq!(
+                JxlDecoderGetColorAsICCProfile(dec, 0, icc.as_mut_ptr(), size + 4),
+                JxlDecoderStatus::Success
+            );
+            assert_eq!(&icc[36..40], b"acsp");
+            assert_eq!(icc[size..], [0; 4]);
+
+            assert_eq!(JxlDecoderProcessInput(dec), JxlDecoderStatus::NeedMoreInput);
+            JxlDecoderReleaseInput(dec);
+            JxlDecoderCloseInput(dec);
+            assert_eq!(JxlDecoderProcessInput(dec), JxlDecoderStatus::Error);
+            JxlDecoderDestroy(dec);
+        }
+    }
+
+    #[test]
+    fn test_unsupported() {
+        unsafe {
+            assert!(JxlDecoderCreate(ptr::dangling()).is_null());
+            let dec = JxlDecoderCreate(ptr::null());
+            // JXL_DEC_FULL_IMAGE
+            assert_eq!(
+                JxlDecoderSubscribeEvents(dec, 0x1000),
+                JxlDecoderStatus::Error
+            );
+            assert_eq!(
+                JxlDecoderGetICCProfileSize(dec, 2, ptr::null_mut()),
+                JxlDecoderStatus::Error
+            );
+            JxlDecoderDestroy(dec);
+        }
+    }
+}
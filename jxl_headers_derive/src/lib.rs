@@ -9,6 +9,7 @@ use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
 use proc_macro_error::{abort, proc_macro_error};
 use quote::quote;
+use std::collections::HashSet;
 use syn::{parse_macro_input, DeriveInput};
 
 fn get_bits(expr_call: &syn::ExprCall) -> syn::Expr {
@@ -169,6 +170,41 @@ fn prettify_condition(cond: &syn::Expr) -> String {
         .replace(" :: ", "::")
 }
 
+// When writing, fields are bound as references to the fields of `self`. This
+// rewrites field names in expressions taken from attributes to dereference
+// them, leaving alone member accesses (`a.field`) and struct field names
+// (`field: value`).
+fn deref_fields(tokens: TokenStream2, fields: &HashSet<String>) -> TokenStream2 {
+    use proc_macro2::{Delimiter, Group, Spacing, TokenTree};
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    let mut out = TokenStream2::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        match tok {
+            TokenTree::Ident(ident) if fields.contains(&ident.to_string()) => {
+                let after_dot =
+                    i > 0 && matches!(&tokens[i - 1], TokenTree::Punct(p) if p.as_char() == '.');
+                let before_colon = matches!(tokens.get(i + 1),
+                    Some(TokenTree::Punct(p)) if p.as_char() == ':' && p.spacing() == Spacing::Alone);
+                if after_dot || before_colon {
+                    out.extend(Some(tok.clone()));
+                } else {
+                    out.extend(Some(TokenTree::Group(Group::new(
+                        Delimiter::Parenthesis,
+                        quote! { *#ident },
+                    ))));
+                }
+            }
+            TokenTree::Group(g) => {
+                let mut group = Group::new(g.delimiter(), deref_fields(g.stream(), fields));
+                group.set_span(g.span());
+                out.extend(Some(TokenTree::Group(group)));
+            }
+            _ => out.extend(Some(tok.clone())),
+        }
+    }
+    out
+}
+
 #[derive(Debug)]
 struct Condition {
     expr: Option<syn::Expr>,
@@ -207,6 +243,7 @@ struct U32 {
     coder: TokenStream2,
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 enum Coder {
     WithoutConfig,
@@ -508,6 +545,70 @@ impl Field {
             }
         }
     }
+
+    // Produces writing code, with all fields bound as references.
+    fn write_fun(
+        &self,
+        all_default_field: &Option<syn::Ident>,
+        fields: &HashSet<String>,
+    ) -> TokenStream2 {
+        let ident = &self.name;
+        let ty = &self.ty;
+        let nonserialized_inits = self
+            .nonserialized_inits
+            .iter()
+            .map(|x| deref_fields(x.clone(), fields));
+        let (cfg_ty, cfg, cnd, write_fn, coder_trait) = match &self.kind {
+            FieldKind::Unconditional(coder) => {
+                let (cfg_ty, cfg) = coder.config(all_default_field);
+                (
+                    cfg_ty,
+                    cfg,
+                    None,
+                    quote! { write_unconditional },
+                    quote! { UnconditionalCoder },
+                )
+            }
+            FieldKind::Conditional(condition, coder) => {
+                let (cfg_ty, cfg) = coder.config(all_default_field);
+                let cnd = condition.get_expr(all_default_field);
+                (
+                    cfg_ty,
+                    cfg,
+                    cnd,
+                    quote! { write_conditional },
+                    quote! { ConditionalCoder },
+                )
+            }
+            FieldKind::Defaulted(condition, coder) => {
+                let (cfg_ty, cfg) = coder.config(all_default_field);
+                let cnd = condition.get_expr(all_default_field);
+                let write_fn = if self.default_element.is_some() {
+                    quote! { write_defaulted_element }
+                } else {
+                    quote! { write_defaulted }
+                };
+                (cfg_ty, cfg, cnd, write_fn, quote! { DefaultedCoder })
+            }
+        };
+        let cfg = deref_fields(cfg, fields);
+        let (cond, cond_arg) = match cnd {
+            Some(cnd) => {
+                let cnd = deref_fields(cnd, fields);
+                (quote! { let cond = #cnd; }, quote! { cond, })
+            }
+            None => (quote! {}, quote! {}),
+        };
+        quote! {
+            {
+                #cond
+                let cfg = #cfg;
+                type NS = <#ty as #coder_trait<#cfg_ty>>::Nonserialized;
+                let nonserialized = NS { #(#nonserialized_inits),* };
+                <#ty>::#write_fn(#ident, &cfg, #cond_arg bw, &nonserialized)?;
+            }
+        }
+    }
 }
 
 fn derive_struct(input: DeriveInput) -> TokenStream2 {
@@ -559,8 +660,30 @@ fn derive_struct(input: DeriveInput) -> TokenStream2 {
         .enumerate()
         .map(|(n, f)| Field::parse(f, n, &mut all_default_field))
         .collect();
-    let fields_read = fields.iter().map(|x| x.read_fun(&all_default_field, trace));
+    let fields_read = fields.iter().map(|x| {
+        let read = x.read_fun(&all_default_field, trace);
+        let ident = &x.name;
+        quote! {
+            #[cfg(feature = "trace")]
+            let trace_bit_offset = br.total_bits_read();
+            #read
+            #[cfg(feature = "trace")]
+            crate::trace::record(
+                concat!(stringify!(#name), ".", stringify!(#ident)),
+                trace_bit_offset,
+                &#ident,
+            );
+        }
+    });
     let fields_names = fields.iter().map(|x| &x.name);
+    let field_set: HashSet<String> = fields.iter().map(|x| x.name.to_string()).collect();
+    let fields_write = fields
+        .iter()
+        .map(|x| x.write_fun(&all_default_field, &field_set));
+    let fields_bind = fields.iter().map(|x| {
+        let ident = &x.name;
+        quote! { let #ident = &self.#ident; }
+    });
 
     let impl_default = if fields.iter().all(|x| x.default.is_some()) {
         let defaults = fields.iter().map(|f| {
@@ -588,9 +711,19 @@ fn derive_struct(input: DeriveInput) -> TokenStream2 {
         quote! {}
     };
 
-    let align = match input.attrs.iter().any(|a| a.path.is_ident("aligned")) {
-        true => quote! { br.jump_to_byte_boundary()?; },
-        false => quote! {},
+    let aligned = input.attrs.iter().any(|a| a.path.is_ident("aligned"));
+    let (align, write_align) = match aligned {
+        true => (
+            quote! { br.jump_to_byte_boundary()?; },
+            quote! { bw.zero_pad_to_byte(); },
+        ),
+        false => (quote! {}, quote! {}),
+    };
+
+    let write_validate = if validate {
+        quote! { self.check(nonserialized)?; }
+    } else {
+        quote! {}
     };
 
     quote! {
@@ -607,10 +740,21 @@ fn derive_struct(input: DeriveInput) -> TokenStream2 {
                 let return_value = #name {
                     #(#fields_names),*
                 };
-                #align
                 #impl_validate
                 Ok(return_value)
             }
+            #[allow(unused_variables)]
+            fn write_unconditional(&self, _: &(), bw: &mut crate::bit_writer::BitWriter, nonserialized: &Self::Nonserialized) -> Result<(), Error> {
+                use crate::headers::encodings::UnconditionalCoder;
+                use crate::headers::encodings::ConditionalCoder;
+                use crate::headers::encodings::DefaultedCoder;
+                use crate::headers::encodings::DefaultedElementCoder;
+                #write_validate
+                #write_align
+                #(#fields_bind)*
+                #(#fields_write)*
+                Ok(())
+            }
         }
     }
 }
@@ -629,6 +773,9 @@ fn derive_enum(input: DeriveInput) -> TokenStream2 {
                     Err(Error::InvalidEnum(u, stringify!(#name).to_string()))
                 }
             }
+            fn write_unconditional(&self, config: &U32Coder, bw: &mut crate::bit_writer::BitWriter, _: &Empty) -> Result<(), Error> {
+                (*self as u32).write_unconditional(config, bw, &Empty{})
+            }
         }
         impl crate::headers::encodings::UnconditionalCoder<()> for #name {
             type Nonserialized = Empty;
@@ -639,6 +786,13 @@ fn derive_enum(input: DeriveInput) -> TokenStream2 {
                         U32::BitsOffset{n: 4, off: 2},
                         U32::BitsOffset{n: 6, off: 18}), br, nonserialized)
             }
+            fn write_unconditional(&self, config: &(), bw: &mut crate::bit_writer::BitWriter, nonserialized: &Empty) -> Result<(), Error> {
+                self.write_unconditional(
+                    &U32Coder::Select(
+                        U32::Val(0), U32::Val(1),
+                        U32::BitsOffset{n: 4, off: 2},
+                        U32::BitsOffset{n: 6, off: 18}), bw, nonserialized)
+            }
         }
     }
 }
@@ -6,9 +6,11 @@
 use crate::error::Error;
 use byteorder::{ByteOrder, LittleEndian};
 
-/// Reads bits from a sequence of bytes.
+/// Reads bits from a sequence of bytes, possibly split into several segments.
 pub struct BitReader<'a> {
     data: &'a [u8],
+    // Segments after `data`.
+    next_segments: &'a [&'a [u8]],
     bit_buf: u64,
     bits_in_buf: usize,
     total_bits_read: usize,
@@ -18,16 +20,55 @@ pub const MAX_BITS_PER_CALL: usize = 56;
 
 impl<'a> BitReader<'a> {
     /// Constructs a BitReader for a given range of data.
-    pub fn new(data: &[u8]) -> BitReader {
+    pub fn new(data: &[u8]) -> BitReader<'_> {
         BitReader {
             data,
+            next_segments: &[],
             bit_buf: 0,
             bits_in_buf: 0,
             total_bits_read: 0,
         }
     }
 
-    /// Reads `num` bits from the buffer without consuming them.
+    /// Constructs a BitReader that reads `segments` one after the other, as if
+    /// they were a single slice.
+    /// ```
+    /// # use jxl::bit_reader::BitReader;
+    /// let segments: [&[u8]; 3] = [&[1], &[], &[2, 3]];
+    /// let mut br = BitReader::new_segmented(&segments);
+    /// assert_eq!(br.read(24)?, 0x030201);
+    /// assert!(br.read(1).is_err());
+    /// # Ok::<(), jxl::error::Error>(())
+    /// ```
+    pub fn new_segmented(segments: &'a [&'a [u8]]) -> BitReader<'a> {
+        match segments.split_first() {
+            Some((first, rest)) => BitReader {
+                data: first,
+                next_segments: rest,
+                bit_buf: 0,
+                bits_in_buf: 0,
+                total_bits_read: 0,
+            },
+            None => BitReader::new(&[]),
+        }
+    }
+
+    /// Reads `num` bits from the buffer without consuming them. Bits past the
+    /// end of the data read as 0; [`BitReader::consume`] reports the overrun.
+    ///
+    /// Together with `consume`, this lets table-driven decoders look ahead by
+    /// the maximum code length and only advance by the length actually used.
+    /// ```
+    /// # use jxl::bit_reader::BitReader;
+    /// let mut br = BitReader::new(&[0b1010_0110]);
+    /// assert_eq!(br.peek(4), 0b0110);
+    /// br.consume(2)?;
+    /// assert_eq!(br.peek(4), 0b1001);
+    /// assert_eq!(br.peek(16), 0b10_1001);
+    /// assert!(br.consume(7).is_err());
+    /// # Ok::<(), jxl::error::Error>(())
+    /// ```
+    #[inline]
     pub fn peek(&mut self, num: usize) -> u64 {
         debug_assert!(num <= MAX_BITS_PER_CALL);
         self.refill();
@@ -35,9 +76,10 @@ impl<'a> BitReader<'a> {
     }
 
     /// Advances by `num` bits. Similar to `skip_bits`, but bits must be in the buffer.
+    #[inline]
     pub fn consume(&mut self, num: usize) -> Result<(), Error> {
         if self.bits_in_buf < num {
-            return Err(Error::OutOfBounds);
+            return Err(Error::OutOfBounds(num - self.bits_in_buf));
         }
         self.bit_buf >>= num;
         self.bits_in_buf -= num;
@@ -56,6 +98,7 @@ impl<'a> BitReader<'a> {
     /// assert!(br.read(1).is_err());
     /// # Ok::<(), jxl::error::Error>(())
     /// ```
+    #[inline]
     pub fn read(&mut self, num: usize) -> Result<u64, Error> {
         let ret = self.peek(num);
         self.consume(num)?;
@@ -74,28 +117,46 @@ impl<'a> BitReader<'a> {
     /// assert_eq!(br.read(8)?, 0);
     /// br.skip_bits(4)?;
     /// assert_eq!(br.total_bits_read(), 12);
+    /// assert_eq!(br.read(4)?, 0);
+    ///
+    /// let data: Vec<u8> = (0..32).collect();
+    /// let mut br = BitReader::new(&data);
+    /// br.read(4)?;
+    /// br.skip_bits(8 * 20 + 4)?;
+    /// assert_eq!(br.read(8)?, 21);
+    /// assert!(br.skip_bits(1000).is_err());
+    /// assert_eq!(br.total_bits_read(), 176);
     /// # Ok::<(), jxl::error::Error>(())
     /// ```
     #[inline(never)]
-    pub fn skip_bits(&mut self, mut num: usize) -> Result<(), Error> {
-        self.total_bits_read += num;
+    pub fn skip_bits(&mut self, num: usize) -> Result<(), Error> {
         if num <= self.bits_in_buf {
             self.bits_in_buf -= num;
             self.bit_buf >>= num;
+            self.total_bits_read += num;
             return Ok(());
         }
-        num -= self.bits_in_buf;
+        let mut remaining = num - self.bits_in_buf;
         self.bits_in_buf = 0;
-        if num > self.data.len() * 8 {
-            return Err(Error::OutOfBounds);
+        self.bit_buf = 0;
+        while remaining >= self.data.len() * 8 && !self.next_segments.is_empty() {
+            remaining -= self.data.len() * 8;
+            self.next_segment();
+        }
+        if remaining > self.data.len() * 8 {
+            return Err(Error::OutOfBounds(remaining - self.data.len() * 8));
         }
-        self.data = &self.data[num / 8..];
+        self.data = &self.data[remaining / 8..];
+        remaining %= 8;
         self.refill();
-        if num > self.bits_in_buf {
-            return Err(Error::OutOfBounds);
+        if remaining > self.bits_in_buf {
+            return Err(Error::OutOfBounds(remaining - self.bits_in_buf));
         }
-        self.bits_in_buf -= num;
-        self.bit_buf >>= num;
+        self.bits_in_buf -= remaining;
+        self.bit_buf >>= remaining;
+        // Only count the bits once they are all skipped, so that errors report
+        // where the skip started.
+        self.total_bits_read += num;
         Ok(())
     }
 
@@ -111,13 +172,14 @@ impl<'a> BitReader<'a> {
     /// ```
     #[inline(never)]
     pub fn jump_to_byte_boundary(&mut self) -> Result<(), Error> {
-        let byte_boundary = (self.total_bits_read + 7) / 8 * 8;
+        let byte_boundary = self.total_bits_read.div_ceil(8) * 8;
         if self.read(byte_boundary - self.total_bits_read)? != 0 {
             return Err(Error::NonZeroPadding);
         }
         Ok(())
     }
 
+    #[inline]
     fn refill(&mut self) {
         // See Refill() in C++ code.
         if self.data.len() >= 8 {
@@ -136,11 +198,20 @@ impl<'a> BitReader<'a> {
     fn refill_slow(&mut self) {
         while self.bits_in_buf < 56 {
             if self.data.is_empty() {
-                return;
+                if self.next_segments.is_empty() {
+                    return;
+                }
+                self.next_segment();
+                continue;
             }
             self.bit_buf |= (self.data[0] as u64) << self.bits_in_buf;
             self.bits_in_buf += 8;
             self.data = &self.data[1..];
         }
     }
+
+    fn next_segment(&mut self) {
+        self.data = self.next_segments[0];
+        self.next_segments = &self.next_segments[1..];
+    }
 }
@@ -0,0 +1,116 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+/// Writes bits to a sequence of bytes, in the order in which [`BitReader`]
+/// reads them.
+///
+/// [`BitReader`]: crate::bit_reader::BitReader
+#[derive(Default)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    bit_buf: u64,
+    bits_in_buf: usize,
+}
+
+pub const MAX_BITS_PER_CALL: usize = 56;
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter::default()
+    }
+
+    /// Writes the `num` lowest bits of `value`, which must not have any other bit set.
+    /// ```
+    /// # use jxl::bit_writer::BitWriter;
+    /// # use jxl::bit_reader::BitReader;
+    /// let mut bw = BitWriter::new();
+    /// bw.write(3, 5);
+    /// bw.write(9, 0x123);
+    /// let data = bw.finalize();
+    /// let mut br = BitReader::new(&data);
+    /// assert_eq!(br.read(3)?, 5);
+    /// assert_eq!(br.read(9)?, 0x123);
+    /// # Ok::<(), jxl::error::Error>(())
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `num` is more than [`MAX_BITS_PER_CALL`], or if `value` does
+    /// not fit in `num` bits.
+    pub fn write(&mut self, num: usize, value: u64) {
+        assert!(num <= MAX_BITS_PER_CALL);
+        assert!(
+            value < (1u64 << num),
+            "{} does not fit in {} bits",
+            value,
+            num
+        );
+        self.bit_buf |= value << self.bits_in_buf;
+        self.bits_in_buf += num;
+        while self.bits_in_buf >= 8 {
+            self.data.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bits_in_buf -= 8;
+        }
+    }
+
+    /// Returns the total number of bits that have been written.
+    pub fn total_bits_written(&self) -> usize {
+        self.data.len() * 8 + self.bits_in_buf
+    }
+
+    /// Pads with zeros up to the next byte boundary.
+    pub fn zero_pad_to_byte(&mut self) {
+        let padding = (8 - self.bits_in_buf % 8) % 8;
+        self.write(padding, 0);
+    }
+
+    /// Pads to a byte boundary, then appends `bytes`.
+    pub fn append_bytes(&mut self, bytes: &[u8]) {
+        self.zero_pad_to_byte();
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Pads to a byte boundary and returns the written bytes.
+    pub fn finalize(mut self) -> Vec<u8> {
+        self.zero_pad_to_byte();
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_reader::BitReader;
+
+    #[test]
+    fn test_roundtrip() -> Result<(), crate::error::Error> {
+        let values: Vec<(usize, u64)> = (0..200)
+            .map(|i| {
+                let num = (i * 7) % (MAX_BITS_PER_CALL + 1);
+                (
+                    num,
+                    (0x9E3779B97F4A7C15u64.wrapping_mul(i as u64 + 1)) & ((1 << num) - 1),
+                )
+            })
+            .collect();
+        let mut bw = BitWriter::new();
+        for (num, value) in values.iter() {
+            bw.write(*num, *value);
+        }
+        let total_bits = bw.total_bits_written();
+        assert_eq!(total_bits, values.iter().map(|(n, _)| n).sum::<usize>());
+        bw.append_bytes(&[0xAB]);
+        let data = bw.finalize();
+        assert_eq!(data.len(), total_bits.div_ceil(8) + 1);
+        let mut br = BitReader::new(&data);
+        for (num, value) in values.iter() {
+            assert_eq!(br.read(*num)?, *value);
+        }
+        br.jump_to_byte_boundary()?;
+        assert_eq!(br.read(8)?, 0xAB);
+        Ok(())
+    }
+}
@@ -3,132 +3,781 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
+use crate::bit_reader::BitReader;
 use crate::error::Error;
+use crate::headers::color_encoding::ColorEncoding;
+use crate::headers::encodings::{Empty, UnconditionalCoder};
+use crate::headers::level::Level;
+use crate::icc::read_icc;
+use crate::util::safe_arith::SafeArith;
 use byteorder::{BigEndian, ByteOrder};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ops::Range;
 
-pub struct JxlCodestream {
-    data: Vec<u8>,
-    codestream_start: usize,
-    codestream_end: usize,
+/// Kind of a metadata box stored in the container next to the codestream.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataKind {
+    /// `Exif` box.
+    Exif,
+    /// `xml ` box, holding XMP.
+    Xmp,
+    /// `jumb` box.
+    Jumbf,
 }
 
-#[derive(PartialEq, Debug)]
-enum State {
-    Empty,
-    Jxlp(usize), // box index
+impl MetadataKind {
+    fn from_box_type(ty: &[u8]) -> Option<MetadataKind> {
+        match ty {
+            b"Exif" => Some(MetadataKind::Exif),
+            b"xml " => Some(MetadataKind::Xmp),
+            b"jumb" => Some(MetadataKind::Jumbf),
+            _ => None,
+        }
+    }
+}
+
+/// Largest decompressed size of a `brob` box. A few bytes of brotli can expand
+/// to gigabytes, so larger boxes are skipped.
+#[cfg(feature = "brotli")]
+const MAX_BROB_SIZE: u64 = 1 << 26;
+
+#[cfg(feature = "brotli")]
+fn decompress_brotli(data: &[u8], max_size: u64) -> Result<Vec<u8>, Error> {
+    use std::io::Read;
+    let mut decompressed = vec![];
+    brotli_decompressor::Decompressor::new(data, 4096)
+        .take(max_size + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| Error::InvalidBrotli)?;
+    if decompressed.len() as u64 > max_size {
+        return Err(Error::InvalidBrotli);
+    }
+    Ok(decompressed)
+}
+
+/// Payload of a metadata box, kept so that it can be copied as-is when
+/// transcoding. Boxes stored in `brob` boxes are decompressed with the `brotli`
+/// feature, and skipped without it.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataBox {
+    pub kind: MetadataKind,
+    pub data: Vec<u8>,
+}
+
+/// A frame listed in a `jxli` box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexedFrame {
+    /// Byte offset of the frame header in the codestream.
+    pub offset: u64,
+    /// Index of the frame among the displayed frames.
+    pub frame: u64,
+    /// Duration in ticks until the next indexed frame.
+    pub ticks: u64,
+}
+
+/// Contents of a `jxli` box, listing frames that can be decoded without the
+/// frames before them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JxliBox {
+    /// Numerator and denominator of the duration of a tick, in seconds.
+    pub tick_duration: (u32, u32),
+    pub frames: Vec<IndexedFrame>,
+}
+
+impl JxliBox {
+    pub fn parse(data: &[u8]) -> Result<JxliBox, Error> {
+        let mut pos = 0;
+        let num_frames = read_varint(data, &mut pos)?;
+        if data.len() < pos + 8 {
+            return Err(Error::InvalidBox);
+        }
+        let tick_duration = (
+            BigEndian::read_u32(&data[pos..]),
+            BigEndian::read_u32(&data[pos + 4..]),
+        );
+        pos += 8;
+        // Each frame takes at least 3 bytes; don't trust the count to allocate.
+        let mut frames = Vec::with_capacity((num_frames as usize).min(data.len() / 3));
+        let (mut offset, mut frame) = (0u64, 0u64);
+        for _ in 0..num_frames {
+            // Offsets and frame counts are relative to the previous indexed frame.
+            offset = offset.safe_add(read_varint(data, &mut pos)?)?;
+            let ticks = read_varint(data, &mut pos)?;
+            frames.push(IndexedFrame {
+                offset,
+                frame,
+                ticks,
+            });
+            frame = frame.safe_add(read_varint(data, &mut pos)?)?;
+        }
+        Ok(JxliBox {
+            tick_duration,
+            frames,
+        })
+    }
+}
+
+/// Contents of a `jhgm` box: a gain map that turns the image into an
+/// alternate rendition, usually an HDR one, following ISO 21496-1.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone)]
+pub struct GainMapBox {
+    /// ISO 21496-1 gain map metadata, in its binary encoding.
+    pub metadata: Vec<u8>,
+    /// Color encoding of the alternate rendition.
+    pub color_encoding: Option<ColorEncoding>,
+    /// ICC profile of the alternate rendition.
+    pub alt_icc: Option<Vec<u8>>,
+    /// The gain map image, as a bare codestream.
+    pub gain_map: Vec<u8>,
+}
+
+impl GainMapBox {
+    pub fn parse(data: &[u8]) -> Result<GainMapBox, Error> {
+        let mut pos = 0;
+        let mut take = |len: usize| -> Result<&[u8], Error> {
+            let bytes = data.get(pos..pos.safe_add(len)?).ok_or(Error::InvalidBox)?;
+            pos += len;
+            Ok(bytes)
+        };
+        // Only version 0 is defined.
+        if take(1)?[0] != 0 {
+            return Err(Error::InvalidBox);
+        }
+        let metadata_size = BigEndian::read_u16(take(2)?) as usize;
+        let metadata = take(metadata_size)?.to_vec();
+        let color_encoding = match take(1)?[0] as usize {
+            0 => None,
+            size => {
+                let mut br = BitReader::new(take(size)?);
+                Some(ColorEncoding::read_unconditional(&(), &mut br, &Empty {})?)
+            }
+        };
+        let alt_icc = match BigEndian::read_u32(take(4)?) as usize {
+            0 => None,
+            size => Some(read_icc(&mut BitReader::new(take(size)?))?),
+        };
+        Ok(GainMapBox {
+            metadata,
+            color_encoding,
+            alt_icc,
+            gain_map: data[pos..].to_vec(),
+        })
+    }
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or(Error::InvalidBox)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::InvalidBox)
+}
+
+/// The codestream of a file, as the ranges of the file that hold it.
+pub struct JxlCodestream<'a> {
+    data: Cow<'a, [u8]>,
+    parts: Vec<Range<usize>>,
+    level: Level,
+    metadata: Vec<MetadataBox>,
+    frame_index: Option<JxliBox>,
+    gain_map: Option<GainMapBox>,
 }
 
-impl JxlCodestream {
-    pub fn get(&self) -> &[u8] {
-        &self.data[self.codestream_start..self.codestream_end]
+impl<'a> JxlCodestream<'a> {
+    /// Returns the codestream, which is only copied if it is split into
+    /// several `jxlp` boxes.
+    pub fn get(&self) -> Cow<'_, [u8]> {
+        match &self.parts[..] {
+            [part] => Cow::Borrowed(&self.data[part.clone()]),
+            parts => Cow::Owned(
+                parts
+                    .iter()
+                    .flat_map(|p| &self.data[p.clone()])
+                    .copied()
+                    .collect(),
+            ),
+        }
+    }
+    /// Returns the pieces of the codestream in order, to be read with
+    /// [`BitReader::new_segmented`](crate::bit_reader::BitReader::new_segmented).
+    pub fn segments(&self) -> Vec<&[u8]> {
+        self.parts.iter().map(|p| &self.data[p.clone()]).collect()
+    }
+    /// Returns the level signalled by the `jxll` box, or level 5 if there is none.
+    pub fn level(&self) -> Level {
+        self.level
+    }
+    /// Returns the payload of the first `Exif` box, if any.
+    pub fn exif(&self) -> Option<&[u8]> {
+        self.metadata
+            .iter()
+            .find(|b| b.kind == MetadataKind::Exif)
+            .map(|b| &b.data[..])
+    }
+    /// Returns the metadata boxes of the container, in file order.
+    pub fn metadata(&self) -> &[MetadataBox] {
+        &self.metadata
     }
-    pub fn new(data: Vec<u8>) -> Result<JxlCodestream, Error> {
+    /// Returns the contents of the `jxli` box, if any.
+    pub fn frame_index(&self) -> Option<&JxliBox> {
+        self.frame_index.as_ref()
+    }
+    /// Returns the contents of the `jhgm` box, if any.
+    pub fn gain_map(&self) -> Option<&GainMapBox> {
+        self.gain_map.as_ref()
+    }
+    pub fn new(data: Vec<u8>) -> Result<JxlCodestream<'static>, Error> {
+        JxlCodestream::parse(Cow::Owned(data))
+    }
+    /// Like [`JxlCodestream::new`], but borrows the file.
+    pub fn from_slice(data: &'a [u8]) -> Result<JxlCodestream<'a>, Error> {
+        JxlCodestream::parse(Cow::Borrowed(data))
+    }
+    fn parse(data: Cow<'a, [u8]>) -> Result<JxlCodestream<'a>, Error> {
         // Box-based file format.
-        if data.starts_with(&[
-            0x00, 0x00, 0x00, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A,
-        ]) {
-            let mut state = State::Empty;
-            let mut assembled_codestream = vec![];
+        if data.starts_with(&CONTAINER_SIGNATURE) {
+            let mut level = Level::Level5;
+            let mut metadata = vec![];
+            let mut frame_index = None;
+            let mut gain_map = None;
+            let mut codestream_done = false;
+            let mut parts = vec![];
+            let mut next_jxlp = 0u32;
             let mut pos = 0usize;
-            loop {
-                if let State::Jxlp(_) = state {
-                    if pos >= data.len() {
-                        return Err(Error::FileTruncated);
-                    }
-                }
-                if pos + 8 >= data.len() {
+            while pos < data.len() {
+                if pos + 8 > data.len() {
                     return Err(Error::FileTruncated);
                 }
                 let box_start = pos;
-                let mut box_size: usize = BigEndian::read_u32(&data[pos..]) as usize;
-                pos += 4;
-                let ty = &data[pos..pos + 4];
-                pos += 4;
-                if pos + 8 >= data.len() {
-                    return Err(Error::FileTruncated);
-                }
+                let mut box_size = BigEndian::read_u32(&data[pos..]) as u64;
+                let ty = &data[pos + 4..pos + 8];
+                pos += 8;
                 if box_size == 1 {
-                    let sz = BigEndian::read_u64(&data[pos..]);
-                    if sz >= usize::MAX as u64 {
-                        return Err(Error::InvalidBox);
+                    if pos + 8 > data.len() {
+                        return Err(Error::FileTruncated);
                     }
-                    box_size = sz as usize;
+                    box_size = BigEndian::read_u64(&data[pos..]);
                     pos += 8;
                 }
-                if box_start + box_size > data.len() {
-                    return Err(Error::FileTruncated);
-                }
                 let eof_box = box_size == 0;
-                if box_size == 0 {
-                    box_size = data.len() - box_start;
-                }
-                let mut handle_jxlp =
-                    |jxlp_idx: Option<usize>| -> Result<Option<JxlCodestream>, Error> {
-                        if box_size <= 4 {
+                let box_end = if eof_box {
+                    data.len()
+                } else if box_size < (pos - box_start) as u64 {
+                    return Err(Error::InvalidBox);
+                } else if (box_start as u64).safe_add(box_size)? > data.len() as u64 {
+                    return Err(Error::FileTruncated);
+                } else {
+                    box_start + box_size as usize
+                };
+                match ty {
+                    b"jxlc" => {
+                        // Can't mix jxlp and jxlc, or have two codestreams.
+                        if codestream_done || next_jxlp != 0 {
+                            return Err(Error::InvalidBox);
+                        }
+                        parts.push(pos..box_end);
+                        codestream_done = true;
+                    }
+                    b"jxlp" => {
+                        if codestream_done || box_end < pos + 4 {
                             return Err(Error::InvalidBox);
                         }
                         let jxlp_count_and_last = BigEndian::read_u32(&data[pos..]);
-                        pos += 4;
                         let jxlp_count = jxlp_count_and_last & ((1u32 << 31) - 1);
-                        if jxlp_count as usize != jxlp_idx.map_or_else(|| 0, |x| x + 1) {
+                        if jxlp_count != next_jxlp {
                             return Err(Error::InvalidBox);
                         }
+                        next_jxlp += 1;
                         let jxlp_is_last = jxlp_count_and_last >= (1u32 << 31);
                         if eof_box && !jxlp_is_last {
                             return Err(Error::InvalidBox);
                         }
-                        assembled_codestream.extend_from_slice(&data[pos..box_size + box_start]);
-                        if !jxlp_is_last {
-                            Ok(None)
-                        } else {
-                            let mut cs = vec![];
-                            std::mem::swap(&mut cs, &mut assembled_codestream);
-                            let len = cs.len();
-                            Ok(Some(JxlCodestream {
-                                data: cs,
-                                codestream_start: 0,
-                                codestream_end: len,
-                            }))
+                        parts.push(pos + 4..box_end);
+                        codestream_done = jxlp_is_last;
+                    }
+                    b"jxll" => {
+                        // The level must be known before the codestream starts.
+                        if codestream_done || next_jxlp != 0 || box_end != pos + 1 {
+                            return Err(Error::InvalidBox);
+                        }
+                        level = Level::from_jxll(data[pos])?;
+                    }
+                    b"jxli" => {
+                        if frame_index.is_some() {
+                            return Err(Error::InvalidBox);
                         }
-                    };
-                println!("{:?}", std::str::from_utf8(ty));
-                match (ty, &state) {
-                    (b"jxlc", State::Empty) => {
-                        break Ok(JxlCodestream {
-                            data,
-                            codestream_start: pos,
-                            codestream_end: box_size + box_start,
-                        });
-                    }
-                    (b"jxlc", State::Jxlp(_)) => {
-                        // Can't mix jxlp and jxlc.
-                        return Err(Error::InvalidBox);
+                        // The index is only a seeking hint: drop it if it is
+                        // broken, and let callers scan the frames instead.
+                        frame_index = JxliBox::parse(&data[pos..box_end]).ok();
                     }
-                    (b"jxlp", State::Empty) => {
-                        if let Some(cs) = handle_jxlp(None)? {
-                            break Ok(cs);
+                    b"jhgm" => {
+                        if gain_map.is_some() {
+                            return Err(Error::InvalidBox);
+                        }
+                        // The base image doesn't depend on the gain map.
+                        gain_map = GainMapBox::parse(&data[pos..box_end]).ok();
+                    }
+                    b"brob" => {
+                        if box_end < pos + 4 {
+                            return Err(Error::InvalidBox);
+                        }
+                        let inner_ty = &data[pos..pos + 4];
+                        // Boxes that structure the file can't be compressed.
+                        if matches!(
+                            inner_ty,
+                            b"JXL "
+                                | b"ftyp"
+                                | b"jxlp"
+                                | b"jxlc"
+                                | b"jxll"
+                                | b"jxli"
+                                | b"jbrd"
+                                | b"brob"
+                        ) {
+                            return Err(Error::InvalidBox);
+                        }
+                        // Broken metadata doesn't affect the image, so it is
+                        // skipped like it would be without the feature.
+                        #[cfg(feature = "brotli")]
+                        if let Some(kind) = MetadataKind::from_box_type(inner_ty) {
+                            if let Ok(data) =
+                                decompress_brotli(&data[pos + 4..box_end], MAX_BROB_SIZE)
+                            {
+                                metadata.push(MetadataBox { kind, data });
+                            }
                         }
-                        state = State::Jxlp(0);
                     }
-                    (b"jxlp", &State::Jxlp(idx)) => {
-                        if let Some(cs) = handle_jxlp(Some(idx))? {
-                            break Ok(cs);
+                    _ => {
+                        if let Some(kind) = MetadataKind::from_box_type(ty) {
+                            metadata.push(MetadataBox {
+                                kind,
+                                data: data[pos..box_end].to_vec(),
+                            });
                         }
-                        state = State::Jxlp(idx + 1);
                     }
-                    _ => {}
                 }
-                pos = box_size + box_start;
+                pos = box_end;
+            }
+            if !codestream_done {
+                return Err(Error::FileTruncated);
             }
+            Ok(JxlCodestream {
+                data,
+                parts,
+                level,
+                metadata,
+                frame_index,
+                gain_map,
+            })
         } else if data.starts_with(&[0xff, 0x0A]) {
-            let codestream_end = data.len();
             Ok(JxlCodestream {
+                parts: std::iter::once(0..data.len()).collect(),
                 data,
-                codestream_start: 0usize,
-                codestream_end,
+                level: Level::Level5,
+                metadata: vec![],
+                frame_index: None,
+                gain_map: None,
             })
+        } else if data.len() < 2 {
+            Err(Error::FileTruncated)
         } else {
             Err(Error::InvalidSignature(data[0], data[1]))
         }
     }
 }
+
+/// The start of a codestream found in a possibly incomplete file.
+pub enum CodestreamPrefix {
+    /// The codestream bytes that are available so far, and the codestream level.
+    /// `complete` is true if the whole codestream is available.
+    Codestream {
+        data: Vec<u8>,
+        level: Level,
+        complete: bool,
+    },
+    /// At least this many more bytes of the file are needed to find the codestream.
+    NeedMoreData(usize),
+}
+
+/// The first box of every container file.
+pub const CONTAINER_SIGNATURE: [u8; 12] = [
+    0x00, 0x00, 0x00, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+];
+
+/// Extracts as much of the codestream as possible from the beginning of a file,
+/// without requiring the file to be complete.
+pub fn codestream_prefix(data: &[u8]) -> Result<CodestreamPrefix, Error> {
+    if data.len() < 2 {
+        return Ok(CodestreamPrefix::NeedMoreData(2 - data.len()));
+    }
+    if data.starts_with(&[0xff, 0x0a]) {
+        return Ok(CodestreamPrefix::Codestream {
+            data: data.to_vec(),
+            level: Level::Level5,
+            complete: false,
+        });
+    }
+    let signature_len = data.len().min(CONTAINER_SIGNATURE.len());
+    if data[..signature_len] != CONTAINER_SIGNATURE[..signature_len] {
+        return Err(Error::InvalidSignature(data[0], data[1]));
+    }
+    if signature_len < CONTAINER_SIGNATURE.len() {
+        return Ok(CodestreamPrefix::NeedMoreData(
+            CONTAINER_SIGNATURE.len() - signature_len,
+        ));
+    }
+
+    let mut level = Level::Level5;
+    let mut codestream = vec![];
+    let mut next_jxlp = 0;
+    let mut pos = CONTAINER_SIGNATURE.len();
+    loop {
+        if pos + 8 > data.len() {
+            return Ok(match (next_jxlp, pos == data.len()) {
+                (0, _) => CodestreamPrefix::NeedMoreData(pos + 8 - data.len()),
+                (_, true) if codestream.is_empty() => CodestreamPrefix::NeedMoreData(8),
+                _ => CodestreamPrefix::Codestream {
+                    data: codestream,
+                    level,
+                    complete: false,
+                },
+            });
+        }
+        let box_start = pos;
+        let mut header_size = 8;
+        let mut box_size = BigEndian::read_u32(&data[pos..]) as u64;
+        let ty = &data[pos + 4..pos + 8];
+        if box_size == 1 {
+            if pos + 16 > data.len() {
+                return Ok(CodestreamPrefix::NeedMoreData(pos + 16 - data.len()));
+            }
+            box_size = BigEndian::read_u64(&data[pos + 8..]);
+            header_size = 16;
+        }
+        let box_end = if box_size == 0 {
+            None
+        } else if box_size < header_size {
+            return Err(Error::InvalidBox);
+        } else {
+            Some((box_start as u64).safe_add(box_size)?)
+        };
+        pos += header_size as usize;
+        let available_end = match box_end {
+            Some(end) if end <= data.len() as u64 => end as usize,
+            _ => data.len(),
+        };
+        let box_complete = available_end as u64 == box_end.unwrap_or(u64::MAX);
+        match ty {
+            b"jxll" => {
+                if next_jxlp != 0 || box_end != Some(pos as u64 + 1) {
+                    return Err(Error::InvalidBox);
+                }
+                if pos >= data.len() {
+                    return Ok(CodestreamPrefix::NeedMoreData(1));
+                }
+                level = Level::from_jxll(data[pos])?;
+            }
+            b"jxlc" => {
+                if next_jxlp != 0 {
+                    return Err(Error::InvalidBox);
+                }
+                return Ok(CodestreamPrefix::Codestream {
+                    data: data[pos..available_end].to_vec(),
+                    level,
+                    complete: box_complete,
+                });
+            }
+            b"jxlp" => {
+                if pos + 4 > available_end {
+                    if box_complete {
+                        return Err(Error::InvalidBox);
+                    }
+                    return Ok(CodestreamPrefix::NeedMoreData(pos + 4 - data.len()));
+                }
+                let index_and_last = BigEndian::read_u32(&data[pos..]);
+                if index_and_last & 0x7fffffff != next_jxlp {
+                    return Err(Error::InvalidBox);
+                }
+                codestream.extend_from_slice(&data[pos + 4..available_end]);
+                next_jxlp += 1;
+                let is_last = index_and_last & 0x80000000 != 0;
+                if !box_complete || is_last {
+                    return Ok(CodestreamPrefix::Codestream {
+                        data: codestream,
+                        level,
+                        complete: box_complete,
+                    });
+                }
+            }
+            _ => {}
+        }
+        match box_end {
+            Some(end) if end <= data.len() as u64 => pos = end as usize,
+            Some(end) => {
+                return Ok(CodestreamPrefix::NeedMoreData(
+                    (end - data.len() as u64 + 8) as usize,
+                ))
+            }
+            None => return Err(Error::InvalidBox),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ContainerState {
+    Signature,
+    BareCodestream,
+    BoxHeader,
+    // States for box payloads hold the number of bytes left in the payload, or
+    // `None` if the box extends to the end of the file.
+    Jxll,
+    JxlpIndex {
+        remaining: Option<u64>,
+    },
+    Codestream {
+        remaining: Option<u64>,
+        is_last: bool,
+    },
+    Skip {
+        remaining: Option<u64>,
+    },
+    Done,
+}
+
+/// Extracts the codestream from a file that arrives in chunks. Codestream bytes
+/// are handed out as soon as they arrive, and only the container bytes that
+/// cannot be parsed yet are buffered.
+#[derive(Debug)]
+pub struct ContainerParser {
+    state: ContainerState,
+    // The incomplete signature, box header or small box payload being read.
+    pending: Vec<u8>,
+    level: Level,
+    next_jxlp: u32,
+}
+
+impl Default for ContainerParser {
+    fn default() -> Self {
+        ContainerParser::new()
+    }
+}
+
+impl ContainerParser {
+    pub fn new() -> ContainerParser {
+        ContainerParser {
+            state: ContainerState::Signature,
+            pending: vec![],
+            level: Level::Level5,
+            next_jxlp: 0,
+        }
+    }
+
+    /// Parses the next chunk of the file, appending the codestream bytes it
+    /// contains to `codestream`.
+    pub fn process_bytes(
+        &mut self,
+        mut data: &[u8],
+        codestream: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        while !data.is_empty() {
+            match self.state {
+                ContainerState::Signature => {
+                    self.fill(&mut data, 2.max(self.pending.len()));
+                    if self.pending.len() < 2 {
+                        continue;
+                    }
+                    if self.pending.starts_with(&[0xff, 0x0a]) {
+                        codestream.append(&mut self.pending);
+                        self.state = ContainerState::BareCodestream;
+                        continue;
+                    }
+                    let len = self.pending.len();
+                    if self.pending[..] != CONTAINER_SIGNATURE[..len] {
+                        return Err(Error::InvalidSignature(self.pending[0], self.pending[1]));
+                    }
+                    if self.fill(&mut data, CONTAINER_SIGNATURE.len()) {
+                        if self.pending != CONTAINER_SIGNATURE {
+                            return Err(Error::InvalidSignature(self.pending[0], self.pending[1]));
+                        }
+                        self.pending.clear();
+                        self.state = ContainerState::BoxHeader;
+                    }
+                }
+                ContainerState::BareCodestream => {
+                    codestream.extend_from_slice(data);
+                    data = &[];
+                }
+                ContainerState::BoxHeader => {
+                    if !self.fill(&mut data, 8) {
+                        continue;
+                    }
+                    let mut header_size = 8;
+                    let mut box_size = BigEndian::read_u32(&self.pending) as u64;
+                    if box_size == 1 {
+                        if !self.fill(&mut data, 16) {
+                            continue;
+                        }
+                        box_size = BigEndian::read_u64(&self.pending[8..]);
+                        header_size = 16;
+                    }
+                    let remaining = match box_size {
+                        0 => None,
+                        size if size < header_size => return Err(Error::InvalidBox),
+                        size => Some(size - header_size),
+                    };
+                    self.state = match &self.pending[4..8] {
+                        b"jxll" if self.next_jxlp == 0 && remaining == Some(1) => {
+                            ContainerState::Jxll
+                        }
+                        b"jxlc" if self.next_jxlp == 0 => ContainerState::Codestream {
+                            remaining,
+                            is_last: true,
+                        },
+                        b"jxlp" if remaining.is_none_or(|r| r >= 4) => {
+                            ContainerState::JxlpIndex { remaining }
+                        }
+                        b"jxll" | b"jxlc" | b"jxlp" => return Err(Error::InvalidBox),
+                        _ => ContainerState::Skip { remaining },
+                    };
+                    self.pending.clear();
+                }
+                ContainerState::Jxll => {
+                    if self.fill(&mut data, 1) {
+                        self.level = Level::from_jxll(self.pending[0])?;
+                        self.pending.clear();
+                        self.state = ContainerState::BoxHeader;
+                    }
+                }
+                ContainerState::JxlpIndex { remaining } => {
+                    if !self.fill(&mut data, 4) {
+                        continue;
+                    }
+                    let index_and_last = BigEndian::read_u32(&self.pending);
+                    let is_last = index_and_last & 0x80000000 != 0;
+                    if index_and_last & 0x7fffffff != self.next_jxlp
+                        || (remaining.is_none() && !is_last)
+                    {
+                        return Err(Error::InvalidBox);
+                    }
+                    self.next_jxlp += 1;
+                    self.pending.clear();
+                    self.state = ContainerState::Codestream {
+                        remaining: remaining.map(|r| r - 4),
+                        is_last,
+                    };
+                }
+                ContainerState::Codestream {
+                    ref mut remaining,
+                    is_last,
+                } => {
+                    codestream.extend_from_slice(Self::take_payload(remaining, &mut data));
+                    if *remaining == Some(0) {
+                        self.state = match is_last {
+                            true => ContainerState::Done,
+                            false => ContainerState::BoxHeader,
+                        };
+                    }
+                }
+                ContainerState::Skip { ref mut remaining } => {
+                    Self::take_payload(remaining, &mut data);
+                    if *remaining == Some(0) {
+                        self.state = ContainerState::BoxHeader;
+                    }
+                }
+                ContainerState::Done => data = &[],
+            }
+            // Empty codestream boxes end without any payload byte.
+            match self.state {
+                ContainerState::Codestream {
+                    remaining: Some(0),
+                    is_last: true,
+                } => self.state = ContainerState::Done,
+                ContainerState::Codestream {
+                    remaining: Some(0),
+                    is_last: false,
+                }
+                | ContainerState::Skip { remaining: Some(0) } => {
+                    self.state = ContainerState::BoxHeader
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The codestream level signalled in the container. It is final once the
+    /// first codestream bytes have been returned.
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// Whether the whole codestream has been returned. This is never the case
+    /// for bare codestreams, whose end is not marked.
+    pub fn is_complete(&self) -> bool {
+        matches!(self.state, ContainerState::Done)
+    }
+
+    /// At least this many more bytes of the file are needed before more of the
+    /// codestream can be returned.
+    pub fn bytes_needed(&self) -> usize {
+        let pending = self.pending.len();
+        match self.state {
+            ContainerState::Signature if pending < 2 => 2 - pending,
+            ContainerState::Signature => CONTAINER_SIGNATURE.len() - pending,
+            ContainerState::BoxHeader if pending >= 8 => 16 - pending,
+            ContainerState::BoxHeader => 8 - pending,
+            ContainerState::JxlpIndex { .. } => 4 - pending,
+            ContainerState::Skip {
+                remaining: Some(remaining),
+            } => usize::try_from(remaining.saturating_add(8)).unwrap_or(usize::MAX),
+            _ => 1,
+        }
+    }
+
+    // Moves bytes from `data` to the pending buffer until it holds `len`
+    // bytes, and returns whether it does.
+    fn fill(&mut self, data: &mut &[u8], len: usize) -> bool {
+        let n = len.saturating_sub(self.pending.len()).min(data.len());
+        self.pending.extend_from_slice(&data[..n]);
+        *data = &data[n..];
+        self.pending.len() >= len
+    }
+
+    fn take_payload<'d>(remaining: &mut Option<u64>, data: &mut &'d [u8]) -> &'d [u8] {
+        let n = match *remaining {
+            Some(r) => (data.len() as u64).min(r) as usize,
+            None => data.len(),
+        };
+        if let Some(r) = remaining {
+            *r -= n as u64;
+        }
+        let (payload, rest) = data.split_at(n);
+        *data = rest;
+        payload
+    }
+}
+
+#[cfg(all(test, feature = "brotli"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_brotli_size_limit() {
+        // "<x:xmpmeta/>" three times.
+        let compressed = [
+            27, 35, 0, 248, 157, 9, 54, 46, 168, 119, 199, 120, 137, 231, 195, 83, 47, 116, 165,
+            211, 214, 97, 101, 109, 112, 130, 168, 88, 64, 133, 103, 12,
+        ];
+        assert_eq!(decompress_brotli(&compressed, 36).unwrap().len(), 36);
+        assert!(decompress_brotli(&compressed, 35).is_err());
+    }
+}
@@ -0,0 +1,1099 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::bit_reader::BitReader;
+use crate::bmff::{
+    codestream_prefix, CodestreamPrefix, GainMapBox, JxlCodestream, MetadataBox, MetadataKind,
+};
+use crate::decode::options::DecoderOptions;
+use crate::entropy_coding::stats::EntropyStats;
+use crate::error::{Error, ErrorLocation};
+use crate::exif::{exif_orientation, OrientationPolicy};
+use crate::headers::encodings::UnconditionalCoder;
+use crate::headers::extra_channels::ExtraChannelInfo;
+use crate::headers::frame_header::{FrameHeader, FrameHeaderNonserialized, FrameType};
+use crate::headers::level::Level;
+use crate::headers::toc::{Section, Toc};
+use crate::headers::{FileHeaders, JxlHeader, Orientation};
+use crate::icc::profile::IccProfile;
+use crate::icc::synthesize::synthesize_icc;
+use crate::icc::{read_icc, read_icc_with_stats};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ops::Range;
+
+#[cfg(feature = "async")]
+pub mod event_stream;
+pub mod frame_index;
+pub mod options;
+pub mod range_fetch;
+pub mod streaming;
+
+/// Basic properties of an image, available as soon as the file headers are.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicInfo {
+    pub xsize: u32,
+    pub ysize: u32,
+    pub orientation: Orientation,
+    pub bits_per_sample: u32,
+    pub floating_point_sample: bool,
+    pub num_extra_channels: usize,
+    pub have_alpha: bool,
+    pub have_animation: bool,
+    pub have_preview: bool,
+    pub xyb_encoded: bool,
+    pub level: Level,
+}
+
+impl BasicInfo {
+    pub fn new(headers: &FileHeaders, level: Level) -> BasicInfo {
+        let metadata = &headers.image_metadata;
+        BasicInfo {
+            xsize: headers.size.xsize(),
+            ysize: headers.size.ysize(),
+            orientation: metadata.orientation,
+            bits_per_sample: metadata.bit_depth.bits_per_sample(),
+            floating_point_sample: metadata.bit_depth.floating_point_sample(),
+            num_extra_channels: metadata.extra_channel_info.len(),
+            have_alpha: metadata.extra_channel_info.iter().any(|ec| ec.is_alpha()),
+            have_animation: metadata.animation.is_some(),
+            have_preview: metadata.preview.is_some(),
+            xyb_encoded: metadata.xyb_encoded,
+            level,
+        }
+    }
+}
+
+/// Outcome of probing the beginning of a file.
+#[derive(Debug, PartialEq)]
+pub enum PeekResult {
+    Info(BasicInfo),
+    /// At least this many more bytes are needed before the basic info can be
+    /// determined.
+    NeedMoreData(usize),
+}
+
+/// Reads the basic info of an image from the beginning of a file, which may be
+/// truncated at any point. Either returns the basic info or the minimum number of
+/// additional bytes that are needed to make progress.
+pub fn peek_info(prefix: &[u8]) -> Result<PeekResult, Error> {
+    let (codestream, level, complete) = match codestream_prefix(prefix)? {
+        CodestreamPrefix::NeedMoreData(n) => return Ok(PeekResult::NeedMoreData(n)),
+        CodestreamPrefix::Codestream {
+            data,
+            level,
+            complete,
+        } => (data, level, complete),
+    };
+    let mut br = BitReader::new(&codestream);
+    match FileHeaders::read(&mut br) {
+        Ok(headers) => Ok(PeekResult::Info(BasicInfo::new(&headers, level))),
+        Err(Error::OutOfBounds(bits)) if !complete => {
+            Ok(PeekResult::NeedMoreData(bits.div_ceil(8)))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// A frame header together with the location of the frame's sections.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug)]
+pub struct FrameInfo {
+    pub header: FrameHeader,
+    pub toc: Toc,
+    /// Byte offset of the frame header in the codestream.
+    pub header_offset: usize,
+    /// Byte offset of the first section in the codestream.
+    pub sections_offset: usize,
+    /// Size of the image, or of the preview, that the frame belongs to.
+    pub image_size: (u32, u32),
+}
+
+/// Location of a section in the codestream.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectionRange {
+    pub section: Section,
+    /// Absolute byte offset in the codestream.
+    pub offset: u64,
+    pub size: u32,
+}
+
+/// Everything in a file except the pixel data.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug)]
+pub struct ImageStructure {
+    pub headers: FileHeaders,
+    pub level: Level,
+    pub icc: Option<Vec<u8>>,
+    pub preview: Option<FrameInfo>,
+    pub frames: Vec<FrameInfo>,
+    /// Exif, XMP and JUMBF boxes of the container, in file order.
+    pub metadata: Vec<MetadataBox>,
+    pub gain_map: Option<GainMapBox>,
+}
+
+impl ImageStructure {
+    /// Frames that are part of the displayed image, skipping reference-only and
+    /// LF frames.
+    pub fn displayed_frames(&self) -> impl Iterator<Item = &FrameInfo> {
+        self.frames.iter().filter(|f| f.header.is_displayed())
+    }
+
+    /// Frames that are returned to the caller with the given options. When
+    /// coalescing, zero-duration frames are only layers of the next frame and
+    /// are not returned on their own.
+    pub fn output_frames<'a>(
+        &'a self,
+        options: &DecoderOptions,
+    ) -> impl Iterator<Item = &'a FrameInfo> {
+        let coalescing = options.get_coalescing();
+        self.displayed_frames()
+            .filter(move |f| !coalescing || f.header.is_last || f.header.duration() != 0)
+    }
+
+    /// Indices of the frames that have to be decoded to reconstruct the frames
+    /// in `range`: those frames, and every earlier frame they blend onto,
+    /// copy patches from or take their LF image from, directly or not. Indices
+    /// past the last frame are ignored.
+    pub fn frames_needed(&self, range: Range<usize>) -> Vec<usize> {
+        let mut dependencies = Vec::with_capacity(self.frames.len());
+        let mut slots = [None; 4];
+        let mut lf_frames = [None; 5];
+        for (i, frame) in self.frames.iter().enumerate() {
+            let header = &frame.header;
+            let (width, height) = frame.image_size;
+            let referenced = header.referenced_slots(width, height);
+            let mut deps: Vec<usize> = (0..4)
+                .filter(|slot| referenced & 1 << slot != 0)
+                .filter_map(|slot| slots[slot])
+                .collect();
+            if header.uses_lf_frame() {
+                deps.extend(
+                    lf_frames
+                        .get(header.lf_level() as usize + 1)
+                        .copied()
+                        .flatten(),
+                );
+            }
+            dependencies.push(deps);
+            if header.frame_type() == FrameType::LFFrame {
+                lf_frames[header.lf_level() as usize] = Some(i);
+            } else if header.can_be_referenced() {
+                slots[header.save_as_reference() as usize] = Some(i);
+            }
+        }
+        let mut needed = vec![false; self.frames.len()];
+        let end = range.end.min(self.frames.len());
+        needed[range.start.min(end)..end].fill(true);
+        for i in (0..self.frames.len()).rev() {
+            if needed[i] {
+                for &dep in dependencies[i].iter() {
+                    needed[dep] = true;
+                }
+            }
+        }
+        (0..self.frames.len()).filter(|&i| needed[i]).collect()
+    }
+
+    /// The ICC profile of the image: the embedded one, or one synthesized from
+    /// the color encoding in the headers.
+    pub fn color_profile(&self) -> Result<Cow<'_, [u8]>, Error> {
+        match self.icc {
+            Some(ref icc) => Ok(Cow::Borrowed(icc)),
+            None => synthesize_icc(&self.headers.image_metadata.color_encoding).map(Cow::Owned),
+        }
+    }
+
+    /// The parsed color space, white point, primaries and tone curves of
+    /// [`color_profile`](Self::color_profile).
+    pub fn parsed_color_profile(&self) -> Result<IccProfile, Error> {
+        IccProfile::parse(&self.color_profile()?)
+    }
+
+    /// Extra channels other than alpha, such as depth, thermal or CMYK black,
+    /// along with their index among all extra channels.
+    pub fn non_alpha_extra_channels(&self) -> impl Iterator<Item = (usize, &ExtraChannelInfo)> {
+        self.headers
+            .image_metadata
+            .extra_channel_info
+            .iter()
+            .enumerate()
+            .filter(|(_, ec)| !ec.is_alpha())
+    }
+
+    /// Payload of the first `Exif` box of the container, if any.
+    pub fn exif(&self) -> Option<&[u8]> {
+        self.metadata
+            .iter()
+            .find(|b| b.kind == MetadataKind::Exif)
+            .map(|b| &b.data[..])
+    }
+
+    /// Orientation of the image, reconciling the codestream orientation with the
+    /// Exif one according to `policy`.
+    pub fn orientation(&self, policy: OrientationPolicy) -> Result<Orientation, Error> {
+        let exif = match (self.exif(), policy) {
+            (None, _) | (_, OrientationPolicy::PreferCodestream) => None,
+            (Some(exif), _) => exif_orientation(exif)?,
+        };
+        policy.resolve(self.headers.image_metadata.orientation, exif)
+    }
+}
+
+impl FrameInfo {
+    /// Reads a frame header and its TOC, leaving `br` at the first section.
+    /// Section payloads are not needed, so this can run as soon as a prefix of
+    /// the frame is available; see [`FrameInfo::sections`].
+    pub fn read(
+        br: &mut BitReader,
+        headers: &FileHeaders,
+        is_preview: bool,
+    ) -> Result<FrameInfo, Error> {
+        FrameInfo::read_impl(br, headers, is_preview, false).map(|(frame, _)| frame)
+    }
+
+    /// Like [`FrameInfo::read`], but also returns statistics about the
+    /// entropy-coded TOC permutation, if the TOC is permuted.
+    pub fn read_with_stats(
+        br: &mut BitReader,
+        headers: &FileHeaders,
+        is_preview: bool,
+    ) -> Result<(FrameInfo, Option<EntropyStats>), Error> {
+        FrameInfo::read_impl(br, headers, is_preview, true)
+    }
+
+    fn read_impl(
+        br: &mut BitReader,
+        headers: &FileHeaders,
+        is_preview: bool,
+        collect_stats: bool,
+    ) -> Result<(FrameInfo, Option<EntropyStats>), Error> {
+        let metadata = &headers.image_metadata;
+        let (img_width, img_height) = match metadata.preview {
+            Some(ref preview) if is_preview => (preview.xsize(), preview.ysize()),
+            _ => (headers.size.xsize(), headers.size.ysize()),
+        };
+        let animation = metadata.animation.as_ref().filter(|_| !is_preview);
+        br.jump_to_byte_boundary()?;
+        let header_offset = br.total_bits_read() / 8;
+        let header = FrameHeader::read_unconditional(
+            &(),
+            br,
+            &FrameHeaderNonserialized {
+                xyb_encoded: metadata.xyb_encoded,
+                num_extra_channels: metadata.extra_channel_info.len() as u32,
+                extra_channel_info: metadata.extra_channel_info.clone(),
+                have_animation: animation.is_some(),
+                have_timecode: animation.is_some_and(|a| a.have_timecodes),
+                img_width,
+                img_height,
+            },
+        )?;
+        let num_toc_entries = header.num_toc_entries(img_width, img_height)?;
+        let (toc, stats) = if collect_stats {
+            Toc::read_with_stats(br, num_toc_entries)?
+        } else {
+            (Toc::read(br, num_toc_entries)?, None)
+        };
+        let sections_offset = br.total_bits_read() / 8;
+        let frame = FrameInfo {
+            header,
+            toc,
+            header_offset,
+            sections_offset,
+            image_size: (img_width, img_height),
+        };
+        Ok((frame, stats))
+    }
+
+    /// Returns the role and location of every section of the frame, so that
+    /// only the sections needed for a region or an LF-only decode can be
+    /// fetched. Sections are listed in decoding order, regardless of how the
+    /// TOC permutes them in the bitstream.
+    pub fn section_ranges(&self) -> Result<Vec<SectionRange>, Error> {
+        let (width, height) = self.image_size;
+        let num_entries = self.toc.entries.len() as u32;
+        let num_lf_groups = self.header.num_lf_groups(width, height)?;
+        let num_groups = self.header.num_groups(width, height)?;
+        Ok(self
+            .toc
+            .section_offsets()
+            .into_iter()
+            .enumerate()
+            .map(|(i, (offset, size))| SectionRange {
+                section: Section::from_index(i as u32, num_entries, num_lf_groups, num_groups),
+                offset: self.sections_offset as u64 + offset,
+                size,
+            })
+            .collect())
+    }
+
+    /// Like [`FrameInfo::section_ranges`], but leaves out the sections of
+    /// passes that only refine detail below a downsampling factor of
+    /// `downsampling` (1, 2, 4 or 8).
+    pub fn section_ranges_for_downsampling(
+        &self,
+        downsampling: u32,
+    ) -> Result<Vec<SectionRange>, Error> {
+        let num_passes = self
+            .header
+            .passes()
+            .num_passes_for_downsampling(downsampling);
+        Ok(self
+            .section_ranges()?
+            .into_iter()
+            .filter(|range| match range.section {
+                Section::Group { pass, .. } => pass < num_passes,
+                _ => true,
+            })
+            .collect())
+    }
+
+    /// Returns the TOC index and location of the section that holds byte
+    /// `offset` of the codestream, if any.
+    pub fn section_at(&self, offset: u64) -> Option<(usize, SectionRange)> {
+        self.section_ranges()
+            .ok()?
+            .into_iter()
+            .enumerate()
+            .find(|(_, range)| (range.offset..range.offset + range.size as u64).contains(&offset))
+    }
+
+    // Records that `err` occurred in the section holding byte `offset` of
+    // this frame, which is frame `index` or the preview if `index` is `None`.
+    pub(crate) fn section_error(&self, err: Error, index: Option<usize>, offset: u64) -> Error {
+        match self.section_at(offset) {
+            Some((section_index, range)) => err.at(
+                ErrorLocation::Section {
+                    frame: index,
+                    index: section_index,
+                    section: range.section,
+                },
+                range.offset as usize * 8,
+            ),
+            None => err,
+        }
+    }
+
+    /// Byte offset in the codestream just past the last section of the frame.
+    pub fn end_offset(&self) -> u64 {
+        self.sections_offset as u64 + self.toc.total_size()
+    }
+
+    /// Returns the section payloads, in bitstream order, from a prefix of the
+    /// codestream. Fails with [`Error::OutOfBounds`] if the prefix does not
+    /// contain the whole frame yet.
+    pub fn sections<'a>(&self, codestream: &'a [u8]) -> Result<Vec<&'a [u8]>, Error> {
+        let end = self.end_offset();
+        if (codestream.len() as u64) < end {
+            let missing_bits = (end - codestream.len() as u64).saturating_mul(8);
+            return Err(Error::OutOfBounds(
+                usize::try_from(missing_bits).unwrap_or(usize::MAX),
+            ));
+        }
+        let mut offset = self.sections_offset;
+        Ok(self
+            .toc
+            .entries
+            .iter()
+            .map(|size| {
+                let section = &codestream[offset..offset + *size as usize];
+                offset += *size as usize;
+                section
+            })
+            .collect())
+    }
+}
+
+// Reads frame `index`, or the preview if `index` is `None`, and skips its
+// sections.
+fn read_frame_info(
+    br: &mut BitReader,
+    headers: &FileHeaders,
+    index: Option<usize>,
+    collect_stats: bool,
+) -> Result<(FrameInfo, Option<EntropyStats>), Error> {
+    let (frame, stats) = FrameInfo::read_impl(br, headers, index.is_none(), collect_stats)?;
+    let sections_bits = usize::try_from(frame.toc.total_size())
+        .ok()
+        .and_then(|size| size.checked_mul(8))
+        .ok_or(Error::OutOfBounds(usize::MAX))?;
+    let start = br.total_bits_read();
+    br.skip_bits(sections_bits).map_err(|err| {
+        // Point at the section in which the codestream ends.
+        let missing = match err {
+            Error::OutOfBounds(missing) => missing,
+            _ => return err,
+        };
+        let end = (start + sections_bits).saturating_sub(missing) / 8;
+        frame.section_error(err, index, end as u64)
+    })?;
+    Ok((frame, stats))
+}
+
+/// Reads the file headers, the ICC profile and the header and TOC of every
+/// frame, seeking over all section payloads.
+pub fn decode_metadata(file: &[u8]) -> Result<ImageStructure, Error> {
+    decode_metadata_with_options(file, &DecoderOptions::default())
+}
+
+/// Like [`decode_metadata`], but fails as soon as the image exceeds the limits
+/// set in `options`.
+pub fn decode_metadata_with_options(
+    file: &[u8],
+    options: &DecoderOptions,
+) -> Result<ImageStructure, Error> {
+    read_structure(file, options, None)
+}
+
+/// Returns statistics about the entropy-coded streams outside of the frame
+/// sections: the ICC profile and the permutations of permuted TOCs.
+pub fn entropy_stats(file: &[u8]) -> Result<Vec<(ErrorLocation, EntropyStats)>, Error> {
+    let mut stats = vec![];
+    read_structure(file, &DecoderOptions::default(), Some(&mut stats))?;
+    Ok(stats)
+}
+
+fn read_structure(
+    file: &[u8],
+    options: &DecoderOptions,
+    mut stats: Option<&mut Vec<(ErrorLocation, EntropyStats)>>,
+) -> Result<ImageStructure, Error> {
+    let codestream = JxlCodestream::from_slice(file)?;
+    let level = codestream.level();
+    let segments = codestream.segments();
+    let mut br = BitReader::new_segmented(&segments);
+    let headers = FileHeaders::read(&mut br)
+        .and_then(|headers| {
+            options.check_headers(&headers, level)?;
+            Ok(headers)
+        })
+        .map_err(|e| e.at(ErrorLocation::FileHeaders, br.total_bits_read()))?;
+    let collect_stats = stats.is_some();
+    let mut record = |location, entropy_stats: Option<EntropyStats>| {
+        if let (Some(stats), Some(entropy_stats)) = (stats.as_mut(), entropy_stats) {
+            stats.push((location, entropy_stats));
+        }
+    };
+    let icc = if headers.image_metadata.color_encoding.want_icc {
+        let read = |br: &mut BitReader| match collect_stats {
+            true => read_icc_with_stats(br).map(|(icc, stats)| (icc, Some(stats))),
+            false => read_icc(br).map(|icc| (icc, None)),
+        };
+        let (icc, icc_stats) =
+            read(&mut br).map_err(|e| e.at(ErrorLocation::Icc, br.total_bits_read()))?;
+        record(ErrorLocation::Icc, icc_stats);
+        Some(icc)
+    } else {
+        None
+    };
+    let preview = if headers.image_metadata.preview.is_some() {
+        let (preview, preview_stats) = read_frame_info(&mut br, &headers, None, collect_stats)
+            .map_err(|e| e.at(ErrorLocation::Preview, br.total_bits_read()))?;
+        record(ErrorLocation::Preview, preview_stats);
+        Some(preview)
+    } else {
+        None
+    };
+    let mut frames = vec![];
+    loop {
+        let index = frames.len();
+        let (frame, frame_stats) = read_frame_info(&mut br, &headers, Some(index), collect_stats)
+            .and_then(|frame| {
+                options.check_frame_count(index + 1)?;
+                Ok(frame)
+            })
+            .map_err(|e| e.at(ErrorLocation::Frame(index), br.total_bits_read()))?;
+        record(ErrorLocation::Frame(index), frame_stats);
+        let is_last = frame.header.is_last;
+        frames.push(frame);
+        if is_last {
+            break;
+        }
+    }
+    Ok(ImageStructure {
+        headers,
+        level,
+        icc,
+        preview,
+        frames,
+        metadata: codestream.metadata().to_vec(),
+        gain_map: codestream.gain_map().cloned(),
+    })
+}
+
+/// Like [`decode_metadata`], but maps the file at `path` into memory instead
+/// of reading it, so that only the pages holding headers and TOCs are loaded.
+///
+/// The file must not be modified while it is being decoded.
+#[cfg(feature = "mmap")]
+pub fn decode_metadata_mmap(path: impl AsRef<std::path::Path>) -> Result<ImageStructure, Error> {
+    let file = std::fs::File::open(path)?;
+    // SAFETY: the caller guarantees that the file is not modified while mapped.
+    let data = unsafe { memmap2::Mmap::map(&file)? };
+    decode_metadata(&data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_writer::BitWriter;
+    use crate::bmff::CONTAINER_SIGNATURE;
+    use crate::headers::color_encoding::{ColorEncoding, ColorSpace};
+    use crate::headers::encodings::Empty;
+    use crate::headers::frame_header::BlendingMode;
+    use crate::icc::known::KnownProfile;
+    use crate::test_util::{CodestreamBuilder, TestFrame};
+
+    const CODESTREAM: [u8; 12] = [
+        0xFF, 0x0A, 0x00, 0x90, 0x01, 0x00, 0x12, 0x88, 0x02, 0x00, 0xD4, 0x00,
+    ];
+
+    const SMALL_FILE: [u8; 65] = [
+        0xFF, 0x0A, 0x00, 0x90, 0x01, 0x00, 0x12, 0x88, 0x02, 0x00, 0xD4, 0x00, 0x55, 0x0F, 0x00,
+        0x00, 0xA8, 0x50, 0x19, 0x65, 0xDC, 0xE0, 0xE5, 0x5C, 0xCF, 0x97, 0x1F, 0x3A, 0x2C, 0xA6,
+        0x6D, 0x5C, 0x67, 0x68, 0xAB, 0x6D, 0x0B, 0x4B, 0x12, 0x45, 0xC6, 0xB1, 0x49, 0x3A, 0x81,
+        0x43, 0x92, 0x58, 0x04, 0x36, 0x2E, 0x98, 0x07, 0x18, 0x00, 0x86, 0x99, 0x03, 0x27, 0x33,
+        0x50, 0xE4, 0x4A, 0x12, 0x00,
+    ];
+
+    fn check_all_prefixes(file: &[u8]) -> BasicInfo {
+        let mut len = 0;
+        loop {
+            match peek_info(&file[..len]).unwrap() {
+                PeekResult::Info(info) => return info,
+                PeekResult::NeedMoreData(n) => {
+                    assert!(n > 0);
+                    // Every byte before the requested amount must still be insufficient.
+                    for shorter in len + 1..(len + n).min(file.len()) {
+                        assert!(matches!(
+                            peek_info(&file[..shorter]).unwrap(),
+                            PeekResult::NeedMoreData(_)
+                        ));
+                    }
+                    len += n;
+                    assert!(len <= file.len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bare_codestream() {
+        let info = check_all_prefixes(&CODESTREAM);
+        assert_eq!(info.orientation, Orientation::Identity);
+        assert!(!info.have_alpha);
+        assert!(!info.have_animation);
+        assert_eq!(info.level, Level::Level5);
+    }
+
+    #[test]
+    fn test_container() {
+        let mut file = vec![
+            0x00, 0x00, 0x00, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+        ];
+        file.extend_from_slice(&[0x00, 0x00, 0x00, 0x09, b'j', b'x', b'l', b'l', 10]);
+        file.extend_from_slice(&[0x00, 0x00, 0x00, 0x0E, b'j', b'x', b'l', b'p', 0, 0, 0, 0]);
+        file.extend_from_slice(&CODESTREAM[..2]);
+        file.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x16, b'j', b'x', b'l', b'p', 0x80, 0, 0, 1,
+        ]);
+        file.extend_from_slice(&CODESTREAM[2..]);
+        let info = check_all_prefixes(&file);
+        assert_eq!(
+            info,
+            check_all_prefixes(&CODESTREAM[..]).with_level(Level::Level10)
+        );
+    }
+
+    #[test]
+    fn test_decode_metadata() {
+        let file = SMALL_FILE;
+        let structure = decode_metadata(&file).unwrap();
+        assert!(structure.icc.is_none());
+        assert!(structure.preview.is_none());
+        assert_eq!(structure.frames.len(), 1);
+        let frame = &structure.frames[0];
+        assert_eq!(frame.header_offset, 5);
+        assert_eq!(frame.sections_offset, 12);
+        assert_eq!(frame.toc.entries, vec![53]);
+        assert_eq!(
+            frame.sections_offset as u64 + frame.toc.total_size(),
+            file.len() as u64
+        );
+        // Truncating the last section is detected.
+        assert!(decode_metadata(&file[..file.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_entropy_stats() {
+        // Neither the profile nor the TOC of this file is entropy coded.
+        assert!(entropy_stats(&SMALL_FILE).unwrap().is_empty());
+        assert!(entropy_stats(&SMALL_FILE[..SMALL_FILE.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_corrupted_files() {
+        // Corrupted and truncated files are rejected or misread, but never panic.
+        for bit in 16..SMALL_FILE.len() * 8 {
+            let mut file = SMALL_FILE;
+            file[bit / 8] ^= 1 << (bit % 8);
+            let _ = decode_metadata(&file);
+        }
+        for len in 0..SMALL_FILE.len() {
+            assert!(decode_metadata(&SMALL_FILE[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_decode_metadata_limits() {
+        let file = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(vec![vec![1; 10]; 7]))
+            .frame(TestFrame::new(vec![vec![2; 10]; 7]))
+            .build();
+        let decode = |options: DecoderOptions| decode_metadata_with_options(&file, &options);
+        assert!(decode(DecoderOptions::new().max_pixels(78000).max_frames(2)).is_ok());
+        let error = |options| decode(options).unwrap_err();
+        assert!(matches!(
+            error(DecoderOptions::new().max_pixels(77999)).inner(),
+            Error::TooManyPixels(78000, 77999)
+        ));
+        assert!(matches!(
+            error(DecoderOptions::new().max_frames(1)),
+            Error::At {
+                location: ErrorLocation::Frame(1),
+                ..
+            }
+        ));
+        assert!(matches!(
+            error(DecoderOptions::new().max_memory(300000)).inner(),
+            Error::MemoryLimitExceeded(936000, 300000)
+        ));
+        let options = DecoderOptions::new().downsampling(2).max_memory(300000);
+        assert!(decode(options).is_ok());
+    }
+
+    #[test]
+    fn test_section_location() {
+        let file = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(vec![vec![1; 10]; 7]))
+            .frame(TestFrame::new(vec![vec![2; 10]; 7]))
+            .build();
+        let second = decode_metadata(&file).unwrap().frames.remove(1);
+        // Cut the file in the middle of the third section of the second frame.
+        let cut = second.sections_offset + 25;
+        let err = decode_metadata(&file[..cut]).unwrap_err();
+        match err {
+            Error::At {
+                location,
+                bit_offset,
+                ..
+            } => {
+                assert_eq!(
+                    location,
+                    ErrorLocation::Section {
+                        frame: Some(1),
+                        index: 2,
+                        section: second.section_ranges().unwrap()[2].section,
+                    }
+                );
+                assert_eq!(bit_offset, (second.sections_offset + 20) * 8);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert!(matches!(err.inner(), Error::OutOfBounds(_)));
+    }
+
+    #[test]
+    fn test_decode_metadata_split() {
+        let codestream = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(vec![vec![1; 100]; 7]))
+            .frame(TestFrame::new(vec![vec![2; 10]; 7]))
+            .build();
+        // Store the codestream in tiny jxlp boxes, so that every element crosses
+        // box boundaries.
+        let mut file = CONTAINER_SIGNATURE.to_vec();
+        let num_parts = codestream.len().div_ceil(3);
+        for (index, part) in codestream.chunks(3).enumerate() {
+            let is_last = (index + 1 == num_parts) as u32;
+            file.extend_from_slice(&(12 + part.len() as u32).to_be_bytes());
+            file.extend_from_slice(b"jxlp");
+            file.extend_from_slice(&(index as u32 | is_last << 31).to_be_bytes());
+            file.extend_from_slice(part);
+        }
+        let expected = decode_metadata(&codestream).unwrap();
+        let structure = decode_metadata(&file).unwrap();
+        assert_eq!(structure.frames.len(), 2);
+        for (frame, expected) in structure.frames.iter().zip(expected.frames.iter()) {
+            assert_eq!(frame.header_offset, expected.header_offset);
+            assert_eq!(frame.toc.entries, expected.toc.entries);
+        }
+        assert!(decode_metadata(&file[..file.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_decode_metadata_extra_channel() {
+        let file = [
+            0xFF, 0x0A, 0x41, 0xC0, 0x4A, 0x08, 0x10, 0x10, 0x00, 0xE4, 0x01, 0x4B, 0x28, 0x36,
+            0x56, 0x1F, 0xDC, 0x4B, 0x28, 0x98, 0x10, 0x01, 0x55, 0x21, 0xC4, 0x30, 0x06, 0x50,
+            0x87, 0x61, 0xAB, 0x2A, 0xB2, 0x17, 0x03, 0x02, 0xA0, 0x97, 0xCC, 0x08, 0x00, 0xC3,
+            0x63, 0x80, 0x49, 0x66, 0x12, 0x04, 0x78, 0x2C, 0xD6, 0x89, 0x53, 0xEF, 0xF9, 0x15,
+            0xFC, 0xD1, 0x6B, 0xC4, 0xF3, 0xC0, 0x0E, 0xA9, 0x8D, 0xB6, 0x16, 0x4E, 0x5C, 0x70,
+            0x06, 0xE2, 0x07, 0x12, 0x62, 0xEC, 0x6C, 0xBE, 0x7C, 0x16, 0xDC, 0x72, 0xCE, 0xF3,
+            0xC1, 0xA2, 0xE2, 0x0A, 0xC8, 0xF9, 0xA1, 0x8C, 0xDA, 0xCF, 0xE3, 0xE8, 0x27, 0xDA,
+            0x66, 0xE2, 0xD6, 0x20, 0x2A, 0x38, 0xC1, 0xF7, 0xD0, 0x66, 0xED, 0xD2, 0xE0, 0x04,
+            0x42, 0x3A, 0x2A, 0x99, 0x2C, 0x12, 0x19, 0x9D, 0x9E, 0x83, 0x28, 0x54, 0x81, 0x55,
+            0x83, 0x3D, 0x69, 0x00, 0x1D, 0x03,
+        ];
+        let structure = decode_metadata(&file).unwrap();
+        assert_eq!(structure.frames.len(), 1);
+        let frame = &structure.frames[0];
+        assert_eq!(
+            frame.sections_offset as u64 + frame.toc.total_size(),
+            file.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_exif_orientation() {
+        let exif = crate::exif::test::exif_box(8, false);
+        let mut file = vec![
+            0x00, 0x00, 0x00, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+        ];
+        file.extend_from_slice(&(8 + SMALL_FILE.len() as u32).to_be_bytes());
+        file.extend_from_slice(b"jxlc");
+        file.extend_from_slice(&SMALL_FILE);
+        file.extend_from_slice(&(8 + exif.len() as u32).to_be_bytes());
+        file.extend_from_slice(b"Exif");
+        file.extend_from_slice(&exif);
+        let structure = decode_metadata(&file).unwrap();
+        assert_eq!(
+            structure
+                .orientation(OrientationPolicy::PreferCodestream)
+                .unwrap(),
+            Orientation::Identity
+        );
+        assert_eq!(
+            structure
+                .orientation(OrientationPolicy::PreferExif)
+                .unwrap(),
+            Orientation::Rotate270
+        );
+        assert!(structure
+            .orientation(OrientationPolicy::ErrorOnConflict)
+            .is_err());
+        let structure = decode_metadata(&SMALL_FILE).unwrap();
+        assert_eq!(
+            structure
+                .orientation(OrientationPolicy::PreferExif)
+                .unwrap(),
+            Orientation::Identity
+        );
+    }
+
+    #[test]
+    fn test_metadata_boxes() {
+        let mut file = CONTAINER_SIGNATURE.to_vec();
+        for (ty, payload) in [
+            (b"xml ", &b"<x:xmpmeta/>"[..]),
+            (b"jxlc", &SMALL_FILE[..]),
+            (b"jumb", &[1, 2, 3][..]),
+            (b"Exif", &[0, 0, 0, 0][..]),
+        ] {
+            file.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+            file.extend_from_slice(ty);
+            file.extend_from_slice(payload);
+        }
+        let structure = decode_metadata(&file).unwrap();
+        let kinds: Vec<_> = structure.metadata.iter().map(|b| b.kind).collect();
+        assert_eq!(
+            kinds,
+            [MetadataKind::Xmp, MetadataKind::Jumbf, MetadataKind::Exif]
+        );
+        assert_eq!(structure.metadata[0].data, b"<x:xmpmeta/>");
+        assert_eq!(structure.exif(), Some(&[0, 0, 0, 0][..]));
+        assert!(decode_metadata(&SMALL_FILE).unwrap().metadata.is_empty());
+    }
+
+    #[test]
+    fn test_gain_map() {
+        let mut bw = BitWriter::new();
+        ColorEncoding::default()
+            .write_unconditional(&(), &mut bw, &Empty {})
+            .unwrap();
+        let color_encoding = bw.finalize();
+        let jhgm = [
+            &[0, 0, 3][..],
+            &[7, 8, 9],
+            &[color_encoding.len() as u8],
+            &color_encoding,
+            &[0, 0, 0, 0],
+            &SMALL_FILE,
+        ]
+        .concat();
+        let mut file = CONTAINER_SIGNATURE.to_vec();
+        for (ty, payload) in [(b"jxlc", &SMALL_FILE[..]), (b"jhgm", &jhgm)] {
+            file.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+            file.extend_from_slice(ty);
+            file.extend_from_slice(payload);
+        }
+        let structure = decode_metadata(&file).unwrap();
+        let gain_map = structure.gain_map.unwrap();
+        assert_eq!(gain_map.metadata, [7, 8, 9]);
+        assert_eq!(
+            gain_map.color_encoding.unwrap().color_space,
+            ColorSpace::RGB
+        );
+        assert!(gain_map.alt_icc.is_none());
+        let gain_map_structure = decode_metadata(&gain_map.gain_map).unwrap();
+        assert_eq!(gain_map_structure.frames.len(), 1);
+        assert!(decode_metadata(&SMALL_FILE).unwrap().gain_map.is_none());
+
+        // Unknown versions and truncated boxes are rejected, but don't prevent
+        // decoding the base image.
+        assert!(GainMapBox::parse(&[1, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(GainMapBox::parse(&jhgm[..5]).is_err());
+        let mut file = CONTAINER_SIGNATURE.to_vec();
+        for (ty, payload) in [(b"jxlc", &SMALL_FILE[..]), (b"jhgm", &jhgm[..5])] {
+            file.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+            file.extend_from_slice(ty);
+            file.extend_from_slice(payload);
+        }
+        let structure = decode_metadata(&file).unwrap();
+        assert!(structure.gain_map.is_none());
+        assert_eq!(structure.frames.len(), 1);
+    }
+
+    #[test]
+    fn test_brob() {
+        let compressed = [
+            27, 35, 0, 248, 157, 9, 54, 46, 168, 119, 199, 120, 137, 231, 195, 83, 47, 116, 165,
+            211, 214, 97, 101, 109, 112, 130, 168, 88, 64, 133, 103, 12,
+        ];
+        let brob_with = |inner_ty: &[u8], compressed: &[u8]| {
+            let mut file = CONTAINER_SIGNATURE.to_vec();
+            file.extend_from_slice(&(8 + SMALL_FILE.len() as u32).to_be_bytes());
+            file.extend_from_slice(b"jxlc");
+            file.extend_from_slice(&SMALL_FILE);
+            file.extend_from_slice(&(12 + compressed.len() as u32).to_be_bytes());
+            file.extend_from_slice(b"brob");
+            file.extend_from_slice(inner_ty);
+            file.extend_from_slice(compressed);
+            file
+        };
+        let brob = |inner_ty: &[u8]| brob_with(inner_ty, &compressed);
+        let structure = decode_metadata(&brob(b"xml ")).unwrap();
+        if cfg!(feature = "brotli") {
+            assert_eq!(structure.metadata.len(), 1);
+            assert_eq!(structure.metadata[0].kind, MetadataKind::Xmp);
+            assert_eq!(structure.metadata[0].data, b"<x:xmpmeta/>".repeat(3));
+        } else {
+            assert!(structure.metadata.is_empty());
+        }
+        assert!(decode_metadata(&brob(b"jxlc")).is_err());
+        // Corrupt metadata is skipped.
+        let structure = decode_metadata(&brob_with(b"xml ", &compressed[..20])).unwrap();
+        assert!(structure.metadata.is_empty());
+    }
+
+    #[test]
+    fn test_streaming_frame() {
+        let sections: Vec<Vec<u8>> = (0..7).map(|i| vec![i as u8; 10 + i]).collect();
+        let file = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(sections.clone()))
+            .build();
+        let mut br = BitReader::new(&file);
+        let headers = FileHeaders::read(&mut br).unwrap();
+        let sections_offset = decode_metadata(&file).unwrap().frames[0].sections_offset;
+
+        // The header and TOC only need the bytes before the first section.
+        let prefix = &file[..sections_offset];
+        let mut br = BitReader::new(prefix);
+        FileHeaders::read(&mut br).unwrap();
+        let frame = FrameInfo::read(&mut br, &headers, false).unwrap();
+        assert_eq!(frame.sections_offset, sections_offset);
+        assert_eq!(frame.end_offset(), file.len() as u64);
+        assert!(matches!(
+            frame.sections(&file[..file.len() - 2]),
+            Err(Error::OutOfBounds(16))
+        ));
+        let read_sections = frame.sections(&file).unwrap();
+        assert_eq!(
+            read_sections,
+            sections.iter().map(|s| &s[..]).collect::<Vec<_>>()
+        );
+
+        // A truncated header is reported as such.
+        let mut br = BitReader::new(&file[..frame.header_offset + 1]);
+        FileHeaders::read(&mut br).unwrap();
+        assert!(matches!(
+            FrameInfo::read(&mut br, &headers, false),
+            Err(Error::OutOfBounds(_))
+        ));
+    }
+
+    #[test]
+    fn test_section_ranges() {
+        let sections: Vec<Vec<u8>> = (0..7).map(|i| vec![i as u8; 10 + i]).collect();
+        let file = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(sections))
+            .build();
+        let structure = decode_metadata(&file).unwrap();
+        let frame = &structure.frames[0];
+        let ranges = frame.section_ranges().unwrap();
+        // 300x260 is one LF group and four groups.
+        assert_eq!(ranges.len(), 7);
+        assert_eq!(ranges[0].section, Section::LfGlobal);
+        assert_eq!(ranges[1].section, Section::LfGroup(0));
+        assert_eq!(ranges[2].section, Section::HfGlobal);
+        assert_eq!(ranges[6].section, Section::Group { pass: 0, group: 3 });
+        for (i, range) in ranges.iter().enumerate() {
+            let start = range.offset as usize;
+            assert_eq!(
+                file[start..start + range.size as usize],
+                vec![i as u8; 10 + i]
+            );
+        }
+
+        // With a single pass, every section is needed at any downsampling.
+        assert_eq!(frame.section_ranges_for_downsampling(8).unwrap(), ranges);
+
+        let structure = decode_metadata(&SMALL_FILE).unwrap();
+        assert_eq!(
+            structure.frames[0].section_ranges().unwrap(),
+            vec![SectionRange {
+                section: Section::All,
+                offset: 12,
+                size: 53
+            }]
+        );
+    }
+
+    #[test]
+    fn test_frame_types() {
+        let file = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(vec![vec![1; 5]; 7]).frame_type(FrameType::ReferenceOnly))
+            // LF frames are coded at 1/8 resolution, so they fit in one group.
+            .frame(TestFrame::new(vec![vec![2; 5]]).frame_type(FrameType::LFFrame))
+            .frame(TestFrame::new(vec![vec![3; 5]; 7]))
+            .build();
+        let structure = decode_metadata(&file).unwrap();
+        let frame_types: Vec<_> = structure
+            .frames
+            .iter()
+            .map(|f| f.header.frame_type())
+            .collect();
+        assert_eq!(
+            frame_types,
+            vec![
+                FrameType::ReferenceOnly,
+                FrameType::LFFrame,
+                FrameType::RegularFrame
+            ]
+        );
+        assert_eq!(structure.frames[1].header.lf_level(), 1);
+        assert_eq!(structure.displayed_frames().count(), 1);
+        assert!(structure.displayed_frames().all(|f| f.header.is_last));
+    }
+
+    #[test]
+    fn test_frames_needed() {
+        const PATCHES: u64 = 2;
+        let file = CodestreamBuilder::new(64, 64)
+            .frame(
+                TestFrame::new(vec![vec![0; 5]])
+                    .frame_type(FrameType::ReferenceOnly)
+                    .blending(0, 1),
+            )
+            .frame(TestFrame::new(vec![vec![1; 5]]))
+            // Added onto frame 1, in reference slot 0.
+            .frame(TestFrame::new(vec![vec![2; 5]]).blending(1, 0))
+            .frame(TestFrame::new(vec![vec![3; 5]]))
+            .frame(TestFrame::new(vec![vec![4; 5]]).flags(PATCHES))
+            .build();
+        let structure = decode_metadata(&file).unwrap();
+        assert_eq!(structure.frames.len(), 5);
+        assert_eq!(structure.frames_needed(1..2), vec![1]);
+        assert_eq!(structure.frames_needed(2..3), vec![1, 2]);
+        assert_eq!(structure.frames_needed(3..4), vec![3]);
+        // Patches may come from any slot: frame 0 in slot 1, frame 3 in slot 0.
+        assert_eq!(structure.frames_needed(4..5), vec![0, 3, 4]);
+        assert_eq!(structure.frames_needed(2..4), vec![1, 2, 3]);
+        assert_eq!(structure.frames_needed(3..100), vec![0, 3, 4]);
+        assert!(structure.frames_needed(7..9).is_empty());
+    }
+
+    #[test]
+    fn test_output_frames() {
+        let file = CodestreamBuilder::new(64, 64)
+            .frame(TestFrame::new(vec![vec![0; 5]]))
+            .frame(TestFrame::new(vec![vec![1; 5]]).blending(1, 0))
+            .frame(TestFrame::new(vec![vec![2; 5]]).frame_type(FrameType::ReferenceOnly))
+            .frame(TestFrame::new(vec![vec![3; 5]]))
+            .build();
+        let structure = decode_metadata(&file).unwrap();
+        // Without animation, all frames but the last one are layers.
+        let coalesced: Vec<_> = structure
+            .output_frames(&DecoderOptions::new())
+            .map(|f| f.header_offset)
+            .collect();
+        assert_eq!(coalesced, vec![structure.frames[3].header_offset]);
+        let raw: Vec<_> = structure
+            .output_frames(&DecoderOptions::new().coalescing(false))
+            .collect();
+        assert_eq!(raw.len(), 3);
+        let blending = raw[1].header.blending_info();
+        assert_eq!(blending.mode(), BlendingMode::Add);
+        assert_eq!(blending.source(), 0);
+    }
+
+    #[test]
+    fn test_color_profile() {
+        let structure = decode_metadata(&SMALL_FILE).unwrap();
+        assert!(structure.icc.is_none());
+        let profile = structure.parsed_color_profile().unwrap();
+        assert_eq!(profile.recognize(), Some(KnownProfile::SRGB));
+        assert_eq!(profile.header.color_space, *b"RGB ");
+        assert!(profile.colorants.is_some());
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        assert!(peek_info(&[0x12, 0x34]).is_err());
+        assert!(peek_info(&[0x00, 0x00, 0x00, 0x0C, b'J', b'X', b'X']).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize() {
+        let structure = decode_metadata(&SMALL_FILE).unwrap();
+        let json = serde_json::to_value(&structure).unwrap();
+        assert_eq!(json["headers"]["image_metadata"]["orientation"], "Identity");
+        assert_eq!(json["level"], "Level5");
+        let frame = &json["frames"][0];
+        assert_eq!(frame["header"]["is_last"], true);
+        assert_eq!(frame["toc"]["entries"], serde_json::json!([53]));
+        assert_eq!(frame["sections_offset"], 12);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap() {
+        let path = std::env::temp_dir().join(format!("jxl-test-{}.jxl", std::process::id()));
+        std::fs::write(&path, SMALL_FILE).unwrap();
+        let structure = decode_metadata_mmap(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(structure.unwrap().frames[0].toc.entries, vec![53]);
+        assert!(matches!(decode_metadata_mmap(&path), Err(Error::Io(_))));
+    }
+
+    impl BasicInfo {
+        fn with_level(self, level: Level) -> BasicInfo {
+            BasicInfo { level, ..self }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::decode::streaming::{DecoderEvent, StreamingDecoder};
+use crate::error::Error;
+use futures_core::Stream;
+use futures_io::AsyncRead;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const READ_SIZE: usize = 64 * 1024;
+
+/// Reads a file from an [`AsyncRead`] and yields the events of a
+/// [`StreamingDecoder`] as they become available. The stream ends after
+/// [`DecoderEvent::Finished`] or the first error; a file that ends early yields
+/// [`Error::FileTruncated`].
+///
+/// [`DecoderEvent::NeedsMoreData`] is never yielded, as reading more is handled
+/// by the stream itself.
+pub struct EventStream<R> {
+    reader: R,
+    decoder: StreamingDecoder,
+    events: VecDeque<DecoderEvent>,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> EventStream<R> {
+    pub fn new(reader: R) -> EventStream<R> {
+        EventStream {
+            reader,
+            decoder: StreamingDecoder::new(),
+            events: VecDeque::new(),
+            buf: vec![0; READ_SIZE],
+            done: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for EventStream<R> {
+    type Item = Result<DecoderEvent, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.events.pop_front() {
+                Some(DecoderEvent::NeedsMoreData(_)) => continue,
+                Some(event) => {
+                    this.done = matches!(event, DecoderEvent::Finished);
+                    return Poll::Ready(Some(Ok(event)));
+                }
+                None if this.done => return Poll::Ready(None),
+                None => {}
+            }
+            let result = match Pin::new(&mut this.reader).poll_read(cx, &mut this.buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => Err(Error::FileTruncated),
+                Poll::Ready(Ok(n)) => this.decoder.feed(&this.buf[..n]),
+                Poll::Ready(Err(err)) => Err(err.into()),
+            };
+            match result {
+                Ok(events) => this.events.extend(events),
+                Err(err) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{CodestreamBuilder, TestFrame};
+    use std::io;
+    use std::task::Waker;
+
+    // Returns at most 7 bytes per read, and is not ready every other time.
+    struct SlowReader<'a> {
+        data: &'a [u8],
+        ready: bool,
+    }
+
+    impl AsyncRead for SlowReader<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(self.data.len()).min(7);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    // Polls the stream to completion, busy-waiting on pending reads.
+    fn collect(data: &[u8]) -> Vec<Result<DecoderEvent, Error>> {
+        let mut stream = EventStream::new(SlowReader { data, ready: false });
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut events = vec![];
+        loop {
+            match Pin::new(&mut stream).poll_next(&mut cx) {
+                Poll::Ready(Some(event)) => events.push(event),
+                Poll::Ready(None) => return events,
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let file = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(vec![vec![1; 20]; 7]))
+            .frame(TestFrame::new(vec![vec![2; 10]; 7]))
+            .build();
+        let events = collect(&file);
+        assert_eq!(events.len(), 7);
+        assert!(matches!(events[0], Ok(DecoderEvent::BasicInfo(_))));
+        assert!(matches!(events[5], Ok(DecoderEvent::FrameDone(1))));
+        assert!(matches!(events[6], Ok(DecoderEvent::Finished)));
+
+        let events = collect(&file[..file.len() - 1]);
+        assert_eq!(events.len(), 6);
+        assert!(matches!(events[5], Err(Error::FileTruncated)));
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::bit_reader::BitReader;
+use crate::bmff::JxlCodestream;
+use crate::decode::range_fetch::{file_ranges, locate_codestream, Segment};
+use crate::decode::{decode_metadata, FrameInfo, ImageStructure};
+use crate::error::Error;
+use crate::headers::{FileHeaders, JxlHeader};
+use crate::icc::read_icc;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ops::Range;
+
+/// The headers and TOCs of all frames of a file, together with where the
+/// codestream is stored in it. Built once, it gives access to the sections of
+/// any frame without parsing the container or the codestream again, e.g. when
+/// seeking in an animation.
+pub struct FrameIndex {
+    structure: ImageStructure,
+    segments: Vec<Segment>,
+}
+
+impl FrameIndex {
+    pub fn new(file: &[u8]) -> Result<FrameIndex, Error> {
+        let structure = decode_metadata(file)?;
+        let (segments, _) = locate_codestream(&mut |range: Range<u64>| {
+            let end = range.end.min(file.len() as u64) as usize;
+            let start = (range.start as usize).min(end);
+            Ok::<_, Error>(file[start..end].to_vec())
+        })?;
+        Ok(FrameIndex {
+            structure,
+            segments,
+        })
+    }
+
+    pub fn structure(&self) -> &ImageStructure {
+        &self.structure
+    }
+
+    pub fn frames(&self) -> &[FrameInfo] {
+        &self.structure.frames
+    }
+
+    /// Returns the section payloads of frame `index`, in bitstream order, from
+    /// the same `file` the index was built from. Sections are only copied if
+    /// they are split across `jxlp` boxes.
+    pub fn frame_sections<'a>(
+        &self,
+        file: &'a [u8],
+        index: usize,
+    ) -> Result<Vec<Cow<'a, [u8]>>, Error> {
+        let frames = &self.structure.frames;
+        let frame = frames
+            .get(index)
+            .ok_or(Error::FrameIndexOutOfRange(index, frames.len()))?;
+        let mut offset = frame.sections_offset as u64;
+        let mut sections = vec![];
+        for size in frame.toc.entries.iter() {
+            let end = offset + *size as u64;
+            let mut parts = file_ranges(&self.segments, offset..end)
+                .into_iter()
+                .map(|range| file.get(range.start as usize..range.end as usize))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| frame.section_error(Error::FileTruncated, Some(index), offset))?;
+            let section = match parts.len() {
+                1 => Cow::Borrowed(parts.pop().unwrap()),
+                _ => Cow::Owned(parts.concat()),
+            };
+            if section.len() != *size as usize {
+                return Err(frame.section_error(Error::FileTruncated, Some(index), offset));
+            }
+            sections.push(section);
+            offset = end;
+        }
+        Ok(sections)
+    }
+}
+
+/// A frame from which decoding can start, found by [`seek_to_frame`].
+#[derive(Debug)]
+pub struct Keyframe {
+    /// Index of the frame among the displayed frames.
+    pub displayed_index: u64,
+    pub frame: FrameInfo,
+}
+
+/// Finds the last frame listed in the `jxli` box of `file` that is not after
+/// displayed frame `n`, reading only the file headers and that frame's header
+/// and TOC. Returns `None` if the file has no usable `jxli` box, in which case
+/// the frames have to be scanned from the start.
+pub fn seek_to_frame(file: &[u8], n: u64) -> Result<Option<Keyframe>, Error> {
+    let codestream = JxlCodestream::from_slice(file)?;
+    let indexed = match codestream.frame_index() {
+        Some(index) => index.frames.iter().take_while(|f| f.frame <= n).last(),
+        None => return Ok(None),
+    };
+    let indexed = match indexed {
+        Some(indexed) => indexed,
+        None => return Ok(None),
+    };
+    let segments = codestream.segments();
+    let mut br = BitReader::new_segmented(&segments);
+    let headers = FileHeaders::read(&mut br)?;
+    if headers.image_metadata.color_encoding.want_icc {
+        read_icc(&mut br)?;
+    }
+    // Offsets into the headers or the ICC profile, or past the codestream,
+    // mean that the index is broken.
+    let codestream_len: usize = segments.iter().map(|s| s.len()).sum();
+    let offset = match usize::try_from(indexed.offset) {
+        Ok(offset) if offset >= br.total_bits_read().div_ceil(8) && offset < codestream_len => {
+            offset
+        }
+        _ => return Ok(None),
+    };
+    let mut br = BitReader::new_segmented(&segments);
+    br.skip_bits(offset * 8)?;
+    // So does an offset that is not at a frame header; if the file itself is
+    // broken, the linear scan reports it.
+    let frame = match FrameInfo::read(&mut br, &headers, false) {
+        Ok(frame) => frame,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(Keyframe {
+        displayed_index: indexed.frame,
+        frame,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bmff::CONTAINER_SIGNATURE;
+    use crate::error::ErrorLocation;
+    use crate::test_util::{CodestreamBuilder, TestFrame};
+
+    fn animation() -> Vec<u8> {
+        let mut builder = CodestreamBuilder::new(64, 64);
+        for i in 0..3 {
+            builder = builder.frame(TestFrame::new(vec![vec![i; 10 + i as usize]]));
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_frame_sections() {
+        let file = animation();
+        let index = FrameIndex::new(&file).unwrap();
+        assert_eq!(index.frames().len(), 3);
+        for i in 0..3 {
+            let sections = index.frame_sections(&file, i).unwrap();
+            assert!(matches!(sections[0], Cow::Borrowed(_)));
+            assert_eq!(*sections[0], vec![i as u8; 10 + i][..]);
+        }
+        assert!(matches!(
+            index.frame_sections(&file, 3),
+            Err(Error::FrameIndexOutOfRange(3, 3))
+        ));
+    }
+
+    #[test]
+    fn test_split_codestream() {
+        let codestream = animation();
+        // Split the codestream in the middle of the last section.
+        let split = codestream.len() - 5;
+        let mut file = CONTAINER_SIGNATURE.to_vec();
+        for (index, part) in [&codestream[..split], &codestream[split..]]
+            .iter()
+            .enumerate()
+        {
+            file.extend_from_slice(&(12 + part.len() as u32).to_be_bytes());
+            file.extend_from_slice(b"jxlp");
+            file.extend_from_slice(&(index as u32 | (index as u32) << 31).to_be_bytes());
+            file.extend_from_slice(part);
+        }
+        let index = FrameIndex::new(&file).unwrap();
+        let sections = index.frame_sections(&file, 2).unwrap();
+        assert!(matches!(sections[0], Cow::Owned(_)));
+        assert_eq!(*sections[0], [2; 12][..]);
+        assert_eq!(*index.frame_sections(&file, 1).unwrap()[0], [1; 11][..]);
+        let err = index
+            .frame_sections(&file[..file.len() - 1], 2)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::At {
+                location: ErrorLocation::Section {
+                    frame: Some(2),
+                    index: 0,
+                    ..
+                },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_seek_to_frame() {
+        let codestream = animation();
+        let frames = decode_metadata(&codestream).unwrap().frames;
+        assert!(frames.iter().all(|f| f.header.is_displayed()));
+        // Index frames 0 and 2, with varint offsets relative to the previous one.
+        let (offset0, offset2) = (frames[0].header_offset, frames[2].header_offset);
+        assert!(offset0 < 128 && offset2 - offset0 < 128);
+        let jxli = [
+            vec![2, 0, 0, 0, 1, 0, 0, 0, 10],
+            vec![offset0 as u8, 5, 2],
+            vec![(offset2 - offset0) as u8, 7, 0],
+        ]
+        .concat();
+        let mut file = CONTAINER_SIGNATURE.to_vec();
+        for (ty, payload) in [(b"jxlc", &codestream), (b"jxli", &jxli)] {
+            file.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+            file.extend_from_slice(ty);
+            file.extend_from_slice(payload);
+        }
+
+        let index = JxlCodestream::from_slice(&file)
+            .unwrap()
+            .frame_index()
+            .cloned()
+            .unwrap();
+        assert_eq!(index.tick_duration, (1, 10));
+        assert_eq!(index.frames[1].offset, offset2 as u64);
+        assert_eq!(index.frames[1].frame, 2);
+
+        let keyframe = seek_to_frame(&file, 1).unwrap().unwrap();
+        assert_eq!(keyframe.displayed_index, 0);
+        assert_eq!(keyframe.frame.header_offset, offset0);
+        let keyframe = seek_to_frame(&file, 5).unwrap().unwrap();
+        assert_eq!(keyframe.displayed_index, 2);
+        assert_eq!(keyframe.frame.toc.entries, frames[2].toc.entries);
+        assert!(seek_to_frame(&codestream, 1).unwrap().is_none());
+
+        // A broken index is ignored.
+        let mut file = CONTAINER_SIGNATURE.to_vec();
+        for (ty, payload) in [(b"jxlc", &codestream[..]), (b"jxli", &[0x80][..])] {
+            file.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+            file.extend_from_slice(ty);
+            file.extend_from_slice(payload);
+        }
+        assert!(JxlCodestream::from_slice(&file)
+            .unwrap()
+            .frame_index()
+            .is_none());
+        assert!(seek_to_frame(&file, 1).unwrap().is_none());
+        assert_eq!(decode_metadata(&file).unwrap().frames.len(), 3);
+
+        // So are offsets past the codestream or into the headers.
+        for offset in [codestream.len() + 1, 2] {
+            let varint = [(offset & 0x7f) as u8 | 0x80, (offset >> 7) as u8];
+            let jxli = [&[1, 0, 0, 0, 1, 0, 0, 0, 10], &varint[..], &[5, 2]].concat();
+            let mut file = CONTAINER_SIGNATURE.to_vec();
+            for (ty, payload) in [(b"jxlc", &codestream), (b"jxli", &jxli)] {
+                file.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+                file.extend_from_slice(ty);
+                file.extend_from_slice(payload);
+            }
+            assert!(JxlCodestream::from_slice(&file)
+                .unwrap()
+                .frame_index()
+                .is_some());
+            assert!(seek_to_frame(&file, 1).unwrap().is_none(), "{}", offset);
+        }
+    }
+}
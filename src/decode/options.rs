@@ -0,0 +1,271 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::error::Error;
+use crate::exif::OrientationPolicy;
+use crate::headers::color_encoding::ColorSpace;
+use crate::headers::level::Level;
+use crate::headers::FileHeaders;
+use crate::image::Image;
+use std::mem;
+
+/// Settings shared by the decoding entry points.
+///
+/// ```
+/// use jxl::decode::options::DecoderOptions;
+/// use jxl::exif::OrientationPolicy;
+///
+/// let options = DecoderOptions::new()
+///     .downsampling(4)
+///     .orientation_policy(OrientationPolicy::PreferExif);
+/// assert_eq!(options.get_downsampling(), 4);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecoderOptions {
+    downsampling: u32,
+    orientation_policy: OrientationPolicy,
+    max_pixels: Option<u64>,
+    max_frames: Option<usize>,
+    max_memory: Option<u64>,
+    coalescing: bool,
+    enforce_level: bool,
+}
+
+impl Default for DecoderOptions {
+    fn default() -> Self {
+        DecoderOptions::new()
+    }
+}
+
+impl DecoderOptions {
+    pub fn new() -> DecoderOptions {
+        DecoderOptions {
+            downsampling: 1,
+            orientation_policy: OrientationPolicy::default(),
+            max_pixels: None,
+            max_frames: None,
+            max_memory: None,
+            coalescing: true,
+            enforce_level: false,
+        }
+    }
+
+    /// Decodes the image at 1/`downsampling` of its resolution, skipping the
+    /// passes that only add finer detail. Decoding fails with
+    /// [`Error::InvalidDownsampling`] unless `downsampling` is 1, 2, 4 or 8.
+    pub fn downsampling(mut self, downsampling: u32) -> DecoderOptions {
+        self.downsampling = downsampling;
+        self
+    }
+
+    pub fn orientation_policy(mut self, policy: OrientationPolicy) -> DecoderOptions {
+        self.orientation_policy = policy;
+        self
+    }
+
+    /// Rejects images with more than `max_pixels` pixels.
+    pub fn max_pixels(mut self, max_pixels: u64) -> DecoderOptions {
+        self.max_pixels = Some(max_pixels);
+        self
+    }
+
+    /// Rejects files with more than `max_frames` frames, counting the ones
+    /// that are not displayed but not the preview.
+    pub fn max_frames(mut self, max_frames: usize) -> DecoderOptions {
+        self.max_frames = Some(max_frames);
+        self
+    }
+
+    /// Rejects images whose decoded channels would need more than
+    /// `max_memory` bytes as `f32` samples at the requested downsampling, and
+    /// fails any single buffer allocated through [`DecoderOptions::new_image`]
+    /// that is larger than that.
+    pub fn max_memory(mut self, max_memory: u64) -> DecoderOptions {
+        self.max_memory = Some(max_memory);
+        self
+    }
+
+    /// Whether frames are returned composited onto full canvases, merging
+    /// zero-duration frames into the next one (the default), or exactly as
+    /// coded, with their own position, size and blending info.
+    pub fn coalescing(mut self, coalescing: bool) -> DecoderOptions {
+        self.coalescing = coalescing;
+        self
+    }
+
+    /// Rejects images that exceed the limits of the codestream level signalled
+    /// in the container, as a decoder constrained to that level would. Off by
+    /// default.
+    pub fn enforce_level(mut self, enforce_level: bool) -> DecoderOptions {
+        self.enforce_level = enforce_level;
+        self
+    }
+
+    pub fn get_downsampling(&self) -> u32 {
+        self.downsampling
+    }
+
+    pub fn get_orientation_policy(&self) -> OrientationPolicy {
+        self.orientation_policy
+    }
+
+    pub fn get_max_pixels(&self) -> Option<u64> {
+        self.max_pixels
+    }
+
+    pub fn get_max_frames(&self) -> Option<usize> {
+        self.max_frames
+    }
+
+    pub fn get_max_memory(&self) -> Option<u64> {
+        self.max_memory
+    }
+
+    pub fn get_coalescing(&self) -> bool {
+        self.coalescing
+    }
+
+    pub fn get_enforce_level(&self) -> bool {
+        self.enforce_level
+    }
+
+    /// Checks the options themselves, then the image described by `headers`
+    /// against the pixel and memory limits, and against the limits of `level`
+    /// if they are enforced.
+    pub fn check_headers(&self, headers: &FileHeaders, level: Level) -> Result<(), Error> {
+        if !matches!(self.downsampling, 1 | 2 | 4 | 8) {
+            return Err(Error::InvalidDownsampling(self.downsampling));
+        }
+        if self.enforce_level {
+            level.check(headers)?;
+        }
+        let (xsize, ysize) = (headers.size.xsize() as u64, headers.size.ysize() as u64);
+        let pixels = xsize * ysize;
+        if let Some(max) = self.max_pixels {
+            if pixels > max {
+                return Err(Error::TooManyPixels(pixels, max));
+            }
+        }
+        if let Some(max) = self.max_memory {
+            let metadata = &headers.image_metadata;
+            let color_channels = match metadata.color_encoding.color_space {
+                ColorSpace::Gray if !metadata.xyb_encoded => 1,
+                _ => 3,
+            };
+            let channels = color_channels + metadata.extra_channel_info.len() as u64;
+            let ds = self.downsampling as u64;
+            let bytes = (xsize.div_ceil(ds) * ysize.div_ceil(ds))
+                .saturating_mul(channels)
+                .saturating_mul(4);
+            if bytes > max {
+                return Err(Error::MemoryLimitExceeded(bytes, max));
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates an image, unless its samples would take more than the memory
+    /// limit.
+    pub fn new_image<T: Copy + Default>(
+        &self,
+        width: usize,
+        height: usize,
+    ) -> Result<Image<T>, Error> {
+        if let Some(max) = self.max_memory {
+            let bytes = (width as u64)
+                .saturating_mul(height as u64)
+                .saturating_mul(mem::size_of::<T>() as u64);
+            if bytes > max {
+                return Err(Error::MemoryLimitExceeded(bytes, max));
+            }
+        }
+        Image::new(width, height)
+    }
+
+    /// Checks that a file has no more than the maximum number of frames after
+    /// reading `num_frames` of them.
+    pub fn check_frame_count(&self, num_frames: usize) -> Result<(), Error> {
+        match self.max_frames {
+            Some(max) if num_frames > max => Err(Error::TooManyFrames(max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_reader::BitReader;
+    use crate::bit_writer::BitWriter;
+    use crate::headers::JxlHeader;
+
+    #[test]
+    fn test_defaults() {
+        let options = DecoderOptions::default();
+        assert_eq!(options.get_downsampling(), 1);
+        assert_eq!(
+            options.get_orientation_policy(),
+            OrientationPolicy::PreferCodestream
+        );
+    }
+
+    #[test]
+    fn test_enforce_level() {
+        // A 300000x1 image, wider than level 5 allows.
+        let mut bw = BitWriter::new();
+        bw.write(16, 0x0AFF);
+        bw.write(1, 0); // not small
+        bw.write(2, 0); // ysize = 1
+        bw.write(9, 0);
+        bw.write(3, 0); // no aspect ratio
+        bw.write(2, 3); // xsize = 300000
+        bw.write(30, 299999);
+        bw.write(1, 1); // metadata all_default
+        bw.write(1, 1); // transform data all_default
+        let data = bw.finalize();
+        let headers = FileHeaders::read(&mut BitReader::new(&data)).unwrap();
+        assert!(DecoderOptions::new()
+            .check_headers(&headers, Level::Level5)
+            .is_ok());
+        let options = DecoderOptions::new().enforce_level(true);
+        assert!(matches!(
+            options.check_headers(&headers, Level::Level5),
+            Err(Error::ImageSizeTooLargeForLevel(300000, 1, 5))
+        ));
+        assert!(options.check_headers(&headers, Level::Level10).is_ok());
+    }
+
+    #[test]
+    fn test_new_image() {
+        let options = DecoderOptions::new().max_memory(4000);
+        let image = options.new_image::<i32>(10, 100).unwrap();
+        assert_eq!((image.width(), image.height()), (10, 100));
+        assert!(matches!(
+            options.new_image::<i32>(10, 101),
+            Err(Error::MemoryLimitExceeded(4040, 4000))
+        ));
+        assert!(options.new_image::<u8>(10, 101).is_ok());
+        assert!(DecoderOptions::new().new_image::<u8>(100, 100).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_downsampling() {
+        let mut bw = BitWriter::new();
+        bw.write(16, 0x0AFF);
+        bw.write(1, 1); // small
+        bw.write(5, 0); // ysize = 8
+        bw.write(3, 1); // 1:1
+        bw.write(1, 1); // metadata all_default
+        bw.write(1, 1); // transform data all_default
+        let data = bw.finalize();
+        let headers = FileHeaders::read(&mut BitReader::new(&data)).unwrap();
+        assert!(matches!(
+            DecoderOptions::new()
+                .downsampling(3)
+                .check_headers(&headers, Level::Level5),
+            Err(Error::InvalidDownsampling(3))
+        ));
+    }
+}
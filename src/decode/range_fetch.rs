@@ -0,0 +1,341 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+//! Planning of partial downloads, e.g. with HTTP range requests.
+//!
+//! [`plan_fetch`] reads only the headers and TOCs of a remote file, and returns
+//! the byte ranges that are needed to decode it at a given downsampling factor.
+
+use crate::bit_reader::BitReader;
+use crate::bmff::CONTAINER_SIGNATURE;
+use crate::decode::options::DecoderOptions;
+use crate::decode::FrameInfo;
+use crate::error::Error;
+use crate::headers::level::Level;
+use crate::headers::{FileHeaders, JxlHeader};
+use crate::icc::read_icc;
+use crate::util::safe_arith::SafeArith;
+use byteorder::{BigEndian, ByteOrder};
+use std::ops::Range;
+
+const INITIAL_FETCH_SIZE: u64 = 1024;
+
+/// The parts of a remote file needed to decode it at some downsampling factor.
+#[derive(Debug)]
+pub struct FetchPlan {
+    pub headers: FileHeaders,
+    pub level: Level,
+    pub icc: Option<Vec<u8>>,
+    pub frames: Vec<FrameInfo>,
+    /// Byte ranges of the file to fetch, sorted and non-overlapping. They cover
+    /// the file headers, the frame headers and TOCs, and the needed sections.
+    pub ranges: Vec<Range<u64>>,
+}
+
+// A piece of the codestream stored contiguously in the file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Segment {
+    codestream_offset: u64,
+    file_offset: u64,
+    len: u64,
+}
+
+// Maps a range of codestream offsets to the file ranges that store it.
+pub(super) fn file_ranges(segments: &[Segment], range: Range<u64>) -> Vec<Range<u64>> {
+    segments
+        .iter()
+        .filter_map(|segment| {
+            let start = range.start.max(segment.codestream_offset);
+            let end = range
+                .end
+                .min(segment.codestream_offset.saturating_add(segment.len));
+            (start < end).then(|| {
+                let file_start = segment.file_offset + (start - segment.codestream_offset);
+                file_start..file_start.saturating_add(end - start)
+            })
+        })
+        .collect()
+}
+
+fn merge_ranges(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = vec![];
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+// Walks the boxes of the file to find where the codestream is stored.
+pub(super) fn locate_codestream<E, F>(fetch: &mut F) -> Result<(Vec<Segment>, Level), E>
+where
+    E: From<Error>,
+    F: FnMut(Range<u64>) -> Result<Vec<u8>, E>,
+{
+    let signature = fetch(0..CONTAINER_SIGNATURE.len() as u64)?;
+    if signature.starts_with(&[0xff, 0x0a]) {
+        let segment = Segment {
+            codestream_offset: 0,
+            file_offset: 0,
+            len: u64::MAX,
+        };
+        return Ok((vec![segment], Level::Level5));
+    }
+    if signature.len() < 2 {
+        return Err(Error::FileTruncated.into());
+    }
+    if signature != CONTAINER_SIGNATURE {
+        return Err(Error::InvalidSignature(signature[0], signature[1]).into());
+    }
+
+    let mut level = Level::Level5;
+    let mut segments = vec![];
+    let mut codestream_len = 0u64;
+    let mut pos = CONTAINER_SIGNATURE.len() as u64;
+    loop {
+        let header = fetch(pos..pos.safe_add(16)?)?;
+        if header.len() < 8 {
+            return Err(Error::FileTruncated.into());
+        }
+        let mut header_size = 8;
+        let mut box_size = BigEndian::read_u32(&header) as u64;
+        if box_size == 1 {
+            if header.len() < 16 {
+                return Err(Error::FileTruncated.into());
+            }
+            box_size = BigEndian::read_u64(&header[8..]);
+            header_size = 16;
+        }
+        let box_end = if box_size == 0 {
+            None
+        } else if box_size < header_size {
+            return Err(Error::InvalidBox.into());
+        } else {
+            Some(pos.safe_add(box_size)?)
+        };
+        let payload = pos + header_size;
+        let payload_len = box_end.map_or(u64::MAX, |end| end - payload);
+        match &header[4..8] {
+            b"jxll" => {
+                if !segments.is_empty() || payload_len != 1 {
+                    return Err(Error::InvalidBox.into());
+                }
+                let value = fetch(payload..payload + 1)?;
+                level = Level::from_jxll(*value.first().ok_or(Error::FileTruncated)?)?;
+            }
+            b"jxlc" => {
+                if !segments.is_empty() {
+                    return Err(Error::InvalidBox.into());
+                }
+                segments.push(Segment {
+                    codestream_offset: 0,
+                    file_offset: payload,
+                    len: payload_len,
+                });
+                return Ok((segments, level));
+            }
+            b"jxlp" => {
+                if payload_len < 4 {
+                    return Err(Error::InvalidBox.into());
+                }
+                let index = fetch(payload..payload + 4)?;
+                if index.len() < 4 {
+                    return Err(Error::FileTruncated.into());
+                }
+                let count_and_last = BigEndian::read_u32(&index);
+                if count_and_last & ((1u32 << 31) - 1) != segments.len() as u32 {
+                    return Err(Error::InvalidBox.into());
+                }
+                let is_last = count_and_last >= (1u32 << 31);
+                if box_end.is_none() && !is_last {
+                    return Err(Error::InvalidBox.into());
+                }
+                segments.push(Segment {
+                    codestream_offset: codestream_len,
+                    file_offset: payload + 4,
+                    len: payload_len - 4,
+                });
+                codestream_len = codestream_len.saturating_add(payload_len - 4);
+                if is_last {
+                    return Ok((segments, level));
+                }
+            }
+            _ => {}
+        }
+        pos = box_end.ok_or(Error::FileTruncated)?;
+    }
+}
+
+// Fetches increasingly large chunks of the codestream, starting at byte
+// `start`, until `read` no longer runs out of data.
+fn read_at<T, E, F>(
+    fetch: &mut F,
+    segments: &[Segment],
+    start: u64,
+    mut read: impl FnMut(&mut BitReader) -> Result<T, Error>,
+) -> Result<T, E>
+where
+    E: From<Error>,
+    F: FnMut(Range<u64>) -> Result<Vec<u8>, E>,
+{
+    let mut data = vec![];
+    let mut len = INITIAL_FETCH_SIZE;
+    loop {
+        let available = start + data.len() as u64;
+        for range in file_ranges(segments, available..start.safe_add(len)?) {
+            let chunk = fetch(range.clone())?;
+            let short = (chunk.len() as u64) < range.end - range.start;
+            data.extend(chunk);
+            if short {
+                break;
+            }
+        }
+        match read(&mut BitReader::new(&data)) {
+            Err(Error::OutOfBounds(_)) if data.len() as u64 == len => len = len.safe_mul(2)?,
+            Err(Error::OutOfBounds(_)) => return Err(Error::FileTruncated.into()),
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Reads the headers and TOCs of a file through `fetch`, which returns the
+/// bytes of the file in the given range (fewer if the file ends before the
+/// end of the range), and plans which parts of the file are needed to decode
+/// it at the downsampling factor of `options`.
+///
+/// Section payloads are never fetched. Frames may be referenced by later ones,
+/// so the plan covers every frame, not only the displayed ones.
+pub fn plan_fetch<E, F>(mut fetch: F, options: &DecoderOptions) -> Result<FetchPlan, E>
+where
+    E: From<Error>,
+    F: FnMut(Range<u64>) -> Result<Vec<u8>, E>,
+{
+    let (segments, level) = locate_codestream(&mut fetch)?;
+    let (headers, icc, headers_end) = read_at(&mut fetch, &segments, 0, |br| {
+        let headers = FileHeaders::read(br)?;
+        options.check_headers(&headers, level)?;
+        let icc = if headers.image_metadata.color_encoding.want_icc {
+            Some(read_icc(br)?)
+        } else {
+            None
+        };
+        Ok((headers, icc, br.total_bits_read().div_ceil(8) as u64))
+    })?;
+
+    let mut codestream_ranges = Vec::new();
+    codestream_ranges.push(0..headers_end);
+    let mut frames = vec![];
+    let mut pos = headers_end;
+    let mut is_preview = headers.image_metadata.preview.is_some();
+    loop {
+        let mut frame = read_at(&mut fetch, &segments, pos, |br| {
+            FrameInfo::read(br, &headers, is_preview)
+        })?;
+        frame.header_offset += pos as usize;
+        frame.sections_offset += pos as usize;
+        pos = frame.end_offset();
+        if is_preview {
+            is_preview = false;
+            continue;
+        }
+        codestream_ranges.push(frame.header_offset as u64..frame.sections_offset as u64);
+        for section in frame.section_ranges_for_downsampling(options.get_downsampling())? {
+            codestream_ranges.push(section.offset..section.offset + section.size as u64);
+        }
+        let is_last = frame.header.is_last;
+        frames.push(frame);
+        options.check_frame_count(frames.len())?;
+        if is_last {
+            break;
+        }
+    }
+
+    let ranges = codestream_ranges
+        .into_iter()
+        .flat_map(|range| file_ranges(&segments, range))
+        .collect();
+    Ok(FetchPlan {
+        headers,
+        level,
+        icc,
+        frames,
+        ranges: merge_ranges(ranges),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::decode_metadata;
+    use crate::test_util::{CodestreamBuilder, TestFrame};
+
+    fn fetch_from(file: &[u8]) -> impl FnMut(Range<u64>) -> Result<Vec<u8>, Error> + '_ {
+        move |range| {
+            let end = (range.end as usize).min(file.len());
+            let start = (range.start as usize).min(end);
+            Ok(file[start..end].to_vec())
+        }
+    }
+
+    // Returns a file whose first frame has two passes, and the offset where
+    // that frame ends.
+    fn progressive_file() -> (Vec<u8>, u64) {
+        // Pass 0 is enough for 8x downsampling.
+        let progressive = TestFrame::new(vec![vec![1; 20]; 11]).passes(2, vec![(8, 0)]);
+        let file = CodestreamBuilder::new(300, 260)
+            .frame(progressive)
+            .frame(TestFrame::new(vec![vec![2; 30]; 7]))
+            .build();
+        let frame_end = decode_metadata(&file).unwrap().frames[0].end_offset();
+        (file, frame_end)
+    }
+
+    #[test]
+    fn test_plan_codestream() {
+        let (file, frame_end) = progressive_file();
+        let plan = plan_fetch(fetch_from(&file), &DecoderOptions::new().downsampling(8)).unwrap();
+        assert_eq!(plan.frames.len(), 2);
+        assert_eq!(plan.frames[0].toc.entries.len(), 11);
+        // The four groups of pass 1 end the first frame, and are skipped.
+        assert_eq!(
+            plan.ranges,
+            vec![0..frame_end - 4 * 20, frame_end..file.len() as u64]
+        );
+
+        let plan = plan_fetch(fetch_from(&file), &DecoderOptions::new()).unwrap();
+        assert_eq!(plan.ranges, vec![0..file.len() as u64]);
+
+        assert!(matches!(
+            plan_fetch(fetch_from(&file[..100]), &DecoderOptions::new()),
+            Err(Error::FileTruncated)
+        ));
+    }
+
+    #[test]
+    fn test_plan_split_codestream() {
+        let (codestream, _) = progressive_file();
+        let split = 50;
+        let mut file = CONTAINER_SIGNATURE.to_vec();
+        for (index, part) in [&codestream[..split], &codestream[split..]]
+            .iter()
+            .enumerate()
+        {
+            file.extend_from_slice(&(12 + part.len() as u32).to_be_bytes());
+            file.extend_from_slice(b"jxlp");
+            file.extend_from_slice(&(index as u32 | (index as u32) << 31).to_be_bytes());
+            file.extend_from_slice(part);
+        }
+        let plan = plan_fetch(fetch_from(&file), &DecoderOptions::new()).unwrap();
+        let second = 12 + 12 + split as u64 + 12;
+        assert_eq!(
+            plan.ranges,
+            vec![24..24 + split as u64, second..file.len() as u64]
+        );
+        assert_eq!(plan.frames[1].toc.entries, vec![30; 7]);
+    }
+}
@@ -0,0 +1,437 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::bit_reader::BitReader;
+use crate::bmff::ContainerParser;
+use crate::decode::options::DecoderOptions;
+use crate::decode::{BasicInfo, FrameInfo, SectionRange};
+use crate::error::{Error, ErrorLocation};
+use crate::headers::level::Level;
+use crate::headers::{FileHeaders, JxlHeader};
+use crate::icc::read_icc;
+use std::convert::TryFrom;
+
+/// Progress reported by a [`StreamingDecoder`].
+#[derive(Debug)]
+pub enum DecoderEvent {
+    BasicInfo(BasicInfo),
+    /// The ICC profile of the image, or `None` if the color encoding is
+    /// described by the file headers instead.
+    ColorProfile(Option<Vec<u8>>),
+    /// The header and TOC of a frame have been read.
+    FrameStarted(Box<FrameInfo>),
+    /// All the sections of the frame with the given index are available.
+    FrameDone(usize),
+    /// The last frame is done.
+    Finished,
+    /// Decoding stopped because the input ends early. At least this many more
+    /// bytes are needed before the next event; this is always the last event
+    /// returned by [`StreamingDecoder::feed`].
+    NeedsMoreData(usize),
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Headers,
+    Icc,
+    Preview,
+    PreviewSections { end: u64 },
+    Frame,
+    Sections { end: u64, is_last: bool },
+    Finished,
+}
+
+/// Decodes a file that arrives in chunks, reporting each piece of the image
+/// structure as soon as enough data is available for it. Previews are skipped.
+///
+/// Pixel data is not decoded yet, so a frame is done once all of its sections
+/// have arrived. Only the codestream bytes of the element being read are kept,
+/// and section payloads are dropped as they arrive.
+pub struct StreamingDecoder {
+    container: ContainerParser,
+    // The codestream bytes from byte `offset` on that have arrived.
+    codestream: Vec<u8>,
+    offset: usize,
+    options: DecoderOptions,
+    state: State,
+    headers: Option<FileHeaders>,
+    // Codestream position just past the last element that was read.
+    bit_pos: usize,
+    num_frames: usize,
+    // Sections of the frame, or preview, being read, in TOC order.
+    sections: Vec<SectionRange>,
+}
+
+impl Default for StreamingDecoder {
+    fn default() -> Self {
+        StreamingDecoder::new()
+    }
+}
+
+impl StreamingDecoder {
+    pub fn new() -> StreamingDecoder {
+        StreamingDecoder::with_options(DecoderOptions::default())
+    }
+
+    /// Creates a decoder that fails as soon as the image exceeds the limits
+    /// set in `options`.
+    pub fn with_options(options: DecoderOptions) -> StreamingDecoder {
+        StreamingDecoder {
+            container: ContainerParser::new(),
+            codestream: vec![],
+            offset: 0,
+            options,
+            state: State::Headers,
+            headers: None,
+            bit_pos: 0,
+            num_frames: 0,
+            sections: vec![],
+        }
+    }
+
+    /// Appends `data` to the input and returns the events that it made
+    /// possible, in order.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<DecoderEvent>, Error> {
+        if matches!(self.state, State::Finished) {
+            return Ok(vec![]);
+        }
+        self.container.process_bytes(data, &mut self.codestream)?;
+        let complete = self.container.is_complete();
+        if self.offset == 0 && self.codestream.is_empty() && !complete {
+            return Ok(vec![DecoderEvent::NeedsMoreData(
+                self.container.bytes_needed(),
+            )]);
+        }
+        let level = self.container.level();
+        let codestream = std::mem::take(&mut self.codestream);
+        let available = self.offset + codestream.len();
+        let mut events = vec![];
+        while !matches!(self.state, State::Finished) {
+            let mut br = BitReader::new(&codestream);
+            br.skip_bits(self.bit_pos - self.offset * 8)?;
+            match self.advance(&mut br, level, available, &mut events) {
+                Ok(state) => {
+                    self.bit_pos = self.offset * 8 + br.total_bits_read();
+                    self.state = state;
+                }
+                Err(Error::OutOfBounds(bits)) if !complete => {
+                    events.push(DecoderEvent::NeedsMoreData(bits.div_ceil(8).max(1)));
+                    break;
+                }
+                Err(err) => {
+                    let bit_pos = self.offset * 8 + br.total_bits_read();
+                    return Err(err.at(self.location(available), bit_pos));
+                }
+            }
+        }
+        self.codestream = codestream;
+        // Section payloads are not decoded, so the ones that arrived can be
+        // dropped along with everything that was read.
+        if let State::Sections { end, .. } | State::PreviewSections { end } = self.state {
+            self.bit_pos = self.bit_pos.max(end.min(available as u64) as usize * 8);
+        }
+        let consumed = (self.bit_pos / 8 - self.offset).min(self.codestream.len());
+        self.codestream.drain(..consumed);
+        self.offset += consumed;
+        Ok(events)
+    }
+
+    /// The file headers, once the [`DecoderEvent::BasicInfo`] event has been
+    /// reported.
+    pub fn headers(&self) -> Option<&FileHeaders> {
+        self.headers.as_ref()
+    }
+
+    /// Signals the end of the input, failing if the file is incomplete.
+    pub fn close(self) -> Result<(), Error> {
+        match self.state {
+            State::Finished => Ok(()),
+            _ => Err(Error::FileTruncated),
+        }
+    }
+
+    // Where reading stopped, given that the first `available` bytes of the
+    // codestream have arrived.
+    fn location(&self, available: usize) -> ErrorLocation {
+        let frame = match self.state {
+            State::Headers => return ErrorLocation::FileHeaders,
+            State::Icc => return ErrorLocation::Icc,
+            State::Preview => return ErrorLocation::Preview,
+            State::Frame | State::Finished => return ErrorLocation::Frame(self.num_frames),
+            State::PreviewSections { .. } => None,
+            State::Sections { .. } => Some(self.num_frames),
+        };
+        // Skipping sections only fails if the codestream ends in one of them.
+        let available = available as u64;
+        let index = self.sections.iter().position(|range| {
+            (range.offset..range.offset + range.size as u64).contains(&available)
+        });
+        match index {
+            Some(index) => ErrorLocation::Section {
+                frame,
+                index,
+                section: self.sections[index].section,
+            },
+            None => frame.map_or(ErrorLocation::Preview, ErrorLocation::Frame),
+        }
+    }
+
+    // Reads the next element and returns the next state. On failure, the
+    // element is read again once more data is available.
+    fn advance(
+        &mut self,
+        br: &mut BitReader,
+        level: Level,
+        available: usize,
+        events: &mut Vec<DecoderEvent>,
+    ) -> Result<State, Error> {
+        match self.state {
+            State::Headers => {
+                let headers = FileHeaders::read(br)?;
+                self.options.check_headers(&headers, level)?;
+                events.push(DecoderEvent::BasicInfo(BasicInfo::new(&headers, level)));
+                self.headers = Some(headers);
+                Ok(State::Icc)
+            }
+            State::Icc => {
+                let headers = self.headers.as_ref().unwrap();
+                let icc = if headers.image_metadata.color_encoding.want_icc {
+                    Some(read_icc(br)?)
+                } else {
+                    None
+                };
+                events.push(DecoderEvent::ColorProfile(icc));
+                Ok(State::Preview)
+            }
+            State::Preview => {
+                let headers = self.headers.as_ref().unwrap();
+                if headers.image_metadata.preview.is_none() {
+                    return Ok(State::Frame);
+                }
+                let preview = self.read_frame(br, true)?;
+                self.sections = preview.section_ranges()?;
+                Ok(State::PreviewSections {
+                    end: preview.end_offset(),
+                })
+            }
+            State::PreviewSections { end } => {
+                self.skip_to(br, end, available)?;
+                Ok(State::Frame)
+            }
+            State::Frame => {
+                let frame = self.read_frame(br, false)?;
+                self.options.check_frame_count(self.num_frames + 1)?;
+                self.sections = frame.section_ranges()?;
+                let state = State::Sections {
+                    end: frame.end_offset(),
+                    is_last: frame.header.is_last,
+                };
+                events.push(DecoderEvent::FrameStarted(Box::new(frame)));
+                Ok(state)
+            }
+            State::Sections { end, is_last } => {
+                self.skip_to(br, end, available)?;
+                events.push(DecoderEvent::FrameDone(self.num_frames));
+                self.num_frames += 1;
+                if is_last {
+                    events.push(DecoderEvent::Finished);
+                    Ok(State::Finished)
+                } else {
+                    Ok(State::Frame)
+                }
+            }
+            State::Finished => Ok(State::Finished),
+        }
+    }
+
+    // Reads a frame header and TOC, with offsets from the start of the
+    // codestream rather than of the buffered part of it.
+    fn read_frame(&self, br: &mut BitReader, is_preview: bool) -> Result<FrameInfo, Error> {
+        let mut frame = FrameInfo::read(br, self.headers.as_ref().unwrap(), is_preview)?;
+        frame.header_offset += self.offset;
+        frame.sections_offset += self.offset;
+        Ok(frame)
+    }
+
+    fn skip_to(&self, br: &mut BitReader, end: u64, available: usize) -> Result<(), Error> {
+        let pos = (self.offset + br.total_bits_read() / 8) as u64;
+        if end > available as u64 {
+            let missing = (end - available as u64).saturating_mul(8);
+            return Err(Error::OutOfBounds(
+                usize::try_from(missing).unwrap_or(usize::MAX),
+            ));
+        }
+        br.skip_bits(((end - pos) * 8) as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bmff::CONTAINER_SIGNATURE;
+    use crate::test_util::{CodestreamBuilder, TestFrame};
+
+    fn describe(events: &[DecoderEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| match e {
+                DecoderEvent::BasicInfo(info) => format!("info {}x{}", info.xsize, info.ysize),
+                DecoderEvent::ColorProfile(icc) => format!("icc {}", icc.is_some()),
+                DecoderEvent::FrameStarted(f) => format!("start {}", f.toc.entries.len()),
+                DecoderEvent::FrameDone(i) => format!("done {}", i),
+                DecoderEvent::Finished => "finished".to_string(),
+                DecoderEvent::NeedsMoreData(bytes) => format!("more {}", bytes),
+            })
+            .collect()
+    }
+
+    fn feed_in_chunks(file: &[u8], chunk_size: usize) -> Vec<DecoderEvent> {
+        let mut decoder = StreamingDecoder::new();
+        let mut events = vec![];
+        for (start, chunk) in (0..).step_by(chunk_size).zip(file.chunks(chunk_size)) {
+            let new_events = decoder.feed(chunk).unwrap();
+            // Every chunk but the last one leaves the decoder waiting for more.
+            let stalled = matches!(new_events.last(), Some(DecoderEvent::NeedsMoreData(_)));
+            assert_eq!(stalled, start + chunk.len() < file.len());
+            events.extend(
+                new_events
+                    .into_iter()
+                    .filter(|e| !matches!(e, DecoderEvent::NeedsMoreData(_))),
+            );
+        }
+        decoder.close().unwrap();
+        events
+    }
+
+    #[test]
+    fn test_streaming() {
+        let file = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(vec![vec![1; 100]; 7]))
+            .frame(TestFrame::new(vec![vec![2; 10]; 7]))
+            .build();
+        let expected = vec![
+            "info 300x260",
+            "icc false",
+            "start 7",
+            "done 0",
+            "start 7",
+            "done 1",
+            "finished",
+        ];
+        for chunk_size in [1, 13, file.len()] {
+            assert_eq!(describe(&feed_in_chunks(&file, chunk_size)), expected);
+        }
+
+        // Events are reported as soon as possible.
+        let mut decoder = StreamingDecoder::new();
+        let events = decoder.feed(&file[..file.len() - 1]).unwrap();
+        assert_eq!(describe(&events[..5]), expected[..5]);
+        assert!(matches!(events[5..], [DecoderEvent::NeedsMoreData(1)]));
+        assert!(decoder.close().is_err());
+    }
+
+    #[test]
+    fn test_truncated_sections() {
+        let codestream = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(vec![vec![1; 10]; 7]))
+            .build();
+        // A complete jxlc box that ends in the fourth section.
+        let sections_offset = codestream.len() - 70;
+        let codestream = &codestream[..sections_offset + 35];
+        let mut file = CONTAINER_SIGNATURE.to_vec();
+        file.extend_from_slice(&(8 + codestream.len() as u32).to_be_bytes());
+        file.extend_from_slice(b"jxlc");
+        file.extend_from_slice(codestream);
+        let err = StreamingDecoder::new().feed(&file).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::At {
+                location: ErrorLocation::Section {
+                    frame: Some(0),
+                    index: 3,
+                    ..
+                },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_streaming_container() {
+        let codestream = CodestreamBuilder::new(64, 64)
+            .frame(TestFrame::new(vec![vec![3; 10]]))
+            .build();
+        let mut file = vec![
+            0x00, 0x00, 0x00, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+        ];
+        file.extend_from_slice(&(8 + codestream.len() as u32).to_be_bytes());
+        file.extend_from_slice(b"jxlc");
+        file.extend_from_slice(&codestream);
+        assert_eq!(
+            describe(&feed_in_chunks(&file, 5)),
+            vec!["info 64x64", "icc false", "start 1", "done 0", "finished"]
+        );
+    }
+
+    #[test]
+    fn test_streaming_memory() {
+        let file = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(vec![vec![1; 1000]; 7]))
+            .frame(TestFrame::new(vec![vec![2; 1000]; 7]))
+            .build();
+        let mut decoder = StreamingDecoder::new();
+        for chunk in file.chunks(100) {
+            decoder.feed(chunk).unwrap();
+            // Section payloads are never kept.
+            assert!(decoder.codestream.len() < 100);
+        }
+        decoder.close().unwrap();
+    }
+
+    #[test]
+    fn test_streaming_split_container() {
+        let codestream = CodestreamBuilder::new(64, 64)
+            .frame(TestFrame::new(vec![vec![3; 10]]))
+            .frame(TestFrame::new(vec![vec![4; 20]]))
+            .build();
+        let mut file = vec![
+            0x00, 0x00, 0x00, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+        ];
+        let split = [0, 10, 30, codestream.len()];
+        for (index, part) in split.windows(2).enumerate() {
+            let part = &codestream[part[0]..part[1]];
+            let is_last = (index == 2) as u32;
+            file.extend_from_slice(&(12 + part.len() as u32).to_be_bytes());
+            file.extend_from_slice(b"jxlp");
+            file.extend_from_slice(&(index as u32 | is_last << 31).to_be_bytes());
+            file.extend_from_slice(part);
+            if is_last == 0 {
+                // An unrelated box between the parts of the codestream.
+                file.extend_from_slice(&[0x00, 0x00, 0x00, 0x0B, b'f', b'r', b'e', b'e', 1, 2, 3]);
+            }
+        }
+        for chunk_size in [1, 7, file.len()] {
+            assert_eq!(
+                describe(&feed_in_chunks(&file, chunk_size)),
+                vec![
+                    "info 64x64",
+                    "icc false",
+                    "start 1",
+                    "done 0",
+                    "start 1",
+                    "done 1",
+                    "finished"
+                ]
+            );
+        }
+
+        // Parts must be in order.
+        file[16] = b'x';
+        assert!(matches!(
+            StreamingDecoder::new().feed(&file),
+            Err(Error::InvalidBox)
+        ));
+    }
+}
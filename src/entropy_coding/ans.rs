@@ -3,5 +3,422 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
-//use crate::bit_reader::BitReader;
-//use crate::error::Error;
+use crate::bit_reader::BitReader;
+use crate::error::Error;
+
+pub const ANS_LOG_TAB_SIZE: usize = 12;
+const ANS_TAB_SIZE: u32 = 1 << ANS_LOG_TAB_SIZE;
+/// State of the decoder before the first and after the last symbol.
+pub const ANS_FINAL_STATE: u32 = 0x13 << 16;
+// Marks a run of repeated counts in a histogram.
+const RLE_LOGCOUNT: u8 = ANS_LOG_TAB_SIZE as u8 + 1;
+
+// Prefix code for the logarithms of histogram counts, indexed by the next 7
+// bits: (code length, logcount).
+#[rustfmt::skip]
+const LOGCOUNT_CODE: [(u8, u8); 128] = [
+    (3, 10), (7, 12), (3, 7), (4, 3), (3, 6), (3, 8), (3, 9), (4, 5),
+    (3, 10), (4, 4), (3, 7), (4, 1), (3, 6), (3, 8), (3, 9), (4, 2),
+    (3, 10), (5, 0), (3, 7), (4, 3), (3, 6), (3, 8), (3, 9), (4, 5),
+    (3, 10), (4, 4), (3, 7), (4, 1), (3, 6), (3, 8), (3, 9), (4, 2),
+    (3, 10), (6, 11), (3, 7), (4, 3), (3, 6), (3, 8), (3, 9), (4, 5),
+    (3, 10), (4, 4), (3, 7), (4, 1), (3, 6), (3, 8), (3, 9), (4, 2),
+    (3, 10), (5, 0), (3, 7), (4, 3), (3, 6), (3, 8), (3, 9), (4, 5),
+    (3, 10), (4, 4), (3, 7), (4, 1), (3, 6), (3, 8), (3, 9), (4, 2),
+    (3, 10), (7, 13), (3, 7), (4, 3), (3, 6), (3, 8), (3, 9), (4, 5),
+    (3, 10), (4, 4), (3, 7), (4, 1), (3, 6), (3, 8), (3, 9), (4, 2),
+    (3, 10), (5, 0), (3, 7), (4, 3), (3, 6), (3, 8), (3, 9), (4, 5),
+    (3, 10), (4, 4), (3, 7), (4, 1), (3, 6), (3, 8), (3, 9), (4, 2),
+    (3, 10), (6, 11), (3, 7), (4, 3), (3, 6), (3, 8), (3, 9), (4, 5),
+    (3, 10), (4, 4), (3, 7), (4, 1), (3, 6), (3, 8), (3, 9), (4, 2),
+    (3, 10), (5, 0), (3, 7), (4, 3), (3, 6), (3, 8), (3, 9), (4, 5),
+    (3, 10), (4, 4), (3, 7), (4, 1), (3, 6), (3, 8), (3, 9), (4, 2),
+];
+
+fn decode_varlen_uint8(br: &mut BitReader) -> Result<usize, Error> {
+    if br.read(1)? == 0 {
+        return Ok(0);
+    }
+    let nbits = br.read(3)? as usize;
+    if nbits == 0 {
+        Ok(1)
+    } else {
+        Ok((1 << nbits) + br.read(nbits)? as usize)
+    }
+}
+
+/// Reads the symbol counts of one distribution. They add up to `ANS_TAB_SIZE`.
+fn read_histogram(br: &mut BitReader) -> Result<Vec<u32>, Error> {
+    if br.read(1)? != 0 {
+        // Simple code: one or two symbols.
+        let num_symbols = br.read(1)? as usize + 1;
+        let mut symbols = [0; 2];
+        for symbol in symbols.iter_mut().take(num_symbols) {
+            *symbol = decode_varlen_uint8(br)?;
+        }
+        let mut counts = vec![0; symbols[..num_symbols].iter().max().unwrap() + 1];
+        if num_symbols == 1 {
+            counts[symbols[0]] = ANS_TAB_SIZE;
+        } else {
+            if symbols[0] == symbols[1] {
+                return Err(Error::InvalidAnsHistogram);
+            }
+            counts[symbols[0]] = br.read(ANS_LOG_TAB_SIZE)? as u32;
+            counts[symbols[1]] = ANS_TAB_SIZE - counts[symbols[0]];
+        }
+        return Ok(counts);
+    }
+
+    if br.read(1)? != 0 {
+        // Flat distribution.
+        let alphabet_size = decode_varlen_uint8(br)? as u32 + 1;
+        return Ok((0..alphabet_size)
+            .map(|i| ANS_TAB_SIZE / alphabet_size + (i < ANS_TAB_SIZE % alphabet_size) as u32)
+            .collect());
+    }
+
+    let mut log = 0;
+    while log < 3 && br.read(1)? != 0 {
+        log += 1;
+    }
+    let shift = (br.read(log)? as usize | (1 << log)) - 1;
+    if shift > ANS_LOG_TAB_SIZE + 1 {
+        return Err(Error::InvalidAnsHistogram);
+    }
+    let length = decode_varlen_uint8(br)? + 3;
+
+    let mut logcounts = vec![0u8; length];
+    // Length of the run of repeated counts starting at each position.
+    let mut same = vec![0; length];
+    let mut omit_pos = None;
+    let mut i = 0;
+    while i < length {
+        let (bits, logcount) = LOGCOUNT_CODE[br.peek(7) as usize];
+        br.consume(bits as usize)?;
+        logcounts[i] = logcount;
+        if logcount == RLE_LOGCOUNT {
+            let rle_length = decode_varlen_uint8(br)?;
+            same[i] = rle_length + 5;
+            i += rle_length + 4;
+            continue;
+        }
+        // The largest count, first one on ties, is implied by the others.
+        if omit_pos.is_none_or(|pos| logcount > logcounts[pos]) {
+            omit_pos = Some(i);
+        }
+        i += 1;
+    }
+    let omit_pos = omit_pos.ok_or(Error::InvalidAnsHistogram)?;
+    // The omitted count cannot be repeated, as in libjxl.
+    if logcounts.get(omit_pos + 1) == Some(&RLE_LOGCOUNT) {
+        return Err(Error::InvalidAnsHistogram);
+    }
+
+    let mut counts = vec![0u32; length];
+    let mut total_count = 0u32;
+    let mut prev = 0;
+    let mut num_same = 0;
+    for i in 0..length {
+        if same[i] != 0 {
+            num_same = same[i] - 1;
+            prev = if i > 0 { counts[i - 1] } else { 0 };
+        }
+        if num_same > 0 {
+            counts[i] = prev;
+            num_same -= 1;
+        } else {
+            let code = logcounts[i] as usize;
+            if i == omit_pos || code == 0 {
+                continue;
+            } else if code == 1 {
+                counts[i] = 1;
+            } else {
+                let log = code - 1;
+                // Number of explicit bits below the leading one.
+                let precision = (shift as isize - ((ANS_LOG_TAB_SIZE - log) >> 1) as isize)
+                    .clamp(0, log as isize) as usize;
+                counts[i] = (1 << log) + ((br.read(precision)? as u32) << (log - precision));
+            }
+        }
+        total_count += counts[i];
+    }
+    if total_count >= ANS_TAB_SIZE {
+        return Err(Error::InvalidAnsHistogram);
+    }
+    counts[omit_pos] = ANS_TAB_SIZE - total_count;
+    Ok(counts)
+}
+
+/// One bucket of an alias table. The first `cutoff` positions of the bucket
+/// belong to the symbol with the bucket's index, the rest to `right_value`.
+#[derive(Debug, Clone, Copy, Default)]
+struct AliasEntry {
+    cutoff: u16,
+    right_value: u16,
+    offsets1: u16,
+    freq0: u16,
+    freq1: u16,
+}
+
+// Splits the distribution into `1 << log_alpha_size` equally sized buckets
+// holding at most two symbols each, so that a symbol is found with a single
+// lookup.
+fn build_alias_table(counts: &[u32], log_alpha_size: usize) -> Result<Vec<AliasEntry>, Error> {
+    let table_size = 1 << log_alpha_size;
+    let entry_size = ANS_TAB_SIZE >> log_alpha_size;
+    let num_symbols = counts.iter().rposition(|&c| c != 0).map_or(0, |s| s + 1);
+    let freq = |s: usize| if s < num_symbols { counts[s] } else { 0 };
+    let mut table = vec![AliasEntry::default(); table_size];
+
+    // A single symbol must leave the state unchanged.
+    if let Some(symbol) = (0..num_symbols).find(|&s| counts[s] == ANS_TAB_SIZE) {
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = AliasEntry {
+                cutoff: 0,
+                right_value: symbol as u16,
+                offsets1: (entry_size as usize * i) as u16,
+                freq0: 0,
+                freq1: ANS_TAB_SIZE as u16,
+            };
+        }
+        return Ok(table);
+    }
+
+    let mut cutoffs: Vec<u32> = (0..table_size).map(freq).collect();
+    let mut offsets1 = vec![0u32; table_size];
+    let mut underfull: Vec<usize> = (0..table_size)
+        .filter(|&i| cutoffs[i] < entry_size)
+        .collect();
+    let mut overfull: Vec<usize> = (0..table_size)
+        .filter(|&i| cutoffs[i] > entry_size)
+        .collect();
+    while let Some(over) = overfull.pop() {
+        let under = underfull.pop().ok_or(Error::InvalidAnsHistogram)?;
+        // The end of the range of `over` fills the rest of bucket `under`.
+        cutoffs[over] -= entry_size - cutoffs[under];
+        table[under].right_value = over as u16;
+        offsets1[under] = cutoffs[over];
+        if cutoffs[over] < entry_size {
+            underfull.push(over);
+        } else if cutoffs[over] > entry_size {
+            overfull.push(over);
+        }
+    }
+    for (i, entry) in table.iter_mut().enumerate() {
+        if cutoffs[i] == entry_size {
+            entry.right_value = i as u16;
+            entry.cutoff = 0;
+        } else {
+            entry.offsets1 = (offsets1[i] - cutoffs[i]) as u16;
+            entry.cutoff = cutoffs[i] as u16;
+        }
+        entry.freq0 = freq(i) as u16;
+        entry.freq1 = freq(entry.right_value as usize) as u16;
+    }
+    Ok(table)
+}
+
+#[derive(Debug)]
+pub struct AnsCodes {
+    log_alpha_size: usize,
+    // `1 << log_alpha_size` entries per distribution.
+    alias_tables: Vec<AliasEntry>,
+    alphabet_sizes: Vec<usize>,
+}
+
+impl AnsCodes {
+    pub fn decode(
+        num: usize,
+        log_alpha_size: usize,
+        br: &mut BitReader,
+    ) -> Result<AnsCodes, Error> {
+        let mut alias_tables = Vec::with_capacity(num << log_alpha_size);
+        let mut alphabet_sizes = Vec::with_capacity(num);
+        for _ in 0..num {
+            let counts = read_histogram(br)?;
+            if counts.len() > 1 << log_alpha_size {
+                return Err(Error::InvalidAnsHistogram);
+            }
+            alias_tables.extend(build_alias_table(&counts, log_alpha_size)?);
+            alphabet_sizes.push(counts.len());
+        }
+        Ok(AnsCodes {
+            log_alpha_size,
+            alias_tables,
+            alphabet_sizes,
+        })
+    }
+
+    /// Number of symbols each distribution was coded with.
+    pub fn alphabet_sizes(&self) -> &[usize] {
+        &self.alphabet_sizes
+    }
+
+    /// Decodes a symbol from distribution `ctx`, updating the ANS `state`.
+    pub fn read(&self, br: &mut BitReader, state: &mut u32, ctx: usize) -> Result<u32, Error> {
+        let log_entry_size = ANS_LOG_TAB_SIZE - self.log_alpha_size;
+        let slot = (*state & (ANS_TAB_SIZE - 1)) as usize;
+        let (bucket, pos) = (slot >> log_entry_size, slot & ((1 << log_entry_size) - 1));
+        let entry = &self.alias_tables[(ctx << self.log_alpha_size) + bucket];
+        let (symbol, offset, freq) = if pos >= entry.cutoff as usize {
+            (
+                entry.right_value as u32,
+                entry.offsets1 as u32 + pos as u32,
+                entry.freq1 as u32,
+            )
+        } else {
+            (bucket as u32, pos as u32, entry.freq0 as u32)
+        };
+        *state = freq * (*state >> ANS_LOG_TAB_SIZE) + offset;
+        if *state < (1 << 16) {
+            *state = (*state << 16) | br.peek(16) as u32;
+            br.consume(16)?;
+        }
+        Ok(symbol)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_writer::BitWriter;
+
+    // Maps each (symbol, offset) pair to the state slot that decodes to it.
+    fn reverse_map(table: &[AliasEntry], counts: &[u32], log_alpha_size: usize) -> Vec<Vec<u32>> {
+        let codes = AnsCodes {
+            log_alpha_size,
+            alias_tables: table.to_vec(),
+            alphabet_sizes: vec![counts.len()],
+        };
+        let mut reverse: Vec<Vec<u32>> =
+            counts.iter().map(|&c| vec![u32::MAX; c as usize]).collect();
+        for slot in 0..ANS_TAB_SIZE {
+            // A state that does not need renormalization after the symbol.
+            let mut state = (1 << 28) | slot;
+            let mut br = BitReader::new(&[]);
+            let symbol = codes.read(&mut br, &mut state, 0).unwrap() as usize;
+            let offset = (state - (counts[symbol] << 16)) as usize;
+            assert_eq!(reverse[symbol][offset], u32::MAX, "slot used twice");
+            reverse[symbol][offset] = slot;
+        }
+        reverse
+    }
+
+    fn encode(counts: &[u32], reverse: &[Vec<u32>], symbols: &[usize], bw: &mut BitWriter) {
+        let mut state = ANS_FINAL_STATE;
+        let mut chunks = vec![];
+        for &symbol in symbols.iter().rev() {
+            let freq = counts[symbol];
+            if (state >> (32 - ANS_LOG_TAB_SIZE)) >= freq {
+                chunks.push(state & 0xffff);
+                state >>= 16;
+            }
+            state = ((state / freq) << ANS_LOG_TAB_SIZE) + reverse[symbol][(state % freq) as usize];
+        }
+        bw.write(32, state as u64);
+        for chunk in chunks.iter().rev() {
+            bw.write(16, *chunk as u64);
+        }
+    }
+
+    #[test]
+    fn test_alias_table() {
+        let distributions: [&[u32]; 4] = [
+            &[4096],
+            &[0, 0, 4096],
+            &[2048, 1024, 1000, 24],
+            &[1, 4000, 0, 0, 0, 50, 45],
+        ];
+        for counts in distributions {
+            for log_alpha_size in 5..=8 {
+                let table = build_alias_table(counts, log_alpha_size).unwrap();
+                let reverse = reverse_map(&table, counts, log_alpha_size);
+                // Every slot of every symbol is reachable.
+                assert!(reverse.iter().flatten().all(|&slot| slot < ANS_TAB_SIZE));
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_histogram() {
+        let mut bw = BitWriter::new();
+        let write_logcount = |bw: &mut BitWriter, logcount: u8| {
+            let (idx, (bits, _)) = LOGCOUNT_CODE
+                .iter()
+                .enumerate()
+                .find(|(_, (_, l))| *l == logcount)
+                .unwrap();
+            bw.write(*bits as usize, idx as u64 & ((1 << bits) - 1));
+        };
+        // Not simple, not flat, shift 0.
+        bw.write(3, 0);
+        // 8 counts.
+        bw.write(1, 1);
+        bw.write(3, 2);
+        bw.write(2, 1);
+        write_logcount(&mut bw, 3);
+        // Four more counts equal to the previous one.
+        write_logcount(&mut bw, RLE_LOGCOUNT);
+        bw.write(1, 0);
+        write_logcount(&mut bw, 6);
+        write_logcount(&mut bw, 1);
+        write_logcount(&mut bw, 0);
+        let data = bw.finalize();
+        let counts = read_histogram(&mut BitReader::new(&data)).unwrap();
+        assert_eq!(counts, [4, 4, 4, 4, 4, 4075, 1, 0]);
+
+        // Flat distribution of 3 symbols.
+        let mut bw = BitWriter::new();
+        bw.write(2, 0b10);
+        bw.write(1, 1);
+        bw.write(3, 1);
+        bw.write(1, 0);
+        let data = bw.finalize();
+        let counts = read_histogram(&mut BitReader::new(&data)).unwrap();
+        assert_eq!(counts, [1366, 1365, 1365]);
+
+        // The largest count is followed by a run of copies of itself.
+        let mut bw = BitWriter::new();
+        bw.write(3, 0);
+        bw.write(1, 1);
+        bw.write(3, 2);
+        bw.write(2, 1);
+        write_logcount(&mut bw, 6);
+        write_logcount(&mut bw, RLE_LOGCOUNT);
+        bw.write(1, 0);
+        write_logcount(&mut bw, 1);
+        write_logcount(&mut bw, 0);
+        write_logcount(&mut bw, 0);
+        let data = bw.finalize();
+        assert!(matches!(
+            read_histogram(&mut BitReader::new(&data)),
+            Err(Error::InvalidAnsHistogram)
+        ));
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<(), Error> {
+        let counts = [1000, 3000, 0, 96];
+        let log_alpha_size = 5;
+        let table = build_alias_table(&counts, log_alpha_size)?;
+        let reverse = reverse_map(&table, &counts, log_alpha_size);
+        let symbols: Vec<usize> = (0..1000).map(|i| [1, 0, 1, 3, 1][i % 5]).collect();
+        let mut bw = BitWriter::new();
+        encode(&counts, &reverse, &symbols, &mut bw);
+        let data = bw.finalize();
+
+        let codes = AnsCodes {
+            log_alpha_size,
+            alias_tables: table,
+            alphabet_sizes: vec![counts.len()],
+        };
+        let mut br = BitReader::new(&data);
+        let mut state = br.read(32)? as u32;
+        for &symbol in symbols.iter() {
+            assert_eq!(codes.read(&mut br, &mut state, 0)?, symbol as u32);
+        }
+        assert_eq!(state, ANS_FINAL_STATE);
+        assert_eq!(br.total_bits_read(), data.len() * 8);
+        Ok(())
+    }
+}
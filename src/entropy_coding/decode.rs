@@ -8,11 +8,12 @@ extern crate jxl_headers_derive;
 use jxl_headers_derive::UnconditionalCoder;
 
 use crate::bit_reader::BitReader;
-#[allow(unused_imports)]
 use crate::entropy_coding::ans::*;
 use crate::entropy_coding::context_map::*;
 use crate::entropy_coding::huffman::*;
 use crate::entropy_coding::hybrid_uint::*;
+use crate::entropy_coding::lz77::Lz77Window;
+use crate::entropy_coding::stats::EntropyStats;
 use crate::error::Error;
 use crate::headers::encodings::*;
 
@@ -43,6 +44,7 @@ struct LZ77Params {
 #[derive(Debug)]
 enum Codes {
     Huffman(HuffmanCodes),
+    Ans(AnsCodes),
 }
 
 #[derive(Debug)]
@@ -58,30 +60,77 @@ pub struct Histograms {
 #[derive(Debug)]
 pub struct Reader<'a> {
     histograms: &'a Histograms,
+    ans_state: u32,
+    lz77_window: Option<Lz77Window>,
+    stats: Option<EntropyStats>,
 }
 
 impl<'a> Reader<'a> {
-    fn read_internal(
-        &self,
-        br: &mut BitReader,
-        uint_config: &HybridUint,
-        cluster: usize,
-    ) -> Result<u32, Error> {
-        let symbol = match &self.histograms.codes {
-            Codes::Huffman(hc) => hc.read(br, cluster)?,
-        };
-        uint_config.read(symbol, br)
+    fn read_symbol(&mut self, br: &mut BitReader, cluster: usize) -> Result<u32, Error> {
+        match &self.histograms.codes {
+            Codes::Huffman(hc) => hc.read(br, cluster),
+            Codes::Ans(ans) => ans.read(br, &mut self.ans_state, cluster),
+        }
     }
 
-    pub fn read(&self, br: &mut BitReader, context: usize) -> Result<u32, Error> {
-        assert!(!self.histograms.lz77_params.enabled);
-        let cluster = self.histograms.context_map[context] as usize;
-        self.read_internal(br, &self.histograms.uint_configs[cluster], cluster)
+    pub fn read(&mut self, br: &mut BitReader, context: usize) -> Result<u32, Error> {
+        let histograms = self.histograms;
+        let cluster = histograms.context_map[context] as usize;
+        if let Some(stats) = &mut self.stats {
+            stats.symbols_per_context[context] += 1;
+            stats.symbols_per_histogram[cluster] += 1;
+        }
+        if let Some(value) = self.lz77_window.as_mut().and_then(|w| w.next_copied()) {
+            return Ok(value);
+        }
+        let token = self.read_symbol(br, cluster)?;
+        if let (Some(min_symbol), Some(min_length), Some(length_uint)) = (
+            histograms.lz77_params.min_symbol,
+            histograms.lz77_params.min_length,
+            &histograms.lz77_length_uint,
+        ) {
+            if token >= min_symbol {
+                let length = length_uint.read(token - min_symbol, br)? as u64 + min_length as u64;
+                // The distance uses the extra context after the regular ones.
+                let distance_context = histograms.context_map.len() - 1;
+                let distance_cluster = histograms.context_map[distance_context] as usize;
+                if let Some(stats) = &mut self.stats {
+                    stats.symbols_per_context[distance_context] += 1;
+                    stats.symbols_per_histogram[distance_cluster] += 1;
+                    stats.add_lz77_copy(length);
+                }
+                let distance_token = self.read_symbol(br, distance_cluster)?;
+                let distance =
+                    histograms.uint_configs[distance_cluster].read(distance_token, br)?;
+                let window = self.lz77_window.as_mut().unwrap();
+                window.start_copy(length, distance);
+                return Ok(window.next_copied().unwrap());
+            }
+        }
+        let value = histograms.uint_configs[cluster].read(token, br)?;
+        if let Some(window) = &mut self.lz77_window {
+            window.push(value);
+        }
+        Ok(value)
+    }
+
+    /// Starts recording per-context symbol counts for the symbols read from now on.
+    pub fn collect_stats(&mut self) {
+        if self.stats.is_none() {
+            self.stats = Some(self.histograms.stats());
+        }
+    }
+
+    /// Returns the statistics recorded so far, if `collect_stats` was called.
+    pub fn stats(&self) -> Option<&EntropyStats> {
+        self.stats.as_ref()
     }
 
     pub fn check_final_state(self) -> Result<(), Error> {
         match &self.histograms.codes {
             Codes::Huffman(_) => Ok(()),
+            Codes::Ans(_) if self.ans_state == ANS_FINAL_STATE => Ok(()),
+            Codes::Ans(_) => Err(Error::InvalidAnsStream),
         }
     }
 }
@@ -92,6 +141,8 @@ impl Histograms {
         br: &mut BitReader,
         allow_lz77: bool,
     ) -> Result<Histograms, Error> {
+        #[cfg(feature = "trace")]
+        let trace_bit_offset = br.total_bits_read();
         let lz77_params = LZ77Params::read_unconditional(&(), br, &Empty {})?;
         if !allow_lz77 && lz77_params.enabled {
             return Err(Error::LZ77Disallowed);
@@ -110,11 +161,11 @@ impl Histograms {
         } else {
             vec![0]
         };
-        // TODO(veluca93): debug print.
-        println!(
-            "nc: {} {:?} {:?} {:?}",
-            num_contexts, lz77_params, context_map, lz77_length_uint
-        );
+        #[cfg(feature = "trace")]
+        {
+            crate::trace::record("Histograms.lz77", trace_bit_offset, &lz77_params);
+            crate::trace::record("Histograms.context_map", trace_bit_offset, &context_map);
+        }
         assert_eq!(context_map.len(), num_contexts);
 
         let use_prefix_code = br.read(1)? != 0;
@@ -130,7 +181,11 @@ impl Histograms {
         let codes = if use_prefix_code {
             Codes::Huffman(HuffmanCodes::decode(num_histograms as usize, br)?)
         } else {
-            unimplemented!();
+            Codes::Ans(AnsCodes::decode(
+                num_histograms as usize,
+                log_alpha_size,
+                br,
+            )?)
         };
 
         Ok(Histograms {
@@ -144,16 +199,46 @@ impl Histograms {
     }
     fn make_reader_impl(
         &self,
-        _br: &mut BitReader,
-        _image_width: Option<usize>,
-    ) -> Result<Reader, Error> {
-        if self.lz77_params.enabled {
-            unimplemented!()
+        br: &mut BitReader,
+        image_width: Option<usize>,
+    ) -> Result<Reader<'_>, Error> {
+        let ans_state = match self.codes {
+            Codes::Huffman(_) => ANS_FINAL_STATE,
+            Codes::Ans(_) => br.read(32)? as u32,
+        };
+        Ok(Reader {
+            histograms: self,
+            ans_state,
+            lz77_window: self
+                .lz77_params
+                .enabled
+                .then(|| Lz77Window::new(image_width)),
+            stats: None,
+        })
+    }
+
+    /// Returns the structure of this set of histograms, with all symbol counts at zero.
+    pub fn stats(&self) -> EntropyStats {
+        let num_contexts = self.context_map.len();
+        let num_histograms = self.uint_configs.len();
+        let histogram_sizes = match &self.codes {
+            Codes::Huffman(hc) => hc.alphabet_sizes(),
+            Codes::Ans(ans) => ans.alphabet_sizes(),
+        };
+        EntropyStats {
+            num_contexts,
+            num_histograms,
+            use_prefix_code: matches!(self.codes, Codes::Huffman(_)),
+            log_alpha_size: self.log_alpha_size,
+            lz77_enabled: self.lz77_params.enabled,
+            histogram_sizes: histogram_sizes.to_vec(),
+            symbols_per_context: vec![0; num_contexts],
+            symbols_per_histogram: vec![0; num_histograms],
+            ..Default::default()
         }
-        Ok(Reader { histograms: self })
     }
 
-    pub fn make_reader(&self, br: &mut BitReader) -> Result<Reader, Error> {
+    pub fn make_reader(&self, br: &mut BitReader) -> Result<Reader<'_>, Error> {
         self.make_reader_impl(br, None)
     }
 
@@ -161,7 +246,147 @@ impl Histograms {
         &self,
         br: &mut BitReader,
         image_width: usize,
-    ) -> Result<Reader, Error> {
+    ) -> Result<Reader<'_>, Error> {
         self.make_reader_impl(br, Some(image_width))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stats() -> Result<(), Error> {
+        // No LZ77, prefix codes, a single histogram with a one-symbol alphabet.
+        let data = [0x3e];
+        let mut br = BitReader::new(&data);
+        let histograms = Histograms::decode(1, &mut br, false)?;
+        let mut reader = histograms.make_reader(&mut br)?;
+        assert_eq!(reader.read(&mut br, 0)?, 0);
+        assert!(reader.stats().is_none());
+        reader.collect_stats();
+        for _ in 0..3 {
+            assert_eq!(reader.read(&mut br, 0)?, 0);
+        }
+        let stats = reader.stats().unwrap();
+        assert_eq!(stats.num_contexts, 1);
+        assert_eq!(stats.num_histograms, 1);
+        assert!(stats.use_prefix_code);
+        assert!(!stats.lz77_enabled);
+        assert_eq!(stats.symbols_per_context, vec![3]);
+        assert_eq!(stats.symbols_per_histogram, vec![3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ans() -> Result<(), Error> {
+        let stream = |final_state: u32| {
+            let mut bw = crate::bit_writer::BitWriter::new();
+            // No LZ77, ANS with log_alpha_size 5, symbols are read as is.
+            bw.write(1, 0);
+            bw.write(1, 0);
+            bw.write(2, 0);
+            bw.write(3, 5);
+            // A single symbol, 2.
+            bw.write(2, 0b01);
+            bw.write(1, 1);
+            bw.write(3, 1);
+            bw.write(1, 0);
+            bw.write(32, final_state as u64);
+            bw.finalize()
+        };
+        let data = stream(ANS_FINAL_STATE);
+        let mut br = BitReader::new(&data);
+        let histograms = Histograms::decode(1, &mut br, false)?;
+        assert!(!histograms.stats().use_prefix_code);
+        let mut reader = histograms.make_reader(&mut br)?;
+        for _ in 0..3 {
+            assert_eq!(reader.read(&mut br, 0)?, 2);
+        }
+        reader.check_final_state()?;
+
+        let data = stream(ANS_FINAL_STATE + 1);
+        let mut br = BitReader::new(&data);
+        let histograms = Histograms::decode(1, &mut br, false)?;
+        let reader = histograms.make_reader(&mut br)?;
+        assert!(matches!(
+            reader.check_final_state(),
+            Err(Error::InvalidAnsStream)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lz77() -> Result<(), Error> {
+        let mut bw = crate::bit_writer::BitWriter::new();
+        // LZ77 with min_symbol 8 and min_length 3, lengths read as is.
+        bw.write(1, 1);
+        bw.write(2, 3);
+        bw.write(15, 0);
+        bw.write(2, 0);
+        bw.write(4, 8);
+        // Contexts 0, 1 and the distance context each have their histogram.
+        bw.write(1, 1);
+        bw.write(2, 2);
+        bw.write(6, 0b10_01_00);
+        // Prefix codes, symbols are read as is.
+        bw.write(1, 1);
+        for _ in 0..3 {
+            bw.write(4, 15);
+        }
+        // Alphabet sizes 9, 9 and 4.
+        for _ in 0..2 {
+            bw.write(1, 1);
+            bw.write(4, 3);
+            bw.write(3, 0);
+        }
+        bw.write(1, 1);
+        bw.write(4, 1);
+        bw.write(1, 1);
+        // Four symbols each, coded with 1, 2, 3 and 3 bits.
+        for (symbols, bits) in [([3, 8, 5, 6], 4), ([4, 8, 5, 6], 4), ([1, 0, 2, 3], 2)] {
+            bw.write(4, 0b11_01);
+            for symbol in symbols {
+                bw.write(bits, symbol);
+            }
+            bw.write(1, 1);
+        }
+        // 3, 4, copy 3 values at distance 2, 4.
+        bw.write(1, 0);
+        bw.write(1, 0);
+        bw.write(2, 0b01);
+        bw.write(1, 0);
+        bw.write(1, 0);
+        let data = bw.finalize();
+
+        let mut br = BitReader::new(&data);
+        let histograms = Histograms::decode(2, &mut br, true)?;
+        assert!(histograms.stats().lz77_enabled);
+        let mut reader = histograms.make_reader(&mut br)?;
+        reader.collect_stats();
+        let values: Vec<u32> = [0, 1, 0, 1, 0, 1]
+            .iter()
+            .map(|&ctx| reader.read(&mut br, ctx))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(values, [3, 4, 3, 4, 3, 4]);
+
+        let stats = reader.stats().unwrap().clone();
+        assert_eq!(stats.num_contexts, 3);
+        assert_eq!(stats.num_histograms, 3);
+        assert_eq!(stats.histogram_sizes, vec![9, 9, 4]);
+        assert_eq!(stats.symbols_per_context, vec![3, 3, 1]);
+        assert_eq!(stats.symbols_per_histogram, vec![3, 3, 1]);
+        assert_eq!(stats.lz77_copies, 1);
+        assert_eq!(stats.lz77_copied_symbols, 3);
+        assert_eq!(stats.lz77_copy_lengths, vec![0, 1]);
+        let text = stats.to_string();
+        assert!(text.contains("1 LZ77 copies of 3 symbols"), "{}", text);
+        assert!(
+            text.contains("histogram    2: 1 (4 symbol alphabet)"),
+            "{}",
+            text
+        );
+        reader.check_final_state()?;
+        Ok(())
+    }
+}
@@ -73,7 +73,7 @@ impl Table {
             }
             *symbol = sym as u16;
         }
-        if (0..num_symbols - 1).any(|i| symbols[..i].contains(&symbols[i + 1])) {
+        if (1..num_symbols).any(|i| symbols[..i].contains(&symbols[i])) {
             return Err(Error::InvalidHuffman);
         }
 
@@ -82,9 +82,12 @@ impl Table {
         } else {
             false
         };
-        if !special_4_symbols {
-            symbols.sort_unstable();
-        };
+        /* canonical codes are assigned by (length, symbol) */
+        match (num_symbols, special_4_symbols) {
+            (3, _) => symbols[1..3].sort_unstable(),
+            (4, true) => symbols[2..4].sort_unstable(),
+            _ => symbols[..num_symbols].sort_unstable(),
+        }
         match (num_symbols, special_4_symbols) {
             (1, _) => Ok(vec![
                 TableEntry {
@@ -110,10 +113,6 @@ impl Table {
             (3, _) => {
                 let mut ret = Vec::with_capacity(TABLE_SIZE);
                 for _ in 0..(TABLE_SIZE >> 2) {
-                    ret.push(TableEntry {
-                        bits: 1,
-                        value: symbols[0],
-                    });
                     ret.push(TableEntry {
                         bits: 1,
                         value: symbols[0],
@@ -122,6 +121,10 @@ impl Table {
                         bits: 2,
                         value: symbols[1],
                     });
+                    ret.push(TableEntry {
+                        bits: 1,
+                        value: symbols[0],
+                    });
                     ret.push(TableEntry {
                         bits: 2,
                         value: symbols[2],
@@ -138,11 +141,11 @@ impl Table {
                     });
                     ret.push(TableEntry {
                         bits: 2,
-                        value: symbols[1],
+                        value: symbols[2],
                     });
                     ret.push(TableEntry {
                         bits: 2,
-                        value: symbols[2],
+                        value: symbols[1],
                     });
                     ret.push(TableEntry {
                         bits: 2,
@@ -153,7 +156,6 @@ impl Table {
             }
             (4, true) => {
                 let mut ret = Vec::with_capacity(TABLE_SIZE);
-                symbols[2..4].sort_unstable();
                 for _ in 0..(TABLE_SIZE >> 3) {
                     ret.push(TableEntry {
                         bits: 1,
@@ -203,9 +205,9 @@ impl Table {
 
         let mut symbol = 0;
         let mut prev_code_len = DEFAULT_CODE_LENGTH;
-        let mut repeat = 0;
+        let mut repeat = 0usize;
         let mut repeat_code_len = 0;
-        let mut space = 1 << 15;
+        let mut space = 1i32 << 15;
 
         let mut code_lengths = vec![0u8; al_size];
 
@@ -219,12 +221,10 @@ impl Table {
                 symbol += 1;
                 if code_len != 0 {
                     prev_code_len = code_len;
-                    space -= 32768usize >> code_len;
+                    space -= 32768 >> code_len;
                 }
             } else {
                 let extra_bits = code_len - 14;
-                let old_repeat;
-                let repeat_delta;
                 let new_len = if code_len == CODE_LENGTH_REPEAT_CODE {
                     prev_code_len
                 } else {
@@ -234,22 +234,20 @@ impl Table {
                     repeat = 0;
                     repeat_code_len = new_len;
                 }
-                old_repeat = repeat;
+                let old_repeat = repeat;
                 if repeat > 0 {
                     repeat -= 2;
                     repeat <<= extra_bits;
                 }
-                repeat += br.read(extra_bits as usize)? as u8 + 3;
-                repeat_delta = repeat - old_repeat;
-                if symbol + repeat_delta as usize > al_size {
+                repeat += br.read(extra_bits as usize)? as usize + 3;
+                let repeat_delta = repeat - old_repeat;
+                if symbol + repeat_delta > al_size {
                     return Err(Error::InvalidHuffman);
                 }
-                for i in 0..repeat_code_len {
-                    code_lengths[symbol + i as usize] = repeat_delta;
-                }
-                symbol += repeat_delta as usize;
+                code_lengths[symbol..symbol + repeat_delta].fill(repeat_code_len);
+                symbol += repeat_delta;
                 if repeat_code_len != 0 {
-                    space -= (repeat_delta as usize) << (15 - repeat_code_len);
+                    space -= (repeat_delta as i32) << (15 - repeat_code_len);
                 }
             }
         }
@@ -360,6 +358,7 @@ impl Table {
                     table_pos += table_size;
                     table_bits = next_table_bit_size(&counts, len, root_bits);
                     table_size = 1 << table_bits;
+                    table.resize(table_pos + table_size, TableEntry { bits: 0, value: 0 });
                     low = key & mask;
                     table[low as usize].bits = (table_bits + root_bits) as u8;
                     table[low as usize].value = (table_pos - low as usize) as u16;
@@ -369,7 +368,11 @@ impl Table {
                 let value = sorted[symbol] as u16;
                 symbol += 1;
                 let pos = table_pos + (key as usize >> root_bits);
-                replicate_value(&mut table[pos..], step, TableEntry { bits, value });
+                replicate_value(
+                    &mut table[pos..table_pos + table_size],
+                    step,
+                    TableEntry { bits, value },
+                );
                 key = get_next_key(key, len);
             }
             step <<= 1;
@@ -381,7 +384,6 @@ impl Table {
         let entries = if al_size == 1 {
             vec![TableEntry { bits: 0, value: 0 }; TABLE_SIZE]
         } else {
-            assert!(al_size < 1 << HUFFMAN_MAX_BITS);
             let simple_code_or_skip = br.read(2)? as usize;
             if simple_code_or_skip == 1 {
                 Table::decode_simple_table(al_size, br)?
@@ -434,24 +436,137 @@ impl Table {
 #[derive(Debug)]
 pub struct HuffmanCodes {
     tables: Vec<Table>,
+    alphabet_sizes: Vec<usize>,
 }
 
 impl HuffmanCodes {
     pub fn decode(num: usize, br: &mut BitReader) -> Result<HuffmanCodes, Error> {
-        let alphabet_sizes: Vec<u16> = (0..num)
-            .map(|_| Ok(decode_varint16(br)? + 1))
-            .collect::<Result<_, _>>()?;
+        let alphabet_sizes: Vec<usize> = (0..num)
+            .map(|_| Ok(decode_varint16(br)? as usize + 1))
+            .collect::<Result<_, Error>>()?;
         let max = *alphabet_sizes.iter().max().unwrap();
-        if max as usize > (1 << HUFFMAN_MAX_BITS) {
-            return Err(Error::AlphabetTooLargeHuff(max as usize));
+        if max > (1 << HUFFMAN_MAX_BITS) {
+            return Err(Error::AlphabetTooLargeHuff(max));
         }
         let tables = alphabet_sizes
             .iter()
-            .map(|sz| Table::decode(*sz as usize, br))
+            .map(|sz| Table::decode(*sz, br))
             .collect::<Result<_, _>>()?;
-        Ok(HuffmanCodes { tables })
+        Ok(HuffmanCodes {
+            tables,
+            alphabet_sizes,
+        })
+    }
+
+    /// Number of symbols each table was coded with.
+    pub fn alphabet_sizes(&self) -> &[usize] {
+        &self.alphabet_sizes
     }
+
     pub fn read(&self, br: &mut BitReader, ctx: usize) -> Result<u32, Error> {
         self.tables[ctx].read(br)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_writer::BitWriter;
+
+    // Writes symbols with the canonical prefix code for the given code lengths.
+    fn write_symbols(bw: &mut BitWriter, code_lengths: &[u8], symbols: &[usize]) {
+        let mut codes = vec![0u32; code_lengths.len()];
+        let mut code = 0;
+        for len in 1..=HUFFMAN_MAX_BITS as u8 {
+            for (symbol, _) in code_lengths.iter().enumerate().filter(|(_, l)| **l == len) {
+                codes[symbol] = code;
+                code += 1;
+            }
+            code <<= 1;
+        }
+        for &symbol in symbols {
+            for i in (0..code_lengths[symbol]).rev() {
+                bw.write(1, (codes[symbol] >> i) as u64 & 1);
+            }
+        }
+    }
+
+    fn check_table(data: &[u8], al_size: usize, symbols: &[usize]) -> Result<(), Error> {
+        let mut br = BitReader::new(data);
+        let table = Table::decode(al_size, &mut br)?;
+        for &symbol in symbols {
+            assert_eq!(table.read(&mut br)?, symbol as u32);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_simple_tables() -> Result<(), Error> {
+        let cases: [(&[(usize, u8)], bool); 4] = [
+            (&[(7, 1), (2, 1)], false),
+            (&[(5, 1), (9, 2), (1, 2)], false),
+            (&[(6, 2), (0, 2), (9, 2), (3, 2)], false),
+            (&[(6, 1), (9, 2), (3, 3), (0, 3)], true),
+        ];
+        for (symbols, tree_select) in cases {
+            let mut bw = BitWriter::new();
+            bw.write(2, 1);
+            bw.write(2, symbols.len() as u64 - 1);
+            let mut code_lengths = [0u8; 10];
+            for &(symbol, len) in symbols {
+                bw.write(4, symbol as u64);
+                code_lengths[symbol] = len;
+            }
+            if symbols.len() == 4 {
+                bw.write(1, tree_select as u64);
+            }
+            let sequence: Vec<_> = symbols.iter().rev().chain(symbols).map(|s| s.0).collect();
+            write_symbols(&mut bw, &code_lengths, &sequence);
+            check_table(&bw.finalize(), 10, &sequence)?;
+        }
+
+        let mut bw = BitWriter::new();
+        bw.write(2, 1);
+        bw.write(2, 1);
+        bw.write(4, 3);
+        bw.write(4, 3);
+        assert!(matches!(
+            check_table(&bw.finalize(), 10, &[]),
+            Err(Error::InvalidHuffman)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_complex_table() -> Result<(), Error> {
+        let mut bw = BitWriter::new();
+        bw.write(2, 0);
+        // Code length code: lengths 1 to 4 use 3 bits, 5 to 9, 11 and the
+        // repeat codes use 4 bits.
+        let mut clcl = [0u8; CODE_LENGTHS_CODE];
+        for (symbol, len) in clcl.iter_mut().enumerate() {
+            *len = match symbol {
+                1..=4 => 3,
+                5..=9 | 11 | 16 | 17 => 4,
+                _ => 0,
+            };
+        }
+        for symbol in [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11] {
+            // (bits, index) of the static code for each length.
+            let (bits, idx) = [(2, 0), (4, 7), (3, 3), (2, 2), (2, 1)][clcl[symbol] as usize];
+            bw.write(bits, idx);
+        }
+        // Lengths 1 to 9, eight zeros, then four symbols of length 11.
+        let mut code_lengths: Vec<u8> = (1..=9).collect();
+        code_lengths.extend([0; 8]);
+        code_lengths.extend([11; 4]);
+        write_symbols(&mut bw, &clcl, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 17]);
+        bw.write(3, 5);
+        write_symbols(&mut bw, &clcl, &[11, 16]);
+        bw.write(2, 0);
+
+        let sequence = [0, 8, 17, 20, 3, 19, 18, 7];
+        write_symbols(&mut bw, &code_lengths, &sequence);
+        check_table(&bw.finalize(), code_lengths.len(), &sequence)
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use std::fmt;
+
+const WINDOW_SIZE: usize = 1 << 20;
+
+// (dx, dy) offsets of the distances that have their own symbol when the image
+// width is known, in symbol order.
+#[rustfmt::skip]
+const SPECIAL_DISTANCES: [(i8, i8); 120] = [
+    (0, 1), (1, 0), (1, 1), (-1, 1), (0, 2), (2, 0), (1, 2), (-1, 2),
+    (2, 1), (-2, 1), (2, 2), (-2, 2), (0, 3), (3, 0), (1, 3), (-1, 3),
+    (3, 1), (-3, 1), (2, 3), (-2, 3), (3, 2), (-3, 2), (0, 4), (4, 0),
+    (1, 4), (-1, 4), (4, 1), (-4, 1), (3, 3), (-3, 3), (2, 4), (-2, 4),
+    (4, 2), (-4, 2), (0, 5), (3, 4), (-3, 4), (4, 3), (-4, 3), (5, 0),
+    (1, 5), (-1, 5), (5, 1), (-5, 1), (2, 5), (-2, 5), (5, 2), (-5, 2),
+    (4, 4), (-4, 4), (3, 5), (-3, 5), (5, 3), (-5, 3), (0, 6), (6, 0),
+    (1, 6), (-1, 6), (6, 1), (-6, 1), (2, 6), (-2, 6), (6, 2), (-6, 2),
+    (4, 5), (-4, 5), (5, 4), (-5, 4), (3, 6), (-3, 6), (6, 3), (-6, 3),
+    (0, 7), (7, 0), (1, 7), (-1, 7), (5, 5), (-5, 5), (7, 1), (-7, 1),
+    (4, 6), (-4, 6), (6, 4), (-6, 4), (2, 7), (-2, 7), (7, 2), (-7, 2),
+    (3, 7), (-3, 7), (7, 3), (-7, 3), (5, 6), (-5, 6), (6, 5), (-6, 5),
+    (8, 0), (4, 7), (-4, 7), (7, 4), (-7, 4), (8, 1), (8, 2), (6, 6),
+    (-6, 6), (8, 3), (5, 7), (-5, 7), (7, 5), (-7, 5), (8, 4), (6, 7),
+    (-6, 7), (7, 6), (-7, 6), (8, 5), (7, 7), (-7, 7), (8, 6), (8, 7),
+];
+
+/// The most recently decoded values of an LZ77-enabled stream, which copies
+/// refer to.
+pub struct Lz77Window {
+    window: Vec<u32>,
+    num_decoded: usize,
+    copy_pos: usize,
+    num_to_copy: u64,
+    special_distances: Vec<usize>,
+}
+
+impl Lz77Window {
+    /// Creates an empty window. If `image_width` is known, the first distance
+    /// symbols refer to nearby pixels in the previous rows.
+    pub fn new(image_width: Option<usize>) -> Lz77Window {
+        let special_distances = match image_width {
+            Some(width) => SPECIAL_DISTANCES
+                .iter()
+                .map(|&(dx, dy)| (dx as isize + width as isize * dy as isize).max(1) as usize)
+                .collect(),
+            None => vec![],
+        };
+        Lz77Window {
+            window: vec![0; WINDOW_SIZE],
+            num_decoded: 0,
+            copy_pos: 0,
+            num_to_copy: 0,
+            special_distances,
+        }
+    }
+
+    pub fn push(&mut self, value: u32) {
+        self.window[self.num_decoded % WINDOW_SIZE] = value;
+        self.num_decoded += 1;
+    }
+
+    /// Returns the next value of the copy in progress, if any.
+    pub fn next_copied(&mut self) -> Option<u32> {
+        if self.num_to_copy == 0 {
+            return None;
+        }
+        let value = self.window[self.copy_pos % WINDOW_SIZE];
+        self.copy_pos += 1;
+        self.num_to_copy -= 1;
+        self.push(value);
+        Some(value)
+    }
+
+    /// Starts copying `length` values, from the position given by the decoded
+    /// distance symbol. Distances reaching before the first value are clamped;
+    /// the window reads as zeros before any value is decoded.
+    pub fn start_copy(&mut self, length: u64, distance: u32) {
+        let distance = distance as usize;
+        let distance = match self.special_distances.get(distance) {
+            Some(&special) => special,
+            None => distance + 1 - self.special_distances.len(),
+        };
+        let distance = distance.min(self.num_decoded).min(WINDOW_SIZE);
+        self.copy_pos = self.num_decoded - distance;
+        self.num_to_copy = length;
+    }
+}
+
+impl fmt::Debug for Lz77Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lz77Window")
+            .field("num_decoded", &self.num_decoded)
+            .field("num_to_copy", &self.num_to_copy)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_copy() {
+        let mut window = Lz77Window::new(None);
+        assert_eq!(window.next_copied(), None);
+        // Copying before anything is decoded produces zeros.
+        window.start_copy(2, 5);
+        assert_eq!(window.next_copied(), Some(0));
+        assert_eq!(window.next_copied(), Some(0));
+        window.push(1);
+        window.push(2);
+        // Distance 2, overlapping the values being copied.
+        window.start_copy(5, 1);
+        let copied: Vec<_> = std::iter::from_fn(|| window.next_copied()).collect();
+        assert_eq!(copied, [1, 2, 1, 2, 1]);
+    }
+
+    #[test]
+    fn test_special_distances() {
+        let mut window = Lz77Window::new(Some(3));
+        for value in 0..9 {
+            window.push(value);
+        }
+        // (0, 1) is the pixel above, (-1, 1) the one above and to the right.
+        window.start_copy(1, 0);
+        assert_eq!(window.next_copied(), Some(6));
+        window.start_copy(1, 3);
+        assert_eq!(window.next_copied(), Some(8));
+        // Regular distances start at 1 after the special ones.
+        window.start_copy(1, 121);
+        assert_eq!(window.next_copied(), Some(6));
+    }
+}
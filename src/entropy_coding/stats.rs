@@ -0,0 +1,105 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use std::fmt;
+
+/// Statistics about a single entropy-coded stream, for analyzing bitstreams.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EntropyStats {
+    /// Number of contexts, including the extra context of LZ77 distances if
+    /// LZ77 is enabled.
+    pub num_contexts: usize,
+    /// Number of distinct histograms (clusters) the contexts map to.
+    pub num_histograms: usize,
+    pub use_prefix_code: bool,
+    pub log_alpha_size: usize,
+    pub lz77_enabled: bool,
+    /// Number of symbols each histogram was coded with.
+    pub histogram_sizes: Vec<usize>,
+    /// Number of symbols decoded for each context, copied ones included. LZ77
+    /// distances are counted in the last context.
+    pub symbols_per_context: Vec<u64>,
+    /// Number of symbols decoded for each histogram, counted like
+    /// `symbols_per_context`.
+    pub symbols_per_histogram: Vec<u64>,
+    /// Number of LZ77 copies.
+    pub lz77_copies: u64,
+    /// Number of LZ77 copies of each length, bucketed by powers of two: entry
+    /// `i` counts the lengths from `1 << i` to `(2 << i) - 1`.
+    pub lz77_copy_lengths: Vec<u64>,
+    /// Number of symbols that LZ77 copies produced.
+    pub lz77_copied_symbols: u64,
+}
+
+impl EntropyStats {
+    pub fn total_symbols(&self) -> u64 {
+        self.symbols_per_context.iter().sum()
+    }
+
+    /// Records an LZ77 copy of `length` symbols.
+    pub(crate) fn add_lz77_copy(&mut self, length: u64) {
+        self.lz77_copies += 1;
+        self.lz77_copied_symbols += length;
+        let bucket = (63 - length.max(1).leading_zeros()) as usize;
+        if self.lz77_copy_lengths.len() <= bucket {
+            self.lz77_copy_lengths.resize(bucket + 1, 0);
+        }
+        self.lz77_copy_lengths[bucket] += 1;
+    }
+}
+
+impl fmt::Display for EntropyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} contexts, {} histograms, {} coding, log_alpha_size {}, lz77 {}",
+            self.num_contexts,
+            self.num_histograms,
+            if self.use_prefix_code {
+                "prefix"
+            } else {
+                "ANS"
+            },
+            self.log_alpha_size,
+            if self.lz77_enabled { "on" } else { "off" },
+        )?;
+        writeln!(f, "{} symbols decoded", self.total_symbols())?;
+        for (ctx, count) in self.symbols_per_context.iter().enumerate() {
+            if *count != 0 {
+                writeln!(f, "  context {:4}: {}", ctx, count)?;
+            }
+        }
+        for (histo, count) in self.symbols_per_histogram.iter().enumerate() {
+            if *count != 0 {
+                writeln!(
+                    f,
+                    "  histogram {:4}: {} ({} symbol alphabet)",
+                    histo,
+                    count,
+                    self.histogram_sizes.get(histo).copied().unwrap_or(0)
+                )?;
+            }
+        }
+        if self.lz77_enabled {
+            writeln!(
+                f,
+                "{} LZ77 copies of {} symbols",
+                self.lz77_copies, self.lz77_copied_symbols
+            )?;
+            for (bucket, count) in self.lz77_copy_lengths.iter().enumerate() {
+                if *count != 0 {
+                    writeln!(
+                        f,
+                        "  length {:6}..{:6}: {}",
+                        1u64 << bucket,
+                        2u64 << bucket,
+                        count
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -3,14 +3,57 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
+use std::fmt;
 use thiserror::Error;
 
 use crate::entropy_coding::huffman::HUFFMAN_MAX_BITS;
+use crate::headers::toc::Section;
+use crate::headers::Orientation;
+
+/// The part of the codestream that was being read when an error occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorLocation {
+    FileHeaders,
+    Icc,
+    Preview,
+    /// The header, TOC or sections of the frame with this index, not counting
+    /// the preview.
+    Frame(usize),
+    /// The section at TOC index `index` of frame `frame`, or of the preview if
+    /// `frame` is `None`.
+    Section {
+        frame: Option<usize>,
+        index: usize,
+        section: Section,
+    },
+}
+
+impl fmt::Display for ErrorLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorLocation::FileHeaders => write!(f, "the file headers"),
+            ErrorLocation::Icc => write!(f, "the ICC profile"),
+            ErrorLocation::Preview => write!(f, "the preview frame"),
+            ErrorLocation::Frame(i) => write!(f, "frame {}", i),
+            ErrorLocation::Section {
+                frame,
+                index,
+                section,
+            } => {
+                write!(f, "section {} ({:?}) of ", index, section)?;
+                match frame {
+                    Some(frame) => write!(f, "frame {}", frame),
+                    None => write!(f, "the preview frame"),
+                }
+            }
+        }
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("Read out of bounds")]
-    OutOfBounds,
+    #[error("Read out of bounds, {0} more bits needed")]
+    OutOfBounds(usize),
     #[error("Non-zero padding bits")]
     NonZeroPadding,
     #[error("Invalid signature {0:02x}{1:02x}, expected ff0a")]
@@ -31,6 +74,8 @@ pub enum Error {
     InvalidGamma(f32),
     #[error("Invalid color encoding: no ICC and unknown TF / ColorSpace")]
     InvalidColorEncoding,
+    #[error("No ICC profile can be synthesized for this color encoding")]
+    UnsupportedColorEncoding,
     #[error("Invalid intensity_target: {0}")]
     InvalidIntensityTarget(f32),
     #[error("Invalid min_nits: {0}")]
@@ -39,12 +84,54 @@ pub enum Error {
     InvalidLinearBelow(bool, f32),
     #[error("Overflow when computing a bitstream size")]
     SizeOverflow,
+    #[error("Arithmetic overflow in a size or offset computation")]
+    ArithmeticOverflow,
     #[error("File truncated")]
     FileTruncated,
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
     #[error("Invalid ISOBMMF container")]
     InvalidBox,
+    #[error("Invalid Exif metadata")]
+    InvalidExif,
+    #[error("Invalid brotli-compressed box")]
+    InvalidBrotli,
+    #[error("Codestream orientation {0:?} conflicts with Exif orientation {1:?}")]
+    OrientationConflict(Orientation, Orientation),
+    #[error("Invalid codestream level {0} in jxll box")]
+    InvalidLevel(u8),
+    #[error("Image size {0}x{1} exceeds the limits of codestream level {2}")]
+    ImageSizeTooLargeForLevel(u64, u64, u8),
+    #[error("{0} extra channels exceed the limits of codestream level {1}")]
+    TooManyExtraChannelsForLevel(usize, u8),
+    #[error("Bit depth {0} exceeds the limits of codestream level {1}")]
+    BitDepthTooLargeForLevel(u32, u8),
+    #[error("Image has {0} pixels, the limit is {1}")]
+    TooManyPixels(u64, u64),
+    #[error("Decoding needs {0} bytes, the limit is {1}")]
+    MemoryLimitExceeded(u64, u64),
+    #[error("Image of {1}x{2} samples cannot be built from {0} samples")]
+    ImageDataSizeMismatch(usize, usize, usize),
+    #[error("File has more than {0} frames")]
+    TooManyFrames(usize),
+    #[error("Frame {0} does not exist, the file has {1} frames")]
+    FrameIndexOutOfRange(usize, usize),
+    #[error("Invalid downsampling factor {0}, expected 1, 2, 4 or 8")]
+    InvalidDownsampling(u32),
     #[error("ICC is too large")]
     ICCTooLarge,
+    #[error("Invalid ICC stream")]
+    InvalidIccStream,
+    #[error("Invalid ICC profile")]
+    InvalidIccProfile,
+    #[error("Invalid ICC tag {0}")]
+    InvalidIccTag(String),
+    #[error("Invalid permutation")]
+    InvalidPermutation,
+    #[error("Value {0} cannot be encoded with the given coder")]
+    ValueNotEncodable(u64),
+    #[error("Field is required by the header but not set")]
+    MissingField,
     #[error("Invalid HybridUintConfig: {0} {1} {2:?}")]
     InvalidUintConfig(u32, u32, Option<u32>),
     #[error("LZ77 enabled when explicitly disallowed")]
@@ -53,6 +140,10 @@ pub enum Error {
     AlphabetTooLargeHuff(usize),
     #[error("Invalid Huffman code")]
     InvalidHuffman,
+    #[error("Invalid ANS histogram")]
+    InvalidAnsHistogram,
+    #[error("ANS stream does not end in the final state")]
+    InvalidAnsStream,
     #[error("Integer too large: nbits {0} > 29")]
     IntegerTooLarge(u32),
     #[error("Invalid context map: context id {0} > 255")]
@@ -64,4 +155,173 @@ pub enum Error {
     InvalidEcUpsampling(u32, u32, u32),
     #[error("Num_ds: {0} should be smaller than num_passes: {1}")]
     NumPassesTooLarge(u32, u32),
+    #[error("Invalid passes: downsample must decrease and last_pass must increase")]
+    InvalidPasses,
+    // Modular format errors
+    #[error("MA tree has more than {0} nodes")]
+    TreeTooLarge(usize),
+    #[error("Invalid MA tree property {0}")]
+    InvalidProperty(u32),
+    #[error("Invalid predictor {0}")]
+    InvalidPredictor(u32),
+    #[error("Invalid MA tree multiplier: log {0}, bits {1}")]
+    InvalidMultiplier(u32, u32),
+    #[error("Modular group uses the global tree, but there is none")]
+    MissingGlobalTree,
+    #[error("Invalid RCT type {0}")]
+    InvalidRctType(u32),
+    #[error(
+        "Transform applies to {1} channels from channel {0}, which do not exist or differ in size"
+    )]
+    InvalidTransformChannels(u32, u32),
+    #[error("Squeeze of channel {0}, which is empty, squeezed too often, or has residuals of the wrong size")]
+    InvalidSqueeze(u32),
+    #[error("{source} (in {location}, at codestream byte {}, bit {})", bit_offset / 8, bit_offset % 8)]
+    At {
+        location: ErrorLocation,
+        bit_offset: usize,
+        source: Box<Error>,
+    },
+}
+
+/// Broad classes of errors, telling callers how to react to an [`Error`].
+/// The discriminants are stable and can be used as status codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// The input ended early. Decoding can resume once more data is
+    /// available; if the input is already complete, the file is truncated.
+    NeedsMoreInput = 1,
+    /// Only optional data, such as metadata, is broken. The rest of the image
+    /// can still be decoded.
+    Recoverable = 2,
+    /// The codestream is invalid or unsupported, and decoding must stop.
+    Fatal = 3,
+}
+
+impl Error {
+    /// Records where in the codestream the error occurred, unless it is known
+    /// already.
+    pub fn at(self, location: ErrorLocation, bit_offset: usize) -> Error {
+        match self {
+            Error::At { .. } => self,
+            _ => Error::At {
+                location,
+                bit_offset,
+                source: Box::new(self),
+            },
+        }
+    }
+
+    /// The error without the position it occurred at.
+    pub fn inner(&self) -> &Error {
+        match self {
+            Error::At { source, .. } => source.inner(),
+            _ => self,
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        use Error::*;
+        match self {
+            At { source, .. } => source.category(),
+            OutOfBounds(_) | FileTruncated => ErrorCategory::NeedsMoreInput,
+            InvalidExif
+            | InvalidBrotli
+            | OrientationConflict(..)
+            | InvalidIccProfile
+            | InvalidIccTag(_) => ErrorCategory::Recoverable,
+            NonZeroPadding
+            | InvalidSignature(..)
+            | InvalidExponent(_)
+            | InvalidMantissa(_)
+            | InvalidBitsPerSample(_)
+            | InvalidEnum(..)
+            | DimShiftTooLarge(_)
+            | FloatNaNOrInf
+            | InvalidGamma(_)
+            | InvalidColorEncoding
+            | UnsupportedColorEncoding
+            | InvalidIntensityTarget(_)
+            | InvalidMinNits(_)
+            | InvalidLinearBelow(..)
+            | SizeOverflow
+            | ArithmeticOverflow
+            | Io(_)
+            | InvalidBox
+            | InvalidLevel(_)
+            | ImageSizeTooLargeForLevel(..)
+            | TooManyExtraChannelsForLevel(..)
+            | BitDepthTooLargeForLevel(..)
+            | TooManyPixels(..)
+            | MemoryLimitExceeded(..)
+            | ImageDataSizeMismatch(..)
+            | TooManyFrames(_)
+            | FrameIndexOutOfRange(..)
+            | InvalidDownsampling(_)
+            | ICCTooLarge
+            | InvalidIccStream
+            | InvalidPermutation
+            | ValueNotEncodable(_)
+            | MissingField
+            | InvalidUintConfig(..)
+            | LZ77Disallowed
+            | AlphabetTooLargeHuff(_)
+            | InvalidHuffman
+            | InvalidAnsHistogram
+            | InvalidAnsStream
+            | IntegerTooLarge(_)
+            | InvalidContextMap(_)
+            | InvalidContextMapHole(..)
+            | InvalidEcUpsampling(..)
+            | NumPassesTooLarge(..)
+            | InvalidPasses
+            | TreeTooLarge(_)
+            | InvalidProperty(_)
+            | InvalidPredictor(_)
+            | InvalidMultiplier(..)
+            | MissingGlobalTree
+            | InvalidRctType(_)
+            | InvalidTransformChannels(..)
+            | InvalidSqueeze(_) => ErrorCategory::Fatal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_category() {
+        assert_eq!(
+            Error::OutOfBounds(3).category(),
+            ErrorCategory::NeedsMoreInput
+        );
+        assert_eq!(Error::InvalidExif.category(), ErrorCategory::Recoverable);
+        assert_eq!(Error::InvalidHuffman.category(), ErrorCategory::Fatal);
+        assert_eq!(ErrorCategory::Fatal as i32, 3);
+    }
+
+    #[test]
+    fn test_location() {
+        let err = Error::InvalidExif
+            .at(ErrorLocation::Frame(2), 83)
+            .at(ErrorLocation::FileHeaders, 0);
+        assert_eq!(
+            err.to_string(),
+            "Invalid Exif metadata (in frame 2, at codestream byte 10, bit 3)"
+        );
+        assert!(matches!(err.inner(), Error::InvalidExif));
+        assert_eq!(err.category(), ErrorCategory::Recoverable);
+
+        let location = ErrorLocation::Section {
+            frame: Some(1),
+            index: 4,
+            section: Section::Group { pass: 0, group: 2 },
+        };
+        assert_eq!(
+            Error::FileTruncated.at(location, 800).to_string(),
+            "File truncated (in section 4 (Group { pass: 0, group: 2 }) of frame 1, at codestream byte 100, bit 0)"
+        );
+    }
 }
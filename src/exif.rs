@@ -0,0 +1,143 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::error::Error;
+use crate::headers::Orientation;
+use crate::util::safe_arith::SafeArith;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use num_traits::FromPrimitive;
+
+const ORIENTATION_TAG: u16 = 0x0112;
+const TYPE_SHORT: u16 = 3;
+
+/// Reads the orientation from the payload of an `Exif` box, which starts with
+/// the offset of the TIFF header. Returns `None` if there is no orientation tag.
+pub fn exif_orientation(exif_box: &[u8]) -> Result<Option<Orientation>, Error> {
+    if exif_box.len() < 4 {
+        return Err(Error::InvalidExif);
+    }
+    let tiff_offset = BigEndian::read_u32(exif_box) as usize;
+    let tiff = exif_box
+        .get(4..)
+        .and_then(|d| d.get(tiff_offset..))
+        .filter(|d| d.len() >= 8)
+        .ok_or(Error::InvalidExif)?;
+    type ReadFn<T> = fn(&[u8]) -> T;
+    let (read_u16, read_u32): (ReadFn<u16>, ReadFn<u32>) = match &tiff[0..4] {
+        b"II*\0" => (LittleEndian::read_u16, LittleEndian::read_u32),
+        b"MM\0*" => (BigEndian::read_u16, BigEndian::read_u32),
+        _ => return Err(Error::InvalidExif),
+    };
+    let ifd = read_u32(&tiff[4..]) as usize;
+    let num_entries = tiff
+        .get(ifd..ifd.safe_add(2)?)
+        .map(read_u16)
+        .ok_or(Error::InvalidExif)? as usize;
+    for i in 0..num_entries {
+        let entry_start = ifd.safe_add(2 + 12 * i)?;
+        let entry = tiff
+            .get(entry_start..entry_start.safe_add(12)?)
+            .ok_or(Error::InvalidExif)?;
+        if read_u16(entry) != ORIENTATION_TAG {
+            continue;
+        }
+        if read_u16(&entry[2..]) != TYPE_SHORT || read_u32(&entry[4..]) != 1 {
+            return Err(Error::InvalidExif);
+        }
+        let value = read_u16(&entry[8..]);
+        return Orientation::from_u16(value)
+            .map(Some)
+            .ok_or(Error::InvalidExif);
+    }
+    Ok(None)
+}
+
+/// What to do when the orientation in the Exif metadata differs from the one in
+/// the codestream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OrientationPolicy {
+    /// Use the codestream orientation, ignoring Exif. This is what the
+    /// specification mandates.
+    #[default]
+    PreferCodestream,
+    /// Use the Exif orientation when present.
+    PreferExif,
+    /// Fail if both are present and they disagree.
+    ErrorOnConflict,
+}
+
+impl OrientationPolicy {
+    pub fn resolve(
+        &self,
+        codestream: Orientation,
+        exif: Option<Orientation>,
+    ) -> Result<Orientation, Error> {
+        match (self, exif) {
+            (_, None) | (OrientationPolicy::PreferCodestream, _) => Ok(codestream),
+            (OrientationPolicy::PreferExif, Some(exif)) => Ok(exif),
+            (OrientationPolicy::ErrorOnConflict, Some(exif)) if exif != codestream => {
+                Err(Error::OrientationConflict(codestream, exif))
+            }
+            (OrientationPolicy::ErrorOnConflict, Some(_)) => Ok(codestream),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Builds an `Exif` box payload containing only an orientation tag.
+    pub(crate) fn exif_box(orientation: u16, big_endian: bool) -> Vec<u8> {
+        let mut data = vec![0, 0, 0, 0];
+        if big_endian {
+            data.extend_from_slice(b"MM\0*\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01");
+            data.extend_from_slice(&orientation.to_be_bytes());
+        } else {
+            data.extend_from_slice(b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0");
+            data.extend_from_slice(&orientation.to_le_bytes());
+        }
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn test_exif_orientation() {
+        assert_eq!(
+            exif_orientation(&exif_box(6, true)).unwrap(),
+            Some(Orientation::Rotate90)
+        );
+        assert_eq!(
+            exif_orientation(&exif_box(3, false)).unwrap(),
+            Some(Orientation::Rotate180)
+        );
+        assert!(exif_orientation(&exif_box(9, false)).is_err());
+        let mut no_tag = exif_box(1, false);
+        no_tag[14] = 0x13;
+        assert_eq!(exif_orientation(&no_tag).unwrap(), None);
+        assert!(exif_orientation(&[0, 0, 0, 0, b'I', b'I']).is_err());
+    }
+
+    #[test]
+    fn test_policy() {
+        let cs = Orientation::Identity;
+        let exif = Some(Orientation::Rotate90);
+        let resolve = |policy: OrientationPolicy, exif| policy.resolve(cs, exif);
+        assert_eq!(
+            resolve(OrientationPolicy::PreferCodestream, exif).unwrap(),
+            cs
+        );
+        assert_eq!(
+            resolve(OrientationPolicy::PreferExif, exif).unwrap(),
+            Orientation::Rotate90
+        );
+        assert!(resolve(OrientationPolicy::ErrorOnConflict, exif).is_err());
+        assert_eq!(
+            resolve(OrientationPolicy::ErrorOnConflict, Some(cs)).unwrap(),
+            cs
+        );
+        assert_eq!(resolve(OrientationPolicy::PreferExif, None).unwrap(), cs);
+    }
+}
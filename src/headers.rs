@@ -13,10 +13,13 @@ pub mod encodings;
 pub mod extra_channels;
 pub mod frame_header;
 pub mod image_metadata;
+pub mod level;
 pub mod size;
+pub mod toc;
 pub mod transform_data;
 
 use crate::bit_reader::BitReader;
+use crate::bit_writer::BitWriter;
 use crate::error::Error;
 use crate::headers::encodings::Empty;
 use crate::headers::encodings::UnconditionalCoder;
@@ -25,9 +28,11 @@ pub use image_metadata::*;
 pub use size::Size;
 pub use transform_data::*;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug)]
 pub struct FileHeaders {
     #[allow(dead_code)]
+    #[cfg_attr(feature = "serde", serde(skip))]
     signature: Signature,
     pub size: Size,
     pub image_metadata: ImageMetadata,
@@ -40,6 +45,7 @@ where
     Self: Sized,
 {
     fn read(br: &mut BitReader) -> Result<Self, Error>;
+    fn write(&self, bw: &mut BitWriter) -> Result<(), Error>;
 }
 
 impl<T> JxlHeader for T
@@ -50,4 +56,7 @@ where
     fn read(br: &mut BitReader) -> Result<Self, Error> {
         Self::read_unconditional(&(), br, &T::Nonserialized::default())
     }
+    fn write(&self, bw: &mut BitWriter) -> Result<(), Error> {
+        self.write_unconditional(&(), bw, &T::Nonserialized::default())
+    }
 }
@@ -11,7 +11,8 @@ use crate::bit_reader::BitReader;
 use crate::error::Error;
 use crate::headers::encodings::*;
 
-#[derive(UnconditionalCoder, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(UnconditionalCoder, Debug, Clone)]
 #[validate]
 pub struct BitDepth {
     #[default(false)]
@@ -28,6 +29,78 @@ pub struct BitDepth {
 }
 
 impl BitDepth {
+    pub fn floating_point_sample(&self) -> bool {
+        self.floating_point_sample
+    }
+
+    pub fn bits_per_sample(&self) -> u32 {
+        self.bits_per_sample
+    }
+
+    pub fn exponent_bits_per_sample(&self) -> u32 {
+        self.exponent_bits_per_sample
+    }
+
+    /// Converts a decoded sample to a float. Integer samples are scaled so that the
+    /// maximum value for this bit depth maps to 1.0; floating point samples hold the
+    /// bits of a float with `exponent_bits_per_sample` exponent bits and are converted
+    /// exactly.
+    pub fn sample_to_f32(&self, sample: i32) -> f32 {
+        if !self.floating_point_sample {
+            let max = ((1u64 << self.bits_per_sample) - 1) as f64;
+            return (sample as f64 / max) as f32;
+        }
+        let bits = sample as u32;
+        if self.bits_per_sample == 32 {
+            return f32::from_bits(bits);
+        }
+        let exp_bits = self.exponent_bits_per_sample;
+        let mantissa_bits = self.bits_per_sample - exp_bits - 1;
+        let sign_shift = self.bits_per_sample - 1;
+        let sign = (bits >> sign_shift) & 1;
+        let magnitude = bits & ((1 << sign_shift) - 1);
+        if magnitude == 0 {
+            return if sign != 0 { -0.0 } else { 0.0 };
+        }
+        let exp_bias = (1i32 << (exp_bits - 1)) - 1;
+        let mut exp = (magnitude >> mantissa_bits) as i32;
+        let mut mantissa = (magnitude & ((1 << mantissa_bits) - 1)) << (23 - mantissa_bits);
+        // Denormals of the narrower format are normal numbers as f32.
+        if exp == 0 && exp_bits < 8 {
+            while mantissa & 0x800000 == 0 {
+                mantissa <<= 1;
+                exp -= 1;
+            }
+            exp += 1;
+            mantissa &= 0x7fffff;
+        }
+        exp += 127 - exp_bias;
+        debug_assert!(exp >= 0);
+        f32::from_bits((sign << 31) | ((exp as u32) << 23) | mantissa)
+    }
+
+    /// Converts a decoded sample to an unsigned integer with `out_bits` bits,
+    /// e.g. 16 for `u16` output, or `bits_per_sample` to keep the native range.
+    /// Out-of-range samples are clamped, and scaling rounds to nearest. Fails
+    /// unless `out_bits` is between 1 and 32.
+    pub fn sample_to_uint(&self, sample: i32, out_bits: u32) -> Result<u32, Error> {
+        if !(1..=32).contains(&out_bits) {
+            return Err(Error::InvalidBitsPerSample(out_bits));
+        }
+        let out_max = u32::MAX >> (32 - out_bits);
+        if self.floating_point_sample {
+            let value = self.sample_to_f32(sample).clamp(0.0, 1.0) as f64;
+            // NaN is clamped to NaN, which casts to 0.
+            return Ok((value * out_max as f64).round() as u32);
+        }
+        let max = (1u64 << self.bits_per_sample) - 1;
+        let sample = (sample.max(0) as u64).min(max);
+        if max == out_max as u64 {
+            return Ok(sample as u32);
+        }
+        Ok(((sample * out_max as u64 + max / 2) / max) as u32)
+    }
+
     fn check(&self, _: &Empty) -> Result<(), Error> {
         if self.floating_point_sample {
             if self.exponent_bits_per_sample < 2 || self.exponent_bits_per_sample > 8 {
@@ -48,3 +121,80 @@ impl BitDepth {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use half::f16;
+
+    fn float_depth(bits_per_sample: u32, exponent_bits_per_sample: u32) -> BitDepth {
+        BitDepth {
+            floating_point_sample: true,
+            bits_per_sample,
+            exponent_bits_per_sample,
+        }
+    }
+
+    #[test]
+    fn test_integer_samples() {
+        for bits in [8, 10, 12, 16, 24, 31] {
+            let depth = BitDepth {
+                bits_per_sample: bits,
+                ..BitDepth::default()
+            };
+            assert_eq!(depth.sample_to_f32(0), 0.0);
+            assert_eq!(depth.sample_to_f32(((1u64 << bits) - 1) as i32), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_sample_to_uint() -> Result<(), Error> {
+        let ten_bit = BitDepth {
+            bits_per_sample: 10,
+            ..BitDepth::default()
+        };
+        assert_eq!(ten_bit.sample_to_uint(0, 16)?, 0);
+        assert_eq!(ten_bit.sample_to_uint(1023, 16)?, 65535);
+        assert_eq!(ten_bit.sample_to_uint(512, 16)?, 32800);
+        assert_eq!(ten_bit.sample_to_uint(1023, 8)?, 255);
+        assert_eq!(ten_bit.sample_to_uint(2000, 16)?, 65535);
+        assert_eq!(ten_bit.sample_to_uint(-5, 16)?, 0);
+        assert_eq!(ten_bit.sample_to_uint(700, 10)?, 700);
+        let twelve_bit = BitDepth {
+            bits_per_sample: 12,
+            ..BitDepth::default()
+        };
+        assert_eq!(twelve_bit.sample_to_uint(4095, 16)?, 65535);
+        assert_eq!(twelve_bit.sample_to_uint(2048, 8)?, 128);
+        assert_eq!(BitDepth::default().sample_to_uint(255, 32)?, u32::MAX);
+        let half = float_depth(16, 5);
+        let bits = |v: f32| f16::from_f32(v).to_bits() as i32;
+        assert_eq!(half.sample_to_uint(bits(0.5), 16)?, 32768);
+        assert_eq!(half.sample_to_uint(bits(2.0), 8)?, 255);
+        assert_eq!(half.sample_to_uint(bits(-1.0), 8)?, 0);
+        assert!(matches!(
+            ten_bit.sample_to_uint(0, 33),
+            Err(Error::InvalidBitsPerSample(33))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_f16_samples() {
+        let depth = float_depth(16, 5);
+        for value in [0.0f32, -0.0, 1.0, -2.5, 65504.0, 6.1035156e-5, 5.9604645e-8] {
+            let bits = f16::from_f32(value).to_bits() as i32;
+            assert_eq!(depth.sample_to_f32(bits).to_bits(), value.to_bits());
+        }
+    }
+
+    #[test]
+    fn test_f32_and_bf16_samples() {
+        assert_eq!(
+            float_depth(32, 8).sample_to_f32(1.5f32.to_bits() as i32),
+            1.5
+        );
+        let bf16 = (0.15625f32.to_bits() >> 16) as i32;
+        assert_eq!(float_depth(16, 8).sample_to_f32(bf16), 0.15625);
+    }
+}
@@ -13,6 +13,7 @@ use crate::error::Error;
 use crate::headers::encodings::*;
 
 #[allow(clippy::upper_case_acronyms)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
 pub enum ColorSpace {
     RGB,
@@ -22,6 +23,7 @@ pub enum ColorSpace {
 }
 
 #[allow(clippy::upper_case_acronyms)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
 pub enum WhitePoint {
     D65 = 1,
@@ -31,6 +33,7 @@ pub enum WhitePoint {
 }
 
 #[allow(clippy::upper_case_acronyms)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
 pub enum Primaries {
     SRGB = 1,
@@ -40,6 +43,7 @@ pub enum Primaries {
 }
 
 #[allow(clippy::upper_case_acronyms)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
 pub enum TransferFunction {
     BT709 = 1,
@@ -51,6 +55,7 @@ pub enum TransferFunction {
     HLG = 18,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
 pub enum RenderingIntent {
     Perceptual = 0,
@@ -59,37 +64,53 @@ pub enum RenderingIntent {
     Absolute,
 }
 
-#[derive(UnconditionalCoder, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(UnconditionalCoder, Debug, Clone)]
 pub struct CustomXY {
     #[default(0)]
     #[coder(u2S(Bits(19), Bits(19) + 524288, Bits(20) + 1048576, Bits(21) + 2097152))]
-    x: i32,
+    pub x: i32,
     #[default(0)]
     #[coder(u2S(Bits(19), Bits(19) + 524288, Bits(20) + 1048576, Bits(21) + 2097152))]
-    y: i32,
+    pub y: i32,
 }
 
 pub struct CustomTransferFunctionNonserialized {
     color_space: ColorSpace,
 }
 
-#[derive(UnconditionalCoder, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(UnconditionalCoder, Debug, Clone)]
 #[nonserialized(CustomTransferFunctionNonserialized)]
 #[validate]
 pub struct CustomTransferFunction {
     #[condition(nonserialized.color_space != ColorSpace::XYB)]
     #[default(false)]
-    have_gamma: bool,
+    pub(crate) have_gamma: bool,
     #[condition(have_gamma)]
     #[default(3333333)] // XYB gamma
     #[coder(Bits(24))]
-    gamma: u32,
+    pub(crate) gamma: u32,
     #[condition(!have_gamma && nonserialized.color_space != ColorSpace::XYB)]
     #[default(TransferFunction::SRGB)]
-    transfer_function: TransferFunction,
+    pub(crate) transfer_function: TransferFunction,
 }
 
 impl CustomTransferFunction {
+    pub fn have_gamma(&self) -> bool {
+        self.have_gamma
+    }
+
+    /// The transfer function, or `None` if it is a pure gamma curve (see
+    /// [`CustomTransferFunction::gamma`]).
+    pub fn transfer_function(&self) -> Option<TransferFunction> {
+        (!self.have_gamma).then_some(self.transfer_function)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the transfer function is not a gamma curve, see
+    /// [`CustomTransferFunction::have_gamma`].
     pub fn gamma(&self) -> f32 {
         assert!(self.have_gamma);
         self.gamma as f32 * 0.0000001
@@ -109,7 +130,8 @@ impl CustomTransferFunction {
     }
 }
 
-#[derive(UnconditionalCoder, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(UnconditionalCoder, Debug, Clone)]
 #[validate]
 pub struct ColorEncoding {
     #[all_default]
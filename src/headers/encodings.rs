@@ -4,7 +4,9 @@
 // license that can be found in the LICENSE file.
 
 use crate::bit_reader::BitReader;
+use crate::bit_writer::BitWriter;
 use crate::error::Error;
+use std::convert::TryFrom;
 
 pub enum U32 {
     Bits(usize),
@@ -20,6 +22,27 @@ impl U32 {
             U32::Val(val) => Ok(val),
         }
     }
+
+    /// Returns the number of bits needed to write `value`, or `None` if it
+    /// cannot be represented by this distribution.
+    fn bits_for(&self, value: u32) -> Option<usize> {
+        match *self {
+            U32::Bits(n) => ((value as u64) < (1u64 << n)).then_some(n),
+            U32::BitsOffset { n, off } => value
+                .checked_sub(off)
+                .filter(|v| (*v as u64) < (1u64 << n))
+                .map(|_| n),
+            U32::Val(val) => (value == val).then_some(0),
+        }
+    }
+
+    fn write(&self, value: u32, bw: &mut BitWriter) {
+        match *self {
+            U32::Bits(n) => bw.write(n, value as u64),
+            U32::BitsOffset { n, off } => bw.write(n, (value - off) as u64),
+            U32::Val(_) => {}
+        }
+    }
 }
 
 pub enum U32Coder {
@@ -40,6 +63,12 @@ where
         br: &mut BitReader,
         nonserialized: &Self::Nonserialized,
     ) -> Result<Self, Error>;
+    fn write_unconditional(
+        &self,
+        config: &Config,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error>;
 }
 
 impl UnconditionalCoder<()> for bool {
@@ -51,6 +80,15 @@ impl UnconditionalCoder<()> for bool {
     ) -> Result<bool, Error> {
         Ok(br.read(1)? != 0)
     }
+    fn write_unconditional(
+        &self,
+        _: &(),
+        bw: &mut BitWriter,
+        _: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        bw.write(1, *self as u64);
+        Ok(())
+    }
 }
 
 impl UnconditionalCoder<()> for f32 {
@@ -68,6 +106,20 @@ impl UnconditionalCoder<()> for f32 {
             Ok(ret.to_f32())
         }
     }
+    fn write_unconditional(
+        &self,
+        _: &(),
+        bw: &mut BitWriter,
+        _: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        use half::f16;
+        let value = f16::from_f32(*self);
+        if !value.is_finite() {
+            return Err(Error::FloatNaNOrInf);
+        }
+        bw.write(16, value.to_bits() as u64);
+        Ok(())
+    }
 }
 
 impl UnconditionalCoder<U32Coder> for u32 {
@@ -91,6 +143,36 @@ impl UnconditionalCoder<U32Coder> for u32 {
             }
         }
     }
+    fn write_unconditional(
+        &self,
+        config: &U32Coder,
+        bw: &mut BitWriter,
+        _: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        match config {
+            U32Coder::Direct(u) => {
+                if u.bits_for(*self).is_none() {
+                    return Err(Error::ValueNotEncodable(*self as u64));
+                }
+                u.write(*self, bw);
+            }
+            U32Coder::Select(u0, u1, u2, u3) => {
+                // Like libjxl, pick the cheapest distribution, preferring the
+                // first one on ties.
+                let distributions = [u0, u1, u2, u3];
+                let (selector, u) = distributions
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, u)| u.bits_for(*self).map(|bits| (bits, i, u)))
+                    .min_by_key(|(bits, i, _)| (*bits, *i))
+                    .map(|(_, i, u)| (i, u))
+                    .ok_or(Error::ValueNotEncodable(*self as u64))?;
+                bw.write(2, selector as u64);
+                u.write(*self, bw);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl UnconditionalCoder<U32Coder> for i32 {
@@ -101,7 +183,16 @@ impl UnconditionalCoder<U32Coder> for i32 {
         nonserialized: &Self::Nonserialized,
     ) -> Result<i32, Error> {
         let u = u32::read_unconditional(config, br, nonserialized)?;
-        Ok(((u >> 1) ^ (((!u) & 1).wrapping_rem(1))) as i32)
+        Ok(((u >> 1) as i32) ^ -((u & 1) as i32))
+    }
+    fn write_unconditional(
+        &self,
+        config: &U32Coder,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        let u = ((*self as u32) << 1) ^ ((*self >> 31) as u32);
+        u.write_unconditional(config, bw, nonserialized)
     }
 }
 
@@ -117,22 +208,66 @@ impl UnconditionalCoder<()> for u64 {
             1 => Ok(1 + br.read(4)?),
             2 => Ok(17 + br.read(8)?),
             _ => {
-                let mut result: u64 = br.read(12)? as u64;
+                let mut result: u64 = br.read(12)?;
                 let mut shift = 12;
                 while br.read(1)? == 1 {
                     if shift >= 60 {
                         assert_eq!(shift, 60);
-                        return Ok(result | ((br.read(4)? as u64) << shift));
+                        return Ok(result | (br.read(4)? << shift));
                     }
-                    result |= (br.read(8)? as u64) << shift;
+                    result |= br.read(8)? << shift;
                     shift += 8;
                 }
                 Ok(result)
             }
         }
     }
+    fn write_unconditional(
+        &self,
+        _: &(),
+        bw: &mut BitWriter,
+        _: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        match *self {
+            0 => bw.write(2, 0),
+            1..=16 => {
+                bw.write(2, 1);
+                bw.write(4, *self - 1);
+            }
+            17..=272 => {
+                bw.write(2, 2);
+                bw.write(8, *self - 17);
+            }
+            _ => {
+                bw.write(2, 3);
+                bw.write(12, *self & 0xfff);
+                let mut value = *self >> 12;
+                let mut shift = 12;
+                while value > 0 && shift < 60 {
+                    bw.write(1, 1);
+                    bw.write(8, value & 0xff);
+                    value >>= 8;
+                    shift += 8;
+                }
+                if value > 0 {
+                    bw.write(1, 1);
+                    bw.write(4, value);
+                } else {
+                    bw.write(1, 0);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
+const STRING_LEN_CODER: U32Coder = U32Coder::Select(
+    U32::Val(0),
+    U32::Bits(4),
+    U32::BitsOffset { n: 5, off: 16 },
+    U32::BitsOffset { n: 10, off: 48 },
+);
+
 impl UnconditionalCoder<()> for String {
     type Nonserialized = Empty;
     fn read_unconditional(
@@ -140,16 +275,7 @@ impl UnconditionalCoder<()> for String {
         br: &mut BitReader,
         nonserialized: &Self::Nonserialized,
     ) -> Result<String, Error> {
-        let len = u32::read_unconditional(
-            &U32Coder::Select(
-                U32::Val(0),
-                U32::Bits(4),
-                U32::BitsOffset { n: 5, off: 16 },
-                U32::BitsOffset { n: 10, off: 48 },
-            ),
-            br,
-            nonserialized,
-        )?;
+        let len = u32::read_unconditional(&STRING_LEN_CODER, br, nonserialized)?;
         let mut ret = String::new();
         ret.reserve(len as usize);
         for _ in 0..len {
@@ -157,6 +283,24 @@ impl UnconditionalCoder<()> for String {
         }
         Ok(ret)
     }
+    fn write_unconditional(
+        &self,
+        _: &(),
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        // Strings are read one byte per character.
+        let bytes = self
+            .chars()
+            .map(|c| u8::try_from(c).map_err(|_| Error::ValueNotEncodable(c as u64)))
+            .collect::<Result<Vec<u8>, Error>>()?;
+        let len = u32::try_from(bytes.len()).map_err(|_| Error::SizeOverflow)?;
+        len.write_unconditional(&STRING_LEN_CODER, bw, nonserialized)?;
+        for b in bytes {
+            bw.write(8, b as u64);
+        }
+        Ok(())
+    }
 }
 
 impl<T: UnconditionalCoder<Config>, Config, const N: usize> UnconditionalCoder<Config> for [T; N] {
@@ -169,6 +313,17 @@ impl<T: UnconditionalCoder<Config>, Config, const N: usize> UnconditionalCoder<C
         use array_init::try_array_init;
         try_array_init(|_| T::read_unconditional(config, br, nonserialized))
     }
+    fn write_unconditional(
+        &self,
+        config: &Config,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        for v in self.iter() {
+            v.write_unconditional(config, bw, nonserialized)?;
+        }
+        Ok(())
+    }
 }
 
 pub struct VectorCoder<T: Sized> {
@@ -195,6 +350,19 @@ impl<Config, T: UnconditionalCoder<Config>> UnconditionalCoder<VectorCoder<Confi
         }
         Ok(ret)
     }
+    fn write_unconditional(
+        &self,
+        config: &VectorCoder<Config>,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        let len = u32::try_from(self.len()).map_err(|_| Error::SizeOverflow)?;
+        len.write_unconditional(&config.size_coder, bw, &Empty {})?;
+        for v in self.iter() {
+            v.write_unconditional(&config.value_coder, bw, nonserialized)?;
+        }
+        Ok(())
+    }
 }
 
 pub struct SelectCoder<T: Sized> {
@@ -222,6 +390,18 @@ impl<Config, T: UnconditionalCoder<Config> + Selectable> UnconditionalCoder<Sele
             T::read_unconditional(&config.coder_false, br, nonserialized)
         }
     }
+    fn write_unconditional(
+        &self,
+        config: &SelectCoder<Config>,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        if config.use_true {
+            self.write_unconditional(&config.coder_true, bw, nonserialized)
+        } else {
+            self.write_unconditional(&config.coder_false, bw, nonserialized)
+        }
+    }
 }
 
 pub trait ConditionalCoder<Config>
@@ -235,6 +415,13 @@ where
         br: &mut BitReader,
         nonserialized: &Self::Nonserialized,
     ) -> Result<Self, Error>;
+    fn write_conditional(
+        &self,
+        config: &Config,
+        condition: bool,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error>;
 }
 
 impl<Config, T: UnconditionalCoder<Config>> ConditionalCoder<Config> for Option<T> {
@@ -251,6 +438,19 @@ impl<Config, T: UnconditionalCoder<Config>> ConditionalCoder<Config> for Option<
             Ok(None)
         }
     }
+    fn write_conditional(
+        &self,
+        config: &Config,
+        condition: bool,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        match (condition, self) {
+            (true, Some(v)) => v.write_unconditional(config, bw, nonserialized),
+            (true, None) => Err(Error::MissingField),
+            (false, _) => Ok(()),
+        }
+    }
 }
 
 impl ConditionalCoder<()> for String {
@@ -267,6 +467,18 @@ impl ConditionalCoder<()> for String {
             Ok(String::new())
         }
     }
+    fn write_conditional(
+        &self,
+        _: &(),
+        condition: bool,
+        bw: &mut BitWriter,
+        nonserialized: &Empty,
+    ) -> Result<(), Error> {
+        if condition {
+            self.write_unconditional(&(), bw, nonserialized)?;
+        }
+        Ok(())
+    }
 }
 
 impl<Config, T: UnconditionalCoder<Config>> ConditionalCoder<VectorCoder<Config>> for Vec<T> {
@@ -283,6 +495,18 @@ impl<Config, T: UnconditionalCoder<Config>> ConditionalCoder<VectorCoder<Config>
             Ok(Vec::new())
         }
     }
+    fn write_conditional(
+        &self,
+        config: &VectorCoder<Config>,
+        condition: bool,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        if condition {
+            self.write_unconditional(config, bw, nonserialized)?;
+        }
+        Ok(())
+    }
 }
 
 pub trait DefaultedElementCoder<Config, T>
@@ -297,6 +521,13 @@ where
         br: &mut BitReader,
         nonserialized: &Self::Nonserialized,
     ) -> Result<Self, Error>;
+    fn write_defaulted_element(
+        &self,
+        config: &Config,
+        condition: bool,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error>;
 }
 
 impl<Config, T> DefaultedElementCoder<VectorCoder<Config>, T> for Vec<T>
@@ -328,6 +559,23 @@ where
             Ok(vec![default; len as usize])
         }
     }
+
+    fn write_defaulted_element(
+        &self,
+        config: &VectorCoder<Config>,
+        condition: bool,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        let len = u32::try_from(self.len()).map_err(|_| Error::SizeOverflow)?;
+        len.write_unconditional(&config.size_coder, bw, &Empty {})?;
+        if condition {
+            for v in self.iter() {
+                v.write_unconditional(&config.value_coder, bw, nonserialized)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub trait DefaultedCoder<Config>
@@ -342,6 +590,13 @@ where
         br: &mut BitReader,
         nonserialized: &Self::Nonserialized,
     ) -> Result<Self, Error>;
+    fn write_defaulted(
+        &self,
+        config: &Config,
+        condition: bool,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error>;
 }
 
 impl<Config, T: UnconditionalCoder<Config>> DefaultedCoder<Config> for T {
@@ -359,10 +614,23 @@ impl<Config, T: UnconditionalCoder<Config>> DefaultedCoder<Config> for T {
             Ok(default)
         }
     }
+    fn write_defaulted(
+        &self,
+        config: &Config,
+        condition: bool,
+        bw: &mut BitWriter,
+        nonserialized: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        if condition {
+            self.write_unconditional(config, bw, nonserialized)?;
+        }
+        Ok(())
+    }
 }
 
 // TODO(veluca93): this will likely need to be implemented differently if
 // there are extensions.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(Debug, PartialEq, Default)]
 pub struct Extensions {}
 
@@ -373,7 +641,6 @@ impl UnconditionalCoder<()> for Extensions {
         br: &mut BitReader,
         _: &Self::Nonserialized,
     ) -> Result<Extensions, Error> {
-        use std::convert::TryFrom;
         let selector = u64::read_unconditional(&(), br, &Empty {})?;
         let mut total_size: u64 = 0;
         for i in 0..64 {
@@ -395,4 +662,80 @@ impl UnconditionalCoder<()> for Extensions {
         }
         Ok(Extensions {})
     }
+    fn write_unconditional(
+        &self,
+        _: &(),
+        bw: &mut BitWriter,
+        _: &Self::Nonserialized,
+    ) -> Result<(), Error> {
+        0u64.write_unconditional(&(), bw, &Empty {})
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_signed() -> Result<(), Error> {
+        let coder = U32Coder::Direct(U32::Bits(8));
+        for (byte, value) in [(0u8, 0i32), (1, -1), (2, 1), (3, -2), (4, 2), (255, -128)] {
+            let data = [byte];
+            let mut br = BitReader::new(&data);
+            assert_eq!(i32::read_unconditional(&coder, &mut br, &Empty {})?, value);
+        }
+        Ok(())
+    }
+
+    fn roundtrip<T, Config>(value: T, config: &Config) -> Result<T, Error>
+    where
+        T: UnconditionalCoder<Config, Nonserialized = Empty>,
+    {
+        let mut bw = BitWriter::new();
+        value.write_unconditional(config, &mut bw, &Empty {})?;
+        let total_bits = bw.total_bits_written();
+        let data = bw.finalize();
+        let mut br = BitReader::new(&data);
+        let ret = T::read_unconditional(config, &mut br, &Empty {})?;
+        assert_eq!(br.total_bits_read(), total_bits);
+        Ok(ret)
+    }
+
+    #[test]
+    fn test_write_roundtrip() -> Result<(), Error> {
+        let coder = U32Coder::Select(
+            U32::Val(0),
+            U32::Bits(4),
+            U32::BitsOffset { n: 5, off: 16 },
+            U32::BitsOffset { n: 10, off: 48 },
+        );
+        for value in [0u32, 1, 15, 16, 47, 48, 1071] {
+            assert_eq!(roundtrip(value, &coder)?, value);
+        }
+        assert!(roundtrip(1072u32, &coder).is_err());
+        for value in [0i32, -1, 1, 100, -535] {
+            assert_eq!(roundtrip(value, &coder)?, value);
+        }
+        for value in [0u64, 1, 16, 17, 272, 273, 1 << 40, u64::MAX] {
+            assert_eq!(roundtrip(value, &())?, value);
+        }
+        for value in [0.0f32, -1.5, 0.25, 65504.0] {
+            assert_eq!(roundtrip(value, &())?, value);
+        }
+        assert!(roundtrip(1e6f32, &()).is_err());
+        let name = String::from("a frame name");
+        assert_eq!(roundtrip(name.clone(), &())?, name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_picks_cheapest_distribution() {
+        // 1 fits in both Bits(4) and Val(1); the latter needs no extra bits.
+        let coder = U32Coder::Select(U32::Bits(4), U32::Val(1), U32::Val(2), U32::Bits(8));
+        let mut bw = BitWriter::new();
+        1u32.write_unconditional(&coder, &mut bw, &Empty {})
+            .unwrap();
+        assert_eq!(bw.total_bits_written(), 2);
+        assert_eq!(bw.finalize(), vec![1]);
+    }
 }
@@ -15,8 +15,9 @@ use crate::headers::bit_depth::BitDepth;
 use crate::headers::encodings::*;
 
 #[allow(clippy::upper_case_acronyms)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
-enum ExtraChannel {
+pub enum ExtraChannel {
     Alpha,
     Depth,
     SpotColor,
@@ -36,7 +37,8 @@ enum ExtraChannel {
     Optional,
 }
 
-#[derive(UnconditionalCoder, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(UnconditionalCoder, Debug, Clone)]
 #[validate]
 pub struct ExtraChannelInfo {
     #[all_default]
@@ -61,6 +63,39 @@ pub struct ExtraChannelInfo {
 }
 
 impl ExtraChannelInfo {
+    pub fn ec_type(&self) -> ExtraChannel {
+        self.ec_type
+    }
+
+    pub fn bit_depth(&self) -> &BitDepth {
+        &self.bit_depth
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the channel is an alpha channel, as opposed to depth, thermal,
+    /// CMYK black and other auxiliary data.
+    pub fn is_alpha(&self) -> bool {
+        self.ec_type == ExtraChannel::Alpha
+    }
+
+    /// Linear RGB and solidity of a spot color channel.
+    pub fn spot_color(&self) -> Option<[f32; 4]> {
+        self.spot_color
+    }
+
+    /// Index of the channel in the color filter array of a CFA channel.
+    pub fn cfa_channel(&self) -> Option<u32> {
+        self.cfa_channel
+    }
+
+    /// Whether the color channels are premultiplied by this alpha channel.
+    pub fn alpha_associated(&self) -> bool {
+        self.alpha_associated
+    }
+
     fn check(&self, _: &Empty) -> Result<(), Error> {
         if self.dim_shift > 3 {
             Err(Error::DimShiftTooLarge(self.dim_shift))
@@ -69,3 +104,34 @@ impl ExtraChannelInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_writer::BitWriter;
+
+    #[test]
+    fn test_accessors() {
+        let info = ExtraChannelInfo {
+            all_default: false,
+            ec_type: ExtraChannel::Thermal,
+            bit_depth: BitDepth::default(),
+            dim_shift: 2,
+            name: String::from("ir"),
+            alpha_associated: false,
+            spot_color: None,
+            cfa_channel: None,
+        };
+        let mut bw = BitWriter::new();
+        info.write_unconditional(&(), &mut bw, &Empty {}).unwrap();
+        let data = bw.finalize();
+        let mut br = BitReader::new(&data);
+        let info = ExtraChannelInfo::read_unconditional(&(), &mut br, &Empty {}).unwrap();
+        assert_eq!(info.ec_type(), ExtraChannel::Thermal);
+        assert!(!info.is_alpha());
+        assert_eq!(info.name(), "ir");
+        assert_eq!(info.dim_shift, 2);
+        assert_eq!(info.bit_depth().bits_per_sample(), 8);
+        assert_eq!(info.spot_color(), None);
+    }
+}
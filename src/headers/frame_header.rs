@@ -4,19 +4,22 @@ use crate::{
     bit_reader::BitReader,
     error::Error,
     headers::{encodings::*, extra_channels::ExtraChannelInfo},
+    util::safe_arith::SafeArith,
 };
 
 use jxl_headers_derive::UnconditionalCoder;
 use num_derive::FromPrimitive;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
-enum FrameType {
+pub enum FrameType {
     RegularFrame = 0,
     LFFrame = 1,
     ReferenceOnly = 2,
     SkipProgressive = 3,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
 enum Encoding {
     VarDCT = 0,
@@ -34,8 +37,9 @@ impl Flags {
     pub const SKIP_ADAPTIVE_LF_SMOOTHING: u64 = 0x80;
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug, PartialEq)]
-struct Passes {
+pub struct Passes {
     #[coder(u2S(1, 2, 3, Bits(3) + 4))]
     #[default(1)]
     num_passes: u32,
@@ -64,8 +68,72 @@ struct Passes {
     last_pass: Vec<u32>,
 }
 
+impl Passes {
+    pub fn num_passes(&self) -> u32 {
+        self.num_passes
+    }
+
+    /// Amount by which AC coefficients decoded in `pass` are shifted left before
+    /// being added to the coefficients of the previous passes.
+    pub fn shift(&self, pass: u32) -> u32 {
+        self.shift.get(pass as usize).copied().unwrap_or(0)
+    }
+
+    pub fn is_last_pass(&self, pass: u32) -> bool {
+        pass + 1 == self.num_passes
+    }
+
+    /// Returns `(min_shift, max_shift)` such that `pass` refines the image from
+    /// a downsampling factor of `2 << max_shift` down to `1 << min_shift`.
+    pub fn downsampling_bracket(&self, pass: u32) -> (i32, i32) {
+        let mut max_shift = 2;
+        let mut min_shift = 3;
+        for i in 0..=pass {
+            for (downsample, last_pass) in self.downsample.iter().zip(&self.last_pass) {
+                if *last_pass == i {
+                    min_shift = downsample.trailing_zeros() as i32;
+                }
+            }
+            if self.is_last_pass(i) {
+                min_shift = 0;
+            }
+            if i != pass {
+                max_shift = min_shift - 1;
+            }
+        }
+        (min_shift, max_shift)
+    }
+
+    /// Number of passes needed to decode the image at a downsampling factor of
+    /// `downsampling` (1, 2, 4 or 8); later passes only add finer detail.
+    pub fn num_passes_for_downsampling(&self, downsampling: u32) -> u32 {
+        self.downsample
+            .iter()
+            .zip(&self.last_pass)
+            .filter(|(downsample, _)| downsampling >= **downsample)
+            .map(|(_, last_pass)| last_pass + 1)
+            .fold(self.num_passes, u32::min)
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        if self.num_ds >= self.num_passes {
+            return Err(Error::NumPassesTooLarge(self.num_ds, self.num_passes));
+        }
+        let increasing = |v: &[u32]| v.windows(2).all(|w| w[0] < w[1]);
+        let decreasing = |v: &[u32]| v.windows(2).all(|w| w[0] > w[1]);
+        if !decreasing(&self.downsample)
+            || !increasing(&self.last_pass)
+            || self.last_pass.iter().any(|p| *p >= self.num_passes)
+        {
+            return Err(Error::InvalidPasses);
+        }
+        Ok(())
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
-enum BlendingMode {
+pub enum BlendingMode {
     Replace = 0,
     Add = 1,
     Blend = 2,
@@ -73,7 +141,7 @@ enum BlendingMode {
     Mul = 4,
 }
 
-struct BlendingInfoNonserialized {
+pub struct BlendingInfoNonserialized {
     num_extra_channels: u32,
     have_crop: bool,
     x0: i32,
@@ -84,9 +152,10 @@ struct BlendingInfoNonserialized {
     img_height: u32,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug, PartialEq, Clone)]
 #[nonserialized(BlendingInfoNonserialized)]
-struct BlendingInfo {
+pub struct BlendingInfo {
     #[coder(u2S(0, 1, 2, Bits(2) + 3))]
     #[default(BlendingMode::Replace)]
     mode: BlendingMode,
@@ -116,10 +185,32 @@ struct BlendingInfo {
     source: u32,
 }
 
+impl BlendingInfo {
+    pub fn mode(&self) -> BlendingMode {
+        self.mode
+    }
+
+    /// Reference slot the frame is blended onto.
+    pub fn source(&self) -> u32 {
+        self.source
+    }
+
+    /// Index of the extra channel used as alpha by `Blend` and
+    /// `AlphaWeightedAdd`.
+    pub fn alpha_channel(&self) -> u32 {
+        self.alpha_channel
+    }
+
+    pub fn clamp(&self) -> bool {
+        self.clamp
+    }
+}
+
 struct RestorationFilterNonserialized {
     encoding: Encoding,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug, PartialEq)]
 #[nonserialized(RestorationFilterNonserialized)]
 struct RestorationFilter {
@@ -215,6 +306,20 @@ struct RestorationFilter {
     extensions: Extensions,
 }
 
+/// The part of a frame that lies on the image canvas.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CanvasIntersection {
+    /// Top-left corner of the intersection in canvas coordinates.
+    pub canvas_x0: u32,
+    pub canvas_y0: u32,
+    /// Top-left corner of the intersection in frame coordinates.
+    pub frame_x0: u32,
+    pub frame_y0: u32,
+    pub xsize: u32,
+    pub ysize: u32,
+}
+
 pub struct FrameHeaderNonserialized {
     pub xyb_encoded: bool,
     pub num_extra_channels: u32,
@@ -225,6 +330,7 @@ pub struct FrameHeaderNonserialized {
     pub img_height: u32,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug, PartialEq)]
 #[nonserialized(FrameHeaderNonserialized)]
 #[aligned]
@@ -349,16 +455,15 @@ pub struct FrameHeader {
     #[condition(frame_type != FrameType::LFFrame && !is_last)]
     save_as_reference: u32,
 
-    // TODO(TomasKralCZ): figure out a way of extracting this huge default into separate variables
-    /* The default value for save_before_ct is d_sbct = !(full_frame && (frame_type ==
-    kRegularFrame || frame_type == kSkipProgressive) && blending_info.mode ==
-    kReplace && (duration == 0 || save_as_reference != 0) && !is_last). */
-    #[default(!(!have_crop || (x0 == 0 && y0 == 0 &&
+    // TODO(TomasKralCZ): figure out a way of extracting this huge condition into separate variables
+    /* save_before_ct is only signalled if the frame is a reference-only frame or a
+    non-last, full-frame kReplace frame that could be saved as a reference. */
+    #[default(frame_type == FrameType::LFFrame)]
+    #[condition(frame_type == FrameType::ReferenceOnly || ((!have_crop || (x0 <= 0 && y0 <= 0 &&
         width as i64 + x0 as i64 >= nonserialized.img_width as i64 &&
         height as i64 + y0 as i64 >= nonserialized.img_height as i64))
         && (frame_type == FrameType::RegularFrame || frame_type == FrameType::SkipProgressive) &&
-        blending_info.mode == BlendingMode::Replace && (duration == 0 || save_as_reference != 0) && !is_last)]
-    #[condition(frame_type != FrameType::LFFrame)]
+        blending_info.mode == BlendingMode::Replace && (duration == 0 || save_as_reference != 0) && !is_last))]
     save_before_ct: bool,
 
     name: String,
@@ -372,6 +477,175 @@ pub struct FrameHeader {
 }
 
 impl FrameHeader {
+    /// Position of the top-left corner of the frame on the canvas; may be negative.
+    pub fn origin(&self) -> (i32, i32) {
+        (self.x0, self.y0)
+    }
+
+    /// Size of the frame, which is the image size unless the frame is cropped.
+    pub fn size(&self, img_width: u32, img_height: u32) -> (u32, u32) {
+        if self.have_crop {
+            (self.width, self.height)
+        } else {
+            (img_width, img_height)
+        }
+    }
+
+    /// Intersects the frame with an image canvas of the given size, taking into
+    /// account frames that start above or to the left of the canvas. Returns
+    /// `None` if the frame lies entirely outside of the canvas.
+    pub fn canvas_intersection(
+        &self,
+        img_width: u32,
+        img_height: u32,
+    ) -> Option<CanvasIntersection> {
+        let (width, height) = self.size(img_width, img_height);
+        let intersect = |origin: i32, size: u32, canvas_size: u32| {
+            let start = (origin as i64).max(0);
+            let end = (origin as i64 + size as i64).min(canvas_size as i64);
+            if start >= end {
+                None
+            } else {
+                Some((
+                    start as u32,
+                    (start - origin as i64) as u32,
+                    (end - start) as u32,
+                ))
+            }
+        };
+        let (canvas_x0, frame_x0, xsize) = intersect(self.x0, width, img_width)?;
+        let (canvas_y0, frame_y0, ysize) = intersect(self.y0, height, img_height)?;
+        Some(CanvasIntersection {
+            canvas_x0,
+            canvas_y0,
+            frame_x0,
+            frame_y0,
+            xsize,
+            ysize,
+        })
+    }
+
+    /// Duration of the frame in ticks of the animation header, 0 if not animated.
+    pub fn duration(&self) -> u32 {
+        self.duration
+    }
+
+    pub fn frame_type(&self) -> FrameType {
+        self.frame_type
+    }
+
+    /// Whether the frame is part of the displayed image. Reference-only frames
+    /// and LF frames are only decoded to be referenced by later frames.
+    pub fn is_displayed(&self) -> bool {
+        matches!(
+            self.frame_type,
+            FrameType::RegularFrame | FrameType::SkipProgressive
+        )
+    }
+
+    /// Number of times (1 to 4) the frame is downsampled by 8 if it is an LF
+    /// frame, 0 otherwise.
+    pub fn lf_level(&self) -> u32 {
+        self.lf_level
+    }
+
+    /// Reference slot (0 to 3) the frame is stored in for later frames to use.
+    pub fn save_as_reference(&self) -> u32 {
+        self.save_as_reference
+    }
+
+    /// How the color channels of the frame are blended onto the canvas.
+    pub fn blending_info(&self) -> &BlendingInfo {
+        &self.blending_info
+    }
+
+    /// How each extra channel of the frame is blended onto the canvas.
+    pub fn ec_blending_info(&self) -> &[BlendingInfo] {
+        &self.ec_blending_info
+    }
+
+    /// Whether the frame is kept in reference slot
+    /// [`save_as_reference`](Self::save_as_reference) after it is decoded.
+    pub fn can_be_referenced(&self) -> bool {
+        !self.is_last
+            && self.frame_type != FrameType::LFFrame
+            && (self.duration == 0 || self.save_as_reference != 0)
+    }
+
+    /// Whether the frame's LF image is an LF frame of level `lf_level() + 1`.
+    pub fn uses_lf_frame(&self) -> bool {
+        self.flags & Flags::USE_LF_FRAME != 0
+    }
+
+    /// Bitmask of the reference slots the frame reads from, either to blend
+    /// onto or to copy patches from.
+    pub fn referenced_slots(&self, img_width: u32, img_height: u32) -> u8 {
+        if self.frame_type == FrameType::LFFrame {
+            return 0;
+        }
+        if self.flags & Flags::ENABLE_PATCHES != 0 {
+            return 0b1111;
+        }
+        let (width, height) = self.size(img_width, img_height);
+        let full_frame = self.x0 <= 0
+            && self.y0 <= 0
+            && width as i64 + self.x0 as i64 >= img_width as i64
+            && height as i64 + self.y0 as i64 >= img_height as i64;
+        std::iter::once(&self.blending_info)
+            .chain(self.ec_blending_info.iter())
+            .filter(|info| info.mode != BlendingMode::Replace || !full_frame)
+            .fold(0, |slots, info| slots | 1 << info.source)
+    }
+
+    /// Side of a (square) group, in pixels.
+    pub fn group_dim(&self) -> u32 {
+        128 << self.group_size_shift
+    }
+
+    pub fn num_passes(&self) -> u32 {
+        self.passes.num_passes
+    }
+
+    pub fn passes(&self) -> &Passes {
+        &self.passes
+    }
+
+    /// Size of the frame as it is coded, i.e. before upsampling and, for LF
+    /// frames, at the reduced resolution.
+    pub fn coded_size(&self, img_width: u32, img_height: u32) -> (u32, u32) {
+        let (width, height) = self.size(img_width, img_height);
+        let lf_shift = 3 * self.lf_level;
+        let scale = |x: u32| (x.div_ceil(1 << lf_shift)).div_ceil(self.upsampling);
+        (scale(width), scale(height))
+    }
+
+    pub fn num_groups(&self, img_width: u32, img_height: u32) -> Result<u32, Error> {
+        let (width, height) = self.coded_size(img_width, img_height);
+        width
+            .div_ceil(self.group_dim())
+            .safe_mul(height.div_ceil(self.group_dim()))
+    }
+
+    pub fn num_lf_groups(&self, img_width: u32, img_height: u32) -> Result<u32, Error> {
+        let (width, height) = self.coded_size(img_width, img_height);
+        let lf_group_dim = self.group_dim() * 8;
+        width
+            .div_ceil(lf_group_dim)
+            .safe_mul(height.div_ceil(lf_group_dim))
+    }
+
+    /// Number of sections, and therefore TOC entries, in the frame.
+    pub fn num_toc_entries(&self, img_width: u32, img_height: u32) -> Result<u32, Error> {
+        let num_groups = self.num_groups(img_width, img_height)?;
+        if num_groups == 1 && self.num_passes() == 1 {
+            Ok(1)
+        } else {
+            self.num_lf_groups(img_width, img_height)?
+                .safe_add(2)?
+                .safe_add(num_groups.safe_mul(self.num_passes())?)
+        }
+    }
+
     fn check(&self, nonserialized: &FrameHeaderNonserialized) -> Result<(), Error> {
         if self.upsampling > 1 {
             if let Some((info, upsampling)) = nonserialized
@@ -390,14 +664,7 @@ impl FrameHeader {
             }
         }
 
-        if self.passes.num_ds >= self.passes.num_passes {
-            return Err(Error::NumPassesTooLarge(
-                self.passes.num_ds,
-                self.passes.num_passes,
-            ));
-        }
-
-        Ok(())
+        self.passes.check()
     }
 }
 
@@ -406,6 +673,7 @@ mod test_frame_header {
     use super::*;
     use crate::{
         bit_reader::BitReader,
+        bit_writer::BitWriter,
         bmff::JxlCodestream,
         headers::{FileHeaders, JxlHeader},
     };
@@ -413,29 +681,46 @@ mod test_frame_header {
     fn test_frame_header(image: Vec<u8>, correct_frame_header: FrameHeader) {
         let codestream = JxlCodestream::new(image).unwrap();
 
-        let mut br = BitReader::new(codestream.get());
+        let segments = codestream.segments();
+        let mut br = BitReader::new_segmented(&segments);
         let fh = FileHeaders::read(&mut br).unwrap();
 
         let have_timecode = match fh.image_metadata.animation {
             Some(ref a) => a.have_timecodes,
             None => false,
         };
-        let frame_header = FrameHeader::read_unconditional(
-            &(),
-            &mut br,
-            &FrameHeaderNonserialized {
-                xyb_encoded: fh.image_metadata.xyb_encoded,
-                num_extra_channels: fh.image_metadata.extra_channel_info.len() as u32,
-                extra_channel_info: fh.image_metadata.extra_channel_info,
-                have_animation: fh.image_metadata.animation.is_some(),
-                have_timecode,
-                img_width: fh.size.xsize(),
-                img_height: fh.size.ysize(),
-            },
-        )
-        .unwrap();
+        let nonserialized = FrameHeaderNonserialized {
+            xyb_encoded: fh.image_metadata.xyb_encoded,
+            num_extra_channels: fh.image_metadata.extra_channel_info.len() as u32,
+            extra_channel_info: fh.image_metadata.extra_channel_info.clone(),
+            have_animation: fh.image_metadata.animation.is_some(),
+            have_timecode,
+            img_width: fh.size.xsize(),
+            img_height: fh.size.ysize(),
+        };
+        let frame_header = FrameHeader::read_unconditional(&(), &mut br, &nonserialized).unwrap();
 
         assert_eq!(correct_frame_header, frame_header);
+
+        // Writing the headers back must reproduce the original bits.
+        let mut bw = BitWriter::new();
+        fh.write(&mut bw).unwrap();
+        frame_header
+            .write_unconditional(&(), &mut bw, &nonserialized)
+            .unwrap();
+        let total_bits = bw.total_bits_written();
+        assert_eq!(total_bits, br.total_bits_read());
+        let written = bw.finalize();
+        assert_eq!(
+            written[..total_bits / 8],
+            codestream.get()[..total_bits / 8]
+        );
+
+        let mut br = BitReader::new(&written);
+        let rewritten_fh = FileHeaders::read(&mut br).unwrap();
+        assert_eq!(format!("{:?}", fh), format!("{:?}", rewritten_fh));
+        let rewritten = FrameHeader::read_unconditional(&(), &mut br, &nonserialized).unwrap();
+        assert_eq!(frame_header, rewritten);
     }
 
     #[test]
@@ -480,12 +765,134 @@ mod test_frame_header {
                 save_as_reference: 0,
                 save_before_ct: false,
                 name: String::from(""),
-                restoration_filter: RestorationFilter::default(),
+                restoration_filter: RestorationFilter {
+                    all_default: false,
+                    epf_iters: 1,
+                    ..RestorationFilter::default()
+                },
                 extensions: Extensions::default(),
             },
         );
     }
 
+    #[test]
+    fn test_group_count_overflow() {
+        let frame_header = FrameHeader {
+            group_size_shift: 0,
+            ..modular_frame_header()
+        };
+        // Four groups, one LF group, LfGlobal and HfGlobal.
+        assert_eq!(frame_header.num_toc_entries(256, 256).unwrap(), 7);
+        assert!(matches!(
+            frame_header.num_toc_entries(1 << 30, 1 << 30),
+            Err(Error::ArithmeticOverflow)
+        ));
+    }
+
+    #[test]
+    fn test_passes() {
+        let passes = Passes {
+            num_passes: 3,
+            num_ds: 2,
+            shift: vec![2, 1],
+            downsample: vec![8, 2],
+            last_pass: vec![0, 1],
+        };
+        passes.check().unwrap();
+        assert_eq!(
+            (0..3).map(|p| passes.shift(p)).collect::<Vec<_>>(),
+            vec![2, 1, 0]
+        );
+        assert!(passes.is_last_pass(2));
+        assert_eq!(passes.downsampling_bracket(0), (3, 2));
+        assert_eq!(passes.downsampling_bracket(1), (1, 2));
+        assert_eq!(passes.downsampling_bracket(2), (0, 0));
+        assert_eq!(Passes::default().downsampling_bracket(0), (0, 2));
+        assert_eq!(passes.num_passes_for_downsampling(1), 3);
+        assert_eq!(passes.num_passes_for_downsampling(2), 2);
+        assert_eq!(passes.num_passes_for_downsampling(4), 2);
+        assert_eq!(passes.num_passes_for_downsampling(8), 1);
+        assert_eq!(Passes::default().num_passes_for_downsampling(8), 1);
+
+        let passes = Passes {
+            downsample: vec![2, 8],
+            ..passes
+        };
+        assert!(passes.check().is_err());
+    }
+
+    fn modular_frame_header() -> FrameHeader {
+        FrameHeader {
+            all_default: false,
+            frame_type: FrameType::RegularFrame,
+            encoding: Encoding::Modular,
+            flags: 0,
+            do_ycbcr: false,
+            jpeg_upsampling: [0, 0, 0],
+            upsampling: 1,
+            ec_upsampling: vec![],
+            group_size_shift: 1,
+            x_qm_scale: 3,
+            b_qm_scale: 2,
+            passes: Passes::default(),
+            lf_level: 0,
+            have_crop: false,
+            x0: 0,
+            y0: 0,
+            width: 0,
+            height: 0,
+            blending_info: BlendingInfo::default(),
+            ec_blending_info: vec![],
+            duration: 0,
+            timecode: 0,
+            is_last: true,
+            save_as_reference: 0,
+            save_before_ct: false,
+            name: String::new(),
+            restoration_filter: RestorationFilter::default(),
+            extensions: Extensions::default(),
+        }
+    }
+
+    #[test]
+    fn test_canvas_intersection() {
+        let mut frame_header = FrameHeader {
+            have_crop: true,
+            x0: -10,
+            y0: 20,
+            width: 50,
+            height: 100,
+            ..modular_frame_header()
+        };
+        assert_eq!(
+            frame_header.canvas_intersection(64, 64),
+            Some(CanvasIntersection {
+                canvas_x0: 0,
+                canvas_y0: 20,
+                frame_x0: 10,
+                frame_y0: 0,
+                xsize: 40,
+                ysize: 44,
+            })
+        );
+        frame_header.x0 = -50;
+        assert_eq!(frame_header.canvas_intersection(64, 64), None);
+        frame_header.have_crop = false;
+        frame_header.x0 = 0;
+        frame_header.y0 = 0;
+        assert_eq!(
+            frame_header.canvas_intersection(64, 32),
+            Some(CanvasIntersection {
+                canvas_x0: 0,
+                canvas_y0: 0,
+                frame_x0: 0,
+                frame_y0: 0,
+                xsize: 64,
+                ysize: 32,
+            })
+        );
+    }
+
     #[test]
     fn test_extra_channel() {
         test_frame_header(
@@ -9,12 +9,14 @@ use jxl_headers_derive::UnconditionalCoder;
 use num_derive::FromPrimitive;
 
 use crate::bit_reader::BitReader;
+use crate::bit_writer::BitWriter;
 use crate::error::Error;
 use crate::headers::bit_depth::*;
 use crate::headers::color_encoding::*;
 use crate::headers::encodings::*;
 use crate::headers::extra_channels::*;
 use crate::headers::size::*;
+use std::time::Duration;
 
 #[derive(Debug, Default)]
 pub struct Signature;
@@ -36,8 +38,14 @@ impl crate::headers::encodings::UnconditionalCoder<()> for Signature {
             Ok(Signature {})
         }
     }
+    fn write_unconditional(&self, _: &(), bw: &mut BitWriter, _: &Empty) -> Result<(), Error> {
+        bw.write(8, 0xff);
+        bw.write(8, 0x0a);
+        Ok(())
+    }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
 pub enum Orientation {
     Identity = 1,
@@ -50,6 +58,39 @@ pub enum Orientation {
     Rotate270 = 8,
 }
 
+impl Orientation {
+    /// Whether displaying the image swaps its width and height.
+    pub fn is_transposing(&self) -> bool {
+        (*self as u32) > 4
+    }
+
+    /// Size of the displayed image, given the size of the coded one.
+    pub fn display_size(&self, xsize: u32, ysize: u32) -> (u32, u32) {
+        if self.is_transposing() {
+            (ysize, xsize)
+        } else {
+            (xsize, ysize)
+        }
+    }
+
+    /// Position in the displayed image of the pixel at `(x, y)` in the coded
+    /// image of size `xsize` x `ysize`.
+    pub fn display_position(&self, x: u32, y: u32, xsize: u32, ysize: u32) -> (u32, u32) {
+        let (flip_x, flip_y) = (xsize - 1 - x, ysize - 1 - y);
+        match self {
+            Orientation::Identity => (x, y),
+            Orientation::FlipHorizontal => (flip_x, y),
+            Orientation::Rotate180 => (flip_x, flip_y),
+            Orientation::FlipVertical => (x, flip_y),
+            Orientation::Transpose => (y, x),
+            Orientation::Rotate90 => (flip_y, x),
+            Orientation::AntiTranspose => (flip_y, flip_x),
+            Orientation::Rotate270 => (y, flip_x),
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug)]
 pub struct Animation {
     #[coder(u2S(100, 1000, Bits(10) + 1, Bits(30) + 1))]
@@ -61,6 +102,65 @@ pub struct Animation {
     pub have_timecodes: bool,
 }
 
+/// How many times an animation should be played.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LoopCount {
+    Infinite,
+    Finite(u32),
+}
+
+impl LoopCount {
+    /// Returns whether another pass over the frames should be played after
+    /// `completed` full passes.
+    pub fn should_play(&self, completed: u32) -> bool {
+        match *self {
+            LoopCount::Infinite => true,
+            LoopCount::Finite(n) => completed < n,
+        }
+    }
+}
+
+impl Animation {
+    /// Interprets `num_loops`, where 0 means the animation repeats forever.
+    pub fn loop_count(&self) -> LoopCount {
+        match self.num_loops {
+            0 => LoopCount::Infinite,
+            n => LoopCount::Finite(n),
+        }
+    }
+
+    /// Number of ticks per second, i.e. the unit of frame durations.
+    pub fn ticks_per_second(&self) -> f64 {
+        self.tps_numerator as f64 / self.tps_denominator as f64
+    }
+
+    /// Converts a frame duration in ticks to wall-clock time, rounded to the
+    /// nearest nanosecond.
+    pub fn ticks_to_duration(&self, ticks: u32) -> Duration {
+        let num = self.tps_numerator as u64;
+        let total = ticks as u64 * self.tps_denominator as u64;
+        let nanos = ((total % num) * 1_000_000_000 + num / 2) / num;
+        Duration::new(total / num, 0) + Duration::from_nanos(nanos)
+    }
+
+    /// Converts a frame duration in ticks to milliseconds, without rounding.
+    pub fn ticks_to_millis(&self, ticks: u32) -> f64 {
+        ticks as f64 * 1000.0 / self.ticks_per_second()
+    }
+
+    /// Frame rate of an animation whose frames all last `ticks` ticks.
+    /// Returns `None` for zero-duration frames.
+    pub fn frames_per_second(&self, ticks: u32) -> Option<f64> {
+        if ticks == 0 {
+            None
+        } else {
+            Some(self.ticks_per_second() / ticks as f64)
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug)]
 #[validate]
 pub struct ToneMapping {
@@ -96,6 +196,7 @@ impl ToneMapping {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug)]
 pub struct ImageMetadata {
     #[all_default]
@@ -136,3 +237,85 @@ pub struct ImageMetadata {
     pub tone_mapping: ToneMapping,
     extensions: Option<Extensions>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn animation(tps_numerator: u32, tps_denominator: u32) -> Animation {
+        Animation {
+            tps_numerator,
+            tps_denominator,
+            num_loops: 0,
+            have_timecodes: false,
+        }
+    }
+
+    #[test]
+    fn test_ticks_to_duration() {
+        let a = animation(1000, 1);
+        assert_eq!(a.ticks_to_duration(40), Duration::from_millis(40));
+        assert_eq!(a.ticks_to_millis(40), 40.0);
+        assert_eq!(a.frames_per_second(40), Some(25.0));
+        assert_eq!(a.frames_per_second(0), None);
+    }
+
+    #[test]
+    fn test_ntsc_rate() {
+        // 30000/1001 ticks per second, one tick per frame.
+        let a = animation(30000, 1001);
+        assert_eq!(a.ticks_to_duration(1), Duration::from_nanos(33_366_667));
+        assert_eq!(a.ticks_to_duration(30000), Duration::from_secs(1001));
+        assert!((a.frames_per_second(1).unwrap() - 29.97002997).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_loop_count() {
+        let mut a = animation(1000, 1);
+        assert_eq!(a.loop_count(), LoopCount::Infinite);
+        assert!(a.loop_count().should_play(u32::MAX));
+        a.num_loops = 2;
+        assert_eq!(a.loop_count(), LoopCount::Finite(2));
+        assert!(a.loop_count().should_play(0));
+        assert!(a.loop_count().should_play(1));
+        assert!(!a.loop_count().should_play(2));
+    }
+
+    #[test]
+    fn test_orientation() {
+        // A 3x2 image with pixels numbered in raster order:
+        // 0 1 2
+        // 3 4 5
+        let expected = [
+            (Orientation::Identity, vec![0, 1, 2, 3, 4, 5]),
+            (Orientation::FlipHorizontal, vec![2, 1, 0, 5, 4, 3]),
+            (Orientation::Rotate180, vec![5, 4, 3, 2, 1, 0]),
+            (Orientation::FlipVertical, vec![3, 4, 5, 0, 1, 2]),
+            (Orientation::Transpose, vec![0, 3, 1, 4, 2, 5]),
+            (Orientation::Rotate90, vec![3, 0, 4, 1, 5, 2]),
+            (Orientation::AntiTranspose, vec![5, 2, 4, 1, 3, 0]),
+            (Orientation::Rotate270, vec![2, 5, 1, 4, 0, 3]),
+        ];
+        for (orientation, pixels) in expected {
+            let (width, height) = orientation.display_size(3, 2);
+            assert_eq!(width * height, 6);
+            let mut displayed = vec![0; 6];
+            for y in 0..2 {
+                for x in 0..3 {
+                    let (dx, dy) = orientation.display_position(x, y, 3, 2);
+                    displayed[(dy * width + dx) as usize] = y * 3 + x;
+                }
+            }
+            assert_eq!(displayed, pixels, "{:?}", orientation);
+        }
+    }
+
+    #[test]
+    fn test_large_durations() {
+        let a = animation(1, 1 << 10);
+        assert_eq!(
+            a.ticks_to_duration(u32::MAX),
+            Duration::from_secs(u32::MAX as u64 * 1024)
+        );
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::error::Error;
+use crate::headers::FileHeaders;
+
+/// Conformance level of a codestream, as signalled by the `jxll` box.
+/// Bare codestreams and containers without a `jxll` box are level 5.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Level {
+    Level5,
+    Level10,
+}
+
+struct Limits {
+    max_dimension: u64,
+    max_area: u64,
+    max_extra_channels: usize,
+    max_bits_per_sample: u32,
+}
+
+impl Level {
+    pub fn from_jxll(value: u8) -> Result<Level, Error> {
+        match value {
+            5 => Ok(Level::Level5),
+            10 => Ok(Level::Level10),
+            _ => Err(Error::InvalidLevel(value)),
+        }
+    }
+
+    pub fn value(&self) -> u8 {
+        match self {
+            Level::Level5 => 5,
+            Level::Level10 => 10,
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        match self {
+            Level::Level5 => Limits {
+                max_dimension: 1 << 18,
+                max_area: 1 << 28,
+                max_extra_channels: 4,
+                max_bits_per_sample: 16,
+            },
+            Level::Level10 => Limits {
+                max_dimension: 1 << 30,
+                max_area: 1 << 40,
+                max_extra_channels: 256,
+                max_bits_per_sample: 32,
+            },
+        }
+    }
+
+    /// Checks that the image described by `headers` stays within the limits of this level.
+    pub fn check(&self, headers: &FileHeaders) -> Result<(), Error> {
+        let limits = self.limits();
+        let xsize = headers.size.xsize() as u64;
+        let ysize = headers.size.ysize() as u64;
+        if xsize > limits.max_dimension
+            || ysize > limits.max_dimension
+            || xsize * ysize > limits.max_area
+        {
+            return Err(Error::ImageSizeTooLargeForLevel(xsize, ysize, self.value()));
+        }
+        let metadata = &headers.image_metadata;
+        let num_extra_channels = metadata.extra_channel_info.len();
+        if num_extra_channels > limits.max_extra_channels {
+            return Err(Error::TooManyExtraChannelsForLevel(
+                num_extra_channels,
+                self.value(),
+            ));
+        }
+        let bit_depths = std::iter::once(&metadata.bit_depth)
+            .chain(metadata.extra_channel_info.iter().map(|ec| ec.bit_depth()));
+        for bit_depth in bit_depths {
+            if bit_depth.bits_per_sample() > limits.max_bits_per_sample {
+                return Err(Error::BitDepthTooLargeForLevel(
+                    bit_depth.bits_per_sample(),
+                    self.value(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bit_reader::BitReader, bmff::JxlCodestream, headers::JxlHeader};
+
+    const CODESTREAM: [u8; 12] = [
+        0xFF, 0x0A, 0x00, 0x90, 0x01, 0x00, 0x12, 0x88, 0x02, 0x00, 0xD4, 0x00,
+    ];
+
+    fn container(jxll: Option<u8>) -> Vec<u8> {
+        let mut data = vec![
+            0x00, 0x00, 0x00, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+        ];
+        if let Some(level) = jxll {
+            data.extend_from_slice(&[0x00, 0x00, 0x00, 0x09, b'j', b'x', b'l', b'l', level]);
+        }
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 8 + CODESTREAM.len() as u8]);
+        data.extend_from_slice(b"jxlc");
+        data.extend_from_slice(&CODESTREAM);
+        data
+    }
+
+    #[test]
+    fn test_jxll_box() {
+        let codestream = JxlCodestream::new(container(None)).unwrap();
+        assert_eq!(codestream.level(), Level::Level5);
+        let codestream = JxlCodestream::new(container(Some(10))).unwrap();
+        assert_eq!(codestream.level(), Level::Level10);
+        assert_eq!(*codestream.get(), CODESTREAM);
+        assert!(JxlCodestream::new(container(Some(6))).is_err());
+        let codestream = JxlCodestream::new(CODESTREAM.to_vec()).unwrap();
+        assert_eq!(codestream.level(), Level::Level5);
+    }
+
+    #[test]
+    fn test_check() {
+        let mut br = BitReader::new(&CODESTREAM);
+        let fh = FileHeaders::read(&mut br).unwrap();
+        Level::Level5.check(&fh).unwrap();
+        Level::Level10.check(&fh).unwrap();
+    }
+
+    #[test]
+    fn test_from_jxll() {
+        assert_eq!(Level::from_jxll(5).unwrap(), Level::Level5);
+        assert_eq!(Level::from_jxll(10).unwrap(), Level::Level10);
+        assert!(Level::from_jxll(7).is_err());
+    }
+}
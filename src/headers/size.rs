@@ -13,6 +13,7 @@ use crate::error::Error;
 use crate::headers::encodings::*;
 use num_derive::FromPrimitive;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Copy, Clone, PartialEq, Debug, FromPrimitive)]
 enum AspectRatio {
     Unknown = 0,
@@ -25,6 +26,7 @@ enum AspectRatio {
     Ratio2Over1 = 7,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug)]
 pub struct Size {
     small: bool,
@@ -44,6 +46,7 @@ pub struct Size {
     xsize: Option<u32>,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug)]
 pub struct Preview {
     div8: bool,
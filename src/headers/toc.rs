@@ -0,0 +1,262 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::bit_reader::BitReader;
+use crate::entropy_coding::decode::Histograms;
+use crate::entropy_coding::stats::EntropyStats;
+use crate::error::Error;
+use crate::headers::encodings::{Empty, U32Coder, UnconditionalCoder, U32};
+
+const PERMUTATION_CONTEXTS: usize = 8;
+
+fn permutation_context(x: u32) -> usize {
+    (32 - x.leading_zeros()).min(7) as usize
+}
+
+/// Reads a Lehmer-coded permutation of `size` elements, and statistics about
+/// its entropy-coded stream if `collect_stats` is set.
+pub(crate) fn decode_permutation(
+    br: &mut BitReader,
+    size: u32,
+    collect_stats: bool,
+) -> Result<(Vec<u32>, Option<EntropyStats>), Error> {
+    let histograms = Histograms::decode(PERMUTATION_CONTEXTS, br, /*allow_lz77=*/ true)?;
+    let mut reader = histograms.make_reader(br)?;
+    if collect_stats {
+        reader.collect_stats();
+    }
+    let end = reader.read(br, permutation_context(size))?;
+    if end > size {
+        return Err(Error::InvalidPermutation);
+    }
+    let mut lehmer = vec![0u32; size as usize];
+    for i in 0..end as usize {
+        let prev = if i > 0 { lehmer[i - 1] } else { 0 };
+        lehmer[i] = reader.read(br, permutation_context(prev))?;
+        if lehmer[i] >= size - i as u32 {
+            return Err(Error::InvalidPermutation);
+        }
+    }
+    let stats = reader.stats().cloned();
+    reader.check_final_state()?;
+
+    let mut remaining: Vec<u32> = (0..size).collect();
+    let permutation = lehmer
+        .iter()
+        .map(|&index| remaining.remove(index as usize))
+        .collect();
+    Ok((permutation, stats))
+}
+
+/// Role of a section within a frame.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// The only section of a single-group, single-pass frame, which holds all
+    /// of the frame data.
+    All,
+    LfGlobal,
+    LfGroup(u32),
+    HfGlobal,
+    Group {
+        pass: u32,
+        group: u32,
+    },
+}
+
+impl Section {
+    /// Returns the role of the section at (unpermuted) `index` in a frame with
+    /// `num_entries` TOC entries and the given number of LF groups and groups.
+    pub fn from_index(
+        index: u32,
+        num_entries: u32,
+        num_lf_groups: u32,
+        num_groups: u32,
+    ) -> Section {
+        if num_entries == 1 {
+            return Section::All;
+        }
+        match index {
+            0 => Section::LfGlobal,
+            i if i <= num_lf_groups => Section::LfGroup(i - 1),
+            i if i == num_lf_groups + 1 => Section::HfGlobal,
+            i => {
+                let i = i - num_lf_groups - 2;
+                Section::Group {
+                    pass: i / num_groups,
+                    group: i % num_groups,
+                }
+            }
+        }
+    }
+}
+
+/// Table of contents of a frame: the sizes of its sections.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Toc {
+    /// Section sizes in bytes, in the order in which sections appear in the
+    /// bitstream.
+    pub entries: Vec<u32>,
+    /// If present, section `i` is stored at position `permutation[i]` in the
+    /// bitstream.
+    pub permutation: Option<Vec<u32>>,
+}
+
+impl Toc {
+    /// Reads a TOC with `num_entries` entries, leaving `br` at the first section.
+    pub fn read(br: &mut BitReader, num_entries: u32) -> Result<Toc, Error> {
+        Toc::read_impl(br, num_entries, false).map(|(toc, _)| toc)
+    }
+
+    /// Like [`Toc::read`], but also returns statistics about the entropy-coded
+    /// permutation, if the TOC is permuted.
+    pub fn read_with_stats(
+        br: &mut BitReader,
+        num_entries: u32,
+    ) -> Result<(Toc, Option<EntropyStats>), Error> {
+        Toc::read_impl(br, num_entries, true)
+    }
+
+    fn read_impl(
+        br: &mut BitReader,
+        num_entries: u32,
+        collect_stats: bool,
+    ) -> Result<(Toc, Option<EntropyStats>), Error> {
+        #[cfg(feature = "trace")]
+        let trace_toc_offset = br.total_bits_read();
+        let permuted = br.read(1)? != 0;
+        let (permutation, stats) = if permuted {
+            let (permutation, stats) = decode_permutation(br, num_entries, collect_stats)?;
+            (Some(permutation), stats)
+        } else {
+            (None, None)
+        };
+        br.jump_to_byte_boundary()?;
+        #[cfg(feature = "trace")]
+        let trace_bit_offset = br.total_bits_read();
+        let coder = U32Coder::Select(
+            U32::Bits(10),
+            U32::BitsOffset { n: 14, off: 1024 },
+            U32::BitsOffset { n: 22, off: 17408 },
+            U32::BitsOffset {
+                n: 30,
+                off: 4211712,
+            },
+        );
+        let entries = (0..num_entries)
+            .map(|_| u32::read_unconditional(&coder, br, &Empty {}))
+            .collect::<Result<Vec<_>, _>>()?;
+        #[cfg(feature = "trace")]
+        {
+            crate::trace::record("Toc.permutation", trace_toc_offset, &permutation);
+            crate::trace::record("Toc.entries", trace_bit_offset, &entries);
+        }
+        br.jump_to_byte_boundary()?;
+        let toc = Toc {
+            entries,
+            permutation,
+        };
+        Ok((toc, stats))
+    }
+
+    pub fn is_permuted(&self) -> bool {
+        self.permutation.is_some()
+    }
+
+    /// Returns, for each position in the bitstream, the index of the section
+    /// stored there. This is the identity if the TOC is not permuted.
+    pub fn bitstream_order(&self) -> Vec<u32> {
+        let mut order: Vec<u32> = (0..self.entries.len() as u32).collect();
+        if let Some(ref permutation) = self.permutation {
+            for (section, pos) in permutation.iter().enumerate() {
+                order[*pos as usize] = section as u32;
+            }
+        }
+        order
+    }
+
+    /// Total size of all sections, in bytes.
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|x| *x as u64).sum()
+    }
+
+    /// Returns the `(offset, size)` in bytes of every section relative to the
+    /// first one, indexed by section rather than bitstream position.
+    pub fn section_offsets(&self) -> Vec<(u64, u32)> {
+        let mut offset = 0;
+        let in_bitstream_order: Vec<(u64, u32)> = self
+            .entries
+            .iter()
+            .map(|size| {
+                let ret = (offset, *size);
+                offset += *size as u64;
+                ret
+            })
+            .collect();
+        match self.permutation {
+            Some(ref permutation) => permutation
+                .iter()
+                .map(|pos| in_bitstream_order[*pos as usize])
+                .collect(),
+            None => in_bitstream_order,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_single_entry() -> Result<(), Error> {
+        // Not permuted, entry coded with selector 0 and value 0x155.
+        let data = [0x00, 0x54, 0x05];
+        let mut br = BitReader::new(&data);
+        let toc = Toc::read(&mut br, 1)?;
+        assert_eq!(toc.entries, vec![0x155]);
+        assert_eq!(toc.permutation, None);
+        assert_eq!(br.total_bits_read(), 24);
+        Ok(())
+    }
+
+    #[test]
+    fn test_section_offsets() {
+        let mut toc = Toc {
+            entries: vec![10, 20, 30],
+            permutation: None,
+        };
+        assert_eq!(toc.section_offsets(), vec![(0, 10), (10, 20), (30, 30)]);
+        assert!(!toc.is_permuted());
+        assert_eq!(toc.bitstream_order(), vec![0, 1, 2]);
+        toc.permutation = Some(vec![2, 0, 1]);
+        assert_eq!(toc.section_offsets(), vec![(30, 30), (0, 10), (10, 20)]);
+        assert!(toc.is_permuted());
+        // Sections 1 and 2 come first in the bitstream, followed by section 0.
+        assert_eq!(toc.bitstream_order(), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn test_section_from_index() {
+        assert_eq!(Section::from_index(0, 1, 1, 1), Section::All);
+        // 2 LF groups, 3 groups, 2 passes.
+        let sections: Vec<_> = (0..10).map(|i| Section::from_index(i, 10, 2, 3)).collect();
+        assert_eq!(
+            sections,
+            vec![
+                Section::LfGlobal,
+                Section::LfGroup(0),
+                Section::LfGroup(1),
+                Section::HfGlobal,
+                Section::Group { pass: 0, group: 0 },
+                Section::Group { pass: 0, group: 1 },
+                Section::Group { pass: 0, group: 2 },
+                Section::Group { pass: 1, group: 0 },
+                Section::Group { pass: 1, group: 1 },
+                Section::Group { pass: 1, group: 2 },
+            ]
+        );
+    }
+}
@@ -18,6 +18,7 @@ pub struct CustomTransformDataNonserialized {
     pub xyb_encoded: bool,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug)]
 pub struct OpsinInverseMatrix {
     #[all_default]
@@ -322,6 +323,13 @@ const DEFAULT_KERN_8: [f32; 210] = [
     -0.00458223,
 ];
 
+// serde only implements `Serialize` for arrays of up to 32 elements.
+#[cfg(feature = "serde")]
+fn serialize_slice<S: serde::Serializer>(values: &[f32], serializer: S) -> Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(values, serializer)
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(UnconditionalCoder, Debug)]
 #[nonserialized(CustomTransformDataNonserialized)]
 pub struct CustomTransformData {
@@ -338,8 +346,10 @@ pub struct CustomTransformData {
     weights2: [f32; 15],
     #[condition((custom_weight_mask & 2) != 0)]
     #[default(DEFAULT_KERN_4)]
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_slice"))]
     weights4: [f32; 55],
     #[condition((custom_weight_mask & 4) != 0)]
     #[default(DEFAULT_KERN_8)]
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_slice"))]
     weights8: [f32; 210],
 }
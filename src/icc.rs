@@ -3,23 +3,501 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
+pub mod known;
+pub mod profile;
+pub mod synthesize;
+
 use crate::bit_reader::*;
 use crate::entropy_coding::decode::Histograms;
+use crate::entropy_coding::stats::EntropyStats;
 use crate::error::Error;
 use crate::headers::encodings::*;
+use crate::util::safe_arith::SafeArith;
+use std::convert::{TryFrom, TryInto};
 
 const ICC_CONTEXTS: usize = 41;
+const ICC_HEADER_SIZE: usize = 128;
+
+const COMMAND_INSERT: u8 = 1;
+const COMMAND_SHUFFLE2: u8 = 2;
+const COMMAND_SHUFFLE4: u8 = 3;
+const COMMAND_PREDICT: u8 = 4;
+const COMMAND_XYZ: u8 = 10;
+const COMMAND_TYPE_START_FIRST: u8 = 16;
+
+const COMMAND_TAG_UNKNOWN: u8 = 1;
+const COMMAND_TAG_TRC: u8 = 2;
+const COMMAND_TAG_XYZ: u8 = 3;
+const COMMAND_TAG_STRING_FIRST: u8 = 4;
+
+const FLAG_BIT_OFFSET: u8 = 64;
+const FLAG_BIT_SIZE: u8 = 128;
+
+const TAG_STRINGS: [&[u8; 4]; 17] = [
+    b"cprt", b"wtpt", b"bkpt", b"rXYZ", b"gXYZ", b"bXYZ", b"kXYZ", b"rTRC", b"gTRC", b"bTRC",
+    b"kTRC", b"chad", b"desc", b"chrm", b"dmnd", b"dmdd", b"lumi",
+];
+
+const TYPE_STRINGS: [&[u8; 4]; 8] = [
+    b"XYZ ", b"desc", b"text", b"mluc", b"para", b"curv", b"sf32", b"gbd ",
+];
+
+fn byte_kind1(b: u8) -> usize {
+    match b {
+        b'a'..=b'z' | b'A'..=b'Z' => 0,
+        b'0'..=b'9' | b'.' | b',' => 1,
+        0 => 2,
+        1 => 3,
+        2..=15 => 4,
+        255 => 6,
+        241..=254 => 5,
+        _ => 7,
+    }
+}
+
+fn byte_kind2(b: u8) -> usize {
+    match b {
+        b'a'..=b'z' | b'A'..=b'Z' => 0,
+        b'0'..=b'9' | b'.' | b',' => 1,
+        0..=15 => 2,
+        241..=255 => 3,
+        _ => 4,
+    }
+}
+
+fn icc_context(i: usize, b1: u8, b2: u8) -> usize {
+    if i <= 128 {
+        0
+    } else {
+        1 + byte_kind1(b1) + byte_kind2(b2) * 8
+    }
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    let mut ret = 0u64;
+    for i in 0..10 {
+        let byte = *data.get(*pos).ok_or(Error::InvalidIccStream)?;
+        *pos += 1;
+        ret |= ((byte & 127) as u64).safe_shl(7 * i)?;
+        if byte & 128 == 0 {
+            return Ok(ret);
+        }
+    }
+    Err(Error::InvalidIccStream)
+}
+
+fn read_u32_varint(data: &[u8], pos: &mut usize) -> Result<u32, Error> {
+    let value = read_varint(data, pos)?;
+    u32::try_from(value).map_err(|_| Error::InvalidIccStream)
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, num: u64) -> Result<&'a [u8], Error> {
+    let end = (*pos as u64)
+        .checked_add(num)
+        .filter(|end| *end <= data.len() as u64)
+        .ok_or(Error::InvalidIccStream)? as usize;
+    let ret = &data[*pos..end];
+    *pos = end;
+    Ok(ret)
+}
+
+fn initial_header_prediction(output_size: u32) -> [u8; ICC_HEADER_SIZE] {
+    let mut header = [0u8; ICC_HEADER_SIZE];
+    header[0..4].copy_from_slice(&output_size.to_be_bytes());
+    header[8] = 4;
+    header[12..16].copy_from_slice(b"mntr");
+    header[16..20].copy_from_slice(b"RGB ");
+    header[20..24].copy_from_slice(b"XYZ ");
+    header[36..40].copy_from_slice(b"acsp");
+    header[70] = 246;
+    header[71] = 214;
+    header[73] = 1;
+    header[78] = 211;
+    header[79] = 45;
+    header
+}
+
+fn predict_header(icc: &[u8], header: &mut [u8; ICC_HEADER_SIZE], pos: usize) {
+    match pos {
+        8 => header[80..84].copy_from_slice(&icc[4..8]),
+        41 => match icc[40] {
+            b'A' => header[41..44].copy_from_slice(b"PPL"),
+            b'M' => header[41..44].copy_from_slice(b"SFT"),
+            _ => {}
+        },
+        42 => match (icc[40], icc[41]) {
+            (b'S', b'G') => header[42..44].copy_from_slice(b"I "),
+            (b'S', b'U') => header[42..44].copy_from_slice(b"NW"),
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+/// Transposes `data`, seen as a matrix with `width` rows, filled column by column.
+fn shuffle(data: &mut [u8], width: usize) {
+    let height = data.len().div_ceil(width);
+    let mut result = Vec::with_capacity(data.len());
+    let mut start = 0;
+    let mut j = 0;
+    for _ in 0..data.len() {
+        result.push(data[j]);
+        j += height;
+        if j >= data.len() {
+            start += 1;
+            j = start;
+        }
+    }
+    data.copy_from_slice(&result);
+}
+
+fn predict_value(p1: u32, p2: u32, p3: u32, order: u8) -> u32 {
+    match order {
+        0 => p1,
+        1 => p1.wrapping_mul(2).wrapping_sub(p2),
+        _ => p1
+            .wrapping_mul(3)
+            .wrapping_sub(p2.wrapping_mul(3))
+            .wrapping_add(p3),
+    }
+}
+
+fn linear_predict(
+    data: &[u8],
+    start: usize,
+    i: usize,
+    stride: usize,
+    width: usize,
+    order: u8,
+) -> u8 {
+    let pos = start + i;
+    match width {
+        1 => {
+            let p = |k: usize| data[pos - stride * k] as u32;
+            predict_value(p(1), p(2), p(3), order) as u8
+        }
+        2 => {
+            let base = start + (i & !1);
+            let p = |k: usize| {
+                let at = base - stride * k;
+                ((data[at] as u32) << 8) + data[at + 1] as u32
+            };
+            let pred = predict_value(p(1), p(2), p(3), order);
+            if i & 1 != 0 {
+                pred as u8
+            } else {
+                (pred >> 8) as u8
+            }
+        }
+        _ => {
+            let base = start + (i & !3);
+            let p = |k: usize| {
+                let at = base - stride * k;
+                if at + 4 > pos {
+                    0
+                } else {
+                    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+                }
+            };
+            let pred = predict_value(p(1), p(2), p(3), order);
+            (pred >> ((3 - (i & 3)) * 8)) as u8
+        }
+    }
+}
+
+/// Reconstructs an ICC profile from the byte stream decoded from the codestream.
+fn unpredict_icc(enc: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut pos = 0;
+    let output_size = read_u32_varint(enc, &mut pos)?;
+    let commands_size = read_varint(enc, &mut pos)?;
+    let commands = take(enc, &mut pos, commands_size)?;
+    let mut cpos = 0;
+    let commands_end = commands.len();
+    let output_size_usize = output_size as usize;
+    if output_size as u64 > 1u64 << 28 {
+        return Err(Error::ICCTooLarge);
+    }
+
+    let mut decoded = Vec::with_capacity(output_size_usize.min(enc.len() * 16));
+    let mut header = initial_header_prediction(output_size);
+    for i in 0..=ICC_HEADER_SIZE {
+        if decoded.len() == output_size_usize {
+            if cpos != commands_end || pos != enc.len() {
+                return Err(Error::InvalidIccStream);
+            }
+            return Ok(decoded);
+        }
+        if i == ICC_HEADER_SIZE {
+            break;
+        }
+        predict_header(&decoded, &mut header, i);
+        let byte = *enc.get(pos).ok_or(Error::InvalidIccStream)?;
+        pos += 1;
+        decoded.push(byte.wrapping_add(header[i]));
+    }
+    if cpos >= commands_end {
+        return Err(Error::InvalidIccStream);
+    }
+
+    // Tag list.
+    let num_tags = read_varint(commands, &mut cpos)?;
+    if num_tags != 0 {
+        let num_tags = u32::try_from(num_tags - 1).map_err(|_| Error::InvalidIccStream)?;
+        decoded.extend_from_slice(&num_tags.to_be_bytes());
+        let mut prev_tag_start = ICC_HEADER_SIZE as u64 + num_tags as u64 * 12;
+        let mut prev_tag_size = 0u64;
+        let to_u32 = |x: u64| u32::try_from(x).map_err(|_| Error::InvalidIccStream);
+        while cpos < commands_end {
+            if decoded.len() > output_size_usize {
+                return Err(Error::InvalidIccStream);
+            }
+            let command = commands[cpos];
+            cpos += 1;
+            let tag_code = command & 63;
+            let tag: [u8; 4] = match tag_code {
+                0 => break,
+                COMMAND_TAG_UNKNOWN => take(enc, &mut pos, 4)?.try_into().unwrap(),
+                COMMAND_TAG_TRC => *b"rTRC",
+                COMMAND_TAG_XYZ => *b"rXYZ",
+                _ => **TAG_STRINGS
+                    .get((tag_code - COMMAND_TAG_STRING_FIRST) as usize)
+                    .ok_or(Error::InvalidIccStream)?,
+            };
+            decoded.extend_from_slice(&tag);
+
+            let mut tag_size = prev_tag_size;
+            if matches!(
+                &tag,
+                b"rXYZ" | b"gXYZ" | b"bXYZ" | b"kXYZ" | b"wtpt" | b"bkpt" | b"lumi"
+            ) {
+                tag_size = 20;
+            }
+            let tag_start = if command & FLAG_BIT_OFFSET != 0 {
+                if cpos >= commands_end {
+                    return Err(Error::InvalidIccStream);
+                }
+                read_varint(commands, &mut cpos)?
+            } else {
+                to_u32(prev_tag_start)? as u64 + prev_tag_size
+            };
+            decoded.extend_from_slice(&to_u32(tag_start)?.to_be_bytes());
+            if command & FLAG_BIT_SIZE != 0 {
+                if cpos >= commands_end {
+                    return Err(Error::InvalidIccStream);
+                }
+                tag_size = read_varint(commands, &mut cpos)?;
+            }
+            decoded.extend_from_slice(&to_u32(tag_size)?.to_be_bytes());
+            prev_tag_start = tag_start;
+            prev_tag_size = tag_size;
+
+            // The green and blue TRCs share the data of the red one, while the
+            // XYZ tags are stored one after the other.
+            let implied_tags = match tag_code {
+                COMMAND_TAG_TRC => Some(([b"gTRC", b"bTRC"], 0)),
+                COMMAND_TAG_XYZ => Some(([b"gXYZ", b"bXYZ"], tag_size)),
+                _ => None,
+            };
+            if let Some((implied_tags, step)) = implied_tags {
+                for (k, tag) in implied_tags.iter().enumerate() {
+                    decoded.extend_from_slice(*tag);
+                    let start = to_u32(tag_start + step * (k as u64 + 1))?;
+                    decoded.extend_from_slice(&start.to_be_bytes());
+                    decoded.extend_from_slice(&to_u32(tag_size)?.to_be_bytes());
+                }
+                prev_tag_start = tag_start + step * 2;
+            }
+        }
+    }
+
+    // Main content.
+    while cpos < commands_end {
+        if decoded.len() > output_size_usize {
+            return Err(Error::InvalidIccStream);
+        }
+        let command = commands[cpos];
+        cpos += 1;
+        match command {
+            COMMAND_INSERT => {
+                let num = read_varint(commands, &mut cpos)?;
+                decoded.extend_from_slice(take(enc, &mut pos, num)?);
+            }
+            COMMAND_SHUFFLE2 | COMMAND_SHUFFLE4 => {
+                let num = read_varint(commands, &mut cpos)?;
+                let mut shuffled = take(enc, &mut pos, num)?.to_vec();
+                shuffle(
+                    &mut shuffled,
+                    if command == COMMAND_SHUFFLE2 { 2 } else { 4 },
+                );
+                decoded.extend_from_slice(&shuffled);
+            }
+            COMMAND_PREDICT => {
+                if cpos + 2 > commands_end {
+                    return Err(Error::InvalidIccStream);
+                }
+                let flags = commands[cpos];
+                cpos += 1;
+                let width = (flags & 3) as usize + 1;
+                let order = (flags & 12) >> 2;
+                if width == 3 || order == 3 {
+                    return Err(Error::InvalidIccStream);
+                }
+                let stride = if flags & 16 != 0 {
+                    let stride = read_varint(commands, &mut cpos)?;
+                    if stride < width as u64 {
+                        return Err(Error::InvalidIccStream);
+                    }
+                    stride
+                } else {
+                    width as u64
+                };
+                if decoded.is_empty() || ((decoded.len() as u64 - 1) >> 2) < stride {
+                    return Err(Error::InvalidIccStream);
+                }
+                let stride = stride as usize;
+                let num = read_varint(commands, &mut cpos)?;
+                let mut shuffled = take(enc, &mut pos, num)?.to_vec();
+                if width > 1 {
+                    shuffle(&mut shuffled, width);
+                }
+                let start = decoded.len();
+                for (i, residual) in shuffled.iter().enumerate() {
+                    let predicted = linear_predict(&decoded, start, i, stride, width, order);
+                    decoded.push(predicted.wrapping_add(*residual));
+                }
+            }
+            COMMAND_XYZ => {
+                decoded.extend_from_slice(b"XYZ \0\0\0\0");
+                decoded.extend_from_slice(take(enc, &mut pos, 12)?);
+            }
+            _ if (COMMAND_TYPE_START_FIRST
+                ..COMMAND_TYPE_START_FIRST + TYPE_STRINGS.len() as u8)
+                .contains(&command) =>
+            {
+                decoded
+                    .extend_from_slice(TYPE_STRINGS[(command - COMMAND_TYPE_START_FIRST) as usize]);
+                decoded.extend_from_slice(&[0; 4]);
+            }
+            _ => return Err(Error::InvalidIccStream),
+        }
+    }
+
+    if pos != enc.len() || decoded.len() != output_size_usize {
+        return Err(Error::InvalidIccStream);
+    }
+    Ok(decoded)
+}
 
 pub fn read_icc(br: &mut BitReader) -> Result<Vec<u8>, Error> {
+    read_icc_impl(br, false).map(|(icc, _)| icc)
+}
+
+/// Like [`read_icc`], but also returns statistics about the entropy-coded
+/// stream.
+pub fn read_icc_with_stats(br: &mut BitReader) -> Result<(Vec<u8>, EntropyStats), Error> {
+    let (icc, stats) = read_icc_impl(br, true)?;
+    Ok((icc, stats.unwrap()))
+}
+
+fn read_icc_impl(
+    br: &mut BitReader,
+    collect_stats: bool,
+) -> Result<(Vec<u8>, Option<EntropyStats>), Error> {
     let len = u64::read_unconditional(&(), br, &Empty {})?;
     if len > 1u64 << 20 {
         return Err(Error::ICCTooLarge);
     }
-    println!("Encoded len: {}", len);
 
     let histograms = Histograms::decode(ICC_CONTEXTS, br, /*allow_lz77=*/ true)?;
+    let mut reader = histograms.make_reader(br)?;
+    if collect_stats {
+        reader.collect_stats();
+    }
+    let mut encoded = Vec::with_capacity(len as usize);
+    for i in 0..len as usize {
+        let b1 = if i > 0 { encoded[i - 1] } else { 0 };
+        let b2 = if i > 1 { encoded[i - 2] } else { 0 };
+        let symbol = reader.read(br, icc_context(i, b1, b2))?;
+        encoded.push(symbol as u8);
+    }
+    let stats = reader.stats().cloned();
+    reader.check_final_state()?;
 
-    println!("{:#?}", histograms);
+    Ok((unpredict_icc(&encoded)?, stats))
+}
 
-    Ok(vec![])
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 128 {
+            out.push((value & 127) as u8 | 128);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    // Encodes an ICC profile using only header prediction and a single insert command.
+    fn encode_simple(icc: &[u8], commands: &[u8]) -> Vec<u8> {
+        let mut enc = vec![];
+        write_varint(icc.len() as u64, &mut enc);
+        write_varint(commands.len() as u64, &mut enc);
+        enc.extend_from_slice(commands);
+        let mut header = initial_header_prediction(icc.len() as u32);
+        for i in 0..icc.len().min(ICC_HEADER_SIZE) {
+            predict_header(&icc[..i], &mut header, i);
+            enc.push(icc[i].wrapping_sub(header[i]));
+        }
+        enc
+    }
+
+    #[test]
+    fn test_header_only() {
+        let icc: Vec<u8> = (0..100u8).map(|x| x.wrapping_mul(37)).collect();
+        assert_eq!(unpredict_icc(&encode_simple(&icc, &[])).unwrap(), icc);
+    }
+
+    #[test]
+    fn test_tag_list() {
+        let mut icc: Vec<u8> = (0..128u8).map(|x| x.wrapping_mul(29)).collect();
+        icc.extend_from_slice(&7u32.to_be_bytes());
+        for (tag, start, size) in [
+            (b"rTRC", 224u32, 14u32),
+            (b"gTRC", 224, 14),
+            (b"bTRC", 224, 14),
+            (b"rXYZ", 238, 20),
+            (b"gXYZ", 258, 20),
+            (b"bXYZ", 278, 20),
+            (b"wtpt", 298, 20),
+        ] {
+            icc.extend_from_slice(tag);
+            icc.extend_from_slice(&start.to_be_bytes());
+            icc.extend_from_slice(&size.to_be_bytes());
+        }
+        let trc = COMMAND_TAG_TRC | FLAG_BIT_OFFSET | FLAG_BIT_SIZE;
+        let mut commands = vec![8, trc];
+        write_varint(224, &mut commands);
+        commands.extend_from_slice(&[14, COMMAND_TAG_XYZ, COMMAND_TAG_STRING_FIRST + 1]);
+        assert_eq!(unpredict_icc(&encode_simple(&icc, &commands)).unwrap(), icc);
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut icc: Vec<u8> = (0..200u32).map(|x| (x * 13) as u8).collect();
+        icc[40..44].copy_from_slice(b"APPL");
+        // No tags, then insert the remaining bytes verbatim.
+        let mut enc = encode_simple(&icc, &[0, COMMAND_INSERT, 72]);
+        enc.extend_from_slice(&icc[ICC_HEADER_SIZE..]);
+        assert_eq!(unpredict_icc(&enc).unwrap(), icc);
+        // Missing data is an error.
+        assert!(unpredict_icc(&enc[..enc.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let mut data = [0, 1, 2, 3, 4, 5, 6];
+        shuffle(&mut data, 2);
+        assert_eq!(data, [0, 4, 1, 5, 2, 6, 3]);
+    }
 }
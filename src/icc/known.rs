@@ -0,0 +1,233 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::headers::color_encoding::{ColorSpace, Primaries, TransferFunction, WhitePoint};
+use crate::icc::profile::{IccProfile, ToneCurve};
+
+/// ICC profiles that describe a color encoding that can also be signalled
+/// with the enum-based color encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KnownProfile {
+    SRGB,
+    DisplayP3,
+    AdobeRGB,
+    /// Gray with a gamma 2.2 tone curve.
+    Gray22,
+}
+
+impl KnownProfile {
+    pub fn color_space(&self) -> ColorSpace {
+        match self {
+            KnownProfile::Gray22 => ColorSpace::Gray,
+            _ => ColorSpace::RGB,
+        }
+    }
+
+    pub fn white_point(&self) -> WhitePoint {
+        WhitePoint::D65
+    }
+
+    /// Primaries of the profile, or `None` if they need to be given as custom
+    /// primaries (see [`KnownProfile::custom_primaries`]) or if the profile is gray.
+    pub fn primaries(&self) -> Option<Primaries> {
+        match self {
+            KnownProfile::SRGB => Some(Primaries::SRGB),
+            KnownProfile::DisplayP3 => Some(Primaries::P3),
+            _ => None,
+        }
+    }
+
+    /// CIE xy chromaticities of the red, green and blue primaries.
+    pub fn custom_primaries(&self) -> Option<[[f32; 2]; 3]> {
+        match self {
+            KnownProfile::AdobeRGB => Some([[0.64, 0.33], [0.21, 0.71], [0.15, 0.06]]),
+            _ => None,
+        }
+    }
+
+    /// Transfer function of the profile, or `None` for a pure gamma curve
+    /// (see [`KnownProfile::gamma`]).
+    pub fn transfer_function(&self) -> Option<TransferFunction> {
+        match self {
+            KnownProfile::SRGB | KnownProfile::DisplayP3 => Some(TransferFunction::SRGB),
+            _ => None,
+        }
+    }
+
+    /// Exponent of the transfer curve, if it is a pure gamma curve.
+    pub fn gamma(&self) -> Option<f32> {
+        match self {
+            KnownProfile::AdobeRGB => Some(563.0 / 256.0),
+            KnownProfile::Gray22 => Some(2.2),
+            _ => None,
+        }
+    }
+}
+
+// Colorants of the known profiles, adapted to D50 with the Bradford transform.
+const SRGB_COLORANTS: [[f32; 3]; 3] = [
+    [0.4361, 0.2225, 0.0139],
+    [0.3851, 0.7169, 0.0971],
+    [0.1431, 0.0606, 0.7141],
+];
+const P3_COLORANTS: [[f32; 3]; 3] = [
+    [0.5151, 0.2412, -0.0011],
+    [0.2920, 0.6922, 0.0419],
+    [0.1571, 0.0666, 0.7841],
+];
+const ADOBE_COLORANTS: [[f32; 3]; 3] = [
+    [0.6097, 0.3111, 0.0195],
+    [0.2053, 0.6257, 0.0609],
+    [0.1492, 0.0632, 0.7446],
+];
+
+const COLORANT_TOLERANCE: f32 = 2e-3;
+const CURVE_TOLERANCE: f32 = 2e-3;
+
+fn colorants_match(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> bool {
+    a.iter()
+        .flatten()
+        .zip(b.iter().flatten())
+        .all(|(x, y)| (x - y).abs() < COLORANT_TOLERANCE)
+}
+
+fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Evaluates a tone curve at `x` in [0, 1], or returns `None` if the curve is
+/// not supported.
+fn evaluate(curve: &ToneCurve, x: f32) -> Option<f32> {
+    match curve {
+        ToneCurve::Identity => Some(x),
+        ToneCurve::Gamma(g) => Some(x.powf(*g)),
+        ToneCurve::Table(table) => {
+            let pos = x * (table.len() - 1) as f32;
+            let i = (pos as usize).min(table.len() - 2);
+            let frac = pos - i as f32;
+            let v = table[i] as f32 * (1.0 - frac) + table[i + 1] as f32 * frac;
+            Some(v / 65535.0)
+        }
+        ToneCurve::Parametric {
+            function_type,
+            params,
+        } => {
+            let p = |i: usize| params[i];
+            Some(match function_type {
+                0 => x.powf(p(0)),
+                1 if x >= -p(2) / p(1) => (p(1) * x + p(2)).powf(p(0)),
+                1 => 0.0,
+                2 if x >= -p(2) / p(1) => (p(1) * x + p(2)).powf(p(0)) + p(3),
+                2 => p(3),
+                3 if x >= p(4) => (p(1) * x + p(2)).powf(p(0)),
+                3 => p(3) * x,
+                4 if x >= p(4) => (p(1) * x + p(2)).powf(p(0)) + p(5),
+                4 => p(3) * x + p(6),
+                _ => return None,
+            })
+        }
+    }
+}
+
+fn curve_matches(curve: &ToneCurve, reference: impl Fn(f32) -> f32) -> bool {
+    (0..=64).all(|i| {
+        let x = i as f32 / 64.0;
+        evaluate(curve, x).is_some_and(|y| (y - reference(x)).abs() < CURVE_TOLERANCE)
+    })
+}
+
+impl IccProfile {
+    /// Recognizes profiles that are equivalent to a [`KnownProfile`], so that
+    /// color conversion does not need a full CMS.
+    pub fn recognize(&self) -> Option<KnownProfile> {
+        match &self.header.color_space {
+            b"RGB " => {
+                let colorants = self.colorants.as_ref()?;
+                let trc = self.rgb_trc.as_ref()?;
+                let all_curves = |reference: &dyn Fn(f32) -> f32| {
+                    trc.iter().all(|c| curve_matches(c, reference))
+                };
+                if colorants_match(colorants, &SRGB_COLORANTS) && all_curves(&srgb_to_linear) {
+                    Some(KnownProfile::SRGB)
+                } else if colorants_match(colorants, &P3_COLORANTS) && all_curves(&srgb_to_linear) {
+                    Some(KnownProfile::DisplayP3)
+                } else if colorants_match(colorants, &ADOBE_COLORANTS)
+                    && all_curves(&|x| x.powf(563.0 / 256.0))
+                {
+                    Some(KnownProfile::AdobeRGB)
+                } else {
+                    None
+                }
+            }
+            b"GRAY" => {
+                let trc = self.gray_trc.as_ref()?;
+                curve_matches(trc, |x| x.powf(2.2)).then_some(KnownProfile::Gray22)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::icc::profile::test::{build_profile, para_tag, xyz_tag};
+
+    fn rgb_profile(colorants: &[[f32; 3]; 3], trc: Vec<u8>) -> IccProfile {
+        let data = build_profile(&[
+            (b"wtpt", xyz_tag([0.9642, 1.0, 0.8249])),
+            (b"rXYZ", xyz_tag(colorants[0])),
+            (b"gXYZ", xyz_tag(colorants[1])),
+            (b"bXYZ", xyz_tag(colorants[2])),
+            (b"rTRC", trc.clone()),
+            (b"gTRC", trc.clone()),
+            (b"bTRC", trc),
+        ]);
+        IccProfile::parse(&data).unwrap()
+    }
+
+    fn srgb_para() -> Vec<u8> {
+        para_tag(&[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045])
+    }
+
+    fn srgb_table() -> Vec<u8> {
+        let mut tag = b"curv\0\0\0\0".to_vec();
+        tag.extend_from_slice(&1024u32.to_be_bytes());
+        for i in 0..1024 {
+            let v = srgb_to_linear(i as f32 / 1023.0);
+            tag.extend_from_slice(&((v * 65535.0).round() as u16).to_be_bytes());
+        }
+        tag
+    }
+
+    #[test]
+    fn test_rgb() {
+        let srgb = rgb_profile(&SRGB_COLORANTS, srgb_para());
+        assert_eq!(srgb.recognize(), Some(KnownProfile::SRGB));
+        let srgb = rgb_profile(&SRGB_COLORANTS, srgb_table());
+        assert_eq!(srgb.recognize(), Some(KnownProfile::SRGB));
+        let p3 = rgb_profile(&P3_COLORANTS, srgb_para());
+        assert_eq!(p3.recognize(), Some(KnownProfile::DisplayP3));
+        let mut gamma = b"curv\0\0\0\0\0\0\0\x01".to_vec();
+        gamma.extend_from_slice(&563u16.to_be_bytes());
+        let adobe = rgb_profile(&ADOBE_COLORANTS, gamma);
+        assert_eq!(adobe.recognize(), Some(KnownProfile::AdobeRGB));
+        // sRGB primaries with a linear transfer function are not known.
+        let linear = rgb_profile(&SRGB_COLORANTS, para_tag(&[1.0]));
+        assert_eq!(linear.recognize(), None);
+    }
+
+    #[test]
+    fn test_gray() {
+        let mut data = build_profile(&[(b"kTRC", para_tag(&[2.2]))]);
+        data[16..20].copy_from_slice(b"GRAY");
+        let gray = IccProfile::parse(&data).unwrap();
+        assert_eq!(gray.recognize(), Some(KnownProfile::Gray22));
+    }
+}
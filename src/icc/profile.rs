@@ -0,0 +1,384 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::error::Error;
+
+const HEADER_SIZE: usize = 128;
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    let bytes = data.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_tag(data: &[u8], pos: usize) -> Option<[u8; 4]> {
+    Some(read_u32(data, pos)?.to_be_bytes())
+}
+
+fn read_s15fixed16(data: &[u8], pos: usize) -> Option<f32> {
+    Some(read_u32(data, pos)? as i32 as f32 / 65536.0)
+}
+
+fn read_xyz(data: &[u8], pos: usize) -> Option<[f32; 3]> {
+    Some([
+        read_s15fixed16(data, pos)?,
+        read_s15fixed16(data, pos + 4)?,
+        read_s15fixed16(data, pos + 8)?,
+    ])
+}
+
+/// The fixed-size header at the start of every ICC profile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IccHeader {
+    pub size: u32,
+    pub cmm_type: [u8; 4],
+    /// Major, minor and bugfix version numbers.
+    pub version: (u8, u8, u8),
+    pub device_class: [u8; 4],
+    pub color_space: [u8; 4],
+    pub pcs: [u8; 4],
+    pub rendering_intent: u32,
+    pub illuminant: [f32; 3],
+}
+
+/// An entry of the tag table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IccTag {
+    pub signature: [u8; 4],
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// A tone reproduction curve, from either a `curv` or a `para` tag.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToneCurve {
+    Identity,
+    Gamma(f32),
+    /// Samples of the curve, uniformly spaced over [0, 1].
+    Table(Vec<u16>),
+    /// Parametric curve as defined by the ICC specification; `params` holds
+    /// g, a, b, c, d, e, f, as many as the function type requires.
+    Parametric {
+        function_type: u16,
+        params: Vec<f32>,
+    },
+}
+
+/// Coding-independent code points, as in ITU-T H.273.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cicp {
+    pub color_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub video_full_range: bool,
+}
+
+/// An ICC profile, with the tags that matter for color conversion decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IccProfile {
+    pub header: IccHeader,
+    pub tags: Vec<IccTag>,
+    pub white_point: Option<[f32; 3]>,
+    /// Red, green and blue colorants (`rXYZ`, `gXYZ`, `bXYZ`).
+    pub colorants: Option<[[f32; 3]; 3]>,
+    /// Red, green and blue tone curves (`rTRC`, `gTRC`, `bTRC`).
+    pub rgb_trc: Option<[ToneCurve; 3]>,
+    pub gray_trc: Option<ToneCurve>,
+    pub cicp: Option<Cicp>,
+    data: Vec<u8>,
+}
+
+fn invalid_tag(signature: &[u8; 4]) -> Error {
+    Error::InvalidIccTag(String::from_utf8_lossy(signature).into_owned())
+}
+
+fn parse_xyz_tag(signature: &[u8; 4], data: &[u8]) -> Result<[f32; 3], Error> {
+    if data.get(0..4) != Some(b"XYZ ") {
+        return Err(invalid_tag(signature));
+    }
+    read_xyz(data, 8).ok_or_else(|| invalid_tag(signature))
+}
+
+fn parse_curve_tag(signature: &[u8; 4], data: &[u8]) -> Result<ToneCurve, Error> {
+    let parse = || -> Option<ToneCurve> {
+        match data.get(0..4)? {
+            b"curv" => {
+                let count = read_u32(data, 8)? as usize;
+                match count {
+                    0 => Some(ToneCurve::Identity),
+                    1 => Some(ToneCurve::Gamma(read_u16(data, 12)? as f32 / 256.0)),
+                    _ => {
+                        let table = data.get(12..12 + count.checked_mul(2)?)?;
+                        Some(ToneCurve::Table(
+                            table
+                                .chunks_exact(2)
+                                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                                .collect(),
+                        ))
+                    }
+                }
+            }
+            b"para" => {
+                let function_type = read_u16(data, 8)?;
+                let num_params = [1, 3, 4, 5, 7].get(function_type as usize)?;
+                let params = (0..*num_params)
+                    .map(|i| read_s15fixed16(data, 12 + 4 * i))
+                    .collect::<Option<_>>()?;
+                Some(ToneCurve::Parametric {
+                    function_type,
+                    params,
+                })
+            }
+            _ => None,
+        }
+    };
+    parse().ok_or_else(|| invalid_tag(signature))
+}
+
+fn parse_cicp_tag(signature: &[u8; 4], data: &[u8]) -> Result<Cicp, Error> {
+    if data.len() < 12 || &data[0..4] != b"cicp" {
+        return Err(invalid_tag(signature));
+    }
+    Ok(Cicp {
+        color_primaries: data[8],
+        transfer_characteristics: data[9],
+        matrix_coefficients: data[10],
+        video_full_range: data[11] != 0,
+    })
+}
+
+impl IccProfile {
+    pub fn parse(data: &[u8]) -> Result<IccProfile, Error> {
+        if data.len() < HEADER_SIZE + 4 || &data[36..40] != b"acsp" {
+            return Err(Error::InvalidIccProfile);
+        }
+        let size = read_u32(data, 0).unwrap();
+        if size as usize != data.len() {
+            return Err(Error::InvalidIccProfile);
+        }
+        let header = IccHeader {
+            size,
+            cmm_type: read_tag(data, 4).unwrap(),
+            version: (data[8], data[9] >> 4, data[9] & 15),
+            device_class: read_tag(data, 12).unwrap(),
+            color_space: read_tag(data, 16).unwrap(),
+            pcs: read_tag(data, 20).unwrap(),
+            rendering_intent: read_u32(data, 64).unwrap(),
+            illuminant: read_xyz(data, 68).unwrap(),
+        };
+
+        let num_tags = read_u32(data, HEADER_SIZE).unwrap() as usize;
+        if num_tags > (data.len() - HEADER_SIZE - 4) / 12 {
+            return Err(Error::InvalidIccProfile);
+        }
+        let tags = (0..num_tags)
+            .map(|i| {
+                let pos = HEADER_SIZE + 4 + 12 * i;
+                let tag = IccTag {
+                    signature: read_tag(data, pos).unwrap(),
+                    offset: read_u32(data, pos + 4).unwrap(),
+                    size: read_u32(data, pos + 8).unwrap(),
+                };
+                if tag.offset as u64 + tag.size as u64 > data.len() as u64 {
+                    Err(invalid_tag(&tag.signature))
+                } else {
+                    Ok(tag)
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut profile = IccProfile {
+            header,
+            tags,
+            white_point: None,
+            colorants: None,
+            rgb_trc: None,
+            gray_trc: None,
+            cicp: None,
+            data: data.to_vec(),
+        };
+        let xyz = |sig| {
+            profile
+                .tag_data(sig)
+                .map(|d| parse_xyz_tag(sig, d))
+                .transpose()
+        };
+        let white_point = xyz(b"wtpt")?;
+        let colorants = match (xyz(b"rXYZ")?, xyz(b"gXYZ")?, xyz(b"bXYZ")?) {
+            (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+            _ => None,
+        };
+        let curve = |sig| {
+            profile
+                .tag_data(sig)
+                .map(|d| parse_curve_tag(sig, d))
+                .transpose()
+        };
+        let rgb_trc = match (curve(b"rTRC")?, curve(b"gTRC")?, curve(b"bTRC")?) {
+            (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+            _ => None,
+        };
+        let gray_trc = curve(b"kTRC")?;
+        let cicp = profile
+            .tag_data(b"cicp")
+            .map(|d| parse_cicp_tag(b"cicp", d))
+            .transpose()?;
+
+        profile.white_point = white_point;
+        profile.colorants = colorants;
+        profile.rgb_trc = rgb_trc;
+        profile.gray_trc = gray_trc;
+        profile.cicp = cicp;
+        Ok(profile)
+    }
+
+    /// Returns the raw contents of the first tag with the given signature.
+    pub fn tag_data(&self, signature: &[u8; 4]) -> Option<&[u8]> {
+        let tag = self.tags.iter().find(|t| &t.signature == signature)?;
+        let start = tag.offset as usize;
+        Some(&self.data[start..start + tag.size as usize])
+    }
+
+    /// The profile, as it was parsed.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    fn s15fixed16(x: f32) -> [u8; 4] {
+        ((x * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    /// Builds a minimal RGB display profile with the given tags.
+    pub(crate) fn build_profile(tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[8] = 4;
+        header[9] = 0x30;
+        header[12..16].copy_from_slice(b"mntr");
+        header[16..20].copy_from_slice(b"RGB ");
+        header[20..24].copy_from_slice(b"XYZ ");
+        header[36..40].copy_from_slice(b"acsp");
+        for (i, v) in [0.9642, 1.0, 0.8249].iter().enumerate() {
+            header[68 + 4 * i..72 + 4 * i].copy_from_slice(&s15fixed16(*v));
+        }
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut contents = vec![];
+        let mut offset = HEADER_SIZE + 4 + 12 * tags.len();
+        for (signature, data) in tags {
+            table.extend_from_slice(*signature);
+            table.extend_from_slice(&(offset as u32).to_be_bytes());
+            table.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            contents.extend_from_slice(data);
+            offset += data.len();
+            while !offset.is_multiple_of(4) {
+                contents.push(0);
+                offset += 1;
+            }
+        }
+        let mut profile = header;
+        profile.extend(table);
+        profile.extend(contents);
+        let size = profile.len() as u32;
+        profile[0..4].copy_from_slice(&size.to_be_bytes());
+        profile
+    }
+
+    pub(crate) fn xyz_tag(xyz: [f32; 3]) -> Vec<u8> {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for v in xyz {
+            tag.extend_from_slice(&s15fixed16(v));
+        }
+        tag
+    }
+
+    pub(crate) fn para_tag(params: &[f32]) -> Vec<u8> {
+        let function_type: u16 = match params.len() {
+            1 => 0,
+            3 => 1,
+            4 => 2,
+            5 => 3,
+            _ => 4,
+        };
+        let mut tag = b"para\0\0\0\0".to_vec();
+        tag.extend_from_slice(&function_type.to_be_bytes());
+        tag.extend_from_slice(&[0, 0]);
+        for v in params {
+            tag.extend_from_slice(&s15fixed16(*v));
+        }
+        tag
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-4, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut gamma = b"curv\0\0\0\0\0\0\0\x01".to_vec();
+        gamma.extend_from_slice(&[0x02, 0x33]);
+        let data = build_profile(&[
+            (b"wtpt", xyz_tag([0.9642, 1.0, 0.8249])),
+            (b"rXYZ", xyz_tag([0.4361, 0.2225, 0.0139])),
+            (b"gXYZ", xyz_tag([0.3851, 0.7169, 0.0971])),
+            (b"bXYZ", xyz_tag([0.1431, 0.0606, 0.7141])),
+            (b"rTRC", gamma.clone()),
+            (b"gTRC", gamma.clone()),
+            (
+                b"bTRC",
+                para_tag(&[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045]),
+            ),
+            (b"cicp", b"cicp\0\0\0\0\x01\x0d\x00\x01".to_vec()),
+        ]);
+        let profile = IccProfile::parse(&data).unwrap();
+        assert_eq!(profile.header.version, (4, 3, 0));
+        assert_eq!(&profile.header.color_space, b"RGB ");
+        assert_close(profile.header.illuminant, [0.9642, 1.0, 0.8249]);
+        assert_close(profile.white_point.unwrap(), [0.9642, 1.0, 0.8249]);
+        assert_close(profile.colorants.unwrap()[1], [0.3851, 0.7169, 0.0971]);
+        let trc = profile.rgb_trc.unwrap();
+        assert_eq!(trc[0], ToneCurve::Gamma(563.0 / 256.0));
+        match &trc[2] {
+            ToneCurve::Parametric {
+                function_type,
+                params,
+            } => {
+                assert_eq!(*function_type, 3);
+                assert!((params[0] - 2.4).abs() < 1e-4);
+            }
+            other => panic!("unexpected curve {:?}", other),
+        }
+        assert_eq!(profile.gray_trc, None);
+        assert_eq!(
+            profile.cicp,
+            Some(Cicp {
+                color_primaries: 1,
+                transfer_characteristics: 13,
+                matrix_coefficients: 0,
+                video_full_range: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut data = build_profile(&[(b"wtpt", xyz_tag([0.9642, 1.0, 0.8249]))]);
+        assert!(IccProfile::parse(&data[..data.len() - 1]).is_err());
+        // Tag pointing outside of the profile.
+        data[HEADER_SIZE + 8] = 0xFF;
+        assert!(IccProfile::parse(&data).is_err());
+        // Wrong tag type.
+        let data = build_profile(&[(b"wtpt", para_tag(&[2.2]))]);
+        assert!(IccProfile::parse(&data).is_err());
+    }
+}
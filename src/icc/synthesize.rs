@@ -0,0 +1,395 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::error::Error;
+use crate::headers::color_encoding::{
+    ColorEncoding, ColorSpace, CustomXY, Primaries, TransferFunction, WhitePoint,
+};
+
+const HEADER_SIZE: usize = 128;
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+const TABLE_SIZE: usize = 1024;
+
+fn custom_xy(xy: &CustomXY) -> [f64; 2] {
+    [xy.x as f64 * 1e-6, xy.y as f64 * 1e-6]
+}
+
+fn white_point(encoding: &ColorEncoding) -> [f64; 2] {
+    match encoding.white_point {
+        WhitePoint::D65 => [0.3127, 0.3290],
+        WhitePoint::E => [1.0 / 3.0, 1.0 / 3.0],
+        WhitePoint::DCI => [0.314, 0.351],
+        WhitePoint::Custom => custom_xy(&encoding.white),
+    }
+}
+
+fn primaries(encoding: &ColorEncoding) -> [[f64; 2]; 3] {
+    match encoding.primaries {
+        Primaries::SRGB => [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06]],
+        Primaries::BT2100 => [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046]],
+        Primaries::P3 => [[0.680, 0.320], [0.265, 0.690], [0.150, 0.060]],
+        Primaries::Custom => {
+            let [r, g, b] = &encoding.custom_primaries;
+            [custom_xy(r), custom_xy(g), custom_xy(b)]
+        }
+    }
+}
+
+fn xy_to_xyz([x, y]: [f64; 2]) -> Result<[f64; 3], Error> {
+    if y <= 0.0 || x < 0.0 || x + y > 1.0 {
+        return Err(Error::InvalidColorEncoding);
+    }
+    Ok([x / y, 1.0, (1.0 - x - y) / y])
+}
+
+type Matrix = [[f64; 3]; 3];
+
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn mul_vec(a: &Matrix, v: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|i| (0..3).map(|k| a[i][k] * v[k]).sum())
+}
+
+fn invert(m: &Matrix) -> Result<Matrix, Error> {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1, c0, c1) = ((r + 1) % 3, (r + 2) % 3, (c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det: f64 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+    if det.abs() < 1e-12 {
+        return Err(Error::InvalidColorEncoding);
+    }
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = cofactor(j, i) / det;
+        }
+    }
+    Ok(out)
+}
+
+/// Bradford chromatic adaptation from `white` to D50.
+fn adaptation_to_d50(white: [f64; 3]) -> Result<Matrix, Error> {
+    const BRADFORD: Matrix = [
+        [0.8951, 0.2664, -0.1614],
+        [-0.7502, 1.7135, 0.0367],
+        [0.0389, -0.0685, 1.0296],
+    ];
+    let from = mul_vec(&BRADFORD, white);
+    let to = mul_vec(&BRADFORD, D50);
+    let mut scale = [[0.0; 3]; 3];
+    for i in 0..3 {
+        scale[i][i] = to[i] / from[i];
+    }
+    Ok(mul(&invert(&BRADFORD)?, &mul(&scale, &BRADFORD)))
+}
+
+/// Returns the D50-adapted XYZ of the red, green and blue primaries.
+fn colorants(
+    primaries: [[f64; 2]; 3],
+    white: [f64; 3],
+    adaptation: &Matrix,
+) -> Result<Matrix, Error> {
+    let xyz = [
+        xy_to_xyz(primaries[0])?,
+        xy_to_xyz(primaries[1])?,
+        xy_to_xyz(primaries[2])?,
+    ];
+    // Columns are the primaries; scale them so that (1, 1, 1) maps to white.
+    let m = [0, 1, 2].map(|i| [xyz[0][i], xyz[1][i], xyz[2][i]]);
+    let scale = mul_vec(&invert(&m)?, white);
+    Ok([0, 1, 2].map(|c| mul_vec(adaptation, xyz[c].map(|v| v * scale[c]))))
+}
+
+fn pq_to_linear(x: f64) -> f64 {
+    const M1: f64 = 2610.0 / 16384.0;
+    const M2: f64 = 2523.0 / 4096.0 * 128.0;
+    const C1: f64 = 3424.0 / 4096.0;
+    const C2: f64 = 2413.0 / 4096.0 * 32.0;
+    const C3: f64 = 2392.0 / 4096.0 * 32.0;
+    let e = x.powf(1.0 / M2);
+    ((e - C1).max(0.0) / (C2 - C3 * e)).powf(1.0 / M1)
+}
+
+fn hlg_to_linear(x: f64) -> f64 {
+    const A: f64 = 0.17883277;
+    const B: f64 = 0.28466892;
+    const C: f64 = 0.55991073;
+    if x <= 0.5 {
+        x * x / 3.0
+    } else {
+        (((x - C) / A).exp() + B) / 12.0
+    }
+}
+
+fn s15fixed16(v: f64) -> [u8; 4] {
+    ((v * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    for v in xyz {
+        tag.extend_from_slice(&s15fixed16(v));
+    }
+    tag
+}
+
+fn para_tag(params: &[f64]) -> Vec<u8> {
+    let function_type: u16 = match params.len() {
+        1 => 0,
+        _ => 3,
+    };
+    let mut tag = b"para\0\0\0\0".to_vec();
+    tag.extend_from_slice(&function_type.to_be_bytes());
+    tag.extend_from_slice(&[0, 0]);
+    for v in params {
+        tag.extend_from_slice(&s15fixed16(*v));
+    }
+    tag
+}
+
+fn table_tag(f: impl Fn(f64) -> f64) -> Vec<u8> {
+    let mut tag = b"curv\0\0\0\0".to_vec();
+    tag.extend_from_slice(&(TABLE_SIZE as u32).to_be_bytes());
+    for i in 0..TABLE_SIZE {
+        let v = f(i as f64 / (TABLE_SIZE - 1) as f64).clamp(0.0, 1.0);
+        tag.extend_from_slice(&((v * 65535.0).round() as u16).to_be_bytes());
+    }
+    tag
+}
+
+fn mluc_tag(text: &str) -> Vec<u8> {
+    let mut tag = b"mluc\0\0\0\0".to_vec();
+    let utf16: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+    tag.extend_from_slice(&1u32.to_be_bytes());
+    tag.extend_from_slice(&12u32.to_be_bytes());
+    tag.extend_from_slice(b"enUS");
+    tag.extend_from_slice(&(utf16.len() as u32).to_be_bytes());
+    tag.extend_from_slice(&28u32.to_be_bytes());
+    tag.extend_from_slice(&utf16);
+    tag
+}
+
+fn tone_curve(encoding: &ColorEncoding) -> Result<Vec<u8>, Error> {
+    Ok(match encoding.tf.transfer_function() {
+        None => para_tag(&[1.0 / encoding.tf.gamma() as f64]),
+        Some(TransferFunction::Linear) => para_tag(&[1.0]),
+        Some(TransferFunction::SRGB) => {
+            para_tag(&[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045])
+        }
+        Some(TransferFunction::BT709) => {
+            para_tag(&[1.0 / 0.45, 1.0 / 1.099, 0.099 / 1.099, 1.0 / 4.5, 0.081])
+        }
+        Some(TransferFunction::DCI) => para_tag(&[2.6]),
+        Some(TransferFunction::PQ) => table_tag(pq_to_linear),
+        Some(TransferFunction::HLG) => table_tag(hlg_to_linear),
+        // An unknown transfer function is only described by an ICC profile.
+        Some(TransferFunction::Unknown) => return Err(Error::UnsupportedColorEncoding),
+    })
+}
+
+// Code points of the encoding, if it can be described by them exactly.
+fn cicp(encoding: &ColorEncoding) -> Option<[u8; 4]> {
+    let transfer = encoding.tf.transfer_function()? as u8;
+    let primaries = match (
+        encoding.color_space,
+        encoding.primaries,
+        encoding.white_point,
+    ) {
+        (ColorSpace::Gray, _, WhitePoint::D65) => 1,
+        (ColorSpace::RGB, Primaries::SRGB, WhitePoint::D65) => 1,
+        (ColorSpace::RGB, Primaries::BT2100, WhitePoint::D65) => 9,
+        (ColorSpace::RGB, Primaries::P3, WhitePoint::DCI) => 11,
+        (ColorSpace::RGB, Primaries::P3, WhitePoint::D65) => 12,
+        _ => return None,
+    };
+    Some([primaries, transfer, 0, 1])
+}
+
+fn description(encoding: &ColorEncoding) -> String {
+    let tf = match encoding.tf.transfer_function() {
+        Some(tf) => format!("{:?}", tf),
+        None => format!("g{:.7}", encoding.tf.gamma()),
+    };
+    let primaries = match encoding.color_space {
+        ColorSpace::Gray => String::new(),
+        _ => format!("{:?}_", encoding.primaries),
+    };
+    format!(
+        "{:?}_{:?}_{}{:?}_{}",
+        encoding.color_space, encoding.white_point, primaries, encoding.rendering_intent, tf
+    )
+}
+
+/// Builds an ICC profile from the enum-based description of a color encoding,
+/// for images that do not embed one. Fails for XYB, which needs a LUT-based
+/// profile, for unknown transfer functions, and if `want_icc` is set.
+pub fn synthesize_icc(encoding: &ColorEncoding) -> Result<Vec<u8>, Error> {
+    let gray = match encoding.color_space {
+        _ if encoding.want_icc => return Err(Error::UnsupportedColorEncoding),
+        ColorSpace::RGB => false,
+        ColorSpace::Gray => true,
+        ColorSpace::XYB => return Err(Error::UnsupportedColorEncoding),
+        ColorSpace::Unknown => return Err(Error::InvalidColorEncoding),
+    };
+    let white = xy_to_xyz(white_point(encoding))?;
+    let adaptation = adaptation_to_d50(white)?;
+
+    let curve = tone_curve(encoding)?;
+    let mut tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (b"desc", mluc_tag(&description(encoding))),
+        (b"cprt", mluc_tag("CC0")),
+        (b"wtpt", xyz_tag(D50)),
+        (b"chad", {
+            let mut tag = b"sf32\0\0\0\0".to_vec();
+            for v in adaptation.iter().flatten() {
+                tag.extend_from_slice(&s15fixed16(*v));
+            }
+            tag
+        }),
+    ];
+    if gray {
+        tags.push((b"kTRC", curve));
+    } else {
+        let [r, g, b] = colorants(primaries(encoding), white, &adaptation)?;
+        tags.push((b"rXYZ", xyz_tag(r)));
+        tags.push((b"gXYZ", xyz_tag(g)));
+        tags.push((b"bXYZ", xyz_tag(b)));
+        // The three curves share their data.
+        tags.push((b"rTRC", curve));
+        tags.push((b"gTRC", vec![]));
+        tags.push((b"bTRC", vec![]));
+    }
+    if let Some(code_points) = cicp(encoding) {
+        let mut tag = b"cicp\0\0\0\0".to_vec();
+        tag.extend_from_slice(&code_points);
+        tags.push((b"cicp", tag));
+    }
+
+    let mut profile = vec![0u8; HEADER_SIZE];
+    profile[4..8].copy_from_slice(b"jxl ");
+    profile[8] = 4;
+    profile[9] = 0x30;
+    profile[12..16].copy_from_slice(b"mntr");
+    profile[16..20].copy_from_slice(if gray { b"GRAY" } else { b"RGB " });
+    profile[20..24].copy_from_slice(b"XYZ ");
+    for (i, v) in [2019u16, 12, 1].iter().enumerate() {
+        profile[24 + 2 * i..26 + 2 * i].copy_from_slice(&v.to_be_bytes());
+    }
+    profile[36..40].copy_from_slice(b"acsp");
+    profile[40..44].copy_from_slice(b"APPL");
+    profile[64..68].copy_from_slice(&(encoding.rendering_intent as u32).to_be_bytes());
+    profile[68..80].copy_from_slice(&xyz_tag(D50)[8..]);
+    profile[80..84].copy_from_slice(b"jxl ");
+
+    profile.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+    let mut contents = vec![];
+    let mut offset = HEADER_SIZE + 4 + 12 * tags.len();
+    let mut last = (0, 0);
+    for (signature, data) in tags.iter() {
+        if !data.is_empty() {
+            last = (offset, data.len());
+            contents.extend_from_slice(data);
+            offset += data.len();
+            while !offset.is_multiple_of(4) {
+                contents.push(0);
+                offset += 1;
+            }
+        }
+        profile.extend_from_slice(*signature);
+        profile.extend_from_slice(&(last.0 as u32).to_be_bytes());
+        profile.extend_from_slice(&(last.1 as u32).to_be_bytes());
+    }
+    profile.extend(contents);
+    let size = profile.len() as u32;
+    profile[0..4].copy_from_slice(&size.to_be_bytes());
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::headers::color_encoding::RenderingIntent;
+    use crate::icc::known::KnownProfile;
+    use crate::icc::profile::{Cicp, IccProfile, ToneCurve};
+
+    fn parse(encoding: &ColorEncoding) -> IccProfile {
+        IccProfile::parse(&synthesize_icc(encoding).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_known_profiles() {
+        let srgb = parse(&ColorEncoding::default());
+        assert_eq!(srgb.recognize(), Some(KnownProfile::SRGB));
+        assert_eq!(
+            srgb.cicp,
+            Some(Cicp {
+                color_primaries: 1,
+                transfer_characteristics: 13,
+                matrix_coefficients: 0,
+                video_full_range: true,
+            })
+        );
+        assert_eq!(
+            srgb.header.rendering_intent,
+            RenderingIntent::Relative as u32
+        );
+
+        let mut encoding = ColorEncoding::default();
+        encoding.primaries = Primaries::P3;
+        let p3 = parse(&encoding);
+        assert_eq!(p3.recognize(), Some(KnownProfile::DisplayP3));
+        assert_eq!(p3.cicp.unwrap().color_primaries, 12);
+    }
+
+    #[test]
+    fn test_transfer_functions() {
+        let mut encoding = ColorEncoding::default();
+        encoding.color_space = ColorSpace::Gray;
+        encoding.tf.have_gamma = true;
+        encoding.tf.gamma = 4545455;
+        let gray = parse(&encoding);
+        assert_eq!(gray.recognize(), Some(KnownProfile::Gray22));
+        assert_eq!(gray.cicp, None);
+
+        encoding.color_space = ColorSpace::RGB;
+        encoding.tf.have_gamma = false;
+        encoding.tf.transfer_function = TransferFunction::PQ;
+        let pq = parse(&encoding);
+        match &pq.rgb_trc.unwrap()[2] {
+            ToneCurve::Table(table) => {
+                assert_eq!(table.len(), TABLE_SIZE);
+                assert_eq!(table[0], 0);
+                assert_eq!(table[TABLE_SIZE - 1], 65535);
+            }
+            other => panic!("unexpected curve {:?}", other),
+        }
+        assert_eq!(pq.cicp.unwrap().transfer_characteristics, 16);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut encoding = ColorEncoding::default();
+        encoding.color_space = ColorSpace::XYB;
+        assert!(synthesize_icc(&encoding).is_err());
+        let mut encoding = ColorEncoding::default();
+        encoding.want_icc = true;
+        assert!(synthesize_icc(&encoding).is_err());
+        let mut encoding = ColorEncoding::default();
+        encoding.tf.transfer_function = TransferFunction::Unknown;
+        assert!(matches!(
+            synthesize_icc(&encoding),
+            Err(Error::UnsupportedColorEncoding)
+        ));
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::error::Error;
+use crate::headers::Orientation;
+use crate::util::safe_arith::SafeArith;
+
+// Side of the square blocks that transposes copy at a time, so that both the
+// rows that are read and the ones that are written stay in cache.
+const BLOCK_SIZE: usize = 32;
+
+/// A plane of samples, stored row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image<T> {
+    width: usize,
+    height: usize,
+    data: Vec<T>,
+}
+
+impl<T: Copy + Default> Image<T> {
+    /// Creates an image filled with `T::default()`.
+    pub fn new(width: usize, height: usize) -> Result<Image<T>, Error> {
+        Ok(Image {
+            width,
+            height,
+            data: vec![T::default(); width.safe_mul(height)?],
+        })
+    }
+
+    /// Creates an image from samples in raster order, of which there must be
+    /// exactly `width * height`.
+    pub fn from_vec(width: usize, height: usize, data: Vec<T>) -> Result<Image<T>, Error> {
+        if Some(data.len()) != width.checked_mul(height) {
+            return Err(Error::ImageDataSizeMismatch(data.len(), width, height));
+        }
+        Ok(Image {
+            width,
+            height,
+            data,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn row(&self, y: usize) -> &[T] {
+        &self.data[y * self.width..(y + 1) * self.width]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [T] {
+        &mut self.data[y * self.width..(y + 1) * self.width]
+    }
+
+    /// All samples, in raster order.
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        self.data
+    }
+
+    /// Mirrors the image left to right.
+    pub fn flip_horizontal(&mut self) {
+        if self.width == 0 {
+            return;
+        }
+        self.data
+            .chunks_exact_mut(self.width)
+            .for_each(|row| row.reverse());
+    }
+
+    /// Mirrors the image top to bottom.
+    pub fn flip_vertical(&mut self) {
+        let height = self.height;
+        for y in 0..height / 2 {
+            let (top, bottom) = self.data.split_at_mut((height - 1 - y) * self.width);
+            top[y * self.width..(y + 1) * self.width].swap_with_slice(&mut bottom[..self.width]);
+        }
+    }
+
+    /// Rotates the image by 180 degrees.
+    pub fn rotate_180(&mut self) {
+        self.data.reverse();
+    }
+
+    /// Returns the image mirrored along its main diagonal, so that rows become
+    /// columns. The copy goes block by block to stay cache friendly on large
+    /// images.
+    pub fn transpose(&self) -> Image<T> {
+        let mut out = Image {
+            width: self.height,
+            height: self.width,
+            data: vec![T::default(); self.data.len()],
+        };
+        for y0 in (0..self.height).step_by(BLOCK_SIZE) {
+            for x0 in (0..self.width).step_by(BLOCK_SIZE) {
+                let x1 = (x0 + BLOCK_SIZE).min(self.width);
+                for y in y0..(y0 + BLOCK_SIZE).min(self.height) {
+                    for (x, &sample) in (x0..x1).zip(&self.row(y)[x0..x1]) {
+                        out.data[x * out.width + y] = sample;
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Transposes the image in place. Square images are transposed without
+    /// allocating; other shapes change the length of the rows, which moves
+    /// samples along long permutation cycles, so they are transposed through a
+    /// copy instead.
+    pub fn transpose_in_place(&mut self) {
+        if self.width != self.height {
+            *self = self.transpose();
+            return;
+        }
+        let size = self.width;
+        for y0 in (0..size).step_by(BLOCK_SIZE) {
+            // Blocks on and above the diagonal, each swapped with its mirror.
+            for x0 in (y0..size).step_by(BLOCK_SIZE) {
+                for y in y0..(y0 + BLOCK_SIZE).min(size) {
+                    for x in x0.max(y + 1)..(x0 + BLOCK_SIZE).min(size) {
+                        self.data.swap(y * size + x, x * size + y);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rotates the image by 90 degrees clockwise in place, without allocating
+    /// if it is square; see [`Image::transpose_in_place`].
+    pub fn rotate_90_in_place(&mut self) {
+        self.transpose_in_place();
+        self.flip_horizontal();
+    }
+
+    /// Rotates the image by 90 degrees counterclockwise in place, without
+    /// allocating if it is square; see [`Image::transpose_in_place`].
+    pub fn rotate_270_in_place(&mut self) {
+        self.transpose_in_place();
+        self.flip_vertical();
+    }
+
+    /// Returns the image rotated by 90 degrees clockwise.
+    pub fn rotate_90(&self) -> Image<T> {
+        let mut out = self.transpose();
+        out.flip_horizontal();
+        out
+    }
+
+    /// Returns the image rotated by 90 degrees counterclockwise.
+    pub fn rotate_270(&self) -> Image<T> {
+        let mut out = self.transpose();
+        out.flip_vertical();
+        out
+    }
+
+    /// Returns the image as it is meant to be displayed, given the orientation
+    /// it was coded with; see [`Orientation::display_position`].
+    pub fn oriented(&self, orientation: Orientation) -> Image<T> {
+        let mut out = match orientation.is_transposing() {
+            true => self.transpose(),
+            false => self.clone(),
+        };
+        match orientation {
+            Orientation::Identity | Orientation::Transpose => {}
+            Orientation::FlipHorizontal | Orientation::Rotate90 => out.flip_horizontal(),
+            Orientation::FlipVertical | Orientation::Rotate270 => out.flip_vertical(),
+            Orientation::Rotate180 | Orientation::AntiTranspose => out.rotate_180(),
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use num_traits::FromPrimitive;
+
+    // An image with each sample set to its raster index.
+    fn numbered(width: usize, height: usize) -> Image<u32> {
+        Image::from_vec(width, height, (0..(width * height) as u32).collect()).unwrap()
+    }
+
+    #[test]
+    fn test_transforms() {
+        let image = numbered(3, 2);
+        assert_eq!(image.transpose().data(), [0, 3, 1, 4, 2, 5]);
+        assert_eq!(image.rotate_90().data(), [3, 0, 4, 1, 5, 2]);
+        assert_eq!(image.rotate_270().data(), [2, 5, 1, 4, 0, 3]);
+        let mut flipped = image.clone();
+        flipped.flip_horizontal();
+        assert_eq!(flipped.data(), [2, 1, 0, 5, 4, 3]);
+        flipped.flip_vertical();
+        assert_eq!(flipped.data(), [5, 4, 3, 2, 1, 0]);
+        flipped.rotate_180();
+        assert_eq!(flipped, image);
+        assert!(matches!(
+            Image::from_vec(2, 2, vec![0u8; 3]),
+            Err(Error::ImageDataSizeMismatch(3, 2, 2))
+        ));
+    }
+
+    #[test]
+    fn test_in_place() {
+        // Square images larger than a block, and a non-square one.
+        for (width, height) in [(70, 70), (33, 33), (5, 3)] {
+            let image = numbered(width, height);
+            let mut transposed = image.clone();
+            transposed.transpose_in_place();
+            assert_eq!(transposed, image.transpose());
+            let mut rotated = image.clone();
+            rotated.rotate_90_in_place();
+            assert_eq!(rotated, image.rotate_90());
+            rotated = image.clone();
+            rotated.rotate_270_in_place();
+            assert_eq!(rotated, image.rotate_270());
+        }
+    }
+
+    #[test]
+    fn test_oriented() {
+        // Larger than a block in both directions.
+        let (width, height) = (70, 45);
+        let image = numbered(width, height);
+        for value in 1..=8 {
+            let orientation = Orientation::from_u32(value).unwrap();
+            let oriented = image.oriented(orientation);
+            let (xsize, ysize) = orientation.display_size(width as u32, height as u32);
+            assert_eq!(
+                (oriented.width(), oriented.height()),
+                (xsize as usize, ysize as usize)
+            );
+            for y in 0..height {
+                for x in 0..width {
+                    let (dx, dy) = orientation.display_position(
+                        x as u32,
+                        y as u32,
+                        width as u32,
+                        height as u32,
+                    );
+                    assert_eq!(
+                        oriented.row(dy as usize)[dx as usize],
+                        image.row(y)[x],
+                        "{:?}",
+                        orientation
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut image = Image::<u8>::new(0, 5).unwrap();
+        image.flip_horizontal();
+        image.flip_vertical();
+        assert_eq!(
+            (image.transpose().width(), image.transpose().height()),
+            (5, 0)
+        );
+        assert!(Image::<u8>::new(usize::MAX, 2).is_err());
+    }
+}
@@ -4,9 +4,18 @@
 // license that can be found in the LICENSE file.
 
 pub mod bit_reader;
+pub mod bit_writer;
 pub mod bmff;
+pub mod decode;
 pub mod entropy_coding;
 pub mod error;
+pub mod exif;
 pub mod headers;
 pub mod icc;
+pub mod image;
+pub mod modular;
+#[cfg(test)]
+pub(crate) mod test_util;
+#[cfg(feature = "trace")]
+pub mod trace;
 mod util;
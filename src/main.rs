@@ -3,69 +3,315 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
-use jxl::bit_reader::BitReader;
 use jxl::bmff::JxlCodestream;
-use jxl::headers::{
-    encodings::UnconditionalCoder,
-    frame_header::{FrameHeader, FrameHeaderNonserialized},
-    FileHeaders,
-};
-use jxl::icc::read_icc;
+use jxl::decode::options::DecoderOptions;
+use jxl::decode::{decode_metadata, entropy_stats, ImageStructure};
+use jxl::error::Error;
 use std::env;
 use std::fs;
+use std::ops::Range;
+use std::process;
+use std::time::{Duration, Instant};
 
-use jxl::headers::JxlHeader;
-
-fn parse_jxl_codestream(data: &[u8]) -> Result<(), jxl::error::Error> {
-    let mut br = BitReader::new(data);
-    let fh = FileHeaders::read(&mut br)?;
-    println!("Image size: {} x {}", fh.size.xsize(), fh.size.ysize());
-    let _icc = if fh.image_metadata.color_encoding.want_icc {
-        let r = read_icc(&mut br)?;
-        println!("ICC: {} {:?}", r.len(), r);
-        Some(r)
-    } else {
-        None
-    };
+const USAGE: &str = "Usage: jxl [OPTIONS] FILE
+
+Prints the structure of a JPEG XL file.
 
-    let have_timecode = match fh.image_metadata.animation {
-        Some(ref a) => a.have_timecodes,
-        None => false,
+Options:
+  --icc-out FILE   Write the ICC profile to FILE, synthesizing it if needed
+  --frame N[..M]   Only print frame N, or frames N to M-1, and the frames
+                   they depend on
+  --downsample N   Count the sections needed at 1/N resolution (1, 2, 4 or 8)
+  --mmap           Map the file into memory instead of reading it
+  --json           Print the image structure, or only the selected frames, as JSON
+  --bench N        Decode the file N times and print the average timings
+  --entropy-stats  Print statistics about the entropy-coded ICC profile and TOCs
+  -v, --verbose    Print the full frame headers and the ICC profile bytes";
+
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    input: String,
+    icc_out: Option<String>,
+    frames: Option<Range<usize>>,
+    downsample: u32,
+    mmap: bool,
+    json: bool,
+    bench: Option<u32>,
+    entropy_stats: bool,
+    verbose: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        downsample: 1,
+        ..Args::default()
     };
-    let _frame_header = FrameHeader::read_unconditional(
-        &(),
-        &mut br,
-        &FrameHeaderNonserialized {
-            xyb_encoded: fh.image_metadata.xyb_encoded,
-            num_extra_channels: fh.image_metadata.extra_channel_info.len() as u32,
-            extra_channel_info: fh.image_metadata.extra_channel_info,
-            have_animation: fh.image_metadata.animation.is_some(),
-            have_timecode,
-            img_width: fh.size.xsize(),
-            img_height: fh.size.ysize(),
-        },
-    )?;
+    let mut input = None;
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or(format!("{} needs a value", flag));
+        match arg.as_str() {
+            "--icc-out" => parsed.icc_out = Some(value(&arg)?),
+            "--frame" => {
+                let frames = value(&arg)?;
+                parsed.frames = match parse_frames(&frames) {
+                    Some(range) if !range.is_empty() => Some(range),
+                    _ => return Err(format!("Invalid frame {}", frames)),
+                };
+            }
+            "--bench" => {
+                let iterations = value(&arg)?;
+                parsed.bench = match iterations.parse() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => return Err(format!("Invalid number of iterations {}", iterations)),
+                };
+            }
+            "--downsample" => {
+                let downsample = value(&arg)?;
+                parsed.downsample = match downsample.parse() {
+                    Ok(d @ (1 | 2 | 4 | 8)) => d,
+                    _ => return Err(format!("Invalid downsampling factor {}", downsample)),
+                };
+            }
+            "--mmap" if !cfg!(feature = "mmap") => {
+                return Err("--mmap requires the mmap feature".to_string())
+            }
+            "--mmap" => parsed.mmap = true,
+            "--json" if !cfg!(feature = "json") => {
+                return Err("--json requires the json feature".to_string())
+            }
+            "--json" => parsed.json = true,
+            "--entropy-stats" => parsed.entropy_stats = true,
+            "-v" | "--verbose" => parsed.verbose = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ if input.is_some() => return Err(format!("Unexpected argument {}", arg)),
+            _ => input = Some(arg),
+        }
+    }
+    parsed.input = input.ok_or("Missing input file")?;
+    Ok(parsed)
+}
 
+fn parse_frames(frames: &str) -> Option<Range<usize>> {
+    match frames.split_once("..") {
+        Some((start, end)) => Some(start.parse().ok()?..end.parse().ok()?),
+        None => {
+            let frame: usize = frames.parse().ok()?;
+            Some(frame..frame.checked_add(1)?)
+        }
+    }
+}
+
+fn read_structure(args: &Args) -> Result<ImageStructure, Error> {
+    if args.mmap {
+        #[cfg(feature = "mmap")]
+        return jxl::decode::decode_metadata_mmap(&args.input);
+        #[cfg(not(feature = "mmap"))]
+        unreachable!("--mmap is rejected without the mmap feature");
+    }
+    decode_metadata(&fs::read(&args.input)?)
+}
+
+#[cfg(feature = "json")]
+fn print_json(structure: &ImageStructure, args: &Args) -> Result<(), Error> {
+    let json = match args.frames {
+        Some(ref frames) => serde_json::to_string_pretty(&structure.frames[frames.clone()]),
+        None => serde_json::to_string_pretty(structure),
+    };
+    println!("{}", json.map_err(std::io::Error::from)?);
     Ok(())
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    assert_eq!(args.len(), 2);
-    let file = &args[1];
-    let contents = fs::read(file).expect("Something went wrong reading the file");
-    let codestream = JxlCodestream::new(contents);
-    let codestream = if let Ok(cs) = codestream {
-        cs
-    } else {
+fn print_structure(structure: &ImageStructure, args: &Args) -> Result<(), Error> {
+    if args.json {
+        #[cfg(feature = "json")]
+        return print_json(structure, args);
+        #[cfg(not(feature = "json"))]
+        unreachable!("--json is rejected without the json feature");
+    }
+    let headers = &structure.headers;
+    println!(
+        "Image size: {} x {}",
+        headers.size.xsize(),
+        headers.size.ysize()
+    );
+    println!("Level: {}", structure.level.value());
+    if let Some(ref icc) = structure.icc {
+        if args.verbose {
+            println!("ICC: {} {:?}", icc.len(), icc);
+        } else {
+            println!("ICC: {} bytes", icc.len());
+        }
+    }
+    if let Some(ref gain_map) = structure.gain_map {
+        println!(
+            "Gain map: {} bytes of metadata, {} byte codestream",
+            gain_map.metadata.len(),
+            gain_map.gain_map.len()
+        );
+    }
+    if let Some(ref a) = headers.image_metadata.animation {
+        println!(
+            "Animation: {} ticks/s, loop count: {:?}",
+            a.ticks_per_second(),
+            a.loop_count()
+        );
+    }
+
+    let options = DecoderOptions::new().downsampling(args.downsample);
+    for (i, frame) in structure.frames.iter().enumerate() {
+        if args.frames.as_ref().is_some_and(|f| !f.contains(&i)) {
+            continue;
+        }
+        let needed = frame.section_ranges_for_downsampling(options.get_downsampling())?;
+        println!(
+            "Frame {}: {:?}, {} sections, {} of {} bytes needed at 1/{}",
+            i,
+            frame.header.frame_type(),
+            frame.toc.entries.len(),
+            needed.iter().map(|r| r.size as u64).sum::<u64>(),
+            frame.toc.total_size(),
+            options.get_downsampling()
+        );
+        if args.verbose {
+            println!("{:#?}", frame.header);
+        }
+    }
+    if let Some(ref frames) = args.frames {
         println!(
-            "Error parsing JXL codestream: {}",
-            codestream.err().unwrap()
+            "Frames needed to decode them: {:?}",
+            structure.frames_needed(frames.clone())
         );
-        return;
+    }
+    Ok(())
+}
+
+// Only the container and the headers and TOCs are decoded, so these are the
+// only stages that are timed.
+fn bench(args: &Args, iterations: u32) -> Result<(), Error> {
+    let data = fs::read(&args.input)?;
+    let time = |f: &mut dyn FnMut() -> Result<(), Error>| -> Result<Duration, Error> {
+        let start = Instant::now();
+        for _ in 0..iterations {
+            f()?;
+        }
+        Ok(start.elapsed() / iterations)
+    };
+    let container = time(&mut || JxlCodestream::from_slice(&data).map(drop))?;
+    let mut pixels = 0;
+    let total = time(&mut || {
+        let size = decode_metadata(&data)?.headers.size;
+        pixels = size.xsize() as u64 * size.ysize() as u64;
+        Ok(())
+    })?;
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    // Tiny files can take less than the resolution of the clock.
+    let speed = match total.as_secs_f64() {
+        0.0 => "n/a MP/s".to_string(),
+        secs => format!("{:.1} MP/s", pixels as f64 / 1e6 / secs),
+    };
+    println!("{} iterations: {:.3} ms, {}", iterations, ms(total), speed);
+    println!("  Container: {:.3} ms", ms(container));
+    println!(
+        "  Headers and TOCs: {:.3} ms",
+        ms(total.saturating_sub(container))
+    );
+    Ok(())
+}
+
+fn print_entropy_stats(args: &Args) -> Result<(), Error> {
+    for (location, stats) in entropy_stats(&fs::read(&args.input)?)? {
+        println!("Entropy stats for {}:\n{}", location, stats);
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
     };
-    let res = parse_jxl_codestream(codestream.get());
-    if let Err(err) = res {
-        println!("Error parsing JXL codestream: {}", err)
+    let structure = match read_structure(&args) {
+        Ok(structure) => structure,
+        Err(err) => {
+            println!("Error parsing JXL codestream: {}", err);
+            process::exit(1);
+        }
+    };
+    if let Some(ref frames) = args.frames {
+        if frames.end > structure.frames.len() {
+            eprintln!("The file has only {} frames", structure.frames.len());
+            process::exit(2);
+        }
+    }
+    if let Err(err) = print_structure(&structure, &args) {
+        println!("Error parsing JXL codestream: {}", err);
+        process::exit(1);
+    }
+    if args.entropy_stats {
+        if let Err(err) = print_entropy_stats(&args) {
+            println!("Error parsing JXL codestream: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Some(iterations) = args.bench {
+        if let Err(err) = bench(&args, iterations) {
+            println!("Error parsing JXL codestream: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Some(ref path) = args.icc_out {
+        let icc = match structure.color_profile() {
+            Ok(icc) => icc,
+            Err(err) => {
+                eprintln!("Failed to get the ICC profile: {}", err);
+                process::exit(1);
+            }
+        };
+        if let Err(err) = fs::write(path, icc) {
+            eprintln!("Failed to write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            parse(&["--downsample", "4", "-v", "in.jxl", "--frame", "2"]).unwrap(),
+            Args {
+                input: "in.jxl".to_string(),
+                frames: Some(2..3),
+                downsample: 4,
+                verbose: true,
+                ..Args::default()
+            }
+        );
+        assert_eq!(parse(&["in.jxl"]).unwrap().downsample, 1);
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["a.jxl", "b.jxl"]).is_err());
+        assert!(parse(&["--downsample", "3", "in.jxl"]).is_err());
+        assert_eq!(parse(&["--bench", "5", "in.jxl"]).unwrap().bench, Some(5));
+        assert!(parse(&["--bench", "0", "in.jxl"]).is_err());
+        assert_eq!(
+            parse(&["--frame", "1..4", "in.jxl"]).unwrap().frames,
+            Some(1..4)
+        );
+        assert!(parse(&["--frame", "3..3", "in.jxl"]).is_err());
+        assert!(parse(&["--frame", "1..", "in.jxl"]).is_err());
+        assert!(parse(&["in.jxl", "--icc-out"]).is_err());
+        assert!(parse(&["--threads", "in.jxl"]).is_err());
+        assert!(parse(&["--entropy-stats", "in.jxl"]).unwrap().entropy_stats);
+        assert_eq!(parse(&["--json", "in.jxl"]).is_ok(), cfg!(feature = "json"));
     }
 }
@@ -0,0 +1,462 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+extern crate jxl_headers_derive;
+
+use jxl_headers_derive::UnconditionalCoder;
+
+use crate::bit_reader::BitReader;
+use crate::decode::options::DecoderOptions;
+use crate::entropy_coding::decode::Histograms;
+use crate::error::Error;
+use crate::headers::encodings::*;
+use crate::headers::JxlHeader;
+use crate::image::Image;
+
+pub mod predict;
+pub mod transforms;
+pub mod tree;
+
+use predict::{clamped_gradient, Neighbors, WeightedHeader, WeightedPredictor};
+use transforms::{
+    inverse_palette, inverse_rct, inverse_squeeze, meta_apply, Transform, TransformId,
+};
+use tree::{unpack_signed, Leaf, Tree, NUM_NONREF_PROPERTIES, NUM_REF_PROPERTIES};
+
+/// A channel of a modular image, subsampled by `1 << hshift` horizontally and
+/// `1 << vshift` vertically. Meta channels, such as palettes, have an
+/// `hshift` of -1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Channel {
+    pub data: Image<i32>,
+    pub hshift: i32,
+    pub vshift: i32,
+}
+
+/// The size and subsampling of a channel, without its samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelShape {
+    pub width: usize,
+    pub height: usize,
+    pub hshift: i32,
+    pub vshift: i32,
+}
+
+impl Channel {
+    pub fn new(data: Image<i32>, hshift: i32, vshift: i32) -> Channel {
+        Channel {
+            data,
+            hshift,
+            vshift,
+        }
+    }
+
+    pub fn shape(&self) -> ChannelShape {
+        ChannelShape {
+            width: self.data.width(),
+            height: self.data.height(),
+            hshift: self.hshift,
+            vshift: self.vshift,
+        }
+    }
+
+    fn has_same_geometry(&self, other: &Channel) -> bool {
+        self.shape() == other.shape()
+    }
+}
+
+/// Header of a modular sub-bitstream.
+#[derive(UnconditionalCoder, Debug, Clone, PartialEq)]
+pub struct GroupHeader {
+    pub use_global_tree: bool,
+    pub wp_header: WeightedHeader,
+    #[size_coder(implicit(u2S(0, 1, Bits(4) + 2, Bits(8) + 18)))]
+    pub transforms: Vec<Transform>,
+}
+
+/// Reads a tree and the histograms of the samples it codes, as found in
+/// global modular data or at the start of a group.
+pub fn read_tree(br: &mut BitReader, max_size: usize) -> Result<(Tree, Histograms), Error> {
+    let tree = Tree::read(br, max_size)?;
+    let histograms = Histograms::decode(tree.num_contexts(), br, /*allow_lz77=*/ true)?;
+    Ok((tree, histograms))
+}
+
+/// Decodes a modular sub-bitstream into `channels`, which must already have
+/// the size of the image channels; the channels that are actually coded
+/// follow from the transforms. Groups that do not code their own tree use
+/// `global_tree`; trees read here may have at most `max_tree_size` nodes.
+/// `bit_depth` is the one of the image, which implicit palette colors are
+/// scaled to.
+pub fn decode_modular(
+    br: &mut BitReader,
+    channels: &mut [Channel],
+    group_id: u32,
+    global_tree: Option<&(Tree, Histograms)>,
+    max_tree_size: usize,
+    bit_depth: u32,
+    options: &DecoderOptions,
+) -> Result<(), Error> {
+    let mut header = GroupHeader::read(br)?;
+    let mut shapes: Vec<ChannelShape> = channels.iter().map(Channel::shape).collect();
+    let mut num_meta = 0;
+    for transform in header.transforms.iter_mut() {
+        meta_apply(transform, &mut shapes, &mut num_meta)?;
+    }
+    let mut coded = shapes
+        .iter()
+        .map(|s| {
+            Ok(Channel::new(
+                options.new_image(s.width, s.height)?,
+                s.hshift,
+                s.vshift,
+            ))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let local_tree;
+    let (tree, histograms) = if header.use_global_tree {
+        global_tree.ok_or(Error::MissingGlobalTree)?
+    } else {
+        local_tree = read_tree(br, max_tree_size)?;
+        &local_tree
+    };
+
+    let max_width = coded.iter().map(|c| c.data.width()).max().unwrap_or(0);
+    let mut reader = histograms.make_reader_with_width(br, max_width)?;
+    for index in 0..coded.len() {
+        predict_channel(
+            &mut coded,
+            index,
+            group_id,
+            &header.wp_header,
+            tree,
+            |leaf, prediction| {
+                let residual = unpack_signed(reader.read(br, leaf.context)?) as i64;
+                Ok(residual
+                    .wrapping_mul(leaf.multiplier as i64)
+                    .wrapping_add(prediction) as i32)
+            },
+        )?;
+    }
+    reader.check_final_state()?;
+
+    for transform in header.transforms.iter().rev() {
+        match transform.id {
+            TransformId::Rct => inverse_rct(
+                &mut coded,
+                transform.begin_channel as usize,
+                transform.rct_type,
+            )?,
+            TransformId::Palette => {
+                inverse_palette(&mut coded, transform, &header.wp_header, bit_depth)?
+            }
+            TransformId::Squeeze => inverse_squeeze(&mut coded, &transform.squeezes)?,
+            TransformId::Invalid => return Err(Error::InvalidEnum(3, "TransformId".to_string())),
+        }
+    }
+    // Undoing the transforms gives back the shapes they were applied to.
+    debug_assert!(coded
+        .iter()
+        .map(Channel::shape)
+        .eq(channels.iter().map(Channel::shape)));
+    for (channel, decoded) in channels.iter_mut().zip(coded) {
+        channel.data = decoded.data;
+    }
+    Ok(())
+}
+
+/// Walks the samples of `channels[index]` in raster order, finds the leaf of
+/// each one, and stores the value that `sample` returns for that leaf and the
+/// prediction, offset included.
+fn predict_channel(
+    channels: &mut [Channel],
+    index: usize,
+    group_id: u32,
+    wp_header: &WeightedHeader,
+    tree: &Tree,
+    mut sample: impl FnMut(&Leaf, i64) -> Result<i32, Error>,
+) -> Result<(), Error> {
+    let (previous, rest) = channels.split_at_mut(index);
+    let channel = &mut rest[0];
+    let (width, height) = (channel.data.width(), channel.data.height());
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    // Previous channels of the same size, nearest first, as far back as the
+    // tree looks.
+    let num_references =
+        (tree.num_properties() - NUM_NONREF_PROPERTIES).div_ceil(NUM_REF_PROPERTIES);
+    let references: Vec<&Image<i32>> = previous
+        .iter()
+        .rev()
+        .filter(|c| c.has_same_geometry(channel))
+        .take(num_references)
+        .map(|c| &c.data)
+        .collect();
+
+    let mut properties = vec![0i32; NUM_NONREF_PROPERTIES + num_references * NUM_REF_PROPERTIES];
+    properties[0] = index as i32;
+    properties[1] = group_id as i32;
+    let mut wp = WeightedPredictor::new(wp_header, width);
+    for y in 0..height {
+        properties[2] = y as i32;
+        properties[9] = 0;
+        for x in 0..width {
+            let n = Neighbors::new(&channel.data, x, y);
+            let (weighted, max_error) = wp.predict(x, y, &n);
+            let p = &mut properties;
+            p[3] = x as i32;
+            p[4] = n.top.abs() as i32;
+            p[5] = n.left.abs() as i32;
+            p[6] = n.top as i32;
+            p[7] = n.left as i32;
+            // Difference from the gradient of the previous sample.
+            p[8] = (n.left - p[9] as i64) as i32;
+            p[9] = (n.left + n.top - n.top_left) as i32;
+            p[10] = (n.left - n.top_left) as i32;
+            p[11] = (n.top_left - n.top) as i32;
+            p[12] = (n.top - n.top_right) as i32;
+            p[13] = (n.top - n.top_top) as i32;
+            p[14] = (n.left - n.left_left) as i32;
+            p[15] = max_error;
+            for (reference, p) in references
+                .iter()
+                .zip(p[NUM_NONREF_PROPERTIES..].chunks_exact_mut(NUM_REF_PROPERTIES))
+            {
+                let value = reference.row(y)[x] as i64;
+                let left = if x > 0 {
+                    reference.row(y)[x - 1] as i64
+                } else {
+                    0
+                };
+                let (top, top_left) = if y > 0 && x > 0 {
+                    (
+                        reference.row(y - 1)[x] as i64,
+                        reference.row(y - 1)[x - 1] as i64,
+                    )
+                } else if y > 0 {
+                    (reference.row(y - 1)[x] as i64, left)
+                } else {
+                    (left, left)
+                };
+                let residual = value - clamped_gradient(left, top, top_left);
+                p[0] = value.abs() as i32;
+                p[1] = value as i32;
+                p[2] = residual.abs() as i32;
+                p[3] = residual as i32;
+            }
+
+            let leaf = tree.leaf(&properties);
+            let prediction = leaf
+                .predictor
+                .predict(&n, weighted)
+                .wrapping_add(leaf.offset as i64);
+            let value = sample(leaf, prediction)?;
+            channel.data.row_mut(y)[x] = value;
+            wp.update(value, x, y);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_writer::BitWriter;
+    use crate::modular::predict::Predictor;
+    use crate::modular::transforms::SqueezeParams;
+
+    // Writes a tree with a single leaf that uses `predictor`.
+    fn write_tree(bw: &mut BitWriter, predictor: Predictor) {
+        let symbols = [0, 0, predictor as u32, 0, 0, 0];
+        // No LZ77, one histogram per context, prefix codes with symbols read
+        // as is.
+        bw.write(1, 0);
+        bw.write(1, 1);
+        bw.write(2, 3);
+        for ctx in 0..symbols.len() {
+            bw.write(3, ctx as u64);
+        }
+        bw.write(1, 1);
+        for _ in 0..symbols.len() {
+            bw.write(4, 15);
+        }
+        // Alphabets of 16 symbols, each with a single symbol.
+        for _ in 0..symbols.len() {
+            bw.write(1, 1);
+            bw.write(4, 3);
+            bw.write(3, 7);
+        }
+        for symbol in symbols {
+            bw.write(2, 1);
+            bw.write(2, 0);
+            bw.write(4, symbol as u64);
+        }
+    }
+
+    // Writes histograms for one context with symbols 0 to 3, each coded with
+    // two bits, followed by `residuals` from -2 to 1.
+    fn write_samples(bw: &mut BitWriter, residuals: &[i32]) {
+        bw.write(1, 0);
+        bw.write(1, 1);
+        bw.write(4, 15);
+        bw.write(1, 1);
+        bw.write(4, 1);
+        bw.write(1, 1);
+        bw.write(2, 1);
+        bw.write(2, 3);
+        for symbol in 0..4 {
+            bw.write(2, symbol);
+        }
+        bw.write(1, 0);
+        for &residual in residuals {
+            let token = if residual < 0 {
+                -2 * residual - 1
+            } else {
+                2 * residual
+            };
+            // The table is indexed by the bits as read, which are reversed.
+            bw.write(2, [0, 2, 1, 3][token as usize]);
+        }
+    }
+
+    fn transform(id: TransformId) -> Transform {
+        Transform {
+            id,
+            begin_channel: 0,
+            rct_type: 6,
+            num_channels: 3,
+            num_colors: 256,
+            num_deltas: 0,
+            predictor: 0,
+            squeezes: vec![],
+        }
+    }
+
+    // Decodes a group with its own tree, which uses `predictor`, into
+    // channels of the given sizes.
+    fn decode(
+        transforms: Vec<Transform>,
+        predictor: Predictor,
+        residuals: &[i32],
+        sizes: &[(usize, usize)],
+    ) -> Result<Vec<Vec<i32>>, Error> {
+        let mut bw = BitWriter::new();
+        GroupHeader {
+            use_global_tree: false,
+            wp_header: WeightedHeader::default(),
+            transforms,
+        }
+        .write(&mut bw)?;
+        write_tree(&mut bw, predictor);
+        write_samples(&mut bw, residuals);
+        let data = bw.finalize();
+        let mut channels: Vec<Channel> = sizes
+            .iter()
+            .map(|&(w, h)| Channel::new(Image::new(w, h).unwrap(), 0, 0))
+            .collect();
+        let options = DecoderOptions::default();
+        decode_modular(
+            &mut BitReader::new(&data),
+            &mut channels,
+            0,
+            None,
+            16,
+            8,
+            &options,
+        )?;
+        Ok(channels.into_iter().map(|c| c.data.into_vec()).collect())
+    }
+
+    #[test]
+    fn test_decode_modular() -> Result<(), Error> {
+        // The gradient predictor predicts the first row from the left, and
+        // the first column from the top. The last sample of the first
+        // channel is -1 + 2 - 1, plus a residual of 0.
+        let residuals = [1, 1, -2, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+        let channels = decode(
+            vec![transform(TransformId::Rct)],
+            Predictor::Gradient,
+            &residuals,
+            &[(2, 2); 3],
+        )?;
+        // YCgCo of (1, 0, 1), (2, 0, 1), (-1, 0, 1) and (0, 0, 1).
+        assert_eq!(
+            channels,
+            vec![vec![1, 2, -1, 0], vec![2, 3, 0, 1], vec![1, 2, -1, 0]]
+        );
+
+        // Groups that use the global tree need one.
+        let mut bw = BitWriter::new();
+        GroupHeader {
+            use_global_tree: true,
+            wp_header: WeightedHeader::default(),
+            transforms: vec![],
+        }
+        .write(&mut bw)?;
+        let data = bw.finalize();
+        let mut channels = vec![Channel::new(Image::new(2, 2)?, 0, 0)];
+        assert!(matches!(
+            decode_modular(
+                &mut BitReader::new(&data),
+                &mut channels,
+                0,
+                None,
+                16,
+                8,
+                &DecoderOptions::default()
+            ),
+            Err(Error::MissingGlobalTree)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_palette() -> Result<(), Error> {
+        // Colors (1, 0, -2) and (-1, 1, 0), coded as a 2x3 meta channel
+        // before the indices. Index -2 is the delta (4, 4, 4).
+        let residuals = [1, -1, 0, 1, -2, 0, -2, 1, 0];
+        let palette = Transform {
+            num_colors: 2,
+            ..transform(TransformId::Palette)
+        };
+        let channels = decode(vec![palette], Predictor::Zero, &residuals, &[(3, 1); 3])?;
+        assert_eq!(
+            channels,
+            vec![vec![4, -1, 1], vec![4, 1, 0], vec![4, 0, -2]]
+        );
+
+        // The palette needs as many channels as it replaces.
+        let palette = Transform {
+            begin_channel: 1,
+            ..transform(TransformId::Palette)
+        };
+        assert!(matches!(
+            decode(vec![palette], Predictor::Zero, &[], &[(3, 1); 3]),
+            Err(Error::InvalidTransformChannels(1, 3))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_squeeze() -> Result<(), Error> {
+        // Averages 1 and -2, then a residual of 0 for the first pair. The
+        // tendency from 1 down to -2 is 1, so the pair is (1, 0); the last
+        // sample is the last average.
+        let squeeze = Transform {
+            squeezes: vec![SqueezeParams {
+                horizontal: true,
+                in_place: true,
+                begin_channel: 0,
+                num_channels: 1,
+            }],
+            ..transform(TransformId::Squeeze)
+        };
+        let channels = decode(vec![squeeze], Predictor::Zero, &[1, -2, 0], &[(3, 1)])?;
+        assert_eq!(channels, vec![vec![1, 0, -2]]);
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+extern crate jxl_headers_derive;
+extern crate num_derive;
+
+use jxl_headers_derive::UnconditionalCoder;
+use num_derive::FromPrimitive;
+
+use crate::bit_reader::BitReader;
+use crate::error::Error;
+use crate::headers::encodings::*;
+use crate::image::Image;
+use crate::util::FloorLog2;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, FromPrimitive)]
+pub enum Predictor {
+    Zero = 0,
+    Left = 1,
+    Top = 2,
+    Average0 = 3,
+    Select = 4,
+    Gradient = 5,
+    Weighted = 6,
+    TopRight = 7,
+    TopLeft = 8,
+    LeftLeft = 9,
+    Average1 = 10,
+    Average2 = 11,
+    Average3 = 12,
+    Average4 = 13,
+}
+
+/// The samples around a position, with the ones outside of the channel
+/// replaced as the predictors expect.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Neighbors {
+    pub left: i64,
+    pub top: i64,
+    pub top_left: i64,
+    pub top_right: i64,
+    pub left_left: i64,
+    pub top_top: i64,
+    pub top_right_right: i64,
+}
+
+impl Neighbors {
+    pub fn new(channel: &Image<i32>, x: usize, y: usize) -> Neighbors {
+        let width = channel.width();
+        let row = channel.row(y);
+        let top_row = (y > 0).then(|| channel.row(y - 1));
+        let left = match (x, top_row) {
+            (0, Some(top_row)) => top_row[x] as i64,
+            (0, None) => 0,
+            _ => row[x - 1] as i64,
+        };
+        let top = top_row.map_or(left, |top_row| top_row[x] as i64);
+        let top_left = match top_row {
+            Some(top_row) if x > 0 => top_row[x - 1] as i64,
+            _ => left,
+        };
+        let top_right = match top_row {
+            Some(top_row) if x + 1 < width => top_row[x + 1] as i64,
+            _ => top,
+        };
+        let left_left = if x > 1 { row[x - 2] as i64 } else { left };
+        let top_top = if y > 1 {
+            channel.row(y - 2)[x] as i64
+        } else {
+            top
+        };
+        let top_right_right = match top_row {
+            Some(top_row) if x + 2 < width => top_row[x + 2] as i64,
+            _ => top_right,
+        };
+        Neighbors {
+            left,
+            top,
+            top_left,
+            top_right,
+            left_left,
+            top_top,
+            top_right_right,
+        }
+    }
+}
+
+/// The gradient `left + top - top_left`, clamped to the range of `left` and
+/// `top` if `top_left` is outside of it.
+pub fn clamped_gradient(left: i64, top: i64, top_left: i64) -> i64 {
+    let (min, max) = (left.min(top), left.max(top));
+    if top_left < min {
+        max
+    } else if top_left > max {
+        min
+    } else {
+        left + top - top_left
+    }
+}
+
+impl Predictor {
+    /// Predicts a sample from its neighbors, given the prediction of the
+    /// weighted predictor for it.
+    pub fn predict(&self, n: &Neighbors, weighted: i64) -> i64 {
+        match self {
+            Predictor::Zero => 0,
+            Predictor::Left => n.left,
+            Predictor::Top => n.top,
+            Predictor::Average0 => (n.left + n.top) / 2,
+            Predictor::Select => {
+                let gradient = n.left + n.top - n.top_left;
+                if (gradient - n.top).abs() < (gradient - n.left).abs() {
+                    n.left
+                } else {
+                    n.top
+                }
+            }
+            Predictor::Gradient => clamped_gradient(n.left, n.top, n.top_left),
+            Predictor::Weighted => weighted,
+            Predictor::TopRight => n.top_right,
+            Predictor::TopLeft => n.top_left,
+            Predictor::LeftLeft => n.left_left,
+            Predictor::Average1 => (n.left + n.top_left) / 2,
+            Predictor::Average2 => (n.top_left + n.top) / 2,
+            Predictor::Average3 => (n.top_right + n.top) / 2,
+            Predictor::Average4 => {
+                (6 * n.top - 2 * n.top_top
+                    + 7 * n.left
+                    + n.left_left
+                    + n.top_right_right
+                    + 3 * n.top_right
+                    + 8)
+                    / 16
+            }
+        }
+    }
+}
+
+/// Parameters of the weighted predictor.
+#[derive(UnconditionalCoder, Debug, Clone, PartialEq)]
+pub struct WeightedHeader {
+    #[all_default]
+    #[default(true)]
+    all_default: bool,
+    #[coder(Bits(5))]
+    #[default(16)]
+    p1c: u32,
+    #[coder(Bits(5))]
+    #[default(10)]
+    p2c: u32,
+    #[coder(Bits(5))]
+    #[default(7)]
+    p3ca: u32,
+    #[coder(Bits(5))]
+    #[default(7)]
+    p3cb: u32,
+    #[coder(Bits(5))]
+    #[default(7)]
+    p3cc: u32,
+    #[coder(Bits(5))]
+    #[default(0)]
+    p3cd: u32,
+    #[coder(Bits(5))]
+    #[default(0)]
+    p3ce: u32,
+    #[coder(Bits(4))]
+    #[default(0xd)]
+    w0: u32,
+    #[coder(Bits(4))]
+    #[default(0xc)]
+    w1: u32,
+    #[coder(Bits(4))]
+    #[default(0xc)]
+    w2: u32,
+    #[coder(Bits(4))]
+    #[default(0xc)]
+    w3: u32,
+}
+
+// Predictions carry this many extra bits of precision.
+const PRED_EXTRA_BITS: u32 = 3;
+const PREDICTION_ROUND: i64 = ((1 << PRED_EXTRA_BITS) >> 1) - 1;
+
+fn div_lookup(i: u32) -> u32 {
+    (1 << 24) / (i + 1)
+}
+
+fn error_weight(error_sum: u64, max_weight: u32) -> u32 {
+    let shift = ((error_sum + 1).floor_log2() as i32 - 5).max(0) as u32;
+    4 + ((max_weight * div_lookup((error_sum >> shift) as u32)) >> shift)
+}
+
+fn weighted_average(predictions: &[i64; 4], mut weights: [u32; 4]) -> i64 {
+    let log_weight = weights.iter().sum::<u32>().floor_log2();
+    weights.iter_mut().for_each(|w| *w >>= log_weight - 4);
+    let weight_sum: u32 = weights.iter().sum();
+    let sum = predictions
+        .iter()
+        .zip(weights)
+        .fold((weight_sum >> 1) as i64 - 1, |sum, (p, w)| {
+            sum + p * w as i64
+        });
+    (sum * div_lookup(weight_sum - 1) as i64) >> 24
+}
+
+/// State of the weighted predictor while a channel is decoded: the errors of
+/// its four sub-predictors on the current and previous rows.
+pub struct WeightedPredictor {
+    params: [i64; 7],
+    max_weights: [u32; 4],
+    width: usize,
+    sub_errors: [Vec<u32>; 4],
+    errors: Vec<i32>,
+    predictions: [i64; 4],
+    prediction: i64,
+}
+
+impl WeightedPredictor {
+    pub fn new(header: &WeightedHeader, width: usize) -> WeightedPredictor {
+        let row_pair = (width + 2) * 2;
+        WeightedPredictor {
+            params: [
+                header.p1c,
+                header.p2c,
+                header.p3ca,
+                header.p3cb,
+                header.p3cc,
+                header.p3cd,
+                header.p3ce,
+            ]
+            .map(|p| p as i64),
+            max_weights: [header.w0, header.w1, header.w2, header.w3],
+            width,
+            sub_errors: std::array::from_fn(|_| vec![0; row_pair]),
+            errors: vec![0; row_pair],
+            predictions: [0; 4],
+            prediction: 0,
+        }
+    }
+
+    // Offsets of the current and previous rows in the error arrays.
+    fn rows(&self, y: usize) -> (usize, usize) {
+        match y % 2 {
+            1 => (0, self.width + 2),
+            _ => (self.width + 2, 0),
+        }
+    }
+
+    /// Returns the prediction for the sample at `(x, y)`, and the largest
+    /// error on the neighbors, which is a property for the MA tree.
+    pub fn predict(&mut self, x: usize, y: usize, n: &Neighbors) -> (i64, i32) {
+        let (cur_row, prev_row) = self.rows(y);
+        let pos_n = prev_row + x;
+        let pos_ne = if x + 1 < self.width { pos_n + 1 } else { pos_n };
+        let pos_nw = if x > 0 { pos_n - 1 } else { pos_n };
+        let weights: [u32; 4] = std::array::from_fn(|i| {
+            let errors = &self.sub_errors[i];
+            let sum = errors[pos_n] as u64 + errors[pos_ne] as u64 + errors[pos_nw] as u64;
+            error_weight(sum, self.max_weights[i])
+        });
+
+        let [n_, w, ne, nw, nn] =
+            [n.top, n.left, n.top_right, n.top_left, n.top_top].map(|v| v << PRED_EXTRA_BITS);
+        let te_w = if x == 0 {
+            0
+        } else {
+            self.errors[cur_row + x - 1] as i64
+        };
+        let te_n = self.errors[pos_n] as i64;
+        let te_nw = self.errors[pos_nw] as i64;
+        let te_ne = self.errors[pos_ne] as i64;
+        let sum_wn = te_n + te_w;
+
+        let max_error =
+            [te_n, te_nw, te_ne]
+                .iter()
+                .fold(te_w, |max, &e| if e.abs() > max.abs() { e } else { max });
+
+        let [p1c, p2c, p3ca, p3cb, p3cc, p3cd, p3ce] = self.params;
+        self.predictions = [
+            w + ne - n_,
+            n_ - (((sum_wn + te_ne) * p1c) >> 5),
+            w - (((sum_wn + te_nw) * p2c) >> 5),
+            n_ - ((te_nw * p3ca + te_n * p3cb + te_ne * p3cc + (nn - n_) * p3cd + (nw - w) * p3ce)
+                >> 5),
+        ];
+        let mut prediction = weighted_average(&self.predictions, weights);
+        // Unless the errors around have the same sign, stay within the range
+        // of the neighbors.
+        if ((te_n ^ te_w) | (te_n ^ te_nw)) <= 0 {
+            let max = w.max(ne).max(n_);
+            let min = w.min(ne).min(n_);
+            prediction = prediction.clamp(min, max);
+        }
+        self.prediction = prediction;
+        (
+            (prediction + PREDICTION_ROUND) >> PRED_EXTRA_BITS,
+            max_error as i32,
+        )
+    }
+
+    /// Records the actual value of the sample at `(x, y)`, which was last
+    /// predicted.
+    pub fn update(&mut self, value: i32, x: usize, y: usize) {
+        let (cur_row, prev_row) = self.rows(y);
+        let value = (value as i64) << PRED_EXTRA_BITS;
+        self.errors[cur_row + x] = (self.prediction - value) as i32;
+        for (errors, prediction) in self.sub_errors.iter_mut().zip(self.predictions) {
+            let error = (((prediction - value).abs() + PREDICTION_ROUND) >> PRED_EXTRA_BITS) as u32;
+            errors[cur_row + x] = error;
+            // Folded into the entry above and to the right, so that the next
+            // two samples of the row also see it as the error on their left
+            // and left-left neighbors.
+            errors[prev_row + x + 1] = errors[prev_row + x + 1].wrapping_add(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_neighbors() {
+        // 1 2 3
+        // 4 5 6
+        // 7 8 9
+        let channel = Image::from_vec(3, 3, (1..=9).collect()).unwrap();
+        let n = Neighbors::new(&channel, 1, 2);
+        assert_eq!(
+            n,
+            Neighbors {
+                left: 7,
+                top: 5,
+                top_left: 4,
+                top_right: 6,
+                left_left: 7,
+                top_top: 2,
+                top_right_right: 6,
+            }
+        );
+        // The first sample of a row uses the one above as left neighbor.
+        let n = Neighbors::new(&channel, 0, 1);
+        assert_eq!((n.left, n.top, n.top_left, n.top_top), (1, 1, 1, 1));
+        assert_eq!(Neighbors::new(&channel, 0, 0), Neighbors::default());
+    }
+
+    #[test]
+    fn test_predictors() {
+        let n = Neighbors {
+            left: 10,
+            top: 20,
+            top_left: 14,
+            top_right: 30,
+            left_left: 6,
+            top_top: 25,
+            top_right_right: 40,
+        };
+        let expected = [0, 10, 20, 15, 10, 16, 99, 30, 14, 6, 12, 17, 25, 17];
+        for (p, &expected) in expected.iter().enumerate() {
+            let predictor: Predictor = num_traits::FromPrimitive::from_usize(p).unwrap();
+            assert_eq!(predictor.predict(&n, 99), expected, "{:?}", predictor);
+        }
+        assert_eq!(clamped_gradient(10, 20, 5), 20);
+        assert_eq!(clamped_gradient(10, 20, 25), 10);
+        assert_eq!(clamped_gradient(-3, 1, 0), -2);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let header = WeightedHeader::default();
+        let channel = Image::from_vec(2, 2, vec![8, 8, 8, 8]).unwrap();
+        let mut wp = WeightedPredictor::new(&header, 2);
+        let mut predictions = vec![];
+        for y in 0..2 {
+            for x in 0..2 {
+                let (prediction, max_error) = wp.predict(x, y, &Neighbors::new(&channel, x, y));
+                predictions.push((prediction, max_error));
+                wp.update(channel.row(y)[x], x, y);
+            }
+        }
+        // The first sample has no neighbors. The others are predicted from
+        // equal neighbors, and stay within their range.
+        assert_eq!(predictions[0], (0, 0));
+        assert_eq!(predictions[1], (8, -64));
+        assert!(predictions[2..].iter().all(|&(p, _)| p == 8));
+    }
+}
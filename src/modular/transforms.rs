@@ -0,0 +1,683 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+extern crate jxl_headers_derive;
+extern crate num_derive;
+
+use jxl_headers_derive::UnconditionalCoder;
+use num_derive::FromPrimitive;
+
+use crate::bit_reader::BitReader;
+use crate::error::Error;
+use crate::headers::encodings::*;
+use crate::image::Image;
+use crate::modular::predict::{Neighbors, Predictor, WeightedHeader, WeightedPredictor};
+use crate::modular::{Channel, ChannelShape};
+use num_traits::FromPrimitive;
+
+#[derive(UnconditionalCoder, Copy, Clone, PartialEq, Eq, Debug, FromPrimitive)]
+pub enum TransformId {
+    Rct = 0,
+    Palette = 1,
+    Squeeze = 2,
+    Invalid = 3,
+}
+
+#[derive(UnconditionalCoder, Debug, Clone, PartialEq)]
+pub struct SqueezeParams {
+    pub horizontal: bool,
+    pub in_place: bool,
+    #[coder(u2S(Bits(3), Bits(6) + 8, Bits(10) + 72, Bits(13) + 1096))]
+    pub begin_channel: u32,
+    #[coder(u2S(1, 2, 3, Bits(4) + 4))]
+    pub num_channels: u32,
+}
+
+#[derive(UnconditionalCoder, Debug, Clone, PartialEq)]
+#[validate]
+pub struct Transform {
+    #[coder(u2S(0, 1, 2, 3))]
+    pub id: TransformId,
+
+    #[condition(id == TransformId::Rct || id == TransformId::Palette)]
+    #[coder(u2S(Bits(3), Bits(6) + 8, Bits(10) + 72, Bits(13) + 1096))]
+    #[default(0)]
+    pub begin_channel: u32,
+
+    #[condition(id == TransformId::Rct)]
+    #[coder(u2S(6, Bits(2), Bits(4) + 2, Bits(6) + 10))]
+    #[default(6)]
+    pub rct_type: u32,
+
+    #[condition(id == TransformId::Palette)]
+    #[coder(u2S(1, 3, 4, Bits(13) + 1))]
+    #[default(3)]
+    pub num_channels: u32,
+
+    #[condition(id == TransformId::Palette)]
+    #[coder(u2S(Bits(8), Bits(10) + 256, Bits(12) + 1280, Bits(16) + 5376))]
+    #[default(256)]
+    pub num_colors: u32,
+
+    #[condition(id == TransformId::Palette)]
+    #[coder(u2S(0, Bits(8) + 1, Bits(10) + 257, Bits(16) + 1281))]
+    #[default(0)]
+    pub num_deltas: u32,
+
+    #[condition(id == TransformId::Palette)]
+    #[coder(Bits(4))]
+    #[default(0)]
+    pub predictor: u32,
+
+    #[condition(id == TransformId::Squeeze)]
+    #[size_coder(implicit(u2S(0, Bits(4) + 1, Bits(6) + 9, Bits(8) + 41)))]
+    #[default(Vec::new())]
+    pub squeezes: Vec<SqueezeParams>,
+}
+
+impl Transform {
+    fn check(&self, _: &Empty) -> Result<(), Error> {
+        if self.id == TransformId::Invalid {
+            return Err(Error::InvalidEnum(3, "TransformId".to_string()));
+        }
+        if self.rct_type >= 42 {
+            return Err(Error::InvalidRctType(self.rct_type));
+        }
+        if Predictor::from_u32(self.predictor).is_none() {
+            return Err(Error::InvalidPredictor(self.predictor));
+        }
+        Ok(())
+    }
+}
+
+// Images wider or taller than this are squeezed until they are not, if the
+// squeeze transform comes without parameters.
+const MAX_FIRST_PREVIEW_SIZE: usize = 8;
+
+// The shifts of a channel that has been squeezed too often to be valid.
+const MAX_SQUEEZE_SHIFT: i32 = 30;
+
+/// Checks that the `num` channels from `begin` exist, have the same size,
+/// and are either all meta channels or none of them.
+fn check_channels(
+    shapes: &[ChannelShape],
+    begin: usize,
+    num: usize,
+    num_meta: usize,
+) -> Result<(), Error> {
+    let error = || Error::InvalidTransformChannels(begin as u32, num as u32);
+    let channels = shapes.get(begin..begin + num).ok_or_else(error)?;
+    if channels.iter().any(|c| *c != channels[0]) || (begin < num_meta && begin + num > num_meta) {
+        return Err(error());
+    }
+    Ok(())
+}
+
+/// The squeezes used if a squeeze transform has no parameters: chroma first
+/// if it looks like 4:2:0 would fit, then all channels until the first one
+/// fits in 8x8.
+fn default_squeezes(shapes: &[ChannelShape], num_meta: usize) -> Vec<SqueezeParams> {
+    let first = match shapes.get(num_meta) {
+        Some(first) => *first,
+        None => return vec![],
+    };
+    let num_channels = (shapes.len() - num_meta) as u32;
+    let (mut width, mut height) = (first.width, first.height);
+    let mut squeezes = vec![];
+    let squeeze = |horizontal, in_place, begin, num| SqueezeParams {
+        horizontal,
+        in_place,
+        begin_channel: begin as u32,
+        num_channels: num,
+    };
+    if num_channels > 2
+        && shapes[num_meta + 1].width == width
+        && shapes[num_meta + 1].height == height
+    {
+        squeezes.push(squeeze(true, false, num_meta + 1, 2));
+        squeezes.push(squeeze(false, false, num_meta + 1, 2));
+    }
+    if width <= height && height > MAX_FIRST_PREVIEW_SIZE {
+        squeezes.push(squeeze(false, true, num_meta, num_channels));
+        height = height.div_ceil(2);
+    }
+    while width > MAX_FIRST_PREVIEW_SIZE || height > MAX_FIRST_PREVIEW_SIZE {
+        if width > MAX_FIRST_PREVIEW_SIZE {
+            squeezes.push(squeeze(true, true, num_meta, num_channels));
+            width = width.div_ceil(2);
+        }
+        if height > MAX_FIRST_PREVIEW_SIZE {
+            squeezes.push(squeeze(false, true, num_meta, num_channels));
+            height = height.div_ceil(2);
+        }
+    }
+    squeezes
+}
+
+/// Turns `shapes`, the channels that `transform` applies to, into the
+/// channels that are coded instead, the first `num_meta` of which are meta
+/// channels. A squeeze transform without parameters gets the default ones,
+/// which its inverse needs.
+pub fn meta_apply(
+    transform: &mut Transform,
+    shapes: &mut Vec<ChannelShape>,
+    num_meta: &mut usize,
+) -> Result<(), Error> {
+    let begin = transform.begin_channel as usize;
+    match transform.id {
+        TransformId::Rct => check_channels(shapes, begin, 3, *num_meta),
+        TransformId::Palette => {
+            let num = transform.num_channels as usize;
+            check_channels(shapes, begin, num, *num_meta)?;
+            if begin < *num_meta {
+                *num_meta = *num_meta + 2 - num;
+            } else {
+                *num_meta += 1;
+            }
+            shapes.drain(begin + 1..begin + num);
+            // The palette, with one row per channel, goes first.
+            shapes.insert(
+                0,
+                ChannelShape {
+                    width: (transform.num_colors + transform.num_deltas) as usize,
+                    height: num,
+                    hshift: -1,
+                    vshift: 0,
+                },
+            );
+            Ok(())
+        }
+        TransformId::Squeeze => {
+            if transform.squeezes.is_empty() {
+                transform.squeezes = default_squeezes(shapes, *num_meta);
+            }
+            for params in transform.squeezes.iter() {
+                let begin = params.begin_channel as usize;
+                let num = params.num_channels as usize;
+                let error = || Error::InvalidTransformChannels(begin as u32, num as u32);
+                if begin + num > shapes.len() {
+                    return Err(error());
+                }
+                if begin < *num_meta {
+                    if begin + num > *num_meta || !params.in_place {
+                        return Err(error());
+                    }
+                    *num_meta += num;
+                }
+                let offset = if params.in_place {
+                    begin + num
+                } else {
+                    shapes.len()
+                };
+                for c in begin..begin + num {
+                    let shape = &mut shapes[c];
+                    if shape.width == 0
+                        || shape.height == 0
+                        || shape.hshift > MAX_SQUEEZE_SHIFT
+                        || shape.vshift > MAX_SQUEEZE_SHIFT
+                    {
+                        return Err(Error::InvalidSqueeze(c as u32));
+                    }
+                    let mut residual = *shape;
+                    if params.horizontal {
+                        residual.width = shape.width / 2;
+                        shape.width -= residual.width;
+                        if shape.hshift >= 0 {
+                            shape.hshift += 1;
+                        }
+                    } else {
+                        residual.height = shape.height / 2;
+                        shape.height -= residual.height;
+                        if shape.vshift >= 0 {
+                            shape.vshift += 1;
+                        }
+                    }
+                    residual.hshift = shape.hshift;
+                    residual.vshift = shape.vshift;
+                    shapes.insert(offset + c - begin, residual);
+                }
+            }
+            Ok(())
+        }
+        TransformId::Invalid => Err(Error::InvalidEnum(3, "TransformId".to_string())),
+    }
+}
+
+/// Undoes a reversible color transform of the three channels starting at
+/// `begin`: decorrelates them with one of seven transforms, and permutes
+/// them back with one of six permutations.
+pub fn inverse_rct(channels: &mut [Channel], begin: usize, rct_type: u32) -> Result<(), Error> {
+    let rct = channels
+        .get_mut(begin..begin + 3)
+        .filter(|rct| rct[1..].iter().all(|c| c.has_same_geometry(&rct[0])))
+        .ok_or(Error::InvalidTransformChannels(begin as u32, 3))?;
+    if rct_type == 0 {
+        return Ok(());
+    }
+    let permutation = (rct_type / 7) as usize;
+    let transform = rct_type % 7;
+    let outputs = [
+        permutation % 3,
+        (permutation + 1 + permutation / 3) % 3,
+        (permutation + 2 - permutation / 3) % 3,
+    ];
+    let (width, height) = (rct[0].data.width(), rct[0].data.height());
+    for y in 0..height {
+        for x in 0..width {
+            let [a, b, c] = [0, 1, 2].map(|i| rct[i].data.row(y)[x]);
+            let values = if transform == 6 {
+                // YCgCo.
+                let tmp = a.wrapping_sub(c >> 1);
+                let green = c.wrapping_add(tmp);
+                let blue = tmp.wrapping_sub(b >> 1);
+                [blue.wrapping_add(b), green, blue]
+            } else {
+                let third = if transform & 1 != 0 {
+                    c.wrapping_add(a)
+                } else {
+                    c
+                };
+                let second = match transform >> 1 {
+                    1 => b.wrapping_add(a),
+                    2 => b.wrapping_add(((a as i64 + third as i64) >> 1) as i32),
+                    _ => b,
+                };
+                [a, second, third]
+            };
+            for (&value, &output) in values.iter().zip(&outputs) {
+                rct[output].data.row_mut(y)[x] = value;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Palette entries used for negative indices, which are deltas.
+#[rustfmt::skip]
+const DELTA_PALETTE: [[i32; 3]; 72] = [
+    [0, 0, 0], [4, 4, 4], [11, 0, 0], [0, 0, -13],
+    [0, -12, 0], [-10, -10, -10], [-18, -18, -18], [-27, -27, -27],
+    [-18, -18, 0], [0, 0, -32], [-32, 0, 0], [-37, -37, -37],
+    [0, -32, -32], [24, 24, 45], [50, 50, 50], [-45, -24, -24],
+    [-24, -45, -45], [0, -24, -24], [-34, -34, 0], [-24, 0, -24],
+    [-45, -45, -24], [64, 64, 64], [-32, 0, -32], [0, -32, 0],
+    [-32, 0, 32], [-24, -45, -24], [45, 24, 45], [24, -24, -45],
+    [-45, -24, 24], [80, 80, 80], [64, 0, 0], [0, 0, -64],
+    [0, -64, -64], [-24, -24, 45], [96, 96, 96], [64, 64, 0],
+    [45, -24, -24], [34, -34, 0], [112, 112, 112], [24, -45, -45],
+    [45, 45, -24], [0, -32, 32], [24, -24, 45], [0, 96, 96],
+    [45, -24, 24], [24, -45, -24], [-24, -45, 24], [0, -64, 0],
+    [96, 0, 0], [128, 128, 128], [64, 0, 64], [144, 144, 144],
+    [96, 96, 0], [-36, -36, 36], [45, -24, -45], [45, -45, -24],
+    [0, 0, -96], [0, 128, 128], [0, 96, 0], [45, 24, -45],
+    [-128, 0, 0], [24, -45, 24], [-45, 24, -45], [64, 0, -64],
+    [64, -64, -64], [96, 0, 96], [45, -45, 24], [24, 45, -45],
+    [64, 64, -64], [128, 128, 0], [0, 0, -128], [-24, 45, -45],
+];
+
+// Indices past the coded palette refer to a 4x4x4 cube of colors, then to a
+// 5x5x5 one.
+const SMALL_CUBE_SIZE: i32 = 64;
+
+/// Returns entry `index` of the palette for channel `c`, where indices that
+/// are negative or past the coded entries stand for implicit colors.
+fn palette_value(palette: &Image<i32>, index: i32, c: usize, bit_depth: u32) -> i64 {
+    let scale = |value: i32, denom: u64| (value as u64 * ((1u64 << bit_depth) - 1) / denom) as i64;
+    let size = palette.width() as i32;
+    if index < 0 {
+        let index = (-(index + 1) % (2 * DELTA_PALETTE.len() as i32 - 1)) as usize;
+        let value = match DELTA_PALETTE[(index + 1) >> 1].get(c) {
+            Some(&value) if index & 1 == 0 => -value as i64,
+            Some(&value) => value as i64,
+            None => return 0,
+        };
+        value << bit_depth.saturating_sub(8)
+    } else if index < size {
+        palette.row(c)[index as usize] as i64
+    } else if c >= 3 {
+        0
+    } else if index - size < SMALL_CUBE_SIZE {
+        let value = ((index - size) >> (2 * c)) % 4;
+        scale(value, 4) + (1 << bit_depth.saturating_sub(3))
+    } else {
+        let value = (index - size - SMALL_CUBE_SIZE) / [1, 5, 25][c] % 5;
+        scale(value, 4)
+    }
+}
+
+/// Undoes a palette transform: replaces the index channel after the palette,
+/// which is channel 0, with the colors it refers to. Indices below
+/// `num_deltas` are added to a prediction from the neighbors instead.
+pub fn inverse_palette(
+    channels: &mut Vec<Channel>,
+    transform: &Transform,
+    wp_header: &WeightedHeader,
+    bit_depth: u32,
+) -> Result<(), Error> {
+    let begin = transform.begin_channel as usize + 1;
+    let error = || Error::InvalidTransformChannels(transform.begin_channel, transform.num_channels);
+    let num = channels.first().ok_or_else(error)?.data.height();
+    if begin >= channels.len() || num == 0 {
+        return Err(error());
+    }
+    let predictor = Predictor::from_u32(transform.predictor)
+        .ok_or(Error::InvalidPredictor(transform.predictor))?;
+    let bit_depth = bit_depth.min(24);
+    let indices = channels[begin].data.clone();
+    for _ in 1..num {
+        channels.insert(begin + 1, channels[begin].clone());
+    }
+    let (palette, rest) = channels.split_at_mut(1);
+    let palette = &palette[0].data;
+    let (width, height) = (indices.width(), indices.height());
+    for (c, channel) in rest[begin - 1..begin - 1 + num].iter_mut().enumerate() {
+        let mut wp = WeightedPredictor::new(wp_header, width);
+        for y in 0..height {
+            for x in 0..width {
+                let index = indices.row(y)[x];
+                let mut value = palette_value(palette, index, c, bit_depth);
+                if index < transform.num_deltas as i32 {
+                    let n = Neighbors::new(&channel.data, x, y);
+                    let weighted = if predictor == Predictor::Weighted {
+                        wp.predict(x, y, &n).0
+                    } else {
+                        0
+                    };
+                    value += predictor.predict(&n, weighted);
+                }
+                channel.data.row_mut(y)[x] = value as i32;
+                if predictor == Predictor::Weighted {
+                    wp.update(value as i32, x, y);
+                }
+            }
+        }
+    }
+    channels.remove(0);
+    Ok(())
+}
+
+/// The difference between two squeezed samples that a smooth ramp from
+/// `left` through `avg` to `next` would have, if the three are monotonic.
+fn smooth_tendency(left: i64, avg: i64, next: i64) -> i64 {
+    if left >= avg && avg >= next {
+        let mut diff = (4 * left - 3 * next - avg + 6) / 12;
+        if diff - (diff & 1) > 2 * (left - avg) {
+            diff = 2 * (left - avg) + 1;
+        }
+        if diff + (diff & 1) > 2 * (avg - next) {
+            diff = 2 * (avg - next);
+        }
+        diff
+    } else if left <= avg && avg <= next {
+        let mut diff = (4 * left - 3 * next - avg - 6) / 12;
+        if diff + (diff & 1) < 2 * (left - avg) {
+            diff = 2 * (left - avg) - 1;
+        }
+        if diff - (diff & 1) < 2 * (avg - next) {
+            diff = 2 * (avg - next);
+        }
+        diff
+    } else {
+        0
+    }
+}
+
+/// Undoes one squeeze of `avg`, interleaving its samples with the ones
+/// reconstructed from `residual`.
+fn inverse_squeeze_channel(
+    avg: &Channel,
+    residual: &Channel,
+    horizontal: bool,
+) -> Result<Channel, Error> {
+    // Vertical squeezes are horizontal ones on the transposed channels.
+    let (avg_data, residual_data) = if horizontal {
+        (avg.data.clone(), residual.data.clone())
+    } else {
+        (avg.data.transpose(), residual.data.transpose())
+    };
+    let (avg_width, residual_width) = (avg_data.width(), residual_data.width());
+    let width = avg_width + residual_width;
+    let mut out = Image::new(width, avg_data.height())?;
+    for y in 0..avg_data.height() {
+        let (avg_row, residual_row) = (avg_data.row(y), residual_data.row(y));
+        let out_row = out.row_mut(y);
+        for x in 0..residual_width {
+            let avg = avg_row[x] as i64;
+            let next_avg = avg_row.get(x + 1).map_or(avg, |&v| v as i64);
+            let left = if x > 0 {
+                out_row[2 * x - 1] as i64
+            } else {
+                avg
+            };
+            let diff = residual_row[x] as i64 + smooth_tendency(left, avg, next_avg);
+            let first = avg + diff / 2;
+            out_row[2 * x] = first as i32;
+            out_row[2 * x + 1] = (first - diff) as i32;
+        }
+        if width % 2 == 1 {
+            out_row[width - 1] = avg_row[avg_width - 1];
+        }
+    }
+    let unshift = |shift: i32| if shift > 0 { shift - 1 } else { shift };
+    Ok(if horizontal {
+        Channel::new(out, unshift(avg.hshift), avg.vshift)
+    } else {
+        Channel::new(out.transpose(), avg.hshift, unshift(avg.vshift))
+    })
+}
+
+/// Undoes the squeezes of a squeeze transform, last one first, merging each
+/// squeezed channel with its residuals.
+pub fn inverse_squeeze(
+    channels: &mut Vec<Channel>,
+    squeezes: &[SqueezeParams],
+) -> Result<(), Error> {
+    for params in squeezes.iter().rev() {
+        let begin = params.begin_channel as usize;
+        let num = params.num_channels as usize;
+        let error = || Error::InvalidTransformChannels(begin as u32, num as u32);
+        if begin + 2 * num > channels.len() {
+            return Err(error());
+        }
+        let offset = if params.in_place {
+            begin + num
+        } else {
+            channels.len() - num
+        };
+        if offset < begin + num {
+            return Err(error());
+        }
+        for c in begin..begin + num {
+            let (avg, residual) = (&channels[c], &channels[offset + c - begin]);
+            let (avg_size, residual_size, other_size, other_residual_size) = if params.horizontal {
+                let (a, r) = (&avg.data, &residual.data);
+                (a.width(), r.width(), a.height(), r.height())
+            } else {
+                let (a, r) = (&avg.data, &residual.data);
+                (a.height(), r.height(), a.width(), r.width())
+            };
+            if other_size != other_residual_size
+                || avg_size < residual_size
+                || avg_size > residual_size + 1
+            {
+                return Err(Error::InvalidSqueeze(c as u32));
+            }
+            channels[c] = inverse_squeeze_channel(avg, residual, params.horizontal)?;
+        }
+        channels.drain(offset..offset + num);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::Image;
+
+    fn channels(values: [i32; 3]) -> Vec<Channel> {
+        values
+            .iter()
+            .map(|&v| Channel::new(Image::from_vec(1, 1, vec![v]).unwrap(), 0, 0))
+            .collect()
+    }
+
+    fn samples(channels: &[Channel]) -> Vec<i32> {
+        channels.iter().map(|c| c.data.row(0)[0]).collect()
+    }
+
+    #[test]
+    fn test_inverse_rct() -> Result<(), Error> {
+        let cases = [
+            // No decorrelation, channels rotated from GBR.
+            (7, [2, 3, 1], [1, 2, 3]),
+            // Second minus first, third minus first.
+            (3, [10, -2, 5], [10, 8, 15]),
+            // Second minus the average of first and third.
+            (4, [10, 1, 20], [10, 16, 20]),
+            // YCgCo.
+            (6, [5, -2, 4], [2, 7, 4]),
+        ];
+        for (rct_type, input, expected) in cases {
+            let mut channels = channels(input);
+            inverse_rct(&mut channels, 0, rct_type)?;
+            assert_eq!(samples(&channels), expected, "RCT {}", rct_type);
+        }
+        assert!(matches!(
+            inverse_rct(&mut channels([0; 3]), 1, 6),
+            Err(Error::InvalidTransformChannels(1, 3))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_palette_value() {
+        let palette = Image::from_vec(2, 3, vec![0; 6]).unwrap();
+        let values = |index, bit_depth| -> Vec<i64> {
+            (0..4)
+                .map(|c| palette_value(&palette, index, c, bit_depth))
+                .collect()
+        };
+        // Deltas, alternately negated.
+        assert_eq!(values(-1, 8), [0, 0, 0, 0]);
+        assert_eq!(values(-2, 8), [4, 4, 4, 0]);
+        assert_eq!(values(-3, 8), [-4, -4, -4, 0]);
+        assert_eq!(values(-4, 10), [44, 0, 0, 0]);
+        // The small cube, offset by half a step, then the large one.
+        assert_eq!(values(2, 8), [32, 32, 32, 0]);
+        assert_eq!(values(2 + 1 + 2 * 4 + 3 * 16, 8), [95, 159, 223, 0]);
+        assert_eq!(values(2 + 64 + 4 + 2 * 5 + 25, 8), [255, 127, 63, 0]);
+    }
+
+    #[test]
+    fn test_inverse_palette() -> Result<(), Error> {
+        // A single delta of 5, added to the left neighbor, then an index past
+        // the palette.
+        let mut channels = vec![
+            Channel::new(Image::from_vec(1, 1, vec![5])?, -1, 0),
+            Channel::new(Image::from_vec(3, 1, vec![0, 0, 1])?, 0, 0),
+        ];
+        let palette = Transform {
+            id: TransformId::Palette,
+            begin_channel: 0,
+            rct_type: 6,
+            num_channels: 1,
+            num_colors: 0,
+            num_deltas: 1,
+            predictor: Predictor::Left as u32,
+            squeezes: vec![],
+        };
+        inverse_palette(&mut channels, &palette, &WeightedHeader::default(), 8)?;
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].data.row(0), [5, 10, 32]);
+        Ok(())
+    }
+
+    fn shape(width: usize, height: usize) -> ChannelShape {
+        ChannelShape {
+            width,
+            height,
+            hshift: 0,
+            vshift: 0,
+        }
+    }
+
+    #[test]
+    fn test_default_squeezes() -> Result<(), Error> {
+        let mut shapes = vec![shape(20, 10); 3];
+        let mut squeeze = Transform {
+            id: TransformId::Squeeze,
+            begin_channel: 0,
+            rct_type: 6,
+            num_channels: 3,
+            num_colors: 256,
+            num_deltas: 0,
+            predictor: 0,
+            squeezes: vec![],
+        };
+        let mut num_meta = 0;
+        meta_apply(&mut squeeze, &mut shapes, &mut num_meta)?;
+        let steps: Vec<_> = squeeze
+            .squeezes
+            .iter()
+            .map(|s| (s.horizontal, s.in_place, s.begin_channel, s.num_channels))
+            .collect();
+        // Chroma first, then everything until the first channel fits in 8x8.
+        assert_eq!(
+            steps,
+            [
+                (true, false, 1, 2),
+                (false, false, 1, 2),
+                (true, true, 0, 3),
+                (false, true, 0, 3),
+                (true, true, 0, 3),
+            ]
+        );
+        assert_eq!(num_meta, 0);
+        assert_eq!(shapes.len(), 16);
+        assert_eq!(
+            shapes[0],
+            ChannelShape {
+                width: 5,
+                height: 5,
+                hshift: 2,
+                vshift: 1,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_inverse_squeeze() -> Result<(), Error> {
+        // A vertical squeeze of a 1x3 channel: averages 4 and 0 with a
+        // residual of 1. The tendency from 4 down to 0 is 1, so the first
+        // pair differs by 2.
+        let mut channels = vec![
+            Channel::new(Image::from_vec(1, 2, vec![4, 0])?, 0, 1),
+            Channel::new(Image::from_vec(1, 1, vec![1])?, 0, 1),
+        ];
+        let squeezes = [SqueezeParams {
+            horizontal: false,
+            in_place: false,
+            begin_channel: 0,
+            num_channels: 1,
+        }];
+        inverse_squeeze(&mut channels, &squeezes)?;
+        assert_eq!(
+            channels,
+            [Channel::new(Image::from_vec(1, 3, vec![5, 3, 0])?, 0, 0)]
+        );
+
+        // Residuals must fit the channel they belong to.
+        let mut channels = vec![
+            Channel::new(Image::from_vec(1, 1, vec![4])?, 0, 1),
+            Channel::new(Image::from_vec(1, 2, vec![0, 0])?, 0, 1),
+        ];
+        assert!(matches!(
+            inverse_squeeze(&mut channels, &squeezes),
+            Err(Error::InvalidSqueeze(0))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use crate::bit_reader::BitReader;
+use crate::entropy_coding::decode::Histograms;
+use crate::error::Error;
+use crate::modular::predict::Predictor;
+use num_traits::FromPrimitive;
+
+const SPLIT_VALUE_CONTEXT: usize = 0;
+const PROPERTY_CONTEXT: usize = 1;
+const PREDICTOR_CONTEXT: usize = 2;
+const OFFSET_CONTEXT: usize = 3;
+const MULTIPLIER_LOG_CONTEXT: usize = 4;
+const MULTIPLIER_BITS_CONTEXT: usize = 5;
+const NUM_TREE_CONTEXTS: usize = 6;
+
+/// Properties that do not depend on previous channels: channel index, group
+/// id, position, neighbors, local gradients and the weighted predictor error.
+pub const NUM_NONREF_PROPERTIES: usize = 16;
+/// Properties added for each previous channel of the same size.
+pub const NUM_REF_PROPERTIES: usize = 4;
+
+pub fn unpack_signed(value: u32) -> i32 {
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
+
+/// How the samples that reach a leaf are predicted and coded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Leaf {
+    /// Context of the residuals in the histograms that follow the tree.
+    pub context: usize,
+    pub predictor: Predictor,
+    pub offset: i32,
+    pub multiplier: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Node {
+    /// Goes to `left` if the property is greater than `value`, to `right`
+    /// otherwise.
+    Split {
+        property: usize,
+        value: i32,
+        left: usize,
+        right: usize,
+    },
+    Leaf(Leaf),
+}
+
+/// A meta-adaptive tree, which picks the context and predictor of every sample
+/// from its properties.
+#[derive(Debug)]
+pub struct Tree {
+    nodes: Vec<Node>,
+    num_properties: usize,
+}
+
+impl Tree {
+    /// Reads a tree of at most `max_size` nodes.
+    pub fn read(br: &mut BitReader, max_size: usize) -> Result<Tree, Error> {
+        let histograms = Histograms::decode(NUM_TREE_CONTEXTS, br, /*allow_lz77=*/ true)?;
+        let mut reader = histograms.make_reader(br)?;
+        let mut nodes = vec![];
+        let mut num_properties = NUM_NONREF_PROPERTIES;
+        let mut num_leaves = 0;
+        let mut to_decode = 1;
+        while to_decode > 0 {
+            if nodes.len() >= max_size {
+                return Err(Error::TreeTooLarge(max_size));
+            }
+            to_decode -= 1;
+            let property = reader.read(br, PROPERTY_CONTEXT)?;
+            if property > 256 {
+                return Err(Error::InvalidProperty(property));
+            }
+            if property == 0 {
+                let predictor = reader.read(br, PREDICTOR_CONTEXT)?;
+                let predictor =
+                    Predictor::from_u32(predictor).ok_or(Error::InvalidPredictor(predictor))?;
+                let offset = unpack_signed(reader.read(br, OFFSET_CONTEXT)?);
+                let multiplier_log = reader.read(br, MULTIPLIER_LOG_CONTEXT)?;
+                if multiplier_log >= 31 {
+                    return Err(Error::InvalidMultiplier(multiplier_log, 0));
+                }
+                let multiplier_bits = reader.read(br, MULTIPLIER_BITS_CONTEXT)?;
+                if multiplier_bits >= (1 << (31 - multiplier_log)) - 1 {
+                    return Err(Error::InvalidMultiplier(multiplier_log, multiplier_bits));
+                }
+                nodes.push(Node::Leaf(Leaf {
+                    context: num_leaves,
+                    predictor,
+                    offset,
+                    multiplier: (multiplier_bits + 1) << multiplier_log,
+                }));
+                num_leaves += 1;
+            } else {
+                let property = property as usize - 1;
+                num_properties = num_properties.max(property + 1);
+                let value = unpack_signed(reader.read(br, SPLIT_VALUE_CONTEXT)?);
+                // Children come after the nodes still to be read at this depth.
+                let left = nodes.len() + to_decode + 1;
+                nodes.push(Node::Split {
+                    property,
+                    value,
+                    left,
+                    right: left + 1,
+                });
+                to_decode += 2;
+            }
+        }
+        reader.check_final_state()?;
+        Ok(Tree {
+            nodes,
+            num_properties,
+        })
+    }
+
+    /// Number of leaves, which is also the number of contexts of the
+    /// histograms for the samples.
+    pub fn num_contexts(&self) -> usize {
+        self.nodes.len().div_ceil(2)
+    }
+
+    /// Number of properties the tree may look at, including the ones of
+    /// previous channels.
+    pub fn num_properties(&self) -> usize {
+        self.num_properties
+    }
+
+    /// Returns the leaf reached by a sample with the given properties, which
+    /// must have at least [`Tree::num_properties`] elements.
+    pub fn leaf(&self, properties: &[i32]) -> &Leaf {
+        let mut node = 0;
+        loop {
+            match &self.nodes[node] {
+                Node::Split {
+                    property,
+                    value,
+                    left,
+                    right,
+                } => {
+                    node = if properties[*property] > *value {
+                        *left
+                    } else {
+                        *right
+                    };
+                }
+                Node::Leaf(leaf) => return leaf,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_writer::BitWriter;
+
+    #[test]
+    fn test_unpack_signed() {
+        let unpacked: Vec<i32> = (0..6).map(unpack_signed).collect();
+        assert_eq!(unpacked, [0, -1, 1, -2, 2, -3]);
+        assert_eq!(unpack_signed(u32::MAX), i32::MIN);
+    }
+
+    // Writes a tree whose contexts each have one histogram with a single
+    // symbol, so that no bits are read per symbol.
+    fn single_symbol_tree(property: u32) -> Vec<u8> {
+        let mut bw = BitWriter::new();
+        // No LZ77, simple context map with one histogram per context.
+        bw.write(1, 0);
+        bw.write(1, 1);
+        bw.write(2, 3);
+        for ctx in 0..NUM_TREE_CONTEXTS {
+            bw.write(3, ctx as u64);
+        }
+        // Prefix codes, symbols are read as is.
+        bw.write(1, 1);
+        for _ in 0..NUM_TREE_CONTEXTS {
+            bw.write(4, 15);
+        }
+        // Alphabet sizes of 512, with symbols 0, except for the property.
+        for _ in 0..NUM_TREE_CONTEXTS {
+            bw.write(1, 1);
+            bw.write(4, 8);
+            bw.write(8, 0xff);
+        }
+        for ctx in 0..NUM_TREE_CONTEXTS {
+            bw.write(2, 1);
+            bw.write(2, 0);
+            let symbol = if ctx == PROPERTY_CONTEXT { property } else { 0 };
+            bw.write(9, symbol as u64);
+        }
+        bw.finalize()
+    }
+
+    #[test]
+    fn test_read() -> Result<(), Error> {
+        let data = single_symbol_tree(0);
+        let tree = Tree::read(&mut BitReader::new(&data), 10)?;
+        assert_eq!(tree.num_contexts(), 1);
+        assert_eq!(
+            *tree.leaf(&[0; NUM_NONREF_PROPERTIES]),
+            Leaf {
+                context: 0,
+                predictor: Predictor::Zero,
+                offset: 0,
+                multiplier: 1,
+            }
+        );
+
+        // Every node splits, so the tree never ends.
+        let data = single_symbol_tree(1);
+        assert!(matches!(
+            Tree::read(&mut BitReader::new(&data), 10),
+            Err(Error::TreeTooLarge(10))
+        ));
+        let data = single_symbol_tree(300);
+        assert!(matches!(
+            Tree::read(&mut BitReader::new(&data), 10),
+            Err(Error::InvalidProperty(300))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+//! Helpers to build small codestreams in unit tests.
+
+use crate::bit_writer::BitWriter;
+use crate::headers::encodings::{Empty, UnconditionalCoder};
+use crate::headers::frame_header::FrameType;
+
+/// Writes `value` with a `U32` coder whose distributions are `BitsOffset`s
+/// with the given bit counts and offsets, picking the first one that fits.
+fn write_u32(w: &mut BitWriter, value: u32, distributions: [(usize, u32); 4]) {
+    let (selector, (bits, offset)) = distributions
+        .iter()
+        .enumerate()
+        .find(|(_, (bits, offset))| value >= *offset && ((value - offset) as u64) < 1u64 << bits)
+        .expect("value not representable");
+    w.write(2, selector as u64);
+    w.write(*bits, (value - offset) as u64);
+}
+
+fn write_bool(w: &mut BitWriter, value: bool) {
+    w.write(1, value as u64);
+}
+
+/// A frame of a synthetic codestream. Section payloads are copied verbatim.
+pub(crate) struct TestFrame {
+    pub(crate) sections: Vec<Vec<u8>>,
+    pub(crate) group_size_shift: u32,
+    pub(crate) frame_type: FrameType,
+    /// Number of passes, and `(downsample, last_pass)` pairs.
+    pub(crate) passes: (u32, Vec<(u32, u32)>),
+    /// Blending mode (0 = Replace, 1 = Add, 2 = Blend) onto reference slot 0.
+    pub(crate) blending_mode: u32,
+    pub(crate) save_as_reference: u32,
+    /// Frame header flags, at most 16.
+    pub(crate) flags: u64,
+}
+
+impl TestFrame {
+    pub(crate) fn new(sections: Vec<Vec<u8>>) -> TestFrame {
+        TestFrame {
+            sections,
+            group_size_shift: 1,
+            frame_type: FrameType::RegularFrame,
+            passes: (1, vec![]),
+            blending_mode: 0,
+            save_as_reference: 0,
+            flags: 0,
+        }
+    }
+
+    pub(crate) fn frame_type(mut self, frame_type: FrameType) -> TestFrame {
+        self.frame_type = frame_type;
+        self
+    }
+
+    pub(crate) fn passes(mut self, num_passes: u32, downsampling: Vec<(u32, u32)>) -> TestFrame {
+        self.passes = (num_passes, downsampling);
+        self
+    }
+
+    pub(crate) fn blending(mut self, mode: u32, save_as_reference: u32) -> TestFrame {
+        self.blending_mode = mode;
+        self.save_as_reference = save_as_reference;
+        self
+    }
+
+    pub(crate) fn flags(mut self, flags: u64) -> TestFrame {
+        self.flags = flags;
+        self
+    }
+}
+
+/// Builds codestreams with modular frames and otherwise default settings.
+pub(crate) struct CodestreamBuilder {
+    xsize: u32,
+    ysize: u32,
+    xyb_encoded: bool,
+    frames: Vec<TestFrame>,
+}
+
+const SIZE_DIST: [(usize, u32); 4] = [(9, 1), (13, 1), (18, 1), (30, 1)];
+
+impl CodestreamBuilder {
+    pub(crate) fn new(xsize: u32, ysize: u32) -> CodestreamBuilder {
+        CodestreamBuilder {
+            xsize,
+            ysize,
+            xyb_encoded: false,
+            frames: vec![],
+        }
+    }
+
+    pub(crate) fn xyb_encoded(mut self, xyb_encoded: bool) -> CodestreamBuilder {
+        self.xyb_encoded = xyb_encoded;
+        self
+    }
+
+    pub(crate) fn frame(mut self, frame: TestFrame) -> CodestreamBuilder {
+        self.frames.push(frame);
+        self
+    }
+
+    fn write_file_headers(&self, w: &mut BitWriter) {
+        w.write(8, 0xFF);
+        w.write(8, 0x0A);
+        // Size: not small, no aspect ratio.
+        write_bool(w, false);
+        write_u32(w, self.ysize, SIZE_DIST);
+        w.write(3, 0);
+        write_u32(w, self.xsize, SIZE_DIST);
+        // ImageMetadata: 8-bit, no extra fields, default color encoding.
+        write_bool(w, false); // all_default
+        write_bool(w, false); // extra_fields
+        write_bool(w, false); // float_sample
+        w.write(2, 0); // bits_per_sample = 8
+        write_bool(w, true); // modular_16bit_sufficient
+        w.write(2, 0); // no extra channels
+        write_bool(w, self.xyb_encoded);
+        write_bool(w, true); // color_encoding.all_default
+        w.write(2, 0); // extensions
+                       // CustomTransformData
+        write_bool(w, true);
+    }
+
+    fn write_frame(&self, w: &mut BitWriter, frame: &TestFrame, is_last: bool) {
+        w.zero_pad_to_byte();
+        let frame_type = frame.frame_type;
+        let is_displayed = matches!(
+            frame_type,
+            FrameType::RegularFrame | FrameType::SkipProgressive
+        );
+        write_bool(w, false); // all_default
+        frame_type.write_unconditional(&(), w, &Empty {}).unwrap();
+        w.write(1, 1); // encoding = Modular
+        if frame.flags == 0 {
+            w.write(2, 0);
+        } else {
+            w.write(2, 1);
+            w.write(4, frame.flags - 1);
+        }
+        if !self.xyb_encoded {
+            write_bool(w, false); // do_ycbcr
+        }
+        w.write(2, 0); // upsampling = 1
+        w.write(2, frame.group_size_shift as u64);
+        if frame_type != FrameType::ReferenceOnly {
+            let (num_passes, ref downsampling) = frame.passes;
+            write_u32(w, num_passes, [(0, 1), (0, 2), (0, 3), (3, 4)]);
+            if num_passes != 1 {
+                write_u32(
+                    w,
+                    downsampling.len() as u32,
+                    [(0, 0), (0, 1), (0, 2), (1, 3)],
+                );
+                for _ in 1..num_passes {
+                    w.write(2, 0); // shift
+                }
+                for (downsample, _) in downsampling.iter() {
+                    write_u32(w, *downsample, [(0, 1), (0, 2), (0, 4), (0, 8)]);
+                }
+                for (_, last_pass) in downsampling.iter() {
+                    write_u32(w, *last_pass, [(0, 0), (0, 1), (0, 2), (3, 0)]);
+                }
+            }
+        }
+        if frame_type == FrameType::LFFrame {
+            w.write(2, 0); // lf_level = 1
+        } else {
+            write_bool(w, false); // have_crop
+        }
+        if is_displayed {
+            w.write(2, frame.blending_mode as u64);
+            write_bool(w, is_last);
+        }
+        if frame_type != FrameType::LFFrame && !is_last {
+            w.write(2, frame.save_as_reference as u64);
+            if frame_type == FrameType::ReferenceOnly || frame.blending_mode == 0 {
+                write_bool(w, false); // save_before_ct
+            }
+        }
+        w.write(2, 0); // empty name
+        write_bool(w, true); // restoration_filter.all_default
+        w.write(2, 0); // extensions
+
+        // TOC
+        write_bool(w, false);
+        w.zero_pad_to_byte();
+        for section in frame.sections.iter() {
+            write_u32(
+                w,
+                section.len() as u32,
+                [(10, 0), (14, 1024), (22, 17408), (30, 4211712)],
+            );
+        }
+        w.zero_pad_to_byte();
+        for section in frame.sections.iter() {
+            w.append_bytes(section);
+        }
+    }
+
+    pub(crate) fn build(&self) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        self.write_file_headers(&mut w);
+        for (i, frame) in self.frames.iter().enumerate() {
+            self.write_frame(&mut w, frame, i + 1 == self.frames.len());
+        }
+        w.finalize()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_reader::BitReader;
+    use crate::decode::decode_metadata;
+
+    #[test]
+    fn test_write_u32() {
+        let mut w = BitWriter::new();
+        w.write(3, 5);
+        w.write(12, 0xABC);
+        write_u32(
+            &mut w,
+            1500,
+            [(10, 0), (14, 1024), (22, 17408), (30, 4211712)],
+        );
+        let data = w.finalize();
+        let mut br = BitReader::new(&data);
+        assert_eq!(br.read(3).unwrap(), 5);
+        assert_eq!(br.read(12).unwrap(), 0xABC);
+        assert_eq!(br.read(2).unwrap(), 1);
+        assert_eq!(br.read(14).unwrap(), 1500 - 1024);
+    }
+
+    #[test]
+    fn test_build() {
+        let file = CodestreamBuilder::new(300, 260)
+            .frame(TestFrame::new(vec![vec![1; 5]; 7]))
+            .frame(TestFrame::new(vec![vec![2; 1030]; 7]))
+            .build();
+        let structure = decode_metadata(&file).unwrap();
+        assert_eq!(structure.headers.size.xsize(), 300);
+        assert_eq!(structure.headers.size.ysize(), 260);
+        assert_eq!(structure.frames.len(), 2);
+        assert_eq!(structure.frames[0].toc.entries, vec![5; 7]);
+        let last = &structure.frames[1];
+        assert_eq!(last.toc.entries, vec![1030; 7]);
+        assert_eq!(last.sections_offset + 7 * 1030, file.len());
+
+        let file = CodestreamBuilder::new(64, 64)
+            .xyb_encoded(true)
+            .frame(TestFrame::new(vec![vec![3; 10]]))
+            .build();
+        let structure = decode_metadata(&file).unwrap();
+        assert!(structure.headers.image_metadata.xyb_encoded);
+        assert_eq!(structure.frames[0].toc.entries, vec![10]);
+    }
+}
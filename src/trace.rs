@@ -0,0 +1,147 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+//! Opt-in recording of parsed syntax elements, for diffing bitstream parsing
+//! against other decoders.
+
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::io::Write;
+
+/// A syntax element, as it was read from the bitstream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    /// Qualified name of the element, e.g. `FrameHeader.frame_type`.
+    pub name: String,
+    /// Position of the first bit of the element, relative to the start of the
+    /// bit reader that read it.
+    pub bit_offset: usize,
+    /// `Debug` representation of the decoded value.
+    pub value: String,
+}
+
+thread_local! {
+    static ENTRIES: RefCell<Option<Vec<TraceEntry>>> = const { RefCell::new(None) };
+}
+
+/// Records the syntax elements parsed on the current thread while it is alive.
+pub struct TraceRecorder {
+    // Recording is per thread, so the recorder must stay on its thread.
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl TraceRecorder {
+    /// Starts recording, discarding any recording already in progress.
+    pub fn start() -> TraceRecorder {
+        ENTRIES.with(|e| *e.borrow_mut() = Some(vec![]));
+        TraceRecorder {
+            _not_send: std::marker::PhantomData,
+        }
+    }
+
+    /// Stops recording and returns the elements parsed since `start`.
+    pub fn finish(self) -> Vec<TraceEntry> {
+        ENTRIES.with(|e| e.borrow_mut().take()).unwrap_or_default()
+    }
+}
+
+impl Drop for TraceRecorder {
+    fn drop(&mut self) {
+        ENTRIES.with(|e| e.borrow_mut().take());
+    }
+}
+
+pub(crate) fn record<T: Debug + ?Sized>(name: &str, bit_offset: usize, value: &T) {
+    ENTRIES.with(|e| {
+        if let Some(entries) = e.borrow_mut().as_mut() {
+            entries.push(TraceEntry {
+                name: name.to_string(),
+                bit_offset,
+                value: format!("{:?}", value),
+            });
+        }
+    });
+}
+
+fn write_json_string<W: Write>(out: &mut W, s: &str) -> std::io::Result<()> {
+    write!(out, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(out, "\\\"")?,
+            '\\' => write!(out, "\\\\")?,
+            '\n' => write!(out, "\\n")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    write!(out, "\"")
+}
+
+/// Writes `entries` as a JSON array of `{"name", "bit_offset", "value"}` objects,
+/// one per line.
+pub fn write_json<W: Write>(entries: &[TraceEntry], out: &mut W) -> std::io::Result<()> {
+    writeln!(out, "[")?;
+    for (i, entry) in entries.iter().enumerate() {
+        write!(out, "  {{\"name\": ")?;
+        write_json_string(out, &entry.name)?;
+        write!(out, ", \"bit_offset\": {}, \"value\": ", entry.bit_offset)?;
+        write_json_string(out, &entry.value)?;
+        let separator = if i + 1 == entries.len() { "" } else { "," };
+        writeln!(out, "}}{}", separator)?;
+    }
+    writeln!(out, "]")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_reader::BitReader;
+    use crate::headers::{FileHeaders, JxlHeader};
+
+    #[test]
+    fn test_trace_headers() {
+        let data = [
+            0xFF, 0x0A, 0x00, 0x90, 0x01, 0x00, 0x12, 0x88, 0x02, 0x00, 0xD4, 0x00,
+        ];
+        let recorder = TraceRecorder::start();
+        FileHeaders::read(&mut BitReader::new(&data)).unwrap();
+        let entries = recorder.finish();
+        assert_eq!(entries[0].name, "FileHeaders.signature");
+        assert_eq!(entries[0].bit_offset, 0);
+        assert_eq!(entries[1].name, "Size.small");
+        assert_eq!(entries[1].bit_offset, 16);
+        assert_eq!(entries[1].value, "false");
+        assert!(entries
+            .iter()
+            .any(|e| e.name == "FileHeaders.image_metadata"));
+
+        // Nothing is recorded once the recorder is gone.
+        FileHeaders::read(&mut BitReader::new(&data)).unwrap();
+        assert!(TraceRecorder::start().finish().is_empty());
+    }
+
+    #[test]
+    fn test_write_json() {
+        let entries = vec![
+            TraceEntry {
+                name: "A.b".to_string(),
+                bit_offset: 3,
+                value: "\"x\"".to_string(),
+            },
+            TraceEntry {
+                name: "A.c".to_string(),
+                bit_offset: 5,
+                value: "1".to_string(),
+            },
+        ];
+        let mut out = vec![];
+        write_json(&entries, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[\n  {\"name\": \"A.b\", \"bit_offset\": 3, \"value\": \"\\\"x\\\"\"},\n  \
+             {\"name\": \"A.c\", \"bit_offset\": 5, \"value\": \"1\"}\n]\n"
+        );
+    }
+}
@@ -3,6 +3,8 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
+pub mod safe_arith;
+
 pub trait FloorLog2 {
     fn floor_log2(&self) -> Self;
 }
@@ -18,6 +20,13 @@ impl FloorLog2 for u32 {
     }
 }
 
+impl FloorLog2 for u64 {
+    fn floor_log2(&self) -> Self {
+        debug_assert_ne!(*self, 0);
+        (0u64.leading_zeros() - self.leading_zeros() - 1) as u64
+    }
+}
+
 impl FloorLog2 for usize {
     fn floor_log2(&self) -> Self {
         debug_assert_ne!(*self, 0);
@@ -0,0 +1,53 @@
+// Copyright (c) the JPEG XL Project Authors. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+//! Checked arithmetic for sizes and offsets derived from untrusted header fields.
+
+use crate::error::Error;
+
+pub trait SafeArith: Sized {
+    fn safe_add(self, rhs: Self) -> Result<Self, Error>;
+    fn safe_mul(self, rhs: Self) -> Result<Self, Error>;
+    /// Shifts left, failing if any set bit would be shifted out.
+    fn safe_shl(self, rhs: u32) -> Result<Self, Error>;
+}
+
+macro_rules! impl_safe_arith {
+    ($($ty:ty),*) => {
+        $(
+            impl SafeArith for $ty {
+                fn safe_add(self, rhs: Self) -> Result<Self, Error> {
+                    self.checked_add(rhs).ok_or(Error::ArithmeticOverflow)
+                }
+                fn safe_mul(self, rhs: Self) -> Result<Self, Error> {
+                    self.checked_mul(rhs).ok_or(Error::ArithmeticOverflow)
+                }
+                fn safe_shl(self, rhs: u32) -> Result<Self, Error> {
+                    self.checked_shl(rhs)
+                        .filter(|ret| ret >> rhs == self)
+                        .ok_or(Error::ArithmeticOverflow)
+                }
+            }
+        )*
+    };
+}
+
+impl_safe_arith!(u32, u64, usize);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_safe_arith() {
+        assert_eq!(3u32.safe_add(4).unwrap(), 7);
+        assert!(u32::MAX.safe_add(1).is_err());
+        assert_eq!(1usize << 40, (1usize << 20).safe_mul(1 << 20).unwrap());
+        assert!((1u32 << 16).safe_mul(1 << 16).is_err());
+        assert_eq!(1u32.safe_shl(31).unwrap(), 1 << 31);
+        assert!(2u32.safe_shl(31).is_err());
+        assert!(1u32.safe_shl(32).is_err());
+    }
+}